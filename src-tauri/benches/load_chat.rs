@@ -0,0 +1,43 @@
+//! 比较最初版本每条消息一个 `{index:04}.message` 文件与对话日志两种格式下加载 5000 条消息的对话
+//!
+//! 运行：`cargo bench --features bench --bench load_chat`
+
+use note_secretary_lib::bench::{load_chat, migrate_chat, write_legacy_chat};
+use std::time::{Duration, Instant};
+
+const MESSAGE_COUNT: u32 = 5000;
+const ROUNDS: u32 = 10;
+
+fn measure(name: &str, mut f: impl FnMut()) {
+  // 预热一次，避免首次读取的文件系统缓存影响结果
+  f();
+  let mut total = Duration::ZERO;
+  for _ in 0..ROUNDS {
+    let start = Instant::now();
+    f();
+    total += start.elapsed();
+  }
+  println!("{name}: {:?}/次", total / ROUNDS);
+}
+
+fn main() {
+  let app_data = std::env::temp_dir().join(format!("note-secretary-bench-{}", std::process::id()));
+  let chat_id = "chat-bench";
+  write_legacy_chat(&app_data, chat_id, MESSAGE_COUNT).expect("写入旧版本对话失败");
+
+  measure("旧版本文件 load_chat", || {
+    let count = load_chat(&app_data, chat_id).expect("读取旧版本对话失败");
+    assert_eq!(count, MESSAGE_COUNT as usize);
+  });
+
+  let start = Instant::now();
+  migrate_chat(&app_data, chat_id).expect("迁移对话失败");
+  println!("迁移到对话日志: {:?}", start.elapsed());
+
+  measure("对话日志 load_chat", || {
+    let count = load_chat(&app_data, chat_id).expect("读取对话日志失败");
+    assert_eq!(count, MESSAGE_COUNT as usize);
+  });
+
+  std::fs::remove_dir_all(app_data).expect("清理临时文件夹失败");
+}
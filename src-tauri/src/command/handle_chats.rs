@@ -1,11 +1,21 @@
-use super::DataPath;
+use super::{DataPath, Database};
 use crate::AppDataPath;
-use crate::database::DatabaseHandler;
+use crate::database::{ChangeAction, ChangeEntity, Chat, ChatSearchHit, DatabaseHandler};
 use crate::error::Result;
-use crate::files::{ChatFile, ChatMessage};
+use crate::files::{
+  ChatBranch, ChatDir, ChatDraft, ChatFile, ChatFileStat, ChatGcReport, ChatMessage, ExportFormat,
+  ExtractedText, ImportReport, ImportSource, collect_chat_garbage, extract_chat_file,
+};
+use crate::sync::request_sync;
+use std::path::PathBuf;
 use tauri::ipc::Response;
 use tauri::{AppHandle, Manager, Runtime};
 
+#[tauri::command]
+pub async fn get_all_chats(db: Database<'_>) -> Result<Vec<Chat>> {
+  db.find_all_chats().await
+}
+
 #[tauri::command]
 pub async fn load_chat(path: DataPath<'_>, chat_id: String) -> Result<Response> {
   let messages = ChatMessage::read_all(&path.0, chat_id).await?;
@@ -13,30 +23,264 @@ pub async fn load_chat(path: DataPath<'_>, chat_id: String) -> Result<Response>
   Ok(Response::new(bytes))
 }
 
+/// 分页读取对话，返回 index 小于 `before_index` 的最新 `limit` 条消息
 #[tauri::command]
-pub async fn save_chat_message(path: DataPath<'_>, message: ChatMessage) -> Result<()> {
-  let message_path = message.save(&path.0).await?;
+pub async fn load_chat_page(
+  path: DataPath<'_>,
+  chat_id: String,
+  before_index: Option<u32>,
+  limit: u32,
+) -> Result<Response> {
+  let page = ChatMessage::read_page(&path.0, chat_id, before_index, limit).await?;
+  let bytes = serde_json::to_vec(&page)?;
+  Ok(Response::new(bytes))
+}
+
+/// 读取 index 大于等于 `index` 的消息，用于增量刷新
+#[tauri::command]
+pub async fn load_chat_since(path: DataPath<'_>, chat_id: String, index: u32) -> Result<Response> {
+  let messages = ChatMessage::read_since(&path.0, chat_id, index).await?;
+  let bytes = serde_json::to_vec(&messages)?;
+  Ok(Response::new(bytes))
+}
+
+#[tauri::command]
+pub async fn save_chat_message(
+  path: DataPath<'_>,
+  db: Database<'_>,
+  message: ChatMessage,
+) -> Result<()> {
+  let chat_id = message.chat_id().to_string();
+  let message_id = message.message_id().to_string();
+  let index = message.index();
+  let text = message.text();
+  if let Some(action) = message.save(&path.0).await? {
+    db.index_chat_message(&chat_id, &message_id, index, &text)
+      .await?;
+    db.log_change(ChangeEntity::Chat, &chat_id, Some(&message_id), action)
+      .await?;
+    request_sync();
+  }
+
+  Ok(())
+}
+
+/// 保存流式生成中的消息草稿，最终消息保存后自动删除
+#[tauri::command]
+pub async fn save_chat_draft(path: DataPath<'_>, draft: ChatDraft) -> Result<()> {
+  draft.save(&path.0).await
+}
+
+/// 读取上次运行时中断的消息草稿，用于启动时恢复
+#[tauri::command]
+pub async fn recover_chat_drafts(path: DataPath<'_>) -> Result<Vec<ChatDraft>> {
+  ChatDraft::recover(&path.0).await
+}
+
+#[tauri::command]
+pub async fn discard_chat_draft(
+  path: DataPath<'_>,
+  chat_id: String,
+  message_id: String,
+) -> Result<()> {
+  ChatDraft::discard(&path.0, chat_id, message_id).await
+}
+
+#[tauri::command]
+pub async fn delete_chat(path: DataPath<'_>, db: Database<'_>, chat_id: String) -> Result<()> {
+  ChatDir::new(&path.0, &chat_id)?.delete().await?;
+  db.remove_chat_index(&chat_id, 0).await?;
+  db.delete_chat_by_id(&chat_id).await?;
+  db.log_change(ChangeEntity::Chat, &chat_id, None, ChangeAction::Delete)
+    .await?;
+
+  request_sync();
+  Ok(())
+}
+
+#[tauri::command]
+pub async fn truncate_chat(
+  path: DataPath<'_>,
+  db: Database<'_>,
+  chat_id: String,
+  from_index: u32,
+) -> Result<()> {
+  ChatDir::new(&path.0, &chat_id)?
+    .truncate(from_index)
+    .await?;
+  db.remove_chat_index(&chat_id, from_index).await?;
+  db.log_change(ChangeEntity::Chat, &chat_id, None, ChangeAction::Update)
+    .await?;
+
+  request_sync();
+  Ok(())
+}
 
-  if let Some(_) = message_path {
-    // TODO: 需要通知 s3 同步
+#[tauri::command]
+pub async fn fork_chat(
+  path: DataPath<'_>,
+  db: Database<'_>,
+  chat_id: String,
+  upto_index: u32,
+) -> Result<String> {
+  let new_id = ChatDir::new(&path.0, &chat_id)?.fork(upto_index).await?;
+  db.index_chat(&path.0, &new_id).await?;
+  if let Some(chat) = db.find_chat_by_id(&chat_id).await? {
+    let model = Chat {
+      id: new_id.clone(),
+      title: format!("{}（副本）", chat.title),
+      source: None,
+      ..chat
+    };
+    db.save_chat(model).await?;
   }
+  db.log_change(ChangeEntity::Chat, &new_id, None, ChangeAction::Insert)
+    .await?;
+
+  request_sync();
+  Ok(new_id)
+}
 
+#[tauri::command]
+pub async fn list_chat_branches(
+  path: DataPath<'_>,
+  chat_id: String,
+  message_id: String,
+) -> Result<Vec<ChatBranch>> {
+  ChatDir::new(&path.0, &chat_id)?.branches(message_id).await
+}
+
+#[tauri::command]
+pub async fn switch_chat_branch(
+  path: DataPath<'_>,
+  chat_id: String,
+  message_id: String,
+) -> Result<()> {
+  ChatDir::new(&path.0, &chat_id)?
+    .switch_branch(message_id)
+    .await?;
+
+  // 激活的分支只保存在本机，不需要同步
   Ok(())
 }
 
+#[tauri::command]
+pub async fn search_chats(
+  db: Database<'_>,
+  query: String,
+  limit: Option<u64>,
+) -> Result<Vec<ChatSearchHit>> {
+  db.search_chats(&query, limit.unwrap_or(50)).await
+}
+
+#[tauri::command]
+pub async fn rebuild_chat_index(path: DataPath<'_>, db: Database<'_>) -> Result<()> {
+  db.rebuild_chat_index(&path.0).await
+}
+
+#[tauri::command]
+pub async fn export_chat(
+  path: DataPath<'_>,
+  db: Database<'_>,
+  chat_id: String,
+  format: ExportFormat,
+  target: PathBuf,
+) -> Result<()> {
+  crate::files::export_chat(&path.0, &db, chat_id, format, target).await
+}
+
+#[tauri::command]
+pub async fn import_chats(
+  path: DataPath<'_>,
+  db: Database<'_>,
+  file: PathBuf,
+  source: Option<ImportSource>,
+) -> Result<ImportReport> {
+  let (mut report, chats) = crate::files::import_chats(&path.0, file, source).await?;
+  for chat in chats {
+    let model = Chat {
+      id: chat.chat_id.clone(),
+      title: chat.title.clone(),
+      created_at: chat.created_at,
+      updated_at: chat.updated_at,
+      source: Some(chat.source.name().to_string()),
+    };
+    let result = async {
+      db.save_chat(model).await?;
+      db.index_chat(&path.0, &chat.chat_id).await?;
+      db.log_change(
+        ChangeEntity::Chat,
+        &chat.chat_id,
+        None,
+        ChangeAction::Insert,
+      )
+      .await
+    }
+    .await;
+    // 单个对话失败时撤销该对话已写入的内容，继续导入其他对话
+    if let Err(e) = result {
+      let _ = ChatDir::new(&path.0, &chat.chat_id)?.delete().await;
+      let _ = db.remove_chat_index(&chat.chat_id, 0).await;
+      let _ = db.delete_chat_by_id(&chat.chat_id).await;
+      report.chat_failed(&chat, &e);
+    }
+  }
+
+  request_sync();
+  Ok(report)
+}
+
 #[tauri::command]
 pub async fn read_chat_file(path: DataPath<'_>, file: ChatFile) -> Result<Response> {
   let bytes = file.read(&path.0).await?;
   Ok(Response::new(bytes))
 }
 
+/// 读取对话文件的元数据：原始文件名、MIME 类型、大小、内容标识以及来源
+#[tauri::command]
+pub async fn stat_chat_file(path: DataPath<'_>, file: ChatFile) -> Result<ChatFileStat> {
+  file.stat(&path.0).await
+}
+
+/// 提取对话文件中的文本，并将其加入引用该文件的消息的搜索索引
+#[tauri::command]
+pub async fn extract_chat_file_text(
+  path: DataPath<'_>,
+  db: Database<'_>,
+  chat_id: String,
+  file_id: String,
+) -> Result<ExtractedText> {
+  let text = extract_chat_file(&path.0, chat_id.clone(), file_id).await?;
+  db.index_chat(&path.0, &chat_id).await?;
+  Ok(text)
+}
+
 #[tauri::command]
 pub async fn save_chat_file<R: Runtime>(app: AppHandle<R>, file: ChatFile) -> Result<()> {
   let path = app.state::<AppDataPath>();
   let database = app.state::<DatabaseHandler>();
+  let (chat_id, file_id) = (file.chat_id().to_string(), file.file_id().to_string());
   let _file_path = file.save(&path.0, &database).await?;
+  database
+    .log_change(
+      ChangeEntity::Chat,
+      &chat_id,
+      Some(&file_id),
+      ChangeAction::Insert,
+    )
+    .await?;
 
-  // TODO: 需要通知 s3 同步
+  request_sync();
 
   Ok(())
 }
+
+/// 清理没有被消息引用的文件、空的对话以及失效的缓存，`dry_run` 时只返回将被清理的内容
+#[tauri::command]
+pub async fn collect_chat_files_garbage(
+  path: DataPath<'_>,
+  db: Database<'_>,
+  dry_run: bool,
+) -> Result<ChatGcReport> {
+  collect_chat_garbage(&path.0, &db, dry_run).await
+}
@@ -1,51 +1,230 @@
-use super::Database;
-use crate::database::{Note, NoteSummary};
+use super::{DataPath, Database};
+use crate::database::{Note, NoteDetail, NoteSource, NoteSummary, NoteWebSource, new_note_id};
 use crate::emitter::event;
 use crate::error::{Error, Result};
+use crate::files::{
+  ChatMessage, FETCH_POLICY_KEY, FetchPolicy, clip_web_page, delete_note_files, messages_summary,
+  messages_to_markdown,
+};
+use crate::sync::{ConflictStyle, MergeResult, NoteMerge, merge_text, request_sync};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 const NOTE_CHANGE_EVENT: &str = "notes-change-event";
 
+/// 当前时间，毫秒时间戳
+fn now_millis() -> i64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|it| it.as_millis() as i64)
+    .unwrap_or_default()
+}
+
 #[tauri::command]
 pub async fn get_all_notes(db: Database<'_>, _search: Option<String>) -> Result<Vec<NoteSummary>> {
   db.find_all_notes().await
 }
 
+/// 读取笔记，设置了密码的笔记未解锁时只返回元数据
 #[tauri::command]
-pub async fn get_note_by_id(db: Database<'_>, id: String) -> Result<Note> {
-  db.find_note_by_id(&id)
+pub async fn get_note_by_id(db: Database<'_>, id: String) -> Result<NoteDetail> {
+  db.find_note_detail(&id)
     .await?
     .ok_or(Error::NotFound(format!("note({id})")))
 }
 
+/// 为笔记设置单独的密码
+#[tauri::command]
+pub async fn lock_note(db: Database<'_>, id: String, password: String) -> Result<()> {
+  db.lock_note(&id, password).await?;
+  event(NOTE_CHANGE_EVENT, NOTE_CHANGE_EVENT);
+
+  request_sync();
+  Ok(())
+}
+
+/// 使用笔记密码解锁，一段时间没有访问后自动锁定
+#[tauri::command]
+pub async fn unlock_note(db: Database<'_>, id: String, password: String) -> Result<Note> {
+  db.unlock_note(&id, password).await
+}
+
+/// 立即锁定已解锁的笔记
+#[tauri::command]
+pub fn relock_note(db: Database<'_>, id: String) {
+  db.relock_note(&id)
+}
+
+/// 取消笔记密码
+#[tauri::command]
+pub async fn remove_note_lock(db: Database<'_>, id: String, password: String) -> Result<()> {
+  db.remove_note_lock(&id, password).await?;
+  event(NOTE_CHANGE_EVENT, NOTE_CHANGE_EVENT);
+
+  request_sync();
+  Ok(())
+}
+
 #[tauri::command]
 pub async fn add_note(db: Database<'_>, note: Note) -> Result<()> {
   db.insert_note(&note).await?;
   event(NOTE_CHANGE_EVENT, NOTE_CHANGE_EVENT);
 
-  // TODO: 需要通知 s3 同步
+  request_sync();
   Ok(())
 }
 
+/// 将对话中激活分支上 `from_index` 至 `to_index`（包含）的消息整理为笔记
+#[tauri::command]
+pub async fn add_note_from_chat(
+  path: DataPath<'_>,
+  db: Database<'_>,
+  chat_id: String,
+  category: String,
+  title: String,
+  from_index: Option<u32>,
+  to_index: Option<u32>,
+) -> Result<Note> {
+  let messages = ChatMessage::read_all(&path.0, chat_id.clone()).await?;
+  let from_index = from_index.unwrap_or(0);
+  let to_index = to_index.unwrap_or(u32::MAX);
+  let messages = messages
+    .get(from_index as usize..messages.len().min(to_index as usize + 1))
+    .filter(|it| !it.is_empty())
+    .ok_or_else(|| Error::new("选择的对话消息范围为空"))?;
+
+  let note = Note {
+    id: new_note_id(),
+    category,
+    title,
+    summary: messages_summary(messages),
+    content: messages_to_markdown(messages)?,
+  };
+  let source = NoteSource {
+    note_id: note.id.clone(),
+    chat_id,
+    from_index,
+    to_index: from_index + messages.len() as u32 - 1,
+    created_at: now_millis(),
+  };
+  db.insert_note_with_source(&note, source).await?;
+  event(NOTE_CHANGE_EVENT, NOTE_CHANGE_EVENT);
+
+  request_sync();
+  Ok(note)
+}
+
+#[tauri::command]
+pub async fn get_note_source(db: Database<'_>, id: String) -> Result<Option<NoteSource>> {
+  db.find_note_source(&id).await
+}
+
+/// 剪藏网页为笔记，正文转换为 markdown，图片保存为笔记文件（`![](file-…)`）
+///
+/// 提供 `html` 时直接使用该内容而不请求 `url`
+#[tauri::command]
+pub async fn clip_web_page_to_note(
+  path: DataPath<'_>,
+  db: Database<'_>,
+  url: String,
+  category: String,
+  title: Option<String>,
+  html: Option<String>,
+) -> Result<Note> {
+  let policy = db.get_setting::<FetchPolicy>(FETCH_POLICY_KEY).await?;
+  let note_id = new_note_id();
+
+  let result = async {
+    let page = clip_web_page(&path.0, &policy, &note_id, &url, html).await?;
+    let note = Note {
+      id: note_id.clone(),
+      category,
+      title: title
+        .filter(|it| !it.trim().is_empty())
+        .unwrap_or(page.title),
+      summary: page.summary,
+      content: format!("> 原文：<{}>\n\n{}\n", page.url, page.content),
+    };
+    let source = NoteWebSource {
+      note_id: note.id.clone(),
+      url: page.url,
+      created_at: now_millis(),
+    };
+    db.insert_note_with_web_source(&note, source).await?;
+    Ok(note)
+  }
+  .await;
+
+  // 笔记和来源在同一事务中保存，失败时只需要清理已下载的图片
+  let note = match result {
+    Ok(note) => note,
+    Err(e) => {
+      let _ = delete_note_files(&path.0, &note_id).await;
+      return Err(e);
+    }
+  };
+  event(NOTE_CHANGE_EVENT, NOTE_CHANGE_EVENT);
+
+  request_sync();
+  Ok(note)
+}
+
+#[tauri::command]
+pub async fn get_note_web_source(db: Database<'_>, id: String) -> Result<Option<NoteWebSource>> {
+  db.find_note_web_source(&id).await
+}
+
 #[tauri::command]
 pub async fn modify_note_meta(db: Database<'_>, note: Note) -> Result<()> {
   db.update_note_metadata(&note).await?;
   event(NOTE_CHANGE_EVENT, NOTE_CHANGE_EVENT);
 
-  // TODO: 需要通知 s3 同步
+  request_sync();
   Ok(())
 }
 
 #[tauri::command]
 pub async fn modify_note_content(db: Database<'_>, id: String, content: String) -> Result<()> {
-  db.update_note_content(&id, &content).await
-  // TODO: 需要通知 s3 同步
+  db.update_note_content(&id, &content).await?;
+  request_sync();
+  Ok(())
 }
 
 #[tauri::command]
-pub async fn delete_note_by_id(db: Database<'_>, id: String) -> Result<()> {
+pub async fn delete_note_by_id(path: DataPath<'_>, db: Database<'_>, id: String) -> Result<()> {
   db.delete_note_by_id(&id).await?;
+  delete_note_files(&path.0, &id).await?;
   event(NOTE_CHANGE_EVENT, NOTE_CHANGE_EVENT);
 
-  // TODO: 需要通知 s3 同步
+  request_sync();
   Ok(())
 }
+
+/// 三方合并笔记正文，只返回合并结果，不修改笔记
+#[tauri::command]
+pub fn merge_note_text(
+  base: String,
+  local: String,
+  remote: String,
+  style: Option<ConflictStyle>,
+) -> MergeResult {
+  merge_text(&base, &local, &remote, style.unwrap_or_default())
+}
+
+/// 将另一设备上的修改合并到笔记中，`base` 为两边修改前的共同版本
+///
+/// `conflict_note` 为 `true` 时冲突部分保留本机内容，远程版本另存为冲突笔记
+#[tauri::command]
+pub async fn merge_note(
+  db: Database<'_>,
+  id: String,
+  base: String,
+  remote: String,
+  conflict_note: Option<bool>,
+) -> Result<NoteMerge> {
+  let merge =
+    crate::sync::merge_note(&db, &id, &base, &remote, conflict_note.unwrap_or_default()).await?;
+  event(NOTE_CHANGE_EVENT, NOTE_CHANGE_EVENT);
+
+  request_sync();
+  Ok(merge)
+}
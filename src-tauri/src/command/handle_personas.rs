@@ -1,6 +1,7 @@
 use super::Database;
 use crate::database::Persona;
 use crate::error::Result;
+use crate::sync::request_sync;
 
 #[tauri::command]
 pub async fn get_all_personas(db: Database<'_>) -> Result<Vec<Persona>> {
@@ -9,6 +10,7 @@ pub async fn get_all_personas(db: Database<'_>) -> Result<Vec<Persona>> {
 
 #[tauri::command]
 pub async fn save_persona(db: Database<'_>, persona: Persona) -> Result<()> {
-  // TODO: 保存时应该数据同步到 s3
-  db.save_persona(persona).await
+  db.save_persona(persona).await?;
+  request_sync();
+  Ok(())
 }
@@ -0,0 +1,42 @@
+use super::Database;
+use crate::database::{NOTE_ENCRYPTION_KEY, NoteEncryption, spawn_note_migration};
+use crate::error::Result;
+use crate::files::{CHAT_GC_SCHEDULE_KEY, ChatGcSchedule, FETCH_POLICY_KEY, FetchPolicy};
+use tauri::{AppHandle, Runtime};
+
+#[tauri::command]
+pub async fn get_fetch_policy(db: Database<'_>) -> Result<FetchPolicy> {
+  db.get_setting(FETCH_POLICY_KEY).await
+}
+
+#[tauri::command]
+pub async fn save_fetch_policy(db: Database<'_>, policy: FetchPolicy) -> Result<()> {
+  db.save_setting(FETCH_POLICY_KEY, &policy).await
+}
+
+#[tauri::command]
+pub async fn get_chat_gc_schedule(db: Database<'_>) -> Result<ChatGcSchedule> {
+  db.get_setting(CHAT_GC_SCHEDULE_KEY).await
+}
+
+#[tauri::command]
+pub async fn save_chat_gc_schedule(db: Database<'_>, schedule: ChatGcSchedule) -> Result<()> {
+  db.save_setting(CHAT_GC_SCHEDULE_KEY, &schedule).await
+}
+
+#[tauri::command]
+pub async fn get_note_encryption(db: Database<'_>) -> Result<NoteEncryption> {
+  db.get_setting(NOTE_ENCRYPTION_KEY).await
+}
+
+/// 修改笔记加密设置，已保存的笔记在后台转换
+#[tauri::command]
+pub async fn save_note_encryption<R: Runtime>(
+  app: AppHandle<R>,
+  db: Database<'_>,
+  setting: NoteEncryption,
+) -> Result<()> {
+  db.save_note_encryption(&setting).await?;
+  spawn_note_migration(&app);
+  Ok(())
+}
@@ -0,0 +1,75 @@
+use super::{DataPath, Database};
+use crate::database::{spawn_chat_indexing, spawn_note_migration};
+use crate::error::Result;
+use crate::files::{
+  KeyRotationProgress, StorageKeyStatus, StorageReport, check_storage, key_rotation_progress,
+  recompress_storage, resume_key_rotation, rotate_storage_keys, storage_key_status, unlock_storage,
+};
+use crate::sync::request_sync;
+use tauri::{AppHandle, Runtime};
+
+#[tauri::command]
+pub async fn get_storage_key_status(path: DataPath<'_>) -> Result<StorageKeyStatus> {
+  storage_key_status(&path.0).await
+}
+
+/// 使用密码解锁本地存储，启动时主密钥由密码派生的情况下需要先调用
+#[tauri::command]
+pub async fn unlock_storage_with_passphrase<R: Runtime>(
+  app: AppHandle<R>,
+  path: DataPath<'_>,
+  passphrase: String,
+) -> Result<()> {
+  unlock_storage(&path.0, passphrase).await?;
+  spawn_note_migration(&app);
+  spawn_chat_indexing(&app);
+  // 未解锁时跳过的对话需要重新同步
+  request_sync();
+  Ok(())
+}
+
+/// 设置或取消存储密码，取消后主密钥随机生成并保存在本地文件中
+///
+/// 在后台重新加密全部数据密钥，进度通过 `storage-key-rotation-event` 事件发送
+#[tauri::command]
+pub async fn change_storage_passphrase(
+  path: DataPath<'_>,
+  passphrase: Option<String>,
+) -> Result<()> {
+  rotate_storage_keys(&path.0, passphrase, false).await
+}
+
+/// 更换主密钥以及全部数据密钥，并在后台重新加密全部对话和文件
+///
+/// `passphrase` 为空时新的主密钥随机生成
+#[tauri::command]
+pub async fn rotate_storage_key(path: DataPath<'_>, passphrase: Option<String>) -> Result<()> {
+  rotate_storage_keys(&path.0, passphrase, true).await
+}
+
+/// 继续执行上次失败的密钥轮换
+#[tauri::command]
+pub async fn resume_storage_key_rotation(path: DataPath<'_>) -> Result<()> {
+  resume_key_rotation(&path.0).await
+}
+
+#[tauri::command]
+pub fn get_storage_key_rotation_progress() -> Option<KeyRotationProgress> {
+  key_rotation_progress()
+}
+
+/// 在后台压缩旧版本没有压缩的对话和文件内容，进度通过 `storage-recompress-event` 发送
+#[tauri::command]
+pub async fn recompress_local_storage(path: DataPath<'_>) -> Result<()> {
+  recompress_storage(&path.0).await
+}
+
+/// 检查本地存储的完整性，`repair` 时隔离无法读取的文件，使对话的其余内容可以正常读取
+#[tauri::command]
+pub async fn verify_storage(
+  path: DataPath<'_>,
+  db: Database<'_>,
+  repair: bool,
+) -> Result<StorageReport> {
+  check_storage(&path.0, &db, repair).await
+}
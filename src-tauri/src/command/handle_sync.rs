@@ -0,0 +1,48 @@
+use super::{DataPath, Database};
+use crate::database::Change;
+use crate::error::{Error, Result};
+use crate::sync::{
+  SyncSettings, SyncStatus, check_connection, load_sync_settings, request_sync, sync_status,
+};
+
+#[tauri::command]
+pub async fn get_sync_settings(path: DataPath<'_>, db: Database<'_>) -> Result<SyncSettings> {
+  load_sync_settings(&db, &path.0).await
+}
+
+/// 保存同步设置，开启同步时立即同步一次
+#[tauri::command]
+pub async fn save_sync_settings(
+  path: DataPath<'_>,
+  db: Database<'_>,
+  settings: SyncSettings,
+) -> Result<()> {
+  crate::sync::save_sync_settings(&db, &path.0, settings).await
+}
+
+/// 使用设置连接存储桶并校验同步密码，不会上传任何内容
+#[tauri::command]
+pub async fn test_sync_connection(settings: SyncSettings) -> Result<()> {
+  check_connection(&settings).await
+}
+
+/// 立即在后台同步一次，结果通过 `s3-sync-event` 发送
+#[tauri::command]
+pub async fn sync_now(path: DataPath<'_>, db: Database<'_>) -> Result<()> {
+  if !load_sync_settings(&db, &path.0).await?.enabled {
+    return Err(Error::new("尚未开启同步"));
+  }
+  request_sync();
+  Ok(())
+}
+
+#[tauri::command]
+pub fn get_sync_status() -> Option<SyncStatus> {
+  sync_status()
+}
+
+/// 序号大于 `seq` 的变更记录，按序号排列，同步时从上次处理到的序号继续
+#[tauri::command]
+pub async fn changes_since(db: Database<'_>, seq: i64, limit: Option<u64>) -> Result<Vec<Change>> {
+  db.changes_since(seq, limit).await
+}
@@ -2,6 +2,9 @@ mod handle_chats;
 mod handle_env;
 mod handle_notes;
 mod handle_personas;
+mod handle_settings;
+mod handle_storage;
+mod handle_sync;
 
 use crate::AppDataPath;
 use crate::database::DatabaseHandler;
@@ -19,20 +22,71 @@ impl<R: Runtime> AppCommand for Builder<R> {
     self.invoke_handler(tauri::generate_handler![
       handle_env::env_is_mobile,
       // chats
+      handle_chats::get_all_chats,
       handle_chats::load_chat,
+      handle_chats::load_chat_page,
+      handle_chats::load_chat_since,
       handle_chats::save_chat_message,
+      handle_chats::save_chat_draft,
+      handle_chats::recover_chat_drafts,
+      handle_chats::discard_chat_draft,
+      handle_chats::delete_chat,
+      handle_chats::truncate_chat,
+      handle_chats::fork_chat,
+      handle_chats::list_chat_branches,
+      handle_chats::switch_chat_branch,
+      handle_chats::search_chats,
+      handle_chats::rebuild_chat_index,
+      handle_chats::export_chat,
+      handle_chats::import_chats,
       handle_chats::read_chat_file,
+      handle_chats::stat_chat_file,
+      handle_chats::extract_chat_file_text,
       handle_chats::save_chat_file,
+      handle_chats::collect_chat_files_garbage,
       // notes
       handle_notes::get_all_notes,
       handle_notes::get_note_by_id,
+      handle_notes::lock_note,
+      handle_notes::unlock_note,
+      handle_notes::relock_note,
+      handle_notes::remove_note_lock,
       handle_notes::add_note,
+      handle_notes::add_note_from_chat,
+      handle_notes::get_note_source,
+      handle_notes::clip_web_page_to_note,
+      handle_notes::get_note_web_source,
       handle_notes::modify_note_meta,
       handle_notes::modify_note_content,
       handle_notes::delete_note_by_id,
+      handle_notes::merge_note_text,
+      handle_notes::merge_note,
       // personas
       handle_personas::get_all_personas,
       handle_personas::save_persona,
+      // settings
+      handle_settings::get_fetch_policy,
+      handle_settings::save_fetch_policy,
+      handle_settings::get_chat_gc_schedule,
+      handle_settings::save_chat_gc_schedule,
+      handle_settings::get_note_encryption,
+      handle_settings::save_note_encryption,
+      // storage
+      handle_storage::get_storage_key_status,
+      handle_storage::unlock_storage_with_passphrase,
+      handle_storage::change_storage_passphrase,
+      handle_storage::rotate_storage_key,
+      handle_storage::resume_storage_key_rotation,
+      handle_storage::get_storage_key_rotation_progress,
+      handle_storage::recompress_local_storage,
+      handle_storage::verify_storage,
+      // sync
+      handle_sync::get_sync_settings,
+      handle_sync::save_sync_settings,
+      handle_sync::test_sync_connection,
+      handle_sync::sync_now,
+      handle_sync::get_sync_status,
+      handle_sync::changes_since,
     ])
   }
 }
@@ -0,0 +1,118 @@
+use super::DatabaseHandler;
+use sea_orm::entity::prelude::*;
+use sea_orm::{ConnectionTrait, NotSet, QueryOrder, QuerySelect, Set};
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// 单次查询最多返回的变更数量
+const MAX_CHANGES: u64 = 1000;
+
+/// 发生变更的数据类型
+#[derive(Clone, Copy, Debug, PartialEq, Eq, EnumIter, DeriveActiveEnum, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+#[sea_orm(rs_type = "String", db_type = "Text")]
+pub enum ChangeEntity {
+  #[sea_orm(string_value = "note")]
+  Note,
+  #[sea_orm(string_value = "persona")]
+  Persona,
+  /// 对话保存在文件中，在文件操作完成后记录
+  #[sea_orm(string_value = "chat")]
+  Chat,
+}
+
+/// 变更的操作
+#[derive(Clone, Copy, Debug, PartialEq, Eq, EnumIter, DeriveActiveEnum, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+#[sea_orm(rs_type = "String", db_type = "Text")]
+pub enum ChangeAction {
+  #[sea_orm(string_value = "insert")]
+  Insert,
+  #[sea_orm(string_value = "update")]
+  Update,
+  #[sea_orm(string_value = "delete")]
+  Delete,
+}
+
+/// 变更日志，笔记和面具的修改与日志在同一事务中写入
+#[sea_orm::model]
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+#[sea_orm(table_name = "change_log")]
+pub struct Model {
+  /// 序号，单调递增，删除的序号不会被重新使用
+  #[sea_orm(primary_key)]
+  pub seq: i64,
+  pub entity: ChangeEntity,
+  /// 笔记、面具或对话的 id
+  pub entity_id: String,
+  /// 对话中发生变更的消息或文件 id，变更整个对话时为空
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub item_id: Option<String>,
+  pub action: ChangeAction,
+  /// 变更时间，毫秒时间戳
+  pub created_at: i64,
+}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+/// 写入一条变更，`conn` 为修改数据时使用的事务
+pub(super) async fn record_change(
+  conn: &impl ConnectionTrait,
+  entity: ChangeEntity,
+  entity_id: &str,
+  item_id: Option<&str>,
+  action: ChangeAction,
+) -> crate::error::Result<()> {
+  let created_at = SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|it| it.as_millis() as i64)
+    .unwrap_or_default();
+  let model = ActiveModel {
+    seq: NotSet,
+    entity: Set(entity),
+    entity_id: Set(entity_id.to_string()),
+    item_id: Set(item_id.map(str::to_string)),
+    action: Set(action),
+    created_at: Set(created_at),
+  };
+  model.insert(conn).await?;
+  Ok(())
+}
+
+impl DatabaseHandler {
+  /// 记录文件中数据的变更，在文件操作成功后调用
+  pub async fn log_change(
+    &self,
+    entity: ChangeEntity,
+    entity_id: &str,
+    item_id: Option<&str>,
+    action: ChangeAction,
+  ) -> crate::error::Result<()> {
+    record_change(&self.0, entity, entity_id, item_id, action).await
+  }
+
+  /// 最后一条变更的序号，没有变更时为 0
+  pub async fn last_change_seq(&self) -> crate::error::Result<i64> {
+    let last = Entity::find()
+      .order_by_desc(Column::Seq)
+      .one(&self.0)
+      .await?;
+    Ok(last.map(|it| it.seq).unwrap_or_default())
+  }
+
+  /// 序号大于 `seq` 的变更，按序号排列，最多返回 `limit` 条
+  pub async fn changes_since(
+    &self,
+    seq: i64,
+    limit: Option<u64>,
+  ) -> crate::error::Result<Vec<Model>> {
+    let result = Entity::find()
+      .filter(Column::Seq.gt(seq))
+      .order_by_asc(Column::Seq)
+      .limit(limit.unwrap_or(MAX_CHANGES).min(MAX_CHANGES))
+      .all(&self.0)
+      .await?;
+    Ok(result)
+  }
+}
@@ -0,0 +1,62 @@
+use super::DatabaseHandler;
+use sea_orm::entity::prelude::*;
+use sea_orm::sea_query::OnConflict;
+use sea_orm::{IntoActiveModel, QueryOrder};
+use serde::{Deserialize, Serialize};
+
+#[sea_orm::model]
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+#[sea_orm(table_name = "chats")]
+pub struct Model {
+  /// id，对应 chats 下的文件夹名
+  #[sea_orm(primary_key, auto_increment = false)]
+  pub id: String,
+  /// 标题
+  pub title: String,
+  /// 创建时间，毫秒时间戳
+  pub created_at: i64,
+  /// 更新时间，毫秒时间戳
+  pub updated_at: i64,
+  /// 导入来源，应用内创建的对话为空
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub source: Option<String>,
+}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+impl DatabaseHandler {
+  pub async fn find_all_chats(&self) -> crate::error::Result<Vec<Model>> {
+    let result = Entity::find()
+      .order_by_desc(Column::UpdatedAt)
+      .all(&self.0)
+      .await?;
+    Ok(result)
+  }
+
+  pub async fn find_chat_by_id(&self, id: &str) -> crate::error::Result<Option<Model>> {
+    Ok(Entity::find_by_id(id).one(&self.0).await?)
+  }
+
+  pub async fn save_chat(&self, model: Model) -> crate::error::Result<()> {
+    Entity::insert(model.into_active_model())
+      .on_conflict(
+        OnConflict::column(Column::Id)
+          .update_columns([
+            Column::Title,
+            Column::CreatedAt,
+            Column::UpdatedAt,
+            Column::Source,
+          ])
+          .to_owned(),
+      )
+      .exec(&self.0)
+      .await?;
+    Ok(())
+  }
+
+  pub async fn delete_chat_by_id(&self, id: &str) -> crate::error::Result<()> {
+    Entity::delete_by_id(id).exec(&self.0).await?;
+    Ok(())
+  }
+}
@@ -0,0 +1,260 @@
+use super::DatabaseHandler;
+use crate::files::{ChatDir, message_search_text};
+use sea_orm::{
+  ConnectOptions, ConnectionTrait, Database, DatabaseConnection, DbBackend, DbErr, FromQueryResult,
+  Statement,
+};
+use serde::Serialize;
+use std::path::Path;
+
+/// 对话全文索引，使用 trigram 分词以支持中文的子串匹配
+///
+/// 索引包含对话和附件的明文，只保存在内存中，启动和解锁存储后从解密的对话重建
+const CREATE_TABLE: &str = "CREATE VIRTUAL TABLE IF NOT EXISTS chat_search USING fts5(\
+  chat_id UNINDEXED, message_id UNINDEXED, message_index UNINDEXED, content, \
+  tokenize = 'trigram')";
+
+/// 片段中关键词两侧的标记，前端按 markdown 加粗显示
+const HIGHLIGHT: &str = "**";
+/// 片段中关键词前后保留的字符数
+const SNIPPET_CHARS: usize = 24;
+/// 短关键词无法使用 trigram 索引，逐条匹配时每次读取的记录数
+const FALLBACK_PAGE: u64 = 500;
+
+#[derive(Debug, Serialize, FromQueryResult)]
+#[serde(rename_all = "camelCase")]
+pub struct ChatSearchHit {
+  pub chat_id: String,
+  pub message_id: String,
+  pub message_index: u32,
+  pub snippet: String,
+}
+
+#[derive(FromQueryResult)]
+struct ChatSearchRow {
+  rowid: i64,
+  chat_id: String,
+  message_id: String,
+  message_index: u32,
+  content: String,
+}
+
+/// 打开内存中的索引数据库
+pub(super) async fn open_index() -> Result<DatabaseConnection, DbErr> {
+  let mut opt = ConnectOptions::new("sqlite::memory:");
+  // 连接关闭时内存数据库随之清空，只使用一个不会被回收的连接
+  opt
+    .max_connections(1)
+    .min_connections(1)
+    .idle_timeout(None)
+    .max_lifetime(None);
+  let index = Database::connect(opt).await?;
+  index.execute_unprepared(CREATE_TABLE).await?;
+  Ok(index)
+}
+
+fn stmt(sql: &str, values: impl IntoIterator<Item = sea_orm::Value>) -> Statement {
+  Statement::from_sql_and_values(DbBackend::Sqlite, sql, values)
+}
+
+/// 截取关键词首次出现位置附近的片段
+fn make_snippet(content: &str, term: &str) -> String {
+  let Some(start) = content.find(term) else {
+    return content.chars().take(SNIPPET_CHARS * 2).collect();
+  };
+  let end = start + term.len();
+
+  let before: Vec<char> = content[..start].chars().rev().take(SNIPPET_CHARS).collect();
+  let after: String = content[end..].chars().take(SNIPPET_CHARS).collect();
+
+  let mut snippet = String::new();
+  if before.len() == SNIPPET_CHARS {
+    snippet.push('…');
+  }
+  snippet.extend(before.into_iter().rev());
+  snippet.push_str(HIGHLIGHT);
+  snippet.push_str(term);
+  snippet.push_str(HIGHLIGHT);
+  snippet.push_str(&after);
+  if after.chars().count() == SNIPPET_CHARS {
+    snippet.push('…');
+  }
+  snippet
+}
+
+impl DatabaseHandler {
+  /// 写入单条消息的索引，已存在的同 id 消息会被替换
+  pub async fn index_chat_message(
+    &self,
+    chat_id: &str,
+    message_id: &str,
+    index: u32,
+    content: &str,
+  ) -> crate::error::Result<()> {
+    self
+      .2
+      .execute_raw(stmt(
+        "DELETE FROM chat_search WHERE chat_id = ? AND message_id = ?",
+        [chat_id.into(), message_id.into()],
+      ))
+      .await?;
+    if content.is_empty() {
+      return Ok(());
+    }
+    self
+      .2
+      .execute_raw(stmt(
+        "INSERT INTO chat_search (chat_id, message_id, message_index, content) \
+         VALUES (?, ?, ?, ?)",
+        [
+          chat_id.into(),
+          message_id.into(),
+          index.into(),
+          content.into(),
+        ],
+      ))
+      .await?;
+    Ok(())
+  }
+
+  /// 删除对话中 index 大于等于 `from_index` 的索引
+  pub async fn remove_chat_index(
+    &self,
+    chat_id: &str,
+    from_index: u32,
+  ) -> crate::error::Result<()> {
+    self
+      .2
+      .execute_raw(stmt(
+        "DELETE FROM chat_search WHERE chat_id = ? AND message_index >= ?",
+        [chat_id.into(), from_index.into()],
+      ))
+      .await?;
+    Ok(())
+  }
+
+  /// 将对话中全部分支的消息写入索引
+  pub async fn index_chat(&self, app_data: &Path, chat_id: &str) -> crate::error::Result<()> {
+    let nodes = ChatDir::new(app_data, chat_id)?.read_nodes().await?;
+    for node in nodes {
+      let text = message_search_text(app_data, chat_id, &node.message).await;
+      self
+        .index_chat_message(chat_id, &node.message_id, node.index, &text)
+        .await?;
+    }
+    Ok(())
+  }
+
+  /// 清空索引后从磁盘重建，存储未解锁等原因无法读取的对话跳过
+  pub async fn rebuild_chat_index(&self, app_data: &Path) -> crate::error::Result<()> {
+    self.2.execute_unprepared("DELETE FROM chat_search").await?;
+    for chat_id in ChatDir::list_ids(app_data).await? {
+      let _ = self.index_chat(app_data, &chat_id).await;
+    }
+    Ok(())
+  }
+
+  /// 按相关度搜索对话记录，多个关键词之间为且的关系
+  pub async fn search_chats(
+    &self,
+    query: &str,
+    limit: u64,
+  ) -> crate::error::Result<Vec<ChatSearchHit>> {
+    let terms: Vec<&str> = query.split_whitespace().collect();
+    if terms.is_empty() {
+      return Ok(Vec::new());
+    }
+
+    // trigram 只能匹配三个字符及以上的关键词
+    if terms.iter().all(|it| it.chars().count() >= 3) {
+      let expr = terms
+        .iter()
+        .map(|it| format!("\"{}\"", it.replace('"', "\"\"")))
+        .collect::<Vec<_>>()
+        .join(" ");
+      let sql = format!(
+        "SELECT chat_id, message_id, message_index, \
+         snippet(chat_search, 3, '{HIGHLIGHT}', '{HIGHLIGHT}', '…', 16) AS snippet \
+         FROM chat_search WHERE chat_search MATCH ? ORDER BY bm25(chat_search) LIMIT ?"
+      );
+      let hits = ChatSearchHit::find_by_statement(stmt(&sql, [expr.into(), limit.into()]))
+        .all(&self.2)
+        .await?;
+      return Ok(hits);
+    }
+
+    // 存在短关键词时分批逐条匹配全部索引，按关键词出现次数排序，次数相同时较新的在前
+    let condition = vec!["instr(content, ?) > 0"; terms.len()].join(" AND ");
+    let sql = format!(
+      "SELECT rowid, chat_id, message_id, message_index, content FROM chat_search \
+       WHERE rowid < ? AND {condition} ORDER BY rowid DESC LIMIT ?"
+    );
+    let mut scored: Vec<(usize, ChatSearchRow)> = Vec::new();
+    let mut before = i64::MAX;
+    loop {
+      let mut values: Vec<sea_orm::Value> = vec![before.into()];
+      values.extend(terms.iter().map(|it| sea_orm::Value::from(*it)));
+      values.push(FALLBACK_PAGE.into());
+      let rows = ChatSearchRow::find_by_statement(stmt(&sql, values))
+        .all(&self.2)
+        .await?;
+      let Some(last) = rows.last() else {
+        break;
+      };
+      before = last.rowid;
+      let page = rows.len() as u64;
+
+      scored.extend(rows.into_iter().map(|row| {
+        let count = terms.iter().map(|it| row.content.matches(it).count()).sum();
+        (count, row)
+      }));
+      scored.sort_by_key(|it| std::cmp::Reverse(it.0));
+      scored.truncate(limit as usize);
+      if page < FALLBACK_PAGE {
+        break;
+      }
+    }
+
+    let hits = scored.into_iter().map(|(_, row)| ChatSearchHit {
+      snippet: make_snippet(&row.content, terms[0]),
+      chat_id: row.chat_id,
+      message_id: row.message_id,
+      message_index: row.message_index,
+    });
+    Ok(hits.collect())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tauri::async_runtime::block_on;
+
+  #[test]
+  fn short_terms_scan_whole_index() {
+    let app_data =
+      std::env::temp_dir().join(format!("note-secretary-{}", uuid::Uuid::new_v4().simple()));
+    std::fs::create_dir_all(&app_data).unwrap();
+    block_on(async {
+      let db = DatabaseHandler::open(&app_data).await.unwrap();
+      db.index_chat_message("chat", "old", 0, "最早的消息：猫猫")
+        .await
+        .unwrap();
+      for index in 1..=FALLBACK_PAGE as u32 * 2 {
+        db.index_chat_message("chat", &format!("m{index}"), index, "无关的内容")
+          .await
+          .unwrap();
+      }
+      db.index_chat_message("chat", "new", 9999, "猫")
+        .await
+        .unwrap();
+
+      let hits = db.search_chats("猫", 10).await.unwrap();
+      let ids: Vec<&str> = hits.iter().map(|it| it.message_id.as_str()).collect();
+      // 出现次数多的在前，超过一批的旧记录同样能搜索到
+      assert_eq!(ids, ["old", "new"]);
+      assert_eq!(hits[0].snippet, "最早的消息：**猫**猫");
+    });
+    let _ = std::fs::remove_dir_all(&app_data);
+  }
+}
@@ -0,0 +1,26 @@
+use super::DatabaseHandler;
+use sea_orm::{DbBackend, FromQueryResult, Statement};
+
+#[derive(FromQueryResult)]
+struct IntegrityRow {
+  integrity_check: String,
+}
+
+impl DatabaseHandler {
+  /// 执行 SQLite 的 `integrity_check`，返回发现的问题，没有问题时为空
+  pub async fn integrity_check(&self) -> crate::error::Result<Vec<String>> {
+    let rows = IntegrityRow::find_by_statement(Statement::from_string(
+      DbBackend::Sqlite,
+      "PRAGMA integrity_check",
+    ))
+    .all(&self.0)
+    .await?;
+    Ok(
+      rows
+        .into_iter()
+        .map(|it| it.integrity_check)
+        .filter(|it| it != "ok")
+        .collect(),
+    )
+  }
+}
@@ -1,28 +1,65 @@
+mod change_log_entity;
+mod chat_entity;
+mod chat_search;
+mod integrity;
+mod note_crypto;
 mod note_entity;
+mod note_lock_entity;
+mod note_source_entity;
+mod note_web_source_entity;
 mod persona_entity;
+mod setting_entity;
 
+pub use change_log_entity::Model as Change;
+pub use change_log_entity::{ChangeAction, ChangeEntity};
+pub use chat_entity::Model as Chat;
+pub use chat_search::ChatSearchHit;
+pub use note_crypto::{NOTE_ENCRYPTION_KEY, NoteEncryption};
 pub use note_entity::Model as Note;
-pub use note_entity::NoteSummary;
+pub use note_entity::{NoteSummary, new_note_id};
+pub use note_lock_entity::NoteDetail;
+pub use note_source_entity::Model as NoteSource;
+pub use note_web_source_entity::Model as NoteWebSource;
 pub use persona_entity::Model as Persona;
 
 use crate::AppDataPath;
+use note_crypto::NoteFields;
 use sea_orm::{ConnectOptions, Database, DatabaseConnection, DbErr};
-use tauri::async_runtime::block_on;
-use tauri::{Error, Manager};
+use tauri::async_runtime::{block_on, spawn};
+use tauri::{AppHandle, Error, Manager, Runtime};
 
-pub struct DatabaseHandler(DatabaseConnection);
+/// 数据库连接、笔记字段的加密设置以及内存中的对话索引
+pub struct DatabaseHandler(DatabaseConnection, NoteFields, DatabaseConnection);
 
 async fn init_database(opt: ConnectOptions) -> Result<DatabaseConnection, DbErr> {
   let database = Database::connect(opt).await?;
   database
     .get_schema_builder()
+    .register(change_log_entity::Entity)
+    .register(chat_entity::Entity)
     .register(note_entity::Entity)
+    .register(note_lock_entity::Entity)
+    .register(note_source_entity::Entity)
+    .register(note_web_source_entity::Entity)
     .register(persona_entity::Entity)
+    .register(setting_entity::Entity)
     .sync(&database)
     .await?;
   Ok(database)
 }
 
+#[cfg(test)]
+impl DatabaseHandler {
+  /// 打开 `app_data` 中的数据库，只用于测试
+  pub(crate) async fn open(app_data: &std::path::Path) -> crate::error::Result<Self> {
+    let path = app_data.join("data.sqlite");
+    let url = format!("sqlite://{}?mode=rwc", path.to_string_lossy());
+    let database = init_database(ConnectOptions::new(url)).await?;
+    let index = chat_search::open_index().await?;
+    Ok(Self(database, NoteFields::new(app_data), index))
+  }
+}
+
 pub fn setup_database(app: &tauri::App) -> tauri::Result<()> {
   let app_data_path = app.state::<AppDataPath>();
   let path = app_data_path.0.join("data.sqlite");
@@ -33,7 +70,35 @@ pub fn setup_database(app: &tauri::App) -> tauri::Result<()> {
     Ok(it) => it,
     Err(e) => return Err(Error::Anyhow(e.into())),
   };
-  app.manage(DatabaseHandler(result));
+  let index = block_on(chat_search::open_index()).map_err(|e| Error::Anyhow(e.into()))?;
+  let handler = DatabaseHandler(result, NoteFields::new(&app_data_path.0), index);
+  let encryption = block_on(handler.get_setting::<NoteEncryption>(NOTE_ENCRYPTION_KEY))
+    .map_err(|e| Error::Anyhow(e.into()))?;
+  handler.1.set_enabled(encryption.enabled);
+  app.manage(handler);
 
   Ok(())
 }
+
+/// 在后台按加密设置迁移已保存的笔记，需要主密钥已解锁
+///
+/// 启动时、解锁存储后以及修改加密设置后调用，失败时下次调用重试
+pub fn spawn_note_migration<R: Runtime>(app: &AppHandle<R>) {
+  let app = app.clone();
+  spawn(async move {
+    let database = app.state::<DatabaseHandler>();
+    let _ = database.migrate_note_fields().await;
+  });
+}
+
+/// 在后台从磁盘重建内存中的对话索引，需要主密钥已解锁
+///
+/// 启动时以及解锁存储后调用，重建完成前搜索结果可能不完整
+pub fn spawn_chat_indexing<R: Runtime>(app: &AppHandle<R>) {
+  let app = app.clone();
+  spawn(async move {
+    let path = app.state::<AppDataPath>();
+    let database = app.state::<DatabaseHandler>();
+    let _ = database.rebuild_chat_index(&path.0).await;
+  });
+}
@@ -0,0 +1,129 @@
+use super::DatabaseHandler;
+use super::note_entity::{Column, Entity, Model};
+use crate::error::Result;
+use crate::files::{FieldCipher, NoteKey};
+use sea_orm::QuerySelect;
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// 笔记加密设置在设置中的名称
+pub const NOTE_ENCRYPTION_KEY: &str = "note_encryption";
+
+/// 笔记加密设置，默认不加密，开启后已保存的笔记在后台转换
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct NoteEncryption {
+  /// 是否加密保存笔记的正文和总结
+  pub enabled: bool,
+}
+
+/// 笔记正文和总结的加密，读取时同时支持明文和加密的字段
+pub(super) struct NoteFields {
+  cipher: FieldCipher,
+  enabled: AtomicBool,
+}
+
+impl NoteFields {
+  pub fn new(app_data: &Path) -> Self {
+    Self {
+      cipher: FieldCipher::new(app_data),
+      enabled: AtomicBool::new(NoteEncryption::default().enabled),
+    }
+  }
+
+  pub fn set_enabled(&self, enabled: bool) {
+    self.enabled.store(enabled, Ordering::SeqCst);
+  }
+
+  /// 按设置转换为保存到数据库的内容
+  pub fn encode(&self, value: &str) -> Result<String> {
+    match self.enabled.load(Ordering::SeqCst) {
+      true => self.cipher.seal(value),
+      false => Ok(value.to_string()),
+    }
+  }
+
+  pub fn decode(&self, value: &str) -> Result<String> {
+    self.cipher.open(value)
+  }
+
+  /// 字段是否与设置不一致，或者加密使用的不是当前的数据密钥
+  fn is_stale(&self, value: &str) -> Result<bool> {
+    match self.enabled.load(Ordering::SeqCst) {
+      true => Ok(!self.cipher.is_current(value)?),
+      false => Ok(FieldCipher::is_sealed(value)),
+    }
+  }
+}
+
+impl DatabaseHandler {
+  /// 解密数据库中的总结和正文，设置了笔记密码时需要提供解锁的密钥
+  pub(super) fn decode_note(&self, mut model: Model, key: Option<&NoteKey>) -> Result<Model> {
+    model.summary = self.1.decode(&model.summary)?;
+    model.content = self.1.decode(&model.content)?;
+    if let Some(key) = key {
+      model.summary = key.open(&model.summary)?;
+      model.content = key.open(&model.content)?;
+    }
+    Ok(model)
+  }
+
+  /// 转换为保存到数据库的总结或正文
+  pub(super) fn encode_note_field(&self, key: Option<&NoteKey>, value: &str) -> Result<String> {
+    match key {
+      Some(key) => self.1.encode(&key.seal(value)?),
+      None => self.1.encode(value),
+    }
+  }
+
+  /// 保存笔记加密设置，已保存的笔记在 [`DatabaseHandler::migrate_note_fields`] 中转换
+  pub async fn save_note_encryption(&self, setting: &NoteEncryption) -> Result<()> {
+    self.save_setting(NOTE_ENCRYPTION_KEY, setting).await?;
+    self.1.set_enabled(setting.enabled);
+    Ok(())
+  }
+
+  /// 按加密设置转换已保存的笔记，包括加密前保存的明文以及轮换密钥前加密的字段
+  ///
+  /// 返回转换的笔记数量；转换期间被修改的笔记跳过，修改时已按设置保存
+  pub async fn migrate_note_fields(&self) -> Result<usize> {
+    let ids: Vec<String> = Entity::find()
+      .select_only()
+      .column(Column::Id)
+      .into_tuple()
+      .all(&self.0)
+      .await?;
+
+    let mut count = 0;
+    for id in ids {
+      let row: Option<(String, String)> = Entity::find_by_id(&id)
+        .select_only()
+        .column(Column::Summary)
+        .column(Column::Content)
+        .into_tuple()
+        .one(&self.0)
+        .await?;
+      let Some((summary, content)) = row else {
+        continue;
+      };
+      if !self.1.is_stale(&summary)? && !self.1.is_stale(&content)? {
+        continue;
+      }
+
+      let new_summary = self.1.encode(&self.1.decode(&summary)?)?;
+      let new_content = self.1.encode(&self.1.decode(&content)?)?;
+      Entity::update_many()
+        .col_expr(Column::Summary, Expr::value(new_summary))
+        .col_expr(Column::Content, Expr::value(new_content))
+        .filter(Column::Id.eq(&id))
+        .filter(Column::Summary.eq(summary))
+        .filter(Column::Content.eq(content))
+        .exec(&self.0)
+        .await?;
+      count += 1;
+    }
+    Ok(count)
+  }
+}
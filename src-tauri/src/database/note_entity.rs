@@ -1,13 +1,15 @@
-use super::DatabaseHandler;
+use super::change_log_entity::{ChangeAction, ChangeEntity, record_change};
+use super::{DatabaseHandler, note_lock_entity, note_source_entity, note_web_source_entity};
+use crate::files::end_note_session;
 use sea_orm::entity::prelude::*;
-use sea_orm::{IntoActiveModel, QuerySelect};
+use sea_orm::{DatabaseTransaction, IntoActiveModel, QuerySelect, TransactionTrait};
 use serde::{Deserialize, Serialize};
 
 #[sea_orm::model]
 #[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Deserialize, Serialize)]
 #[sea_orm(table_name = "notes")]
 pub struct Model {
-  /// id，唯一标识符，UUID
+  /// id，唯一标识符，`note-` 开头
   #[sea_orm(primary_key, auto_increment = false)]
   pub id: String,
   /// 分组，note 的文件夹
@@ -16,9 +18,9 @@ pub struct Model {
   /// 标题，note 的文件名
   #[sea_orm(unique_key = "item")]
   pub title: String,
-  /// 总结，用于AI问答和搜寻
+  /// 总结，用于AI问答和搜寻，开启笔记加密时在数据库中加密保存
   pub summary: String,
-  /// 正文，markdown
+  /// 正文，markdown，开启笔记加密时在数据库中加密保存
   #[serde(default)]
   pub content: String,
 }
@@ -33,6 +35,11 @@ pub struct NoteSummary {
   pub title: String,
 }
 
+/// 新笔记的 id，与前端生成的 id 一样以 `note-` 开头
+pub fn new_note_id() -> String {
+  format!("note-{}", uuid::Uuid::new_v4().simple())
+}
+
 impl DatabaseHandler {
   pub async fn find_all_notes(&self) -> crate::error::Result<Vec<NoteSummary>> {
     let result = Entity::find()
@@ -47,37 +54,139 @@ impl DatabaseHandler {
   }
 
   pub async fn find_note_by_id(&self, id: &str) -> crate::error::Result<Option<Model>> {
-    Ok(Entity::find_by_id(id).one(&self.0).await?)
+    let Some(model) = Entity::find_by_id(id).one(&self.0).await? else {
+      return Ok(None);
+    };
+    let key = self.note_key(id).await?;
+    self.decode_note(model, key.as_ref()).map(Some)
+  }
+
+  /// 在事务中写入笔记以及对应的变更
+  pub(super) async fn insert_note_in(
+    &self,
+    txn: &DatabaseTransaction,
+    model: &Model,
+  ) -> crate::error::Result<()> {
+    let mut model = model.clone();
+    model.summary = self.encode_note_field(None, &model.summary)?;
+    model.content = self.encode_note_field(None, &model.content)?;
+    let id = model.id.clone();
+    model.into_active_model().insert(txn).await?;
+    record_change(txn, ChangeEntity::Note, &id, None, ChangeAction::Insert).await
   }
 
   pub async fn insert_note(&self, model: &Model) -> crate::error::Result<()> {
-    let model = model.clone().into_active_model();
-    model.insert(&self.0).await?;
+    let txn = self.0.begin().await?;
+    self.insert_note_in(&txn, model).await?;
+    txn.commit().await?;
     Ok(())
   }
 
   pub async fn update_note_metadata(&self, model: &Model) -> crate::error::Result<()> {
+    let key = self.note_key(&model.id).await?;
+    let summary = self.encode_note_field(key.as_ref(), &model.summary)?;
+    let txn = self.0.begin().await?;
     Entity::update_many()
       .col_expr(Column::Category, Expr::value(model.category.clone()))
       .col_expr(Column::Title, Expr::value(model.title.clone()))
-      .col_expr(Column::Summary, Expr::value(model.summary.clone()))
+      .col_expr(Column::Summary, Expr::value(summary))
       .filter(Column::Id.eq(model.id.clone()))
-      .exec(&self.0)
+      .exec(&txn)
       .await?;
+    record_change(
+      &txn,
+      ChangeEntity::Note,
+      &model.id,
+      None,
+      ChangeAction::Update,
+    )
+    .await?;
+    txn.commit().await?;
     Ok(())
   }
 
   pub async fn update_note_content(&self, id: &str, content: &str) -> crate::error::Result<()> {
+    let key = self.note_key(id).await?;
+    let content = self.encode_note_field(key.as_ref(), content)?;
+    let txn = self.0.begin().await?;
     Entity::update_many()
       .col_expr(Column::Content, Expr::value(content))
       .filter(Column::Id.eq(id))
-      .exec(&self.0)
+      .exec(&txn)
       .await?;
+    record_change(&txn, ChangeEntity::Note, id, None, ChangeAction::Update).await?;
+    txn.commit().await?;
+    Ok(())
+  }
+
+  /// 在同一事务中更新元数据和正文
+  pub async fn update_note(&self, model: &Model) -> crate::error::Result<()> {
+    let key = self.note_key(&model.id).await?;
+    let summary = self.encode_note_field(key.as_ref(), &model.summary)?;
+    let content = self.encode_note_field(key.as_ref(), &model.content)?;
+    let txn = self.0.begin().await?;
+    Entity::update_many()
+      .col_expr(Column::Category, Expr::value(model.category.clone()))
+      .col_expr(Column::Title, Expr::value(model.title.clone()))
+      .col_expr(Column::Summary, Expr::value(summary))
+      .col_expr(Column::Content, Expr::value(content))
+      .filter(Column::Id.eq(model.id.clone()))
+      .exec(&txn)
+      .await?;
+    record_change(
+      &txn,
+      ChangeEntity::Note,
+      &model.id,
+      None,
+      ChangeAction::Update,
+    )
+    .await?;
+    txn.commit().await?;
+    Ok(())
+  }
+
+  /// 保存合并后的正文，有冲突笔记时在同一事务中写入，`content` 为空时正文不变
+  pub async fn save_merged_note(
+    &self,
+    id: &str,
+    content: Option<&str>,
+    conflict: Option<&Model>,
+  ) -> crate::error::Result<()> {
+    let key = self.note_key(id).await?;
+    let content = content
+      .map(|it| self.encode_note_field(key.as_ref(), it))
+      .transpose()?;
+    let txn = self.0.begin().await?;
+    if let Some(content) = content {
+      Entity::update_many()
+        .col_expr(Column::Content, Expr::value(content))
+        .filter(Column::Id.eq(id))
+        .exec(&txn)
+        .await?;
+      record_change(&txn, ChangeEntity::Note, id, None, ChangeAction::Update).await?;
+    }
+    if let Some(conflict) = conflict {
+      self.insert_note_in(&txn, conflict).await?;
+    }
+    txn.commit().await?;
     Ok(())
   }
 
   pub async fn delete_note_by_id(&self, id: &str) -> crate::error::Result<()> {
-    Entity::delete_by_id(id).exec(&self.0).await?;
+    let txn = self.0.begin().await?;
+    Entity::delete_by_id(id).exec(&txn).await?;
+    note_source_entity::Entity::delete_by_id(id)
+      .exec(&txn)
+      .await?;
+    note_web_source_entity::Entity::delete_by_id(id)
+      .exec(&txn)
+      .await?;
+    note_lock_entity::Entity::delete_by_id(id)
+      .exec(&txn)
+      .await?;
+    record_change(&txn, ChangeEntity::Note, id, None, ChangeAction::Delete).await?;
+    txn.commit().await?;
+    end_note_session(id);
     Ok(())
   }
 }
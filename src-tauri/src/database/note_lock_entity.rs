@@ -0,0 +1,171 @@
+use super::DatabaseHandler;
+use super::change_log_entity::{ChangeAction, ChangeEntity, record_change};
+use super::note_entity::{self, Column as NoteColumn};
+use crate::error::Error;
+use crate::files::{NoteKey, NoteLock, end_note_session, note_session, start_note_session};
+use sea_orm::entity::prelude::*;
+use sea_orm::{IntoActiveModel, TransactionTrait};
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tauri::async_runtime::spawn_blocking;
+
+#[sea_orm::model]
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+#[sea_orm(table_name = "note_locks")]
+pub struct Model {
+  /// 笔记 id
+  #[sea_orm(primary_key, auto_increment = false)]
+  pub note_id: String,
+  /// 笔记密码的派生参数，json
+  pub params: String,
+  /// 设置密码的时间，毫秒时间戳
+  pub created_at: i64,
+}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+/// 笔记详情，设置了密码的笔记未解锁时只包含元数据
+#[derive(Debug, Serialize)]
+pub struct NoteDetail {
+  #[serde(flatten)]
+  pub note: note_entity::Model,
+  /// 是否设置了笔记密码
+  pub locked: bool,
+  /// 设置了密码的笔记是否已解锁，未解锁时总结和正文为空
+  pub unlocked: bool,
+}
+
+impl DatabaseHandler {
+  async fn find_note_lock(&self, note_id: &str) -> crate::error::Result<Option<NoteLock>> {
+    match Entity::find_by_id(note_id).one(&self.0).await? {
+      Some(model) => Ok(Some(serde_json::from_str(&model.params)?)),
+      None => Ok(None),
+    }
+  }
+
+  /// 笔记的解锁密钥，没有设置密码时为空，未解锁时返回错误
+  pub(super) async fn note_key(&self, note_id: &str) -> crate::error::Result<Option<NoteKey>> {
+    if self.find_note_lock(note_id).await?.is_none() {
+      return Ok(None);
+    }
+    match note_session(note_id) {
+      Some(key) => Ok(Some(key)),
+      None => Err(Error::new("笔记已锁定，请先输入笔记密码解锁")),
+    }
+  }
+
+  /// 读取笔记详情，设置了密码的笔记未解锁时不返回总结和正文
+  pub async fn find_note_detail(&self, id: &str) -> crate::error::Result<Option<NoteDetail>> {
+    let Some(model) = note_entity::Entity::find_by_id(id).one(&self.0).await? else {
+      return Ok(None);
+    };
+    let locked = self.find_note_lock(id).await?.is_some();
+    let key = note_session(id).filter(|_| locked);
+    if locked && key.is_none() {
+      let note = note_entity::Model {
+        summary: String::new(),
+        content: String::new(),
+        ..model
+      };
+      return Ok(Some(NoteDetail {
+        note,
+        locked,
+        unlocked: false,
+      }));
+    }
+    Ok(Some(NoteDetail {
+      note: self.decode_note(model, key.as_ref())?,
+      locked,
+      unlocked: locked,
+    }))
+  }
+
+  /// 为笔记设置密码，总结和正文使用由密码派生的密钥加密
+  pub async fn lock_note(&self, id: &str, password: String) -> crate::error::Result<()> {
+    if self.find_note_lock(id).await?.is_some() {
+      return Err(Error::new("笔记已设置密码"));
+    }
+    let note = self
+      .find_note_by_id(id)
+      .await?
+      .ok_or_else(|| Error::NotFound(format!("note({id})")))?;
+    let (lock, key) = spawn_blocking(move || NoteLock::new(&password)).await??;
+
+    let created_at = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .map(|it| it.as_millis() as i64)
+      .unwrap_or_default();
+    let model = Model {
+      note_id: id.to_string(),
+      params: serde_json::to_string(&lock)?,
+      created_at,
+    };
+    let summary = self.encode_note_field(Some(&key), &note.summary)?;
+    let content = self.encode_note_field(Some(&key), &note.content)?;
+    let txn = self.0.begin().await?;
+    model.into_active_model().insert(&txn).await?;
+    note_entity::Entity::update_many()
+      .col_expr(NoteColumn::Summary, Expr::value(summary))
+      .col_expr(NoteColumn::Content, Expr::value(content))
+      .filter(NoteColumn::Id.eq(id))
+      .exec(&txn)
+      .await?;
+    record_change(&txn, ChangeEntity::Note, id, None, ChangeAction::Update).await?;
+    txn.commit().await?;
+    end_note_session(id);
+    Ok(())
+  }
+
+  async fn unlock_note_key(&self, id: &str, password: String) -> crate::error::Result<NoteKey> {
+    let lock = self
+      .find_note_lock(id)
+      .await?
+      .ok_or_else(|| Error::new("笔记没有设置密码"))?;
+    spawn_blocking(move || lock.unlock(&password)).await?
+  }
+
+  /// 使用笔记密码解锁，一段时间没有访问后自动锁定
+  pub async fn unlock_note(
+    &self,
+    id: &str,
+    password: String,
+  ) -> crate::error::Result<note_entity::Model> {
+    let key = self.unlock_note_key(id, password).await?;
+    start_note_session(id, key);
+    self
+      .find_note_by_id(id)
+      .await?
+      .ok_or_else(|| Error::NotFound(format!("note({id})")))
+  }
+
+  /// 立即锁定已解锁的笔记
+  pub fn relock_note(&self, id: &str) {
+    end_note_session(id);
+  }
+
+  /// 取消笔记密码，总结和正文恢复为普通笔记的保存方式
+  pub async fn remove_note_lock(&self, id: &str, password: String) -> crate::error::Result<()> {
+    let key = self.unlock_note_key(id, password).await?;
+    let model = note_entity::Entity::find_by_id(id)
+      .one(&self.0)
+      .await?
+      .ok_or_else(|| Error::NotFound(format!("note({id})")))?;
+    let note = self.decode_note(model, Some(&key))?;
+
+    let summary = self.encode_note_field(None, &note.summary)?;
+    let content = self.encode_note_field(None, &note.content)?;
+    let txn = self.0.begin().await?;
+    note_entity::Entity::update_many()
+      .col_expr(NoteColumn::Summary, Expr::value(summary))
+      .col_expr(NoteColumn::Content, Expr::value(content))
+      .filter(NoteColumn::Id.eq(id))
+      .exec(&txn)
+      .await?;
+    Entity::delete_by_id(id).exec(&txn).await?;
+    record_change(&txn, ChangeEntity::Note, id, None, ChangeAction::Update).await?;
+    txn.commit().await?;
+    end_note_session(id);
+    Ok(())
+  }
+}
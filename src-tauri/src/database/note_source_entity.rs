@@ -0,0 +1,44 @@
+use super::DatabaseHandler;
+use super::note_entity::Model as Note;
+use sea_orm::entity::prelude::*;
+use sea_orm::{IntoActiveModel, TransactionTrait};
+use serde::{Deserialize, Serialize};
+
+#[sea_orm::model]
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+#[sea_orm(table_name = "note_sources")]
+pub struct Model {
+  /// 笔记 id
+  #[sea_orm(primary_key, auto_increment = false)]
+  pub note_id: String,
+  /// 来源对话 id
+  pub chat_id: String,
+  /// 来源消息的起始 index（包含）
+  pub from_index: u32,
+  /// 来源消息的结束 index（包含）
+  pub to_index: u32,
+  /// 创建时间，毫秒时间戳
+  pub created_at: i64,
+}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+impl DatabaseHandler {
+  pub async fn find_note_source(&self, note_id: &str) -> crate::error::Result<Option<Model>> {
+    Ok(Entity::find_by_id(note_id).one(&self.0).await?)
+  }
+
+  /// 在同一事务中保存从对话整理的笔记以及笔记的来源
+  pub async fn insert_note_with_source(
+    &self,
+    note: &Note,
+    source: Model,
+  ) -> crate::error::Result<()> {
+    let txn = self.0.begin().await?;
+    self.insert_note_in(&txn, note).await?;
+    source.into_active_model().insert(&txn).await?;
+    txn.commit().await?;
+    Ok(())
+  }
+}
@@ -1,7 +1,8 @@
 use super::DatabaseHandler;
-use sea_orm::IntoActiveModel;
+use super::change_log_entity::{ChangeAction, ChangeEntity, record_change};
 use sea_orm::entity::prelude::*;
 use sea_orm::sea_query::OnConflict;
+use sea_orm::{IntoActiveModel, TransactionTrait};
 use serde::{Deserialize, Serialize};
 
 #[sea_orm::model]
@@ -52,7 +53,21 @@ impl DatabaseHandler {
     Ok(Entity::find().all(&self.0).await?)
   }
 
+  pub async fn delete_persona_by_id(&self, id: &str) -> crate::error::Result<()> {
+    let txn = self.0.begin().await?;
+    Entity::delete_by_id(id).exec(&txn).await?;
+    record_change(&txn, ChangeEntity::Persona, id, None, ChangeAction::Delete).await?;
+    txn.commit().await?;
+    Ok(())
+  }
+
   pub async fn save_persona(&self, model: Model) -> crate::error::Result<()> {
+    let txn = self.0.begin().await?;
+    let action = match Entity::find_by_id(&model.id).one(&txn).await? {
+      Some(_) => ChangeAction::Update,
+      None => ChangeAction::Insert,
+    };
+    let id = model.id.clone();
     Entity::insert(model.into_active_model())
       .on_conflict(
         OnConflict::column(Column::Id)
@@ -72,8 +87,10 @@ impl DatabaseHandler {
           ])
           .to_owned(),
       )
-      .exec(&self.0)
+      .exec(&txn)
       .await?;
+    record_change(&txn, ChangeEntity::Persona, &id, None, action).await?;
+    txn.commit().await?;
     Ok(())
   }
 }
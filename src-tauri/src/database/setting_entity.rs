@@ -0,0 +1,48 @@
+use super::DatabaseHandler;
+use sea_orm::IntoActiveModel;
+use sea_orm::entity::prelude::*;
+use sea_orm::sea_query::OnConflict;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+#[sea_orm::model]
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Deserialize, Serialize)]
+#[sea_orm(table_name = "settings")]
+pub struct Model {
+  /// 设置项名称
+  #[sea_orm(primary_key, auto_increment = false)]
+  pub key: String,
+  /// 设置内容，json
+  pub value: String,
+}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+impl DatabaseHandler {
+  /// 读取设置项，不存在时返回默认值
+  pub async fn get_setting<T: DeserializeOwned + Default>(
+    &self,
+    key: &str,
+  ) -> crate::error::Result<T> {
+    match Entity::find_by_id(key).one(&self.0).await? {
+      Some(model) => Ok(serde_json::from_str(&model.value)?),
+      None => Ok(T::default()),
+    }
+  }
+
+  pub async fn save_setting<T: Serialize>(&self, key: &str, value: &T) -> crate::error::Result<()> {
+    let model = Model {
+      key: key.to_string(),
+      value: serde_json::to_string(value)?,
+    };
+    Entity::insert(model.into_active_model())
+      .on_conflict(
+        OnConflict::column(Column::Key)
+          .update_column(Column::Value)
+          .to_owned(),
+      )
+      .exec(&self.0)
+      .await?;
+    Ok(())
+  }
+}
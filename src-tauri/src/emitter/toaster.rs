@@ -23,7 +23,6 @@ pub fn info<'a>(title: &'a str, description: Option<&'a str>) {
   app_handle.emit("toaster", payload).ok();
 }
 
-#[allow(dead_code)]
 pub fn warning<'a>(title: &'a str, description: Option<&'a str>) {
   let Some(app_handle) = APP_HANDLE.get() else {
     return;
@@ -24,6 +24,29 @@ impl<T> OnceLockSetup<T> for OnceLock<T> {
   }
 }
 
+/// 远程获取文件时违反获取策略的错误
+#[derive(Debug, thiserror::Error)]
+pub enum FetchError {
+  #[error("无效的链接: {0}")]
+  InvalidUrl(String),
+  #[error("不允许的协议: {0}")]
+  SchemeNotAllowed(String),
+  #[error("不允许访问本机或局域网地址: {0}")]
+  BlockedAddress(String),
+  #[error("无法解析域名: {0}")]
+  Resolve(String),
+  #[error("重定向次数超过 {0} 次")]
+  TooManyRedirects(usize),
+  #[error("请求超时")]
+  Timeout,
+  #[error("请求失败，状态码 {0}")]
+  Status(u16),
+  #[error("不允许的内容类型: {0}")]
+  ContentType(String),
+  #[error("文件大小超过 {0} 字节")]
+  TooLarge(u64),
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
   #[error("{0}")]
@@ -39,6 +62,12 @@ pub enum Error {
   DataUrl(#[from] data_url::DataUrlError),
   #[error("decode data-url: {0}")]
   DecodeDataUrl(#[from] data_url::forgiving_base64::InvalidBase64),
+  #[error("handle image: {0}")]
+  Image(#[from] image::ImageError),
+  #[error("format: {0}")]
+  Fmt(#[from] std::fmt::Error),
+  #[error("{0}")]
+  Fetch(#[from] FetchError),
   #[error("request: {0}")]
   Request(#[from] tauri_plugin_http::reqwest::Error),
 
@@ -0,0 +1,60 @@
+//! 对话存储的性能测试辅助函数，只在 `bench` feature 下编译
+
+use super::chat_tree::ChatTree;
+use super::{LEGACY_PASSWORD, SAVE_DIR};
+use crate::error::Result;
+use serde_json::{Value, json, to_writer};
+use std::fs::{File, create_dir_all};
+use std::path::{Path, PathBuf};
+use zip::write::SimpleFileOptions;
+use zip::{AesMode, CompressionMethod, ZipWriter};
+
+fn sample_message(index: u32) -> Value {
+  let role = if index.is_multiple_of(2) {
+    "user"
+  } else {
+    "assistant"
+  };
+  let text = format!("第 {index} 条消息。").repeat(20);
+  json!({
+    "id": format!("msg-{index}"),
+    "role": role,
+    "parts": [{ "type": "text", "text": text }],
+  })
+}
+
+fn chat_dir(app_data: &Path, chat_id: &str) -> PathBuf {
+  app_data.join(SAVE_DIR).join(chat_id)
+}
+
+/// 按最初版本的格式写入一个线性对话：每条消息一个 `{index:04}.message` 文件，注释为消息 id
+pub fn write_legacy_chat(app_data: &Path, chat_id: &str, count: u32) -> Result<()> {
+  let dir = chat_dir(app_data, chat_id);
+  create_dir_all(&dir)?;
+
+  let options = SimpleFileOptions::default()
+    .compression_method(CompressionMethod::Stored)
+    .with_aes_encryption(AesMode::Aes256, LEGACY_PASSWORD);
+  for index in 0..count {
+    let file = File::create(dir.join(format!("{index:04}.message")))?;
+    let mut writer = ZipWriter::new(file);
+    writer.start_file("message.json", options)?;
+    to_writer(&mut writer, &sample_message(index))?;
+    writer.set_comment(format!("msg-{index}"));
+    writer.finish()?;
+  }
+  Ok(())
+}
+
+/// 只读方式加载激活分支，不触发迁移
+pub fn load_chat(app_data: &Path, chat_id: &str) -> Result<usize> {
+  let tree = ChatTree::load(&chat_dir(app_data, chat_id))?;
+  Ok(tree.read(&tree.active_path())?.len())
+}
+
+/// 将旧版本的对话迁移到日志
+pub fn migrate_chat(app_data: &Path, chat_id: &str) -> Result<()> {
+  super::key_store::load_master(app_data)?;
+  ChatTree::open(&chat_dir(app_data, chat_id))?;
+  Ok(())
+}
@@ -0,0 +1,343 @@
+use super::crypto::Key;
+use super::key_store::{KeySet, blob_name_key_path, shared_key_path};
+use super::reencrypt::is_current_file;
+use super::{atomic_write, crypto};
+use crate::error::{Error, Result};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::fs::{create_dir_all, remove_file};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+const BLOB_DIR: &str = "blobs";
+/// 旧版本以明文保存在 `blobs/` 下的命名密钥
+const LEGACY_NAME_KEY_FILE: &str = "name.key";
+
+/// 引用计数的读写需要互斥，避免并发保存时计数丢失
+static REFS_LOCK: Mutex<()> = Mutex::new(());
+
+fn to_hex(hash: &[u8]) -> String {
+  let mut hex = String::with_capacity(hash.len() * 2);
+  for byte in hash {
+    let _ = write!(hex, "{byte:02x}");
+  }
+  hex
+}
+
+/// 内容的 SHA-256，十六进制小写，与设备无关，可用于和外部文件比较
+pub(super) fn sha256_hex(data: &[u8]) -> String {
+  to_hex(&Sha256::digest(data))
+}
+
+/// 文件内容的标识：使用本机命名密钥计算的 HMAC-SHA256，十六进制小写
+///
+/// 同一设备上相同内容的标识相同，没有密钥时无法通过猜测内容确认某个文件是否存在
+pub(super) struct ContentId(Key);
+
+impl ContentId {
+  /// 读取命名密钥，没有时导入旧版本的命名密钥，已有文件的标识保持不变
+  fn load(path: &Path, legacy: &Path) -> Result<Self> {
+    let keys = KeySet::load_or_import(path, || match std::fs::read(legacy) {
+      Ok(data) => Key::from_slice(&data),
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Key::generate()),
+      Err(e) => Err(e.into()),
+    })?;
+    match remove_file(legacy) {
+      Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+      _ => {}
+    }
+    Ok(Self(keys.oldest()?.clone()))
+  }
+
+  pub fn of(&self, data: &[u8]) -> String {
+    let mut mac =
+      Hmac::<Sha256>::new_from_slice(self.0.as_bytes()).expect("HMAC 接受任意长度的密钥");
+    mac.update(data);
+    to_hex(&mac.finalize().into_bytes())
+  }
+}
+
+fn check_hash(hash: &str) -> Result<()> {
+  let valid = hash.len() == 64 && hash.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f'));
+  match valid {
+    true => Ok(()),
+    false => Err(Error::new(format!("非法的文件哈希: {hash}"))),
+  }
+}
+
+/// 以 [`ContentId`] 命名的加密文件存储，`blobs/<hash 前两位>/<hash>.blob`
+///
+/// 相同内容只保存一份，`<hash>.refs` 记录被引用的次数，计数归零时删除。
+/// 引用文件与计数在同一把锁内修改，中断时留下的计数偏差在启动时按实际的引用修正
+pub(super) struct BlobStore {
+  dir: PathBuf,
+  /// 全部对话共用的数据密钥
+  key_path: PathBuf,
+  /// 计算内容标识的密钥
+  name_key_path: PathBuf,
+}
+
+impl BlobStore {
+  pub fn new(app_data: &Path) -> Self {
+    Self {
+      dir: app_data.join(BLOB_DIR),
+      key_path: shared_key_path(app_data),
+      name_key_path: blob_name_key_path(app_data),
+    }
+  }
+
+  pub fn content_ids(&self) -> Result<ContentId> {
+    ContentId::load(&self.name_key_path, &self.dir.join(LEGACY_NAME_KEY_FILE))
+  }
+
+  fn path(&self, hash: &str, extension: &str) -> PathBuf {
+    self
+      .dir
+      .join(&hash[..2])
+      .join(format!("{hash}.{extension}"))
+  }
+
+  fn read_refs(&self, hash: &str) -> Result<u32> {
+    match std::fs::read_to_string(self.path(hash, "refs")) {
+      Ok(it) => Ok(it.trim().parse().unwrap_or_default()),
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(0),
+      Err(e) => Err(e.into()),
+    }
+  }
+
+  fn write_refs(&self, hash: &str, refs: u32) -> Result<()> {
+    atomic_write(&self.path(hash, "refs"), refs.to_string().as_bytes())?;
+    Ok(())
+  }
+
+  /// 减少一次引用，没有引用时删除内容，调用时需要持有 [`REFS_LOCK`]
+  fn release_locked(&self, hash: &str) -> Result<()> {
+    check_hash(hash)?;
+    let refs = self.read_refs(hash)?.saturating_sub(1);
+    if refs > 0 {
+      return self.write_refs(hash, refs);
+    }
+    self.remove(hash)
+  }
+
+  /// 删除内容以及计数文件
+  fn remove(&self, hash: &str) -> Result<()> {
+    // 先删除内容再删除计数，中断时只会留下计数文件
+    for extension in ["blob", "refs"] {
+      match remove_file(self.path(hash, extension)) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+      }
+    }
+    Ok(())
+  }
+
+  /// 保存内容并增加一次引用，返回内容标识
+  ///
+  /// `write_ref` 在持有锁时写入引用，返回被替换的引用指向的内容，该内容减少一次引用
+  pub fn put(
+    &self,
+    data: &[u8],
+    write_ref: impl FnOnce(&str) -> Result<Option<String>>,
+  ) -> Result<String> {
+    let hash = self.content_ids()?.of(data);
+    let path = self.path(&hash, "blob");
+    let keys = KeySet::load_or_create(&self.key_path)?;
+    let _guard = REFS_LOCK.lock().unwrap_or_else(|it| it.into_inner());
+
+    if !path.try_exists()? {
+      if let Some(parent) = path.parent() {
+        create_dir_all(parent)?;
+      }
+      atomic_write(&path, &keys.seal(data)?)?;
+    }
+    let refs = self.read_refs(&hash)?;
+    self.write_refs(&hash, refs + 1)?;
+    match write_ref(&hash) {
+      Ok(Some(old)) => self.release_locked(&old)?,
+      Ok(None) => {}
+      Err(e) => {
+        self.release_locked(&hash)?;
+        return Err(e);
+      }
+    }
+    Ok(hash)
+  }
+
+  /// 为已保存的内容增加一次引用，`write_ref` 在持有锁时写入引用
+  pub fn acquire(&self, hash: &str, write_ref: impl FnOnce() -> Result<()>) -> Result<()> {
+    check_hash(hash)?;
+    let _guard = REFS_LOCK.lock().unwrap_or_else(|it| it.into_inner());
+    if !self.path(hash, "blob").try_exists()? {
+      return Err(Error::NotFound(format!("blob({hash})")));
+    }
+    let refs = self.read_refs(hash)?;
+    self.write_refs(hash, refs + 1)?;
+    if let Err(e) = write_ref() {
+      self.write_refs(hash, refs)?;
+      return Err(e);
+    }
+    Ok(())
+  }
+
+  /// 删除引用并减少一次引用，没有引用时删除内容
+  ///
+  /// `remove_ref` 在持有锁时删除引用，返回引用指向的内容，引用不存在时返回 `None`
+  pub fn release(&self, remove_ref: impl FnOnce() -> Result<Option<String>>) -> Result<()> {
+    let _guard = REFS_LOCK.lock().unwrap_or_else(|it| it.into_inner());
+    match remove_ref()? {
+      Some(hash) => self.release_locked(&hash),
+      None => Ok(()),
+    }
+  }
+
+  /// 是否保存了该内容
+  pub fn contains(&self, hash: &str) -> Result<bool> {
+    check_hash(hash)?;
+    Ok(self.path(hash, "blob").try_exists()?)
+  }
+
+  /// 当前的引用次数
+  pub fn ref_count(&self, hash: &str) -> Result<u32> {
+    check_hash(hash)?;
+    let _guard = REFS_LOCK.lock().unwrap_or_else(|it| it.into_inner());
+    self.read_refs(hash)
+  }
+
+  /// 读取并解密内容，同时校验内容标识
+  pub fn read(&self, hash: &str) -> Result<Vec<u8>> {
+    check_hash(hash)?;
+    let keys = KeySet::load(&self.key_path)?;
+    let data = keys.open(&std::fs::read(self.path(hash, "blob"))?)?;
+    if self.content_ids()?.of(&data) != hash {
+      return Err(Error::new(format!("文件内容与哈希不一致: {hash}")));
+    }
+    Ok(data)
+  }
+
+  /// 全部内容文件的路径
+  fn blob_paths(&self) -> Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    if !self.dir.try_exists()? {
+      return Ok(paths);
+    }
+    for prefix in std::fs::read_dir(&self.dir)? {
+      let prefix = prefix?.path();
+      if !prefix.is_dir() {
+        continue;
+      }
+      for entry in std::fs::read_dir(prefix)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|it| it == "blob") {
+          paths.push(path);
+        }
+      }
+    }
+    Ok(paths)
+  }
+
+  /// 使用当前数据密钥重新加密旧版本以及其他密钥加密的内容，返回处理的数量
+  pub fn reseal(&self) -> Result<usize> {
+    let keys = KeySet::load_or_create(&self.key_path)?;
+    let mut count = 0;
+    for path in self.blob_paths()? {
+      // 持有锁避免与释放引用同时进行，重新写入已删除的内容
+      let _guard = REFS_LOCK.lock().unwrap_or_else(|it| it.into_inner());
+      let sealed = match std::fs::read(&path) {
+        Ok(it) => it,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+        Err(e) => return Err(e.into()),
+      };
+      if !keys.is_current(&sealed) {
+        atomic_write(&path, &keys.seal(&keys.open(&sealed)?)?)?;
+        count += 1;
+      }
+    }
+    Ok(count)
+  }
+
+  /// 压缩后重新加密旧版本没有压缩的内容，返回节省的字节数
+  pub fn recompress(&self) -> Result<u64> {
+    let keys = KeySet::load_or_create(&self.key_path)?;
+    let mut saved = 0;
+    for path in self.blob_paths()? {
+      let _guard = REFS_LOCK.lock().unwrap_or_else(|it| it.into_inner());
+      let sealed = match std::fs::read(&path) {
+        Ok(it) => it,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+        Err(e) => return Err(e.into()),
+      };
+      if crypto::is_compressed(&sealed) {
+        continue;
+      }
+      // 已经压缩过的内容（图片等）无法再变小，保持原样
+      let resealed = keys.seal(&keys.open(&sealed)?)?;
+      if crypto::is_compressed(&resealed) {
+        atomic_write(&path, &resealed)?;
+        saved += sealed.len().saturating_sub(resealed.len()) as u64;
+      }
+    }
+    Ok(saved)
+  }
+
+  /// 确认全部内容都使用当前数据密钥加密，并且解密后与内容标识一致
+  pub fn verify(&self) -> Result<()> {
+    let keys = KeySet::load(&self.key_path)?.current();
+    let ids = self.content_ids()?;
+    for path in self.blob_paths()? {
+      let sealed = match std::fs::read(&path) {
+        Ok(it) => it,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+        Err(e) => return Err(e.into()),
+      };
+      let hash = path.file_stem().unwrap_or_default().to_string_lossy();
+      if !keys.is_current(&sealed) || ids.of(&keys.open(&sealed)?) != hash {
+        return Err(Error::new(format!("文件内容无法使用新密钥读取: {hash}")));
+      }
+    }
+    Ok(())
+  }
+
+  /// 确认全部内容都使用当前数据密钥加密后执行 `then`，期间不会写入新的内容
+  pub fn with_current_keys(&self, then: impl FnOnce() -> Result<()>) -> Result<()> {
+    let keys = KeySet::load(&self.key_path)?;
+    let _guard = REFS_LOCK.lock().unwrap_or_else(|it| it.into_inner());
+    for path in self.blob_paths()? {
+      if !is_current_file(&keys, &path)? {
+        return Err(Error::new("存在未重新加密的文件内容"));
+      }
+    }
+    then()
+  }
+
+  /// 按实际的引用数量修正计数，删除没有引用的内容，返回修正的数量
+  ///
+  /// `count_refs` 在持有锁时统计全部引用，调用方需要保证期间不会移动引用所在的文件夹
+  pub fn repair_refs(
+    &self,
+    count_refs: impl FnOnce() -> Result<HashMap<String, u32>>,
+  ) -> Result<usize> {
+    let _guard = REFS_LOCK.lock().unwrap_or_else(|it| it.into_inner());
+    let counts = count_refs()?;
+    let mut repaired = 0;
+    for path in self.blob_paths()? {
+      let hash = path
+        .file_stem()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_string();
+      let expected = counts.get(&hash).copied().unwrap_or_default();
+      if self.read_refs(&hash)? == expected {
+        continue;
+      }
+      match expected {
+        0 => self.remove(&hash)?,
+        refs => self.write_refs(&hash, refs)?,
+      }
+      repaired += 1;
+    }
+    Ok(repaired)
+  }
+}
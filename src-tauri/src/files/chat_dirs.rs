@@ -0,0 +1,288 @@
+use super::blob_store::BlobStore;
+use super::chat_files::{FILE_DIR_NAME, copy_ref, ref_ids, release_ref, release_refs};
+use super::chat_log::ChatLog;
+use super::chat_messages::file_refs;
+use super::chat_tree::{ChatNode, ChatTree, chat_lock, write_head};
+use super::{SAVE_DIR, STAGING_DIR, check_id};
+use crate::error::{Error, Result};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::HashSet;
+use std::fs::{create_dir_all, remove_dir_all, rename};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tauri::async_runtime::spawn_blocking;
+
+/// 对话树中某一分支的起点
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChatBranch {
+  message_id: String,
+  index: u32,
+  /// 是否位于当前激活的分支上
+  active: bool,
+}
+
+/// 对话中保存的单条消息，包括全部分支
+pub struct StoredMessage {
+  pub index: u32,
+  pub message_id: String,
+  /// 父消息 id，第一条消息为空
+  pub parent_id: Option<String>,
+  pub message: Value,
+}
+
+/// 单个对话的文件夹，`chats/<chat_id>`
+pub struct ChatDir {
+  app_data: PathBuf,
+  root: PathBuf,
+  dir: PathBuf,
+}
+
+impl ChatDir {
+  pub fn new(app_data: &Path, chat_id: &str) -> Result<Self> {
+    // chat_id 会直接拼接为路径，需要避免跳出 chats 文件夹
+    check_id("对话", chat_id)?;
+    let root = app_data.join(SAVE_DIR);
+    let dir = root.join(chat_id);
+    let app_data = app_data.to_path_buf();
+    Ok(Self {
+      app_data,
+      root,
+      dir,
+    })
+  }
+
+  /// 列出全部对话 id
+  pub async fn list_ids(app_data: &Path) -> Result<Vec<String>> {
+    let root = app_data.join(SAVE_DIR);
+    spawn_blocking(move || {
+      let mut ids = Vec::new();
+      if !root.try_exists()? {
+        return Ok(ids);
+      }
+      for entry in std::fs::read_dir(root)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        // 跳过暂存区等隐藏文件夹
+        if entry.file_type()?.is_dir() && check_id("对话", &name).is_ok() {
+          ids.push(name);
+        }
+      }
+      Ok(ids)
+    })
+    .await?
+  }
+
+  /// 生成一个暂存路径，暂存文件夹中的内容在启动时会被清理
+  fn staging_path(&self, name: &str) -> PathBuf {
+    let nanos = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .map(|it| it.as_nanos())
+      .unwrap_or_default();
+    self.root.join(STAGING_DIR).join(format!("{name}-{nanos}"))
+  }
+
+  fn delete_blocking(self) -> Result<()> {
+    let _guard = chat_lock(&self.dir);
+    if !self.dir.try_exists()? {
+      return Ok(());
+    }
+
+    // 先整体移动至暂存区，保证对话要么完整存在，要么完全消失
+    let staging = self.staging_path("deleted");
+    if let Some(parent) = staging.parent() {
+      create_dir_all(parent)?;
+    }
+    rename(&self.dir, &staging)?;
+    release_refs(&self.app_data, &staging.join(FILE_DIR_NAME))?;
+    remove_dir_all(staging)?;
+    Ok(())
+  }
+
+  fn truncate_blocking(self, from_index: u32) -> Result<()> {
+    let _guard = chat_lock(&self.dir);
+    if !self.dir.try_exists()? {
+      return Ok(());
+    }
+
+    let tree = ChatTree::open(&self.dir)?;
+    let active = tree.active_path();
+    if from_index as usize > active.len() {
+      return Err(Error::new(format!(
+        "截断位置 {from_index} 超出当前分支的长度 {}",
+        active.len()
+      )));
+    }
+    // 激活分支指向保留下来的最后一条消息
+    let head = match from_index {
+      0 => None,
+      index => Some(active[index as usize - 1].meta.id.clone()),
+    };
+
+    // 只释放被删除的消息引用、保留下来的消息没有引用的文件
+    let file_ids = |nodes: &[&ChatNode]| -> Result<HashSet<String>> {
+      let messages = tree.read(nodes)?;
+      Ok(
+        messages
+          .iter()
+          .flat_map(file_refs)
+          .map(str::to_owned)
+          .collect(),
+      )
+    };
+    let (kept, removed): (Vec<&ChatNode>, Vec<&ChatNode>) =
+      tree.nodes().iter().partition(|it| it.index < from_index);
+    let kept_files = file_ids(&kept)?;
+    let released = file_ids(&removed)?
+      .into_iter()
+      .filter(|it| !kept_files.contains(it))
+      .collect::<Vec<_>>();
+
+    // 所有分支都删除，重写日志的过程中断时原有日志和激活分支都保持不变
+    tree.rewrite(|it| it.index < from_index)?;
+    write_head(&self.dir, head.as_deref())?;
+
+    // 日志重写完成后再释放文件，中断时最多留下没有被引用的文件
+    let store = BlobStore::new(&self.app_data);
+    let files = self.dir.join(FILE_DIR_NAME);
+    for file_id in released {
+      release_ref(&store, &files, &file_id)?;
+    }
+    Ok(())
+  }
+
+  fn fork_blocking(self, upto_index: u32) -> Result<String> {
+    let _guard = chat_lock(&self.dir);
+    let tree = ChatTree::load(&self.dir)?;
+    let new_id = format!("chat-{}", uuid::Uuid::new_v4().simple());
+
+    // 先在暂存区组装完整的对话，最后一步再移动到 chats 下
+    let staging = self.staging_path(&new_id);
+    let staging_files = staging.join(FILE_DIR_NAME);
+    create_dir_all(&staging_files)?;
+
+    // 只复制当前激活的分支
+    let path = tree.active_path();
+    let nodes: Vec<_> = path
+      .into_iter()
+      .filter(|it| it.index <= upto_index)
+      .collect();
+    let messages = tree.read(&nodes)?;
+    let file_ids: HashSet<String> = messages
+      .iter()
+      .flat_map(file_refs)
+      .map(str::to_owned)
+      .collect();
+
+    let head = nodes.last().map(|it| it.meta.id.as_str());
+    write_head(&staging, head)?;
+    let records = nodes
+      .iter()
+      .zip(messages)
+      .map(|(node, message)| (node.meta.clone(), node.index, message));
+    ChatLog::rewrite(&staging, records.collect())?;
+
+    // 文件内容在对话间共享，只复制引用
+    let files = self.dir.join(FILE_DIR_NAME);
+    for file_id in file_ids {
+      copy_ref(&self.app_data, &files, &staging_files, &file_id)?;
+    }
+
+    rename(staging, self.root.join(&new_id))?;
+    Ok(new_id)
+  }
+
+  fn branches_blocking(self, message_id: String) -> Result<Vec<ChatBranch>> {
+    let tree = ChatTree::load(&self.dir)?;
+    let active: HashSet<&str> = tree
+      .active_path()
+      .into_iter()
+      .map(|it| it.meta.id.as_str())
+      .collect();
+    let branches = tree
+      .siblings(&message_id)?
+      .into_iter()
+      .map(|it| ChatBranch {
+        message_id: it.meta.id.clone(),
+        index: it.index,
+        active: active.contains(it.meta.id.as_str()),
+      });
+    Ok(branches.collect())
+  }
+
+  fn read_nodes_blocking(self) -> Result<Vec<StoredMessage>> {
+    let tree = ChatTree::load(&self.dir)?;
+    let nodes: Vec<_> = tree.nodes().iter().collect();
+    let messages = tree.read(&nodes)?;
+    let nodes = nodes
+      .into_iter()
+      .zip(messages)
+      .map(|(node, message)| StoredMessage {
+        index: node.index,
+        message_id: node.meta.id.clone(),
+        parent_id: node.meta.parent.clone(),
+        message,
+      });
+    Ok(nodes.collect())
+  }
+
+  fn switch_branch_blocking(self, message_id: String) -> Result<()> {
+    let _guard = chat_lock(&self.dir);
+    let tree = ChatTree::load(&self.dir)?;
+    let node = tree
+      .get(&message_id)
+      .ok_or_else(|| Error::NotFound(format!("message({message_id})")))?;
+    write_head(&self.dir, Some(&tree.leaf_of(node).meta.id))
+  }
+}
+
+impl ChatDir {
+  /// 删除对话，包括全部消息和 `files/` 文件夹
+  pub async fn delete(self) -> Result<()> {
+    spawn_blocking(move || self.delete_blocking()).await?
+  }
+
+  /// 删除全部分支中 index 大于等于 `from_index` 的消息
+  pub async fn truncate(self, from_index: u32) -> Result<()> {
+    spawn_blocking(move || self.truncate_blocking(from_index)).await?
+  }
+
+  /// 复制激活分支上 index 小于等于 `upto_index` 的消息及其引用的文件到新对话，返回新对话 id
+  pub async fn fork(self, upto_index: u32) -> Result<String> {
+    spawn_blocking(move || self.fork_blocking(upto_index)).await?
+  }
+
+  /// 列出与指定消息同一父消息下的全部分支
+  pub async fn branches(self, message_id: String) -> Result<Vec<ChatBranch>> {
+    spawn_blocking(move || self.branches_blocking(message_id)).await?
+  }
+
+  /// 读取对话中全部分支的消息
+  pub async fn read_nodes(self) -> Result<Vec<StoredMessage>> {
+    spawn_blocking(move || self.read_nodes_blocking()).await?
+  }
+
+  /// 对话中保存的全部文件 id
+  pub async fn file_ids(self) -> Result<Vec<String>> {
+    spawn_blocking(move || ref_ids(&self.dir.join(FILE_DIR_NAME))).await?
+  }
+
+  /// 切换到指定消息所在的分支，激活该消息下最新的叶子消息
+  pub async fn switch_branch(self, message_id: String) -> Result<()> {
+    spawn_blocking(move || self.switch_branch_blocking(message_id)).await?
+  }
+}
+
+/// 清理上次运行时中断遗留的暂存文件夹，并释放其中的文件引用
+pub fn cleanup_staging(app_data: &Path) -> Result<()> {
+  let staging = app_data.join(SAVE_DIR).join(STAGING_DIR);
+  if !staging.try_exists()? {
+    return Ok(());
+  }
+  for entry in std::fs::read_dir(&staging)? {
+    release_refs(app_data, &entry?.path().join(FILE_DIR_NAME))?;
+  }
+  remove_dir_all(staging)?;
+  Ok(())
+}
@@ -0,0 +1,156 @@
+use super::chat_tree::{ChatTree, chat_lock};
+use super::key_store::{KeySet, chat_key_path};
+use super::{SAVE_DIR, atomic_write, check_id};
+use crate::error::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs::create_dir_all;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tauri::async_runtime::spawn_blocking;
+
+pub(super) const DRAFT_DIR_NAME: &str = "drafts";
+
+/// 正在流式生成中的消息草稿，`chats/<chat_id>/drafts/<message_id>.draft`
+///
+/// 最终消息保存后草稿会被自动删除，启动时仍存在的草稿即为中断的消息
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChatDraft {
+  chat_id: String,
+  /// 此信息在对话中的 index
+  index: u32,
+  message_id: String,
+  /// 父消息 id，与 `save_chat_message` 一致
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  parent_id: Option<String>,
+  /// 已生成的部分消息内容
+  message: Value,
+  /// 最后一次保存的时间，毫秒时间戳
+  #[serde(default)]
+  updated_at: u64,
+}
+
+fn draft_path(chat_dir: &Path, message_id: &str) -> PathBuf {
+  chat_dir
+    .join(DRAFT_DIR_NAME)
+    .join(format!("{message_id}.draft"))
+}
+
+/// 删除指定消息的草稿，草稿不存在时忽略
+pub(super) fn remove_draft(chat_dir: &Path, message_id: &str) -> Result<()> {
+  match std::fs::remove_file(draft_path(chat_dir, message_id)) {
+    Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+    _ => Ok(()),
+  }
+}
+
+pub(super) fn read_draft(keys: &KeySet, path: &Path) -> Result<ChatDraft> {
+  let data = keys.open(&std::fs::read(path)?)?;
+  Ok(serde_json::from_slice(&data)?)
+}
+
+/// 对话中全部可以解密的草稿消息
+pub(super) fn draft_messages(chat_dir: &Path) -> Result<Vec<Value>> {
+  let dir = chat_dir.join(DRAFT_DIR_NAME);
+  let mut messages = Vec::new();
+  if !dir.is_dir() {
+    return Ok(messages);
+  }
+  let keys = KeySet::load(&chat_key_path(chat_dir))?;
+  for entry in std::fs::read_dir(dir)? {
+    let path = entry?.path();
+    if path.extension().filter(|it| *it == "draft").is_none() {
+      continue;
+    }
+    if let Ok(draft) = read_draft(&keys, &path) {
+      messages.push(draft.message);
+    }
+  }
+  Ok(messages)
+}
+
+fn recover_blocking(root: PathBuf) -> Result<Vec<ChatDraft>> {
+  let mut drafts = Vec::new();
+  if !root.try_exists()? {
+    return Ok(drafts);
+  }
+
+  for entry in std::fs::read_dir(root)? {
+    let chat_dir = entry?.path();
+    let dir = chat_dir.join(DRAFT_DIR_NAME);
+    if !dir.is_dir() {
+      continue;
+    }
+
+    // 存储锁定时无法读取密钥，不能把草稿当作无法解密删除
+    let Ok(keys) = KeySet::load(&chat_key_path(&chat_dir)) else {
+      continue;
+    };
+    let tree = ChatTree::load(&chat_dir).ok();
+    for entry in std::fs::read_dir(dir)? {
+      let path = entry?.path();
+      if path.extension().filter(|it| *it == "draft").is_none() {
+        continue;
+      }
+      // 无法解密的草稿已经没有恢复的价值
+      let Ok(draft) = read_draft(&keys, &path) else {
+        std::fs::remove_file(path)?;
+        continue;
+      };
+      // 最终消息已保存，但草稿未来得及删除
+      if tree
+        .as_ref()
+        .is_some_and(|it| it.get(&draft.message_id).is_some())
+      {
+        std::fs::remove_file(path)?;
+        continue;
+      }
+      drafts.push(draft);
+    }
+  }
+
+  drafts.sort_by_key(|it| it.updated_at);
+  Ok(drafts)
+}
+
+impl ChatDraft {
+  /// 覆盖保存草稿，写入过程中断不会破坏上一次保存的草稿
+  pub async fn save(mut self, app_data: &Path) -> Result<()> {
+    check_id("对话", &self.chat_id)?;
+    check_id("消息", &self.message_id)?;
+    let chat_dir = app_data.join(SAVE_DIR).join(&self.chat_id);
+    self.updated_at = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .map(|it| it.as_millis() as u64)
+      .unwrap_or_default();
+
+    spawn_blocking(move || {
+      // 避免与删除、截断和分叉对话同时写入对话文件夹
+      let _guard = chat_lock(&chat_dir);
+      let path = draft_path(&chat_dir, &self.message_id);
+      if let Some(parent) = path.parent() {
+        create_dir_all(parent)?;
+      }
+      let keys = KeySet::load_or_create(&chat_key_path(&chat_dir))?;
+      let data = keys.seal(&serde_json::to_vec(&self)?)?;
+      atomic_write(&path, &data)?;
+      Ok(())
+    })
+    .await?
+  }
+
+  /// 读取全部对话中遗留的草稿，按保存时间排列
+  pub async fn recover(app_data: &Path) -> Result<Vec<ChatDraft>> {
+    let root = app_data.join(SAVE_DIR);
+    spawn_blocking(move || recover_blocking(root)).await?
+  }
+
+  /// 丢弃草稿
+  pub async fn discard(app_data: &Path, chat_id: String, message_id: String) -> Result<()> {
+    check_id("对话", &chat_id)?;
+    check_id("消息", &message_id)?;
+    let chat_dir = app_data.join(SAVE_DIR).join(chat_id);
+    spawn_blocking(move || remove_draft(&chat_dir, &message_id)).await?
+  }
+}
@@ -0,0 +1,340 @@
+use super::chat_messages::{file_refs, message_text};
+use super::{ChatFile, ChatMessage, atomic_write};
+use crate::database::DatabaseHandler;
+use crate::error::Result;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use data_url::DataUrl;
+use pulldown_cmark::{Event, Options, Parser, Tag, TagEnd, html};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use tauri::async_runtime::spawn_blocking;
+use tauri_plugin_http::reqwest::Url;
+
+const HTML_STYLE: &str = "body{max-width:860px;margin:0 auto;padding:24px;\
+font-family:system-ui,sans-serif;line-height:1.6;color:#171717}\
+section{border-bottom:1px solid #e5e5e5;padding:12px 0}\
+h2{font-size:14px;color:#737373;margin:0 0 8px}\
+pre{background:#f5f5f5;padding:12px;overflow:auto;border-radius:6px}\
+code{font-family:ui-monospace,monospace}\
+img{max-width:100%}\
+blockquote{margin:0;padding-left:12px;border-left:3px solid #d4d4d4;color:#525252}";
+
+/// 导出的网页中链接和图片允许的协议，其余的只保留文字
+const URL_SCHEMES: &[&str] = &["http", "https", "mailto", "data"];
+
+/// 生成笔记总结时最多保留的字符数
+const SUMMARY_CHARS: usize = 120;
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+  Markdown,
+  Html,
+  Json,
+}
+
+/// 导出文件中附件的引用方式
+enum AttachmentLink<'a> {
+  /// 以 data url 的形式内嵌
+  Inline,
+  /// 复制到导出文件旁的文件夹中，记录文件夹路径及相对路径前缀
+  Alongside(&'a Path, &'a str),
+  /// 只保留文件名
+  Placeholder,
+}
+
+fn role_label(role: &str) -> &str {
+  match role {
+    "user" => "用户",
+    "assistant" => "助手",
+    "system" => "系统",
+    other => other,
+  }
+}
+
+fn escape_label(text: &str) -> String {
+  text.replace('[', "\\[").replace(']', "\\]")
+}
+
+fn quote(text: &str) -> String {
+  text.lines().map(|it| format!("> {it}\n")).collect()
+}
+
+/// 将单条消息渲染为 markdown，附件按 `link` 方式引用
+fn render_message(
+  message: &Value,
+  files: &HashMap<String, Vec<u8>>,
+  link: &AttachmentLink,
+) -> Result<String> {
+  let mut out = String::new();
+  let parts = message.get("parts").and_then(Value::as_array);
+  for part in parts.into_iter().flatten() {
+    let kind = part.get("type").and_then(Value::as_str).unwrap_or_default();
+    let text = part.get("text").and_then(Value::as_str).unwrap_or_default();
+    match kind {
+      "text" => writeln!(out, "{text}\n")?,
+      "reasoning" => writeln!(out, "{}", quote(text))?,
+      "file" => {
+        let url = part.get("url").and_then(Value::as_str).unwrap_or_default();
+        let mime = part
+          .get("mediaType")
+          .and_then(Value::as_str)
+          .unwrap_or("application/octet-stream");
+        let name = part.get("filename").and_then(Value::as_str).unwrap_or(url);
+
+        if let AttachmentLink::Placeholder = link {
+          writeln!(out, "> 附件：{name}\n")?;
+          continue;
+        }
+
+        let target = match (files.get(url), link) {
+          (Some(data), AttachmentLink::Inline) => {
+            format!("data:{mime};base64,{}", BASE64.encode(data))
+          }
+          (Some(data), AttachmentLink::Alongside(dir, prefix)) => {
+            let ext = mime_guess::get_mime_extensions_str(mime)
+              .and_then(|it| it.first())
+              .map(|it| format!(".{it}"))
+              .unwrap_or_default();
+            let file_name = format!("{url}{ext}");
+            std::fs::create_dir_all(dir)?;
+            std::fs::write(dir.join(&file_name), data)?;
+            format!("{prefix}/{file_name}")
+          }
+          // 未保存的文件保留原始 url
+          _ => url.to_string(),
+        };
+
+        let bang = if mime.starts_with("image/") { "!" } else { "" };
+        writeln!(out, "{bang}[{}](<{target}>)\n", escape_label(name))?;
+      }
+      kind if kind.starts_with("tool-") || kind == "dynamic-tool" => {
+        let name = part
+          .get("toolName")
+          .and_then(Value::as_str)
+          .unwrap_or_else(|| kind.trim_start_matches("tool-"));
+        let detail = serde_json::json!({
+          "input": part.get("input"),
+          "output": part.get("output"),
+        });
+        let detail = serde_json::to_string_pretty(&detail)?;
+        writeln!(out, "`{name}`\n\n```json\n{detail}\n```\n")?;
+      }
+      // 其余类型（step-start、source 等）不导出
+      _ => {}
+    }
+  }
+  Ok(out)
+}
+
+/// 将消息渲染为 markdown 正文，附件只保留文件名
+pub fn messages_to_markdown(messages: &[Value]) -> Result<String> {
+  let mut out = String::new();
+  for message in messages {
+    let role = message
+      .get("role")
+      .and_then(Value::as_str)
+      .unwrap_or_default();
+    writeln!(out, "## {}\n", role_label(role))?;
+    out.push_str(&render_message(
+      message,
+      &HashMap::new(),
+      &AttachmentLink::Placeholder,
+    )?);
+  }
+  Ok(out)
+}
+
+/// 根据第一条用户消息生成简短的总结
+pub fn messages_summary(messages: &[Value]) -> String {
+  let first = messages
+    .iter()
+    .find(|it| it.get("role").and_then(Value::as_str) == Some("user"))
+    .or(messages.first());
+  let text = first.map(message_text).unwrap_or_default();
+  let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+
+  let mut summary: String = text.chars().take(SUMMARY_CHARS).collect();
+  if text.chars().count() > SUMMARY_CHARS {
+    summary.push('…');
+  }
+  format!("由 {} 条对话记录整理：{summary}", messages.len())
+}
+
+fn render_markdown(
+  title: &str,
+  messages: &[Value],
+  files: &HashMap<String, Vec<u8>>,
+  link: &AttachmentLink,
+) -> Result<String> {
+  let mut out = format!("# {title}\n\n");
+  for message in messages {
+    let role = message
+      .get("role")
+      .and_then(Value::as_str)
+      .unwrap_or_default();
+    writeln!(out, "## {}\n", role_label(role))?;
+    out.push_str(&render_message(message, files, link)?);
+  }
+  Ok(out)
+}
+
+/// 地址是否可以保留在导出的网页中，相对地址按导出文件所在位置解析
+///
+/// 协议在解析之后检查，`JavaScript:`、夹杂空白等写法都已经被规范化；
+/// 链接中的 data url 不能是网页、脚本等可以执行代码的类型，图片中的不受限制
+fn is_safe_url(url: &str, image: bool) -> bool {
+  let Ok(url) = Url::parse("file:///").and_then(|it| it.join(url.trim())) else {
+    return false;
+  };
+  match url.scheme() {
+    "file" => true,
+    "data" if !image => DataUrl::process(url.as_str()).is_ok_and(|it| {
+      let subtype = &it.mime_type().subtype;
+      !["html", "xml", "javascript"]
+        .iter()
+        .any(|it| subtype.contains(it))
+    }),
+    scheme => URL_SCHEMES.contains(&scheme),
+  }
+}
+
+fn markdown_to_html(markdown: &str) -> String {
+  // 原始 html 按文本处理，不安全的链接和图片只保留文字，避免导出的页面执行脚本
+  let mut kept = Vec::new();
+  let parser = Parser::new_ext(markdown, Options::all()).filter_map(|event| match event {
+    Event::Html(it) | Event::InlineHtml(it) => Some(Event::Text(it)),
+    Event::Start(Tag::Link { ref dest_url, .. }) => {
+      let keep = is_safe_url(dest_url, false);
+      kept.push(keep);
+      keep.then_some(event)
+    }
+    Event::Start(Tag::Image { ref dest_url, .. }) => {
+      let keep = is_safe_url(dest_url, true);
+      kept.push(keep);
+      keep.then_some(event)
+    }
+    Event::End(TagEnd::Link | TagEnd::Image) => kept.pop().unwrap_or(true).then_some(event),
+    other => Some(other),
+  });
+  let mut out = String::new();
+  html::push_html(&mut out, parser);
+  out
+}
+
+fn escape_html(text: &str) -> String {
+  text
+    .replace('&', "&amp;")
+    .replace('<', "&lt;")
+    .replace('>', "&gt;")
+    .replace('"', "&quot;")
+}
+
+fn render_html(
+  title: &str,
+  messages: &[Value],
+  files: &HashMap<String, Vec<u8>>,
+) -> Result<String> {
+  let title = escape_html(title);
+  let mut out = format!(
+    "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{title}</title>\n\
+     <style>{HTML_STYLE}</style>\n</head>\n<body>\n<h1>{title}</h1>\n"
+  );
+  for message in messages {
+    let role = message
+      .get("role")
+      .and_then(Value::as_str)
+      .unwrap_or_default();
+    let body = render_message(message, files, &AttachmentLink::Inline)?;
+    writeln!(
+      out,
+      "<section class=\"{}\">\n<h2>{}</h2>\n{}</section>",
+      escape_html(role),
+      escape_html(role_label(role)),
+      markdown_to_html(&body)
+    )?;
+  }
+  out.push_str("</body>\n</html>\n");
+  Ok(out)
+}
+
+fn export_to_disk(
+  chat_id: String,
+  title: String,
+  messages: Vec<Value>,
+  files: HashMap<String, Vec<u8>>,
+  format: ExportFormat,
+  target: PathBuf,
+) -> Result<()> {
+  let data = match format {
+    ExportFormat::Json => serde_json::to_vec_pretty(&messages)?,
+    ExportFormat::Html => render_html(&title, &messages, &files)?.into_bytes(),
+    ExportFormat::Markdown => {
+      // 附件复制到 `<文件名>_files` 文件夹中
+      let stem = target
+        .file_stem()
+        .map(|it| it.to_string_lossy().to_string())
+        .unwrap_or_else(|| chat_id.clone());
+      let prefix = format!("{stem}_files");
+      let dir = target.with_file_name(&prefix);
+      let link = AttachmentLink::Alongside(&dir, &prefix);
+      render_markdown(&title, &messages, &files, &link)?.into_bytes()
+    }
+  };
+  atomic_write(&target, &data)?;
+  Ok(())
+}
+
+/// 将当前激活分支上的对话导出到 `target`，标题使用数据库中的对话标题
+pub async fn export_chat(
+  app_data: &Path,
+  database: &DatabaseHandler,
+  chat_id: String,
+  format: ExportFormat,
+  target: PathBuf,
+) -> Result<()> {
+  let title = match database.find_chat_by_id(&chat_id).await? {
+    Some(chat) => chat.title,
+    None => chat_id.clone(),
+  };
+  let messages = ChatMessage::read_all(app_data, chat_id.clone()).await?;
+
+  let mut files = HashMap::new();
+  if !matches!(format, ExportFormat::Json) {
+    for file_id in messages.iter().flat_map(file_refs) {
+      if files.contains_key(file_id) {
+        continue;
+      }
+      // 缺失的附件不影响导出，保留原始引用
+      if let Ok(data) = ChatFile::new(&chat_id, file_id).read(app_data).await {
+        files.insert(file_id.to_string(), data);
+      }
+    }
+  }
+
+  spawn_blocking(move || export_to_disk(chat_id, title, messages, files, format, target)).await?
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn drops_unsafe_links() {
+    let html = markdown_to_html(
+      "[a](<JavaScript:alert(1)>) [b](< javascript:alert(1)>) \
+       [c](data:text/html,<script>) [d](https://example.com) \
+       [e](data:text/plain;base64,YQ==) ![f](data:image/png;base64,YQ==)",
+    );
+    assert!(!html.contains("javascript"), "{html}");
+    assert!(!html.contains("text/html"), "{html}");
+    assert!(html.contains("<a href=\"https://example.com\">d</a>"));
+    assert!(html.contains("href=\"data:text/plain;base64,YQ==\""));
+    assert!(html.contains("<img src=\"data:image/png;base64,YQ==\""));
+    assert!(html.contains("a b c"));
+  }
+}
@@ -1,18 +1,26 @@
-use super::{PASSWORD, SAVE_DIR};
+use super::blob_store::{BlobStore, sha256_hex};
+use super::chat_tree::all_chats_lock;
+use super::note_files::NOTE_DIR;
+use super::remote_fetch::{FETCH_POLICY_KEY, FetchPolicy};
+use super::text_extract::remove_text_cache;
+use super::{LEGACY_PASSWORD, SAVE_DIR, STAGING_DIR, atomic_write, check_id};
 use crate::database::DatabaseHandler;
-use crate::error::{MapToCustomError, Result};
+use crate::error::{Error, MapToCustomError, Result};
 use data_url::DataUrl;
-use serde::Deserialize;
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fs::{File, create_dir_all};
-use std::io::{Read, Write};
+use std::io::Read;
 use std::path::{Path, PathBuf};
 use tauri::async_runtime::spawn_blocking;
-use tauri_plugin_http::reqwest;
-use zip::write::SimpleFileOptions;
-use zip::{AesMode, CompressionMethod, ZipArchive, ZipWriter};
+use zip::ZipArchive;
 
-const FILE_DIR_NAME: &str = "files";
+pub(super) const FILE_DIR_NAME: &str = "files";
 const DEFAULT_FILENAME: &str = "data";
+const REF_EXTENSION: &str = "ref";
+/// 旧版本每个对话单独保存的加密文件
+const LEGACY_EXTENSION: &str = "file";
 
 #[derive(Debug, Clone, Deserialize)]
 #[serde(tag = "kind", content = "payload")]
@@ -23,35 +31,119 @@ enum ChatFileData {
   Path(PathBuf),
   #[serde(rename = "saved-id")]
   RefId(String),
+  /// 从其他设备同步的文件内容
+  #[serde(skip)]
+  Synced(Vec<u8>),
+}
+
+/// 读取到的文件内容，以及来源中能得到的文件名和 MIME 类型
+struct FileContent {
+  data: Vec<u8>,
+  name: Option<String>,
+  mime_type: Option<String>,
 }
 
 impl ChatFileData {
-  async fn to_data(&self, database: &DatabaseHandler) -> Result<Vec<u8>> {
+  /// 来源类型，与序列化时的 `kind` 一致
+  fn kind(&self) -> &'static str {
+    match self {
+      Self::Url(_) => "url",
+      Self::Path(_) => "local-path",
+      Self::RefId(_) => "saved-id",
+      Self::Synced(_) => "sync",
+    }
+  }
+
+  async fn to_data(&self, database: &DatabaseHandler) -> Result<FileContent> {
     match self {
       Self::Url(url) => {
         if url.starts_with("data:") {
           let url = DataUrl::process(url)?;
-          let (body, _) = url.decode_to_vec()?;
-          return Ok(body);
+          let (data, _) = url.decode_to_vec()?;
+          let mime_type = Some(url.mime_type().to_string());
+          return Ok(FileContent {
+            data,
+            name: None,
+            mime_type,
+          });
         }
-        let res = reqwest::get(url).await?;
-        Ok(res.bytes().await?.into())
+        // 远程文件按设置中的获取策略下载
+        let policy: FetchPolicy = database.get_setting(FETCH_POLICY_KEY).await?;
+        let fetched = policy.fetch(url).await?;
+        let name = fetched
+          .url
+          .path_segments()
+          .and_then(|mut it| it.next_back())
+          .filter(|it| !it.is_empty())
+          .map(|it| percent_decode_str(it).decode_utf8_lossy().to_string());
+        Ok(FileContent {
+          data: fetched.data,
+          name,
+          mime_type: fetched.content_type,
+        })
       }
       Self::Path(path) => {
+        let name = path.file_name().map(|it| it.to_string_lossy().to_string());
         let path = path.clone();
-        Ok(spawn_blocking(move || std::fs::read(path)).await??)
+        let data = spawn_blocking(move || std::fs::read(path)).await??;
+        Ok(FileContent {
+          data,
+          name,
+          mime_type: None,
+        })
       }
       Self::RefId(note_id) => {
         let note = database
           .find_note_by_id(note_id)
           .await?
           .map_custom_err(|_| format!("找不到对应笔记（{note_id}）"))?;
-        Ok(note.content.into_bytes())
+        Ok(FileContent {
+          data: note.content.into_bytes(),
+          name: Some(format!("{}.md", note.title)),
+          mime_type: Some("text/markdown".to_string()),
+        })
       }
+      Self::Synced(data) => Ok(FileContent {
+        data: data.clone(),
+        name: None,
+        mime_type: None,
+      }),
     }
   }
 }
 
+/// 根据文件内容判断 MIME 类型，无法从内容判断时依次参考文件名和来源给出的类型
+pub(super) fn sniff_mime(data: &[u8], name: Option<&str>, declared: Option<&str>) -> String {
+  if let Some(kind) = infer::get(data) {
+    return kind.mime_type().to_string();
+  }
+  let guessed = name.and_then(|it| mime_guess::from_path(it).first_raw());
+  if let Some(mime_type) = guessed.or(declared) {
+    return mime_type.to_string();
+  }
+  match std::str::from_utf8(data) {
+    Ok(_) => "text/plain".to_string(),
+    Err(_) => "application/octet-stream".to_string(),
+  }
+}
+
+/// 对话文件的元数据
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChatFileStat {
+  file_id: String,
+  /// 原始文件名
+  name: Option<String>,
+  mime_type: String,
+  size: u64,
+  /// 内容标识，见 [`ContentId`](super::blob_store::ContentId)，只在本机有效
+  content_id: String,
+  /// 内容的 SHA-256，十六进制小写
+  sha256: String,
+  /// 来源类型：url、local-path、saved-id、sync，旧版本迁移的文件为空
+  source: Option<String>,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ChatFile {
@@ -59,60 +151,375 @@ pub struct ChatFile {
   chat_id: String,
   /// 此消息所属的文件 id
   file_id: String,
+  /// 原始文件名，缺省时从来源中获取
+  #[serde(default)]
+  filename: Option<String>,
   /// 文件内容
   data: Option<ChatFileData>,
 }
 
-impl ChatFile {
-  fn save_to_disk(self, path: PathBuf, data: Vec<u8>) -> Result<PathBuf> {
-    // 如果不存在父文件夹，创建文件夹
-    if let Some(parent) = path.parent() {
-      create_dir_all(parent)?;
+impl ChatFileStat {
+  pub fn name(&self) -> Option<&str> {
+    self.name.as_deref()
+  }
+
+  pub fn sha256(&self) -> &str {
+    &self.sha256
+  }
+}
+
+/// 对话中的文件引用，`files/<file_id>.ref`，内容保存在 [`BlobStore`] 中
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct FileRef {
+  /// 内容标识，见 [`ContentId`](super::blob_store::ContentId)
+  pub blob: String,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub name: Option<String>,
+  /// 旧版本迁移的引用没有元数据，读取时补全
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub mime_type: Option<String>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub size: Option<u64>,
+  /// 内容的 SHA-256，保存时计算
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub sha256: Option<String>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub source: Option<String>,
+}
+
+pub(super) fn ref_path(files_dir: &Path, file_id: &str) -> PathBuf {
+  files_dir.join(format!("{file_id}.{REF_EXTENSION}"))
+}
+
+pub(super) fn legacy_path(files_dir: &Path, file_id: &str) -> PathBuf {
+  files_dir.join(format!("{file_id}.{LEGACY_EXTENSION}"))
+}
+
+pub(super) fn read_ref(files_dir: &Path, file_id: &str) -> Result<Option<FileRef>> {
+  match std::fs::read(ref_path(files_dir, file_id)) {
+    Ok(it) => Ok(Some(serde_json::from_slice(&it)?)),
+    Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+    Err(e) => Err(e.into()),
+  }
+}
+
+pub(super) fn write_ref(files_dir: &Path, file_id: &str, file_ref: &FileRef) -> Result<()> {
+  create_dir_all(files_dir)?;
+  atomic_write(
+    &ref_path(files_dir, file_id),
+    &serde_json::to_vec(file_ref)?,
+  )?;
+  Ok(())
+}
+
+/// 读取旧版本每个对话单独保存的 `<file_id>.file`
+fn read_legacy(path: &Path) -> Result<Vec<u8>> {
+  let file = File::open(path)?;
+  let mut archive = ZipArchive::new(file)?;
+  let mut entry = archive.by_name_decrypt(DEFAULT_FILENAME, LEGACY_PASSWORD.as_bytes())?;
+  let mut buffer = Vec::with_capacity(entry.size() as usize);
+  entry.read_to_end(&mut buffer)?;
+  Ok(buffer)
+}
+
+pub(super) fn read_legacy_file(files_dir: &Path, file_id: &str) -> Result<Vec<u8>> {
+  read_legacy(&legacy_path(files_dir, file_id))
+}
+
+/// 文件夹中全部文件引用的 id
+pub(super) fn ref_ids(files_dir: &Path) -> Result<Vec<String>> {
+  let mut ids = Vec::new();
+  if !files_dir.try_exists()? {
+    return Ok(ids);
+  }
+  for entry in std::fs::read_dir(files_dir)? {
+    let path = entry?.path();
+    if path.extension().filter(|it| *it == REF_EXTENSION).is_none() {
+      continue;
+    }
+    if let Some(id) = path.file_stem().and_then(|it| it.to_str()) {
+      ids.push(id.to_string());
     }
+  }
+  Ok(ids)
+}
+
+/// 将旧版本的 `<file_id>.file` 转换为文件引用
+fn migrate_legacy(store: &BlobStore, files_dir: &Path, file_id: &str) -> Result<()> {
+  let path = legacy_path(files_dir, file_id);
+  let data = read_legacy(&path)?;
+  store.put(&data, |blob| {
+    let file_ref = FileRef {
+      blob: blob.to_string(),
+      mime_type: Some(sniff_mime(&data, None, None)),
+      size: Some(data.len() as u64),
+      sha256: Some(sha256_hex(&data)),
+      ..Default::default()
+    };
+    let old = read_ref(files_dir, file_id)?;
+    write_ref(files_dir, file_id, &file_ref)?;
+    Ok(old.map(|it| it.blob))
+  })?;
+  std::fs::remove_file(path)?;
+  Ok(())
+}
+
+/// 迁移全部对话中旧版本的文件，返回迁移失败的文件
+///
+/// 迁移失败的文件保持原样，仍可按旧版本读取，下次启动时重试
+pub(super) fn migrate_legacy_files(app_data: &Path) -> Result<Vec<String>> {
+  let mut failed = Vec::new();
+  let root = app_data.join(SAVE_DIR);
+  if !root.try_exists()? {
+    return Ok(failed);
+  }
+
+  let store = BlobStore::new(app_data);
+  for entry in std::fs::read_dir(root)? {
+    let files_dir = entry?.path().join(FILE_DIR_NAME);
+    if !files_dir.is_dir() {
+      continue;
+    }
+    for entry in std::fs::read_dir(&files_dir)? {
+      let path = entry?.path();
+      if path
+        .extension()
+        .filter(|it| *it == LEGACY_EXTENSION)
+        .is_none()
+      {
+        continue;
+      }
+      if let Some(file_id) = path.file_stem().and_then(|it| it.to_str())
+        && let Err(e) = migrate_legacy(&store, &files_dir, file_id)
+      {
+        let path = path.strip_prefix(app_data).unwrap_or(&path);
+        failed.push(format!("{}: {e}", path.display()));
+      }
+    }
+  }
+  Ok(failed)
+}
 
-    let file = File::create(&path)?;
-    let mut writer = ZipWriter::new(file);
+/// 读取文件引用，旧版本的文件会先迁移
+pub(super) fn load_ref(store: &BlobStore, files_dir: &Path, file_id: &str) -> Result<FileRef> {
+  let legacy = legacy_path(files_dir, file_id);
+  if read_ref(files_dir, file_id)?.is_none() && legacy.try_exists()? {
+    migrate_legacy(store, files_dir, file_id)?;
+  }
+  read_ref(files_dir, file_id)?.ok_or_else(|| Error::NotFound(format!("file({file_id})")))
+}
 
-    let options = SimpleFileOptions::default()
-      .compression_method(CompressionMethod::Stored)
-      .with_aes_encryption(AesMode::Aes256, PASSWORD);
+/// 复制文件引用到另一个对话，内容增加一次引用
+pub(super) fn copy_ref(app_data: &Path, from: &Path, to: &Path, file_id: &str) -> Result<()> {
+  let store = BlobStore::new(app_data);
+  if legacy_path(from, file_id).try_exists()? {
+    migrate_legacy(&store, from, file_id)?;
+  }
+  // 引用的文件可能尚未保存成功，跳过即可
+  let Some(file_ref) = read_ref(from, file_id)? else {
+    return Ok(());
+  };
+  store.acquire(&file_ref.blob, || write_ref(to, file_id, &file_ref))
+}
 
-    writer.start_file(DEFAULT_FILENAME, options)?;
-    writer.write_all(&data)?;
-    writer.finish()?;
+/// 删除文件夹中的全部文件引用并释放对应内容
+pub(super) fn release_refs(app_data: &Path, files_dir: &Path) -> Result<()> {
+  let store = BlobStore::new(app_data);
+  for file_id in ref_ids(files_dir)? {
+    release_ref(&store, files_dir, &file_id)?;
+  }
+  Ok(())
+}
 
-    Ok(path)
+/// 删除单个文件引用并释放内容，引用不存在时忽略
+pub(super) fn release_ref(store: &BlobStore, files_dir: &Path, file_id: &str) -> Result<()> {
+  store.release(|| {
+    let Some(file_ref) = read_ref(files_dir, file_id)? else {
+      return Ok(None);
+    };
+    // 先删除引用再释放，中断时最多多出一次计数，不会误删内容
+    std::fs::remove_file(ref_path(files_dir, file_id))?;
+    Ok(Some(file_ref.blob))
+  })?;
+  remove_text_cache(files_dir, file_id)
+}
+
+/// 全部可能包含文件引用的文件夹：对话、暂存区中的对话以及笔记
+fn ref_dirs(app_data: &Path) -> Result<Vec<PathBuf>> {
+  let root = app_data.join(SAVE_DIR);
+  let mut dirs = Vec::new();
+  for parent in [
+    root.clone(),
+    root.join(STAGING_DIR),
+    app_data.join(NOTE_DIR),
+  ] {
+    if !parent.is_dir() {
+      continue;
+    }
+    for entry in std::fs::read_dir(parent)? {
+      let files_dir = entry?.path().join(FILE_DIR_NAME);
+      if files_dir.is_dir() {
+        dirs.push(files_dir);
+      }
+    }
   }
+  Ok(dirs)
+}
+
+/// 统计全部文件引用，修正中断时留下的引用计数偏差
+pub(super) fn repair_ref_counts(app_data: &Path) -> Result<usize> {
+  // 持有全部对话的写入锁，避免统计期间写入或移动对话文件夹
+  let _guard = all_chats_lock();
+  BlobStore::new(app_data).repair_refs(|| {
+    let mut counts = HashMap::new();
+    for files_dir in ref_dirs(app_data)? {
+      for file_id in ref_ids(&files_dir)? {
+        // 无法解析的引用由存储检查处理
+        if let Ok(Some(file_ref)) = read_ref(&files_dir, &file_id) {
+          *counts.entry(file_ref.blob).or_default() += 1;
+        }
+      }
+    }
+    Ok(counts)
+  })
 }
 
 impl ChatFile {
-  pub async fn save(self, app_data: &Path, database: &DatabaseHandler) -> Result<PathBuf> {
-    let path = app_data
+  fn files_dir(&self, app_data: &Path) -> PathBuf {
+    app_data
       .join(SAVE_DIR)
       .join(&self.chat_id)
       .join(FILE_DIR_NAME)
-      .join(format!("{}.file", &self.file_id));
+  }
+
+  fn save_to_disk(self, app_data: PathBuf, content: FileContent) -> Result<PathBuf> {
+    let store = BlobStore::new(&app_data);
+    let files_dir = self.files_dir(&app_data);
+
+    let FileContent {
+      data,
+      name,
+      mime_type,
+    } = content;
+    // 前端给出的文件名优先
+    let name = self.filename.clone().or(name);
+    let mime_type = sniff_mime(&data, name.as_deref(), mime_type.as_deref());
+
+    // 先保存内容再写入引用，覆盖保存时释放原有的内容
+    let source = self.data.as_ref().map(|it| it.kind().to_string());
+    store.put(&data, |blob| {
+      let file_ref = FileRef {
+        blob: blob.to_string(),
+        name,
+        mime_type: Some(mime_type),
+        size: Some(data.len() as u64),
+        sha256: Some(sha256_hex(&data)),
+        source,
+      };
+      let old = read_ref(&files_dir, &self.file_id)?;
+      write_ref(&files_dir, &self.file_id, &file_ref)?;
+      Ok(old.map(|it| it.blob))
+    })?;
+
+    let legacy = legacy_path(&files_dir, &self.file_id);
+    if legacy.try_exists()? {
+      std::fs::remove_file(legacy)?;
+    }
+    Ok(ref_path(&files_dir, &self.file_id))
+  }
+}
+
+impl ChatFile {
+  /// 引用已保存的对话文件
+  pub fn new(chat_id: &str, file_id: &str) -> Self {
+    Self {
+      chat_id: chat_id.to_string(),
+      file_id: file_id.to_string(),
+      filename: None,
+      data: None,
+    }
+  }
+
+  /// 从其他设备同步的文件
+  pub fn synced(chat_id: &str, file_id: &str, filename: Option<String>, data: Vec<u8>) -> Self {
+    Self {
+      chat_id: chat_id.to_string(),
+      file_id: file_id.to_string(),
+      filename,
+      data: Some(ChatFileData::Synced(data)),
+    }
+  }
+
+  pub fn chat_id(&self) -> &str {
+    &self.chat_id
+  }
+
+  pub fn file_id(&self) -> &str {
+    &self.file_id
+  }
+
+  pub async fn save(self, app_data: &Path, database: &DatabaseHandler) -> Result<PathBuf> {
+    check_id("对话", &self.chat_id)?;
+    check_id("文件", &self.file_id)?;
     let data = self
       .data
       .as_ref()
       .map_custom_err(|_| "保存文件时数据缺失")?;
     let data = data.to_data(database).await?;
-    spawn_blocking(move || self.save_to_disk(path, data)).await?
+    let app_data = app_data.to_path_buf();
+    spawn_blocking(move || self.save_to_disk(app_data, data)).await?
   }
 
   pub async fn read(self, app_data: &Path) -> Result<Vec<u8>> {
-    let path = app_data
-      .join(SAVE_DIR)
-      .join(&self.chat_id)
-      .join(FILE_DIR_NAME)
-      .join(format!("{}.file", &self.file_id));
+    check_id("对话", &self.chat_id)?;
+    check_id("文件", &self.file_id)?;
+    let store = BlobStore::new(app_data);
+    let files_dir = self.files_dir(app_data);
+    spawn_blocking(move || match read_ref(&files_dir, &self.file_id)? {
+      Some(file_ref) => store.read(&file_ref.blob),
+      None => read_legacy(&legacy_path(&files_dir, &self.file_id)),
+    })
+    .await?
+  }
+
+  /// 删除文件引用，内容没有其他引用时一并删除
+  pub async fn delete(self, app_data: &Path) -> Result<()> {
+    check_id("对话", &self.chat_id)?;
+    check_id("文件", &self.file_id)?;
+    let store = BlobStore::new(app_data);
+    let files_dir = self.files_dir(app_data);
+    spawn_blocking(move || release_ref(&store, &files_dir, &self.file_id)).await?
+  }
+
+  /// 读取文件的元数据，缺少元数据的文件会读取内容补全
+  pub async fn stat(self, app_data: &Path) -> Result<ChatFileStat> {
+    check_id("对话", &self.chat_id)?;
+    check_id("文件", &self.file_id)?;
+    let store = BlobStore::new(app_data);
+    let files_dir = self.files_dir(app_data);
     spawn_blocking(move || {
-      let file = File::open(path)?;
-      let mut archive = ZipArchive::new(file)?;
-      let mut entry = archive.by_name_decrypt(DEFAULT_FILENAME, PASSWORD.as_bytes())?;
-      let mut buffer = Vec::with_capacity(entry.size() as usize);
-      entry.read_to_end(&mut buffer)?;
-      Ok(buffer)
+      let file_id = self.file_id;
+      let mut file_ref = load_ref(&store, &files_dir, &file_id)?;
+
+      if file_ref.mime_type.is_none() || file_ref.size.is_none() || file_ref.sha256.is_none() {
+        let data = store.read(&file_ref.blob)?;
+        let name = file_ref.name.as_deref();
+        file_ref.mime_type = Some(sniff_mime(&data, name, None));
+        file_ref.size = Some(data.len() as u64);
+        file_ref.sha256 = Some(sha256_hex(&data));
+        write_ref(&files_dir, &file_id, &file_ref)?;
+      }
+
+      Ok(ChatFileStat {
+        file_id,
+        name: file_ref.name,
+        mime_type: file_ref.mime_type.unwrap_or_default(),
+        size: file_ref.size.unwrap_or_default(),
+        content_id: file_ref.blob,
+        sha256: file_ref.sha256.unwrap_or_default(),
+        source: file_ref.source,
+      })
     })
     .await?
   }
@@ -0,0 +1,325 @@
+use super::blob_store::BlobStore;
+use super::chat_dirs::ChatDir;
+use super::chat_drafts::draft_messages;
+use super::chat_files::{FILE_DIR_NAME, legacy_path, read_ref, ref_ids, ref_path, release_ref};
+use super::chat_messages::file_refs;
+use super::chat_tree::ChatTree;
+use super::reencrypt::chat_dirs;
+use super::text_extract::text_cache_ids;
+use super::thumbnails::thumbnail_caches;
+use super::{SAVE_DIR, check_id};
+use crate::database::{ChangeAction, ChangeEntity, DatabaseHandler};
+use crate::emitter::event;
+use crate::error::Result;
+use crate::sync::request_sync;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tauri::async_runtime::{block_on, spawn_blocking};
+use tauri::{AppHandle, Manager};
+
+/// 定时清理在设置中的名称
+pub const CHAT_GC_SCHEDULE_KEY: &str = "chat_gc_schedule";
+/// 上次定时清理的时间，毫秒时间戳
+const CHAT_GC_LAST_RUN_KEY: &str = "chat_gc_last_run";
+/// 定时清理完成后发送的事件，内容为 [`ChatGcReport`]
+const CHAT_GC_EVENT: &str = "chat-gc-event";
+/// 检查是否需要定时清理的间隔
+const SCHEDULE_CHECK_INTERVAL: Duration = Duration::from_secs(10 * 60);
+/// 最近修改过的文件和对话不会被清理，避免与正在保存的消息冲突
+const MIN_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// 定时清理的设置
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ChatGcSchedule {
+  pub enabled: bool,
+  /// 两次清理之间的间隔，小时
+  pub interval_hours: u64,
+}
+
+impl Default for ChatGcSchedule {
+  fn default() -> Self {
+    Self {
+      enabled: false,
+      interval_hours: 24,
+    }
+  }
+}
+
+/// 没有被任何消息或草稿引用的文件
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OrphanFile {
+  chat_id: String,
+  file_id: String,
+  name: Option<String>,
+  size: u64,
+  #[serde(skip)]
+  blob: String,
+}
+
+/// 清理结果，试运行时只列出将被清理的内容
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChatGcReport {
+  dry_run: bool,
+  orphan_files: Vec<OrphanFile>,
+  /// 数据库中没有对应对话、也没有消息和草稿的对话文件夹
+  empty_chats: Vec<String>,
+  /// 实际可以释放的空间，内容仍被其他对话或笔记引用时不计入
+  reclaimable_bytes: u64,
+  /// 对应的文件已经删除的缓存数量：提取的文本以及缩略图
+  stale_caches: usize,
+  stale_cache_bytes: u64,
+}
+
+/// 对应的文件已经删除的缓存，返回缓存的路径
+///
+/// 文本缓存对应的文件引用不存在，或缩略图对应的内容已经释放；最近生成的缓存保留
+fn stale_caches(app_data: &Path) -> Result<Vec<PathBuf>> {
+  let mut caches = Vec::new();
+  for chat_dir in chat_dirs(app_data)? {
+    let files_dir = chat_dir.join(FILE_DIR_NAME);
+    for file_id in text_cache_ids(&files_dir)? {
+      let exists = ref_path(&files_dir, &file_id).try_exists()?
+        || legacy_path(&files_dir, &file_id).try_exists()?;
+      if !exists {
+        caches.push(files_dir.join(format!("{file_id}.text")));
+      }
+    }
+  }
+  // 本地图片的缩略图没有对应的内容，同样视为可以清理的缓存
+  let store = BlobStore::new(app_data);
+  for (hash, path) in thumbnail_caches(app_data)? {
+    if !store.contains(&hash).unwrap_or_default() {
+      caches.push(path);
+    }
+  }
+  caches.retain(|it| is_stale(it));
+  Ok(caches)
+}
+
+/// 缓存文件的总大小
+fn total_size(paths: &[PathBuf]) -> u64 {
+  paths
+    .iter()
+    .filter_map(|it| std::fs::metadata(it).ok())
+    .map(|it| it.len())
+    .sum()
+}
+
+/// 最近修改时间是否早于 [`MIN_AGE`]，无法获取时视为最近修改过
+fn is_stale(path: &Path) -> bool {
+  let elapsed = std::fs::metadata(path)
+    .and_then(|it| it.modified())
+    .ok()
+    .and_then(|it| it.elapsed().ok());
+  elapsed.is_some_and(|it| it >= MIN_AGE)
+}
+
+/// 检查单个对话，返回孤立的文件以及对话是否为空
+///
+/// 对话无法读取时跳过，不清理任何内容
+fn scan_chat(chat_id: &str, chat_dir: &Path) -> Option<(Vec<OrphanFile>, bool)> {
+  let tree = ChatTree::load(chat_dir).ok()?;
+  let nodes = tree.nodes().iter().collect::<Vec<_>>();
+  let mut messages = tree.read(&nodes).ok()?;
+  let drafts = draft_messages(chat_dir).ok()?;
+  let has_content = !messages.is_empty() || !drafts.is_empty();
+  messages.extend(drafts);
+
+  let referenced: HashSet<&str> = messages.iter().flat_map(file_refs).collect();
+  let files_dir = chat_dir.join(FILE_DIR_NAME);
+  let mut orphans = Vec::new();
+  let mut all_orphans = true;
+  for file_id in ref_ids(&files_dir).ok()? {
+    if referenced.contains(file_id.as_str()) {
+      continue;
+    }
+    let file_ref = match is_stale(&ref_path(&files_dir, &file_id)) {
+      true => read_ref(&files_dir, &file_id).ok().flatten(),
+      false => None,
+    };
+    let Some(file_ref) = file_ref else {
+      all_orphans = false;
+      continue;
+    };
+    orphans.push(OrphanFile {
+      chat_id: chat_id.to_string(),
+      file_id,
+      name: file_ref.name,
+      size: file_ref.size.unwrap_or_default(),
+      blob: file_ref.blob,
+    });
+  }
+
+  let empty = !has_content && all_orphans && is_stale(chat_dir);
+  Some((orphans, empty))
+}
+
+/// `chat_ids` 为数据库中的全部对话，这些对话即使为空也不会被清理
+fn scan_blocking(app_data: PathBuf, chat_ids: HashSet<String>) -> Result<ChatGcReport> {
+  let root = app_data.join(SAVE_DIR);
+  let mut report = ChatGcReport::default();
+  if !root.try_exists()? {
+    return Ok(report);
+  }
+
+  for entry in std::fs::read_dir(root)? {
+    let entry = entry?;
+    let chat_id = entry.file_name().to_string_lossy().to_string();
+    // 跳过暂存区等隐藏文件夹
+    if !entry.file_type()?.is_dir() || check_id("对话", &chat_id).is_err() {
+      continue;
+    }
+    let Some((orphans, empty)) = scan_chat(&chat_id, &entry.path()) else {
+      continue;
+    };
+    report.orphan_files.extend(orphans);
+    if empty && !chat_ids.contains(&chat_id) {
+      report.empty_chats.push(chat_id);
+    }
+  }
+
+  // 同一内容的全部引用都被清理时才会释放空间
+  let store = BlobStore::new(&app_data);
+  let mut blobs: HashMap<&str, (u32, u64)> = HashMap::new();
+  for file in &report.orphan_files {
+    let (count, size) = blobs.entry(&file.blob).or_default();
+    *count += 1;
+    *size = file.size;
+  }
+  for (blob, (count, size)) in blobs {
+    if store.ref_count(blob)? <= count {
+      report.reclaimable_bytes += size;
+    }
+  }
+
+  let caches = stale_caches(&app_data)?;
+  report.stale_caches = caches.len();
+  report.stale_cache_bytes = total_size(&caches);
+  Ok(report)
+}
+
+fn delete_orphans_blocking(app_data: PathBuf, report: &ChatGcReport) -> Result<()> {
+  let store = BlobStore::new(&app_data);
+  let empty_chats: HashSet<&str> = report.empty_chats.iter().map(String::as_str).collect();
+  for file in &report.orphan_files {
+    // 空对话的文件随对话一起删除
+    if empty_chats.contains(file.chat_id.as_str()) {
+      continue;
+    }
+    let files_dir = app_data
+      .join(SAVE_DIR)
+      .join(&file.chat_id)
+      .join(FILE_DIR_NAME);
+    release_ref(&store, &files_dir, &file.file_id)?;
+  }
+  Ok(())
+}
+
+/// 删除对应文件已经删除的缓存，返回删除的数量和大小
+fn delete_caches_blocking(app_data: PathBuf) -> Result<(usize, u64)> {
+  let caches = stale_caches(&app_data)?;
+  let size = total_size(&caches);
+  for path in &caches {
+    match std::fs::remove_file(path) {
+      Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+      _ => {}
+    }
+  }
+  Ok((caches.len(), size))
+}
+
+/// 清理没有被引用的对话文件、遗留的空对话文件夹以及对应文件已经删除的缓存
+///
+/// `dry_run` 时只返回将被清理的内容；确认清理时重新检查一遍，最近修改过的内容始终保留。
+/// 数据库中存在的对话即使没有消息也不会被清理
+pub async fn collect_chat_garbage(
+  app_data: &Path,
+  database: &DatabaseHandler,
+  dry_run: bool,
+) -> Result<ChatGcReport> {
+  let path = app_data.to_path_buf();
+  let chat_ids = database
+    .find_all_chats()
+    .await?
+    .into_iter()
+    .map(|it| it.id)
+    .collect();
+  let mut report = spawn_blocking(move || scan_blocking(path, chat_ids)).await??;
+  report.dry_run = dry_run;
+  if dry_run {
+    return Ok(report);
+  }
+
+  let path = app_data.to_path_buf();
+  let mut report =
+    spawn_blocking(move || delete_orphans_blocking(path, &report).map(|_| report)).await??;
+  for file in &report.orphan_files {
+    if report.empty_chats.contains(&file.chat_id) {
+      continue;
+    }
+    database
+      .log_change(
+        ChangeEntity::Chat,
+        &file.chat_id,
+        Some(&file.file_id),
+        ChangeAction::Delete,
+      )
+      .await?;
+  }
+  for chat_id in &report.empty_chats {
+    ChatDir::new(app_data, chat_id)?.delete().await?;
+  }
+  // 本次释放的内容对应的缩略图也一并清理
+  let path = app_data.to_path_buf();
+  (report.stale_caches, report.stale_cache_bytes) =
+    spawn_blocking(move || delete_caches_blocking(path)).await??;
+
+  if !report.orphan_files.is_empty() {
+    request_sync();
+  }
+  Ok(report)
+}
+
+fn now_millis() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|it| it.as_millis() as u64)
+    .unwrap_or_default()
+}
+
+/// 到达设置的间隔时执行一次清理
+async fn run_scheduled(app: &AppHandle, app_data: &Path) -> Result<()> {
+  let database = app.state::<DatabaseHandler>();
+  let schedule: ChatGcSchedule = database.get_setting(CHAT_GC_SCHEDULE_KEY).await?;
+  if !schedule.enabled {
+    return Ok(());
+  }
+  let last_run: u64 = database.get_setting(CHAT_GC_LAST_RUN_KEY).await?;
+  let interval = Duration::from_secs(schedule.interval_hours.max(1) * 60 * 60);
+  if now_millis().saturating_sub(last_run) < interval.as_millis() as u64 {
+    return Ok(());
+  }
+
+  let report = collect_chat_garbage(app_data, &database, false).await?;
+  database
+    .save_setting(CHAT_GC_LAST_RUN_KEY, &now_millis())
+    .await?;
+  event(CHAT_GC_EVENT, report);
+  Ok(())
+}
+
+/// 启动定时清理的后台线程，是否执行由设置决定
+pub(super) fn spawn_schedule(app: AppHandle, app_data: PathBuf) {
+  std::thread::spawn(move || {
+    loop {
+      // 失败时等待下次检查，不影响应用运行
+      let _ = block_on(run_scheduled(&app, &app_data));
+      std::thread::sleep(SCHEDULE_CHECK_INTERVAL);
+    }
+  });
+}
@@ -0,0 +1,491 @@
+use super::{ChatDir, ChatMessage, SAVE_DIR, check_id};
+use crate::error::{Error, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use tauri::async_runtime::spawn_blocking;
+
+const UNTITLED: &str = "未命名对话";
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportSource {
+  /// ChatGPT 数据导出中的 `conversations.json`
+  Chatgpt,
+  /// Claude 数据导出中的 `conversations.json`
+  Claude,
+}
+
+impl ImportSource {
+  pub fn name(&self) -> &'static str {
+    match self {
+      Self::Chatgpt => "chatgpt",
+      Self::Claude => "claude",
+    }
+  }
+
+  fn detect(conversation: &Value) -> Result<Self> {
+    if conversation.get("mapping").is_some() {
+      Ok(Self::Chatgpt)
+    } else if conversation.get("chat_messages").is_some() {
+      Ok(Self::Claude)
+    } else {
+      Err(Error::new("无法识别导入文件的格式"))
+    }
+  }
+}
+
+/// 导入成功的对话信息
+pub struct ImportedChat {
+  pub chat_id: String,
+  pub title: String,
+  pub created_at: i64,
+  pub updated_at: i64,
+  /// 导入文件的格式，未指定时为自动识别的结果
+  pub source: ImportSource,
+  /// 导入的消息数量
+  pub messages: u32,
+}
+
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImportReport {
+  imported_chats: u32,
+  imported_messages: u32,
+  /// 跳过的对话及原因
+  skipped_chats: Vec<String>,
+  /// 不支持的内容类型及其出现次数
+  skipped_content: BTreeMap<String, u32>,
+}
+
+impl ImportReport {
+  /// 对话已写入磁盘但后续保存失败，记录原因并从导入数量中扣除
+  pub fn chat_failed(&mut self, chat: &ImportedChat, error: &Error) {
+    self.imported_chats -= 1;
+    self.imported_messages -= chat.messages;
+    self.skipped_chats.push(format!("{}：{error}", chat.title));
+  }
+
+  fn skip_content(&mut self, kind: impl Into<String>) {
+    *self.skipped_content.entry(kind.into()).or_default() += 1;
+  }
+}
+
+/// 转换后的单条消息，按父消息在前的顺序排列
+struct ImportNode {
+  id: String,
+  parent: Option<String>,
+  index: u32,
+  message: Value,
+}
+
+struct Conversation {
+  chat: ImportedChat,
+  nodes: Vec<ImportNode>,
+  /// 当前激活分支上的消息
+  head: Option<String>,
+}
+
+fn secs_to_millis(value: Option<&Value>) -> Option<i64> {
+  value.and_then(Value::as_f64).map(|it| (it * 1000.0) as i64)
+}
+
+fn iso_to_millis(value: Option<&Value>) -> Option<i64> {
+  let value = value.and_then(Value::as_str)?;
+  let time = chrono::DateTime::parse_from_rfc3339(value).ok()?;
+  Some(time.timestamp_millis())
+}
+
+fn text_part(text: &str) -> Value {
+  json!({ "type": "text", "text": text })
+}
+
+fn reasoning_part(text: &str) -> Value {
+  json!({ "type": "reasoning", "text": text })
+}
+
+fn ui_message(id: &str, role: &str, parts: Vec<Value>, created_at: Option<i64>) -> Value {
+  let mut message = json!({ "id": id, "role": role, "parts": parts });
+  if let Some(created_at) = created_at {
+    message["metadata"] = json!({ "createdAt": created_at });
+  }
+  message
+}
+
+fn chat_id_of(conversation: &Value, key: &str) -> String {
+  let id = conversation
+    .get(key)
+    .and_then(Value::as_str)
+    .unwrap_or_default();
+  let chat_id = format!("chat-{id}");
+  match check_id("对话", &chat_id) {
+    Ok(_) if !id.is_empty() => chat_id,
+    _ => format!("chat-{}", uuid::Uuid::new_v4().simple()),
+  }
+}
+
+fn title_of(conversation: &Value, key: &str) -> String {
+  let title = conversation
+    .get(key)
+    .and_then(Value::as_str)
+    .unwrap_or_default();
+  match title.trim() {
+    "" => UNTITLED.to_string(),
+    it => it.to_string(),
+  }
+}
+
+/// 消息 id 会作为文件名使用，不合法时重新生成
+fn message_id_of(id: Option<&str>) -> String {
+  match id {
+    Some(id) if check_id("消息", id).is_ok() => id.to_string(),
+    _ => format!("msg-{}", uuid::Uuid::new_v4().simple()),
+  }
+}
+
+/// 转换 ChatGPT 的消息内容，返回 `None` 表示此消息不需要导入
+fn chatgpt_parts(message: &Value, report: &mut ImportReport) -> Option<(String, Vec<Value>)> {
+  let role = message.pointer("/author/role").and_then(Value::as_str)?;
+  let hidden = message
+    .pointer("/metadata/is_visually_hidden_from_conversation")
+    .and_then(Value::as_bool)
+    .unwrap_or(false);
+  if hidden {
+    return None;
+  }
+
+  let content = message.get("content")?;
+  let kind = content.get("content_type").and_then(Value::as_str)?;
+  let mut parts = Vec::new();
+
+  match kind {
+    "text" | "multimodal_text" => {
+      let items = content.get("parts").and_then(Value::as_array);
+      for item in items.into_iter().flatten() {
+        match item {
+          Value::String(text) if text.trim().is_empty() => {}
+          Value::String(text) => parts.push(text_part(text)),
+          other => {
+            let kind = other.get("content_type").and_then(Value::as_str);
+            report.skip_content(format!("chatgpt/{}", kind.unwrap_or("unknown")));
+          }
+        }
+      }
+    }
+    "code" => {
+      let text = content
+        .get("text")
+        .and_then(Value::as_str)
+        .unwrap_or_default();
+      let lang = match content.get("language").and_then(Value::as_str) {
+        Some("unknown") | None => "",
+        Some(it) => it,
+      };
+      parts.push(text_part(&format!("```{lang}\n{text}\n```")));
+    }
+    "thoughts" => {
+      let thoughts = content.get("thoughts").and_then(Value::as_array);
+      for thought in thoughts.into_iter().flatten() {
+        if let Some(text) = thought.get("content").and_then(Value::as_str) {
+          parts.push(reasoning_part(text));
+        }
+      }
+    }
+    other => {
+      report.skip_content(format!("chatgpt/{other}"));
+      return None;
+    }
+  }
+
+  match role {
+    "user" | "assistant" if !parts.is_empty() => Some((role.to_string(), parts)),
+    "user" | "assistant" => None,
+    // system 消息通常为空的隐藏消息
+    "system" => None,
+    other => {
+      report.skip_content(format!("chatgpt/role:{other}"));
+      None
+    }
+  }
+}
+
+fn convert_chatgpt(conversation: &Value, report: &mut ImportReport) -> Result<Conversation> {
+  let empty = serde_json::Map::new();
+  let mapping = conversation
+    .get("mapping")
+    .and_then(Value::as_object)
+    .unwrap_or(&empty);
+
+  // 深度优先遍历，跳过的消息由其子消息继承父消息
+  let mut roots: Vec<&str> = mapping
+    .iter()
+    .filter(|(_, node)| {
+      let parent = node.get("parent").and_then(Value::as_str);
+      parent.is_none_or(|it| !mapping.contains_key(it))
+    })
+    .map(|(id, _)| id.as_str())
+    .collect();
+  roots.sort();
+
+  let mut nodes = Vec::new();
+  // 原始节点 id 到最近的已导入消息（id, index）
+  let mut imported: HashMap<&str, Option<(String, u32)>> = HashMap::new();
+  let mut stack: Vec<(&str, Option<(String, u32)>)> =
+    roots.into_iter().rev().map(|it| (it, None)).collect();
+
+  while let Some((node_id, parent)) = stack.pop() {
+    // 损坏的数据可能形成环，每个节点只处理一次
+    let Some(node) = mapping.get(node_id) else {
+      continue;
+    };
+    if imported.contains_key(node_id) {
+      continue;
+    }
+    let mut current = parent.clone();
+
+    let converted = node
+      .get("message")
+      .filter(|it| !it.is_null())
+      .and_then(|message| Some((message, chatgpt_parts(message, report)?)));
+    if let Some((message, (role, parts))) = converted {
+      let index = match &parent {
+        Some((_, index)) => index
+          .checked_add(1)
+          .ok_or_else(|| Error::new("对话层级过深"))?,
+        None => 0,
+      };
+      let id = message_id_of(message.get("id").and_then(Value::as_str));
+      let created_at = secs_to_millis(message.get("create_time"));
+      nodes.push(ImportNode {
+        id: id.clone(),
+        parent: parent.map(|(id, _)| id),
+        index,
+        message: ui_message(&id, &role, parts, created_at),
+      });
+      current = Some((id, index));
+    }
+
+    imported.insert(node_id, current.clone());
+    let children = node.get("children").and_then(Value::as_array);
+    for child in children.into_iter().flatten().rev() {
+      if let Some(child) = child.as_str() {
+        stack.push((child, current.clone()));
+      }
+    }
+  }
+
+  let head = conversation
+    .get("current_node")
+    .and_then(Value::as_str)
+    .and_then(|it| imported.get(it).cloned().flatten())
+    .map(|(id, _)| id);
+
+  let created_at = secs_to_millis(conversation.get("create_time")).unwrap_or_default();
+  let chat = ImportedChat {
+    chat_id: chat_id_of(conversation, "id"),
+    title: title_of(conversation, "title"),
+    created_at,
+    updated_at: secs_to_millis(conversation.get("update_time")).unwrap_or(created_at),
+    source: ImportSource::Chatgpt,
+    messages: 0,
+  };
+  Ok(Conversation { chat, nodes, head })
+}
+
+fn convert_claude(conversation: &Value, report: &mut ImportReport) -> Result<Conversation> {
+  let mut nodes: Vec<ImportNode> = Vec::new();
+  let messages = conversation.get("chat_messages").and_then(Value::as_array);
+
+  for message in messages.into_iter().flatten() {
+    let role = match message.get("sender").and_then(Value::as_str) {
+      Some("human") => "user",
+      Some("assistant") => "assistant",
+      other => {
+        report.skip_content(format!("claude/sender:{}", other.unwrap_or("unknown")));
+        continue;
+      }
+    };
+
+    let mut parts = Vec::new();
+    match message.get("content").and_then(Value::as_array) {
+      Some(content) => {
+        for item in content {
+          let kind = item
+            .get("type")
+            .and_then(Value::as_str)
+            .unwrap_or("unknown");
+          match kind {
+            "text" => {
+              let text = item.get("text").and_then(Value::as_str).unwrap_or_default();
+              if !text.trim().is_empty() {
+                parts.push(text_part(text));
+              }
+            }
+            "thinking" => {
+              let text = item
+                .get("thinking")
+                .and_then(Value::as_str)
+                .unwrap_or_default();
+              parts.push(reasoning_part(text));
+            }
+            other => report.skip_content(format!("claude/{other}")),
+          }
+        }
+      }
+      // 旧版本的导出只有 text 字段
+      None => {
+        let text = message
+          .get("text")
+          .and_then(Value::as_str)
+          .unwrap_or_default();
+        if !text.trim().is_empty() {
+          parts.push(text_part(text));
+        }
+      }
+    }
+
+    let attachments = message.get("attachments").and_then(Value::as_array);
+    for attachment in attachments.into_iter().flatten() {
+      let name = attachment
+        .get("file_name")
+        .and_then(Value::as_str)
+        .unwrap_or_default();
+      match attachment.get("extracted_content").and_then(Value::as_str) {
+        Some(content) => parts.push(text_part(&format!("附件 {name}：\n\n{content}"))),
+        None => report.skip_content("claude/attachment"),
+      }
+    }
+    // 图片等文件不包含在数据导出中
+    let files = message.get("files").and_then(Value::as_array);
+    for _ in files.into_iter().flatten() {
+      report.skip_content("claude/file");
+    }
+
+    if parts.is_empty() {
+      continue;
+    }
+
+    let parent = nodes.last();
+    let index = match parent {
+      Some(it) => it
+        .index
+        .checked_add(1)
+        .ok_or_else(|| Error::new("对话层级过深"))?,
+      None => 0,
+    };
+    let id = message_id_of(message.get("uuid").and_then(Value::as_str));
+    let created_at = iso_to_millis(message.get("created_at"));
+    nodes.push(ImportNode {
+      parent: parent.map(|it| it.id.clone()),
+      index,
+      message: ui_message(&id, role, parts, created_at),
+      id,
+    });
+  }
+
+  let created_at = iso_to_millis(conversation.get("created_at")).unwrap_or_default();
+  let chat = ImportedChat {
+    chat_id: chat_id_of(conversation, "uuid"),
+    title: title_of(conversation, "name"),
+    created_at,
+    updated_at: iso_to_millis(conversation.get("updated_at")).unwrap_or(created_at),
+    source: ImportSource::Claude,
+    messages: 0,
+  };
+  let head = nodes.last().map(|it| it.id.clone());
+  Ok(Conversation { chat, nodes, head })
+}
+
+fn read_export(file: PathBuf, source: Option<ImportSource>) -> Result<(ImportSource, Vec<Value>)> {
+  let reader = BufReader::new(File::open(file)?);
+  let conversations: Vec<Value> = serde_json::from_reader(reader)?;
+  let source = match (source, conversations.first()) {
+    (Some(source), _) => source,
+    (None, Some(first)) => ImportSource::detect(first)?,
+    (None, None) => ImportSource::Chatgpt,
+  };
+  Ok((source, conversations))
+}
+
+/// 从其他 AI 助手的数据导出中导入对话，已存在的对话会被跳过
+pub async fn import_chats(
+  app_data: &Path,
+  file: PathBuf,
+  source: Option<ImportSource>,
+) -> Result<(ImportReport, Vec<ImportedChat>)> {
+  let (source, conversations) = spawn_blocking(move || read_export(file, source)).await??;
+
+  let mut report = ImportReport::default();
+  let mut chats = Vec::new();
+  for conversation in conversations {
+    let converted = match source {
+      ImportSource::Chatgpt => convert_chatgpt(&conversation, &mut report),
+      ImportSource::Claude => convert_claude(&conversation, &mut report),
+    };
+    let Conversation {
+      mut chat,
+      nodes,
+      head,
+    } = match converted {
+      Ok(it) => it,
+      Err(e) => {
+        let key = match source {
+          ImportSource::Chatgpt => "title",
+          ImportSource::Claude => "name",
+        };
+        let title = title_of(&conversation, key);
+        report.skipped_chats.push(format!("{title}：{e}"));
+        continue;
+      }
+    };
+
+    if nodes.is_empty() {
+      report
+        .skipped_chats
+        .push(format!("{}：没有可导入的消息", chat.title));
+      continue;
+    }
+    if app_data.join(SAVE_DIR).join(&chat.chat_id).try_exists()? {
+      report
+        .skipped_chats
+        .push(format!("{}：对话已存在", chat.title));
+      continue;
+    }
+
+    chat.messages = nodes.len() as u32;
+    let saved = async {
+      for node in nodes {
+        let message = ChatMessage::new(
+          &chat.chat_id,
+          node.index,
+          node.id,
+          node.parent,
+          node.message,
+        );
+        message.save(app_data).await?;
+      }
+      if let Some(head) = head {
+        ChatDir::new(app_data, &chat.chat_id)?
+          .switch_branch(head)
+          .await?;
+      }
+      Ok::<_, Error>(())
+    }
+    .await;
+    // 写入失败时删除不完整的对话，再次导入时可以重新导入
+    if let Err(e) = saved {
+      let _ = ChatDir::new(app_data, &chat.chat_id)?.delete().await;
+      report.skipped_chats.push(format!("{}：{e}", chat.title));
+      continue;
+    }
+
+    report.imported_chats += 1;
+    report.imported_messages += chat.messages;
+    chats.push(chat);
+  }
+
+  Ok((report, chats))
+}
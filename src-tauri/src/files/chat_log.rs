@@ -0,0 +1,689 @@
+use super::chat_tree::NodeMeta;
+use super::crypto;
+use super::key_store::{KeySet, chat_key_path};
+use crate::error::{Error, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs::{File, OpenOptions, create_dir_all, remove_dir_all, rename};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+pub(super) const LOG_DIR: &str = "log";
+/// 重写日志时的暂存文件夹，替换完成前中断会被丢弃
+const LOG_NEW_DIR: &str = "log.new";
+/// 替换日志时被替换下来的旧日志
+const LOG_OLD_DIR: &str = "log.old";
+const INDEX_FILENAME: &str = "index";
+/// 单个分段文件的大小上限，超过后写入新的分段
+const SEGMENT_MAX_BYTES: u64 = 4 << 20;
+/// 记录头：4 字节长度 + 1 字节格式版本
+const RECORD_HEADER_LEN: usize = 5;
+/// 单条记录的长度上限，超过时视为记录头损坏
+const MAX_RECORD_BYTES: u32 = 64 << 20;
+/// 记录格式版本：使用对话数据密钥加密的 json，是否压缩由加密数据头区分
+const RECORD_VERSION: u8 = 2;
+/// 旧版本使用公开密码加密的记录
+const LEGACY_RECORD_VERSION: u8 = 1;
+
+/// 记录在日志中的位置
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub(super) struct RecordPos {
+  pub seg: u32,
+  /// 记录头在分段文件中的偏移量
+  pub off: u64,
+  /// 记录的总长度，包括记录头
+  pub len: u32,
+}
+
+/// 偏移索引中的一行，同一条消息可能有多行，以最后一行为准
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(super) struct IndexEntry {
+  #[serde(flatten)]
+  pub meta: NodeMeta,
+  pub index: u32,
+  #[serde(flatten)]
+  pub pos: RecordPos,
+  /// 记录格式版本，旧版本的索引中没有此字段
+  #[serde(default = "legacy_version")]
+  pub version: u8,
+}
+
+fn legacy_version() -> u8 {
+  LEGACY_RECORD_VERSION
+}
+
+#[derive(Serialize, Deserialize)]
+struct Record {
+  #[serde(flatten)]
+  meta: NodeMeta,
+  index: u32,
+  message: Value,
+}
+
+/// 扫描到的分段末尾无法读取的内容
+#[derive(Debug, Clone, Copy)]
+enum Tail {
+  /// 写入中断留下的不完整记录，从此位置开始截断后可以继续写入
+  Incomplete(u64),
+  /// 记录头中的长度无效，之后的内容无法定位，继续写入时使用新的分段
+  Damaged,
+}
+
+/// 扫描分段的结果
+struct SegmentScan {
+  entries: Vec<IndexEntry>,
+  /// 完整但无法解密或解析的记录，以及记录头损坏后无法定位的剩余内容
+  damaged: Vec<RecordPos>,
+  tail: Option<Tail>,
+}
+
+fn segment_name(seg: u32) -> String {
+  format!("{seg:06}.seg")
+}
+
+/// 单个对话的追加写入日志，`chats/<chat_id>/log`
+///
+/// 消息按记录依次追加到分段文件中，`index` 文件记录每条消息的位置，
+/// 读取对话结构时只需要读取索引。打开日志时不会写入，索引缺失的记录只在内存中补全，
+/// 通过 [`ChatLog::open_writable`] 打开时才会写回索引。
+pub(super) struct ChatLog {
+  dir: PathBuf,
+  entries: Vec<IndexEntry>,
+  key_path: PathBuf,
+  /// 对话的数据密钥，首次写入时才会生成
+  keys: KeySet,
+  /// 最后一个分段末尾无法读取的内容
+  tail: Option<Tail>,
+  /// 索引文件与内存中的索引不一致，写入前需要重写
+  index_stale: bool,
+}
+
+impl ChatLog {
+  /// 对话文件夹下是否存在日志（包括未完成替换的日志）
+  pub fn exists(chat_dir: &Path) -> bool {
+    [LOG_DIR, LOG_OLD_DIR]
+      .iter()
+      .any(|it| chat_dir.join(it).is_dir())
+  }
+
+  /// 完成或回滚上次中断的日志替换
+  fn recover_swap(chat_dir: &Path) -> Result<()> {
+    let log = chat_dir.join(LOG_DIR);
+    let log_new = chat_dir.join(LOG_NEW_DIR);
+    let log_old = chat_dir.join(LOG_OLD_DIR);
+
+    if log_old.try_exists()? {
+      // 旧日志已被移走，新日志可能还未移入
+      if !log.try_exists()? && log_new.try_exists()? {
+        rename(&log_new, &log)?;
+      }
+      if log.try_exists()? {
+        remove_dir_all(&log_old)?;
+      } else {
+        rename(&log_old, &log)?;
+      }
+    }
+    if log_new.try_exists()? {
+      remove_dir_all(&log_new)?;
+    }
+    Ok(())
+  }
+
+  /// 当前有效的日志文件夹，上次替换中断时可能是尚未移入的新日志或被替换下来的旧日志
+  fn current_dir(chat_dir: &Path) -> Result<PathBuf> {
+    let log = chat_dir.join(LOG_DIR);
+    let log_new = chat_dir.join(LOG_NEW_DIR);
+    let log_old = chat_dir.join(LOG_OLD_DIR);
+    if log.try_exists()? || !log_old.try_exists()? {
+      return Ok(log);
+    }
+    // 旧日志被移走前新日志已经写入完成
+    match log_new.try_exists()? {
+      true => Ok(log_new),
+      false => Ok(log_old),
+    }
+  }
+
+  /// 只读方式打开日志，不会修改任何文件
+  pub fn open(chat_dir: &Path) -> Result<Self> {
+    let key_path = chat_key_path(chat_dir);
+    let mut log = Self {
+      dir: Self::current_dir(chat_dir)?,
+      entries: Vec::new(),
+      keys: KeySet::load(&key_path)?,
+      key_path,
+      tail: None,
+      index_stale: false,
+    };
+    let valid = log.read_index()?;
+    if !valid {
+      log.rebuild_index()?;
+    }
+    Ok(log)
+  }
+
+  /// 打开日志用于写入，完成上次中断的替换并写回补全的索引
+  ///
+  /// 调用时需要持有对话的 [`chat_lock`](super::chat_tree::chat_lock)
+  pub fn open_writable(chat_dir: &Path) -> Result<Self> {
+    Self::recover_swap(chat_dir)?;
+    create_dir_all(chat_dir.join(LOG_DIR))?;
+    let log = Self::open(chat_dir)?;
+    if log.index_stale {
+      log.write_index()?;
+    }
+    Ok(log)
+  }
+
+  pub fn entries(&self) -> &[IndexEntry] {
+    &self.entries
+  }
+
+  /// 扫描全部分段，返回无法读取的记录，不使用索引
+  pub fn damaged_records(&self) -> Result<Vec<RecordPos>> {
+    let mut damaged = Vec::new();
+    for seg in self.segments()? {
+      damaged.extend(self.scan_segment(seg, 0)?.damaged);
+    }
+    Ok(damaged)
+  }
+
+  /// 是否存在旧版本使用公开密码加密的记录
+  pub fn has_legacy_records(&self) -> bool {
+    self.entries.iter().any(|it| it.version < RECORD_VERSION)
+  }
+
+  /// 分段文件中满足 `matches` 的记录数量，只读取加密后的内容
+  fn count_records(&self, matches: impl Fn(&[u8]) -> bool) -> Result<usize> {
+    let mut count = 0;
+    for seg in self.segments()? {
+      let file = File::open(self.dir.join(segment_name(seg)))?;
+      let mut remaining = file.metadata()?.len();
+      let mut reader = BufReader::new(file);
+      while remaining > 0 {
+        let (sealed, len, _) = read_sealed(&mut reader, remaining)?;
+        if matches(&sealed) {
+          count += 1;
+        }
+        remaining -= len as u64;
+      }
+    }
+    Ok(count)
+  }
+
+  /// 没有使用当前数据密钥加密的记录数量
+  pub fn stale_records(&self) -> Result<usize> {
+    self.count_records(|sealed| !self.keys.is_current(sealed))
+  }
+
+  /// 加密前没有压缩的记录数量
+  pub fn uncompressed_records(&self) -> Result<usize> {
+    self.count_records(|sealed| !crypto::is_compressed(sealed))
+  }
+
+  fn segments(&self) -> Result<Vec<u32>> {
+    let mut segments = Vec::new();
+    let entries = match std::fs::read_dir(&self.dir) {
+      Ok(it) => it,
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(segments),
+      Err(e) => return Err(e.into()),
+    };
+    for entry in entries {
+      let path = entry?.path();
+      if path.extension().filter(|it| *it == "seg").is_none() {
+        continue;
+      }
+      let seg = path.file_stem().and_then(|it| it.to_str());
+      if let Some(seg) = seg.and_then(|it| it.parse().ok()) {
+        segments.push(seg);
+      }
+    }
+    segments.sort();
+    Ok(segments)
+  }
+
+  /// 读取偏移索引，并补全最后一个分段中尚未写入索引的记录
+  ///
+  /// 返回 `false` 表示索引与分段不一致，需要重建
+  fn read_index(&mut self) -> Result<bool> {
+    let segments = self.segments()?;
+    let path = self.dir.join(INDEX_FILENAME);
+    let file = match File::open(&path) {
+      Ok(it) => it,
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(segments.is_empty()),
+      Err(e) => return Err(e.into()),
+    };
+
+    // 写入中断时最后一行可能不完整，忽略该行，写入前重写索引
+    let mut reader = BufReader::new(file);
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 0 {
+      if !line.ends_with('\n') {
+        self.index_stale = true;
+        break;
+      }
+      match serde_json::from_str::<IndexEntry>(&line) {
+        Ok(entry) => self.entries.push(entry),
+        Err(_) => return Ok(false),
+      }
+      line.clear();
+    }
+
+    let Some(&last_seg) = segments.last() else {
+      return Ok(self.entries.is_empty());
+    };
+    let indexed_end = self
+      .entries
+      .iter()
+      .filter(|it| it.pos.seg == last_seg)
+      .map(|it| it.pos.off + it.pos.len as u64)
+      .max()
+      .unwrap_or_default();
+    let seg_len = std::fs::metadata(self.dir.join(segment_name(last_seg)))?.len();
+    let indexed_last = self.entries.iter().map(|it| it.pos.seg).max();
+
+    // 索引指向了不存在的数据
+    if indexed_end > seg_len || indexed_last.is_some_and(|it| it > last_seg) {
+      return Ok(false);
+    }
+    // 数据写入后索引未写入，补全索引
+    if indexed_end < seg_len {
+      let scan = self.scan_segment(last_seg, indexed_end)?;
+      self.index_stale |= !scan.entries.is_empty();
+      self.entries.extend(scan.entries);
+      self.tail = scan.tail;
+    }
+    Ok(true)
+  }
+
+  /// 扫描全部分段重建索引，写入前重写索引文件
+  fn rebuild_index(&mut self) -> Result<()> {
+    self.entries.clear();
+    self.tail = None;
+    for seg in self.segments()? {
+      let scan = self.scan_segment(seg, 0)?;
+      self.entries.extend(scan.entries);
+      self.tail = scan.tail;
+    }
+    self.index_stale = true;
+    Ok(())
+  }
+
+  fn write_index(&self) -> Result<()> {
+    let mut data = Vec::new();
+    for entry in &self.entries {
+      serde_json::to_writer(&mut data, entry)?;
+      data.push(b'\n');
+    }
+    super::atomic_write(&self.dir.join(INDEX_FILENAME), &data)?;
+    Ok(())
+  }
+
+  /// 从 `start` 开始读取分段中的记录
+  ///
+  /// 无法解密或解析的完整记录按记录头中的长度跳过，末尾不完整的记录不会被读取
+  fn scan_segment(&self, seg: u32, start: u64) -> Result<SegmentScan> {
+    let mut file = File::open(self.dir.join(segment_name(seg)))?;
+    let seg_len = file.metadata()?.len();
+    file.seek(SeekFrom::Start(start))?;
+    let mut reader = BufReader::new(file);
+
+    let mut scan = SegmentScan {
+      entries: Vec::new(),
+      damaged: Vec::new(),
+      tail: None,
+    };
+    let mut off = start;
+    while off < seg_len {
+      let remaining = seg_len - off;
+      if remaining < RECORD_HEADER_LEN as u64 {
+        scan.tail = Some(Tail::Incomplete(off));
+        break;
+      }
+      let mut header = [0u8; RECORD_HEADER_LEN];
+      reader.read_exact(&mut header)?;
+      let Ok(len) = record_len(&header) else {
+        scan.damaged.push(RecordPos {
+          seg,
+          off,
+          len: remaining as u32,
+        });
+        scan.tail = Some(Tail::Damaged);
+        break;
+      };
+      if len as u64 > remaining {
+        scan.tail = Some(Tail::Incomplete(off));
+        break;
+      }
+
+      let mut sealed = vec![0u8; len as usize - RECORD_HEADER_LEN];
+      reader.read_exact(&mut sealed)?;
+      let pos = RecordPos { seg, off, len };
+      match open_record(&self.keys, header[4], &sealed) {
+        Ok(record) => scan.entries.push(IndexEntry {
+          meta: record.meta,
+          index: record.index,
+          pos,
+          version: header[4],
+        }),
+        Err(_) => scan.damaged.push(pos),
+      }
+      off += len as u64;
+    }
+    Ok(scan)
+  }
+
+  fn append_index(&self, entry: &IndexEntry) -> Result<()> {
+    let mut line = serde_json::to_vec(entry)?;
+    line.push(b'\n');
+    let mut file = OpenOptions::new()
+      .create(true)
+      .append(true)
+      .open(self.dir.join(INDEX_FILENAME))?;
+    file.write_all(&line)?;
+    file.sync_data()?;
+    Ok(())
+  }
+
+  /// 追加一条消息记录，已存在的同 id 消息会被新记录覆盖
+  pub fn append(&mut self, meta: &NodeMeta, index: u32, message: &Value) -> Result<PathBuf> {
+    let record = serde_json::to_vec(&RecordRef {
+      meta,
+      index,
+      message,
+    })?;
+    if self.keys.is_empty() {
+      self.keys = KeySet::load_or_create(&self.key_path)?;
+    }
+    let sealed = self.keys.seal(&record)?;
+    let len = RECORD_HEADER_LEN + sealed.len();
+    if len > MAX_RECORD_BYTES as usize {
+      return Err(Error::new(format!("消息 {} 过大，无法保存", meta.id)));
+    }
+    let len = len as u32;
+
+    let mut seg = self.segments()?.last().copied().unwrap_or(0);
+    let mut path = self.dir.join(segment_name(seg));
+    let mut off = match std::fs::metadata(&path) {
+      Ok(it) => it.len(),
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+      Err(e) => return Err(e.into()),
+    };
+    match self.tail.take() {
+      // 截断写入中断留下的不完整记录
+      Some(Tail::Incomplete(end)) => {
+        OpenOptions::new().write(true).open(&path)?.set_len(end)?;
+        off = end;
+      }
+      // 保留无法定位的内容用于检查和修复，写入新的分段
+      Some(Tail::Damaged) => {
+        seg += 1;
+        path = self.dir.join(segment_name(seg));
+        off = 0;
+      }
+      None => {}
+    }
+    // 超过分段大小上限时写入新的分段
+    if off > 0 && off + len as u64 > SEGMENT_MAX_BYTES {
+      seg += 1;
+      path = self.dir.join(segment_name(seg));
+      off = 0;
+    }
+
+    // 先写入数据再写入索引，索引缺失的记录在打开时会被补全
+    let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+    let mut frame = Vec::with_capacity(len as usize);
+    frame.extend_from_slice(&len.to_le_bytes());
+    frame.push(RECORD_VERSION);
+    frame.extend_from_slice(&sealed);
+    file.write_all(&frame)?;
+    file.sync_data()?;
+
+    let entry = IndexEntry {
+      meta: meta.clone(),
+      index,
+      pos: RecordPos { seg, off, len },
+      version: RECORD_VERSION,
+    };
+    self.append_index(&entry)?;
+    self.entries.push(entry);
+    Ok(path)
+  }
+
+  /// 按位置读取多条消息，返回顺序与 `positions` 一致
+  pub fn read_many(&self, positions: &[RecordPos]) -> Result<Vec<Value>> {
+    let mut current: Option<(u32, File, u64)> = None;
+    let mut values = Vec::with_capacity(positions.len());
+    for pos in positions {
+      let (file, seg_len) = match &mut current {
+        Some((seg, file, seg_len)) if *seg == pos.seg => (file, *seg_len),
+        _ => {
+          let file = File::open(self.dir.join(segment_name(pos.seg)))?;
+          let seg_len = file.metadata()?.len();
+          let (_, file, seg_len) = current.insert((pos.seg, file, seg_len));
+          (file, *seg_len)
+        }
+      };
+      file.seek(SeekFrom::Start(pos.off))?;
+      let remaining = seg_len.saturating_sub(pos.off).min(pos.len as u64);
+      let (sealed, len, version) = read_sealed(file, remaining)?;
+      if len != pos.len {
+        return Err(Error::new("记录长度与索引不一致"));
+      }
+      values.push(open_record(&self.keys, version, &sealed)?.message);
+    }
+    Ok(values)
+  }
+
+  /// 用 `records` 重写整个日志，替换过程中断不会破坏原有日志
+  pub fn rewrite(chat_dir: &Path, records: Vec<(NodeMeta, u32, Value)>) -> Result<()> {
+    Self::recover_swap(chat_dir)?;
+    let log = chat_dir.join(LOG_DIR);
+    let log_new = chat_dir.join(LOG_NEW_DIR);
+    let log_old = chat_dir.join(LOG_OLD_DIR);
+
+    create_dir_all(&log_new)?;
+    let key_path = chat_key_path(chat_dir);
+    let mut new = Self {
+      dir: log_new.clone(),
+      entries: Vec::new(),
+      keys: KeySet::load_or_create(&key_path)?,
+      key_path,
+      tail: None,
+      index_stale: false,
+    };
+    for (meta, index, message) in &records {
+      new.append(meta, *index, message)?;
+    }
+
+    if log.try_exists()? {
+      rename(&log, &log_old)?;
+    }
+    rename(&log_new, &log)?;
+    if log_old.try_exists()? {
+      remove_dir_all(&log_old)?;
+    }
+    Ok(())
+  }
+}
+
+#[derive(Serialize)]
+struct RecordRef<'a> {
+  #[serde(flatten)]
+  meta: &'a NodeMeta,
+  index: u32,
+  message: &'a Value,
+}
+
+/// 解析记录头中的总长度，长度不足一个记录头或超过上限时返回错误
+fn record_len(header: &[u8; RECORD_HEADER_LEN]) -> Result<u32> {
+  let len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+  if len as usize <= RECORD_HEADER_LEN || len > MAX_RECORD_BYTES {
+    return Err(Error::new(format!("记录长度错误: {len}")));
+  }
+  Ok(len)
+}
+
+/// 解密并解析一条记录的内容
+fn open_record(keys: &KeySet, version: u8, sealed: &[u8]) -> Result<Record> {
+  if !matches!(version, LEGACY_RECORD_VERSION | RECORD_VERSION) {
+    return Err(Error::new(format!("不支持的记录格式版本: {version}")));
+  }
+  let plain = keys.open(sealed)?;
+  Ok(serde_json::from_slice(&plain)?)
+}
+
+/// 读取当前位置的一条记录的加密内容，返回内容、总长度以及格式版本
+///
+/// `remaining` 为当前位置之后可以读取的字节数，记录长度超过时返回错误
+fn read_sealed(reader: &mut impl Read, remaining: u64) -> Result<(Vec<u8>, u32, u8)> {
+  let mut header = [0u8; RECORD_HEADER_LEN];
+  reader.read_exact(&mut header)?;
+  let len = record_len(&header)?;
+  if len as u64 > remaining {
+    return Err(Error::new("记录不完整"));
+  }
+
+  let mut sealed = vec![0u8; len as usize - RECORD_HEADER_LEN];
+  reader.read_exact(&mut sealed)?;
+  Ok((sealed, len, header[4]))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::OnceLock;
+
+  /// 新建一个对话文件夹，全部测试共用同一个主密钥
+  fn chat_dir() -> PathBuf {
+    static APP_DATA: OnceLock<PathBuf> = OnceLock::new();
+    let app_data = APP_DATA.get_or_init(|| {
+      let app_data =
+        std::env::temp_dir().join(format!("note-secretary-{}", uuid::Uuid::new_v4().simple()));
+      create_dir_all(&app_data).unwrap();
+      super::super::key_store::load_master(&app_data).unwrap();
+      app_data
+    });
+    let dir = app_data.join(format!("chat-{}", uuid::Uuid::new_v4().simple()));
+    create_dir_all(&dir).unwrap();
+    dir
+  }
+
+  fn append(log: &mut ChatLog, index: u32) {
+    let meta = NodeMeta {
+      id: format!("msg-{index}"),
+      parent: index.checked_sub(1).map(|it| format!("msg-{it}")),
+      created: index as u64,
+    };
+    log
+      .append(&meta, index, &serde_json::json!({ "index": index }))
+      .unwrap();
+  }
+
+  fn ids(log: &ChatLog) -> Vec<&str> {
+    log.entries().iter().map(|it| it.meta.id.as_str()).collect()
+  }
+
+  /// 写入三条消息，返回第一个分段的路径以及每条记录的位置
+  fn three_records(chat_dir: &Path) -> (PathBuf, Vec<RecordPos>) {
+    let mut log = ChatLog::open_writable(chat_dir).unwrap();
+    for index in 0..3 {
+      append(&mut log, index);
+    }
+    let positions = log.entries().iter().map(|it| it.pos).collect();
+    (chat_dir.join(LOG_DIR).join(segment_name(0)), positions)
+  }
+
+  fn remove_index(chat_dir: &Path) {
+    std::fs::remove_file(chat_dir.join(LOG_DIR).join(INDEX_FILENAME)).unwrap();
+  }
+
+  #[test]
+  fn skips_damaged_record_without_writing() {
+    let chat_dir = chat_dir();
+    let (seg_path, positions) = three_records(&chat_dir);
+    remove_index(&chat_dir);
+
+    // 损坏第二条记录中间的一个字节
+    let mut data = std::fs::read(&seg_path).unwrap();
+    let middle = positions[1].off as usize + positions[1].len as usize / 2;
+    data[middle] ^= 0xff;
+    std::fs::write(&seg_path, &data).unwrap();
+
+    let log = ChatLog::open(&chat_dir).unwrap();
+    assert_eq!(ids(&log), ["msg-0", "msg-2"]);
+    let damaged = log.damaged_records().unwrap();
+    assert_eq!(damaged.len(), 1);
+    assert_eq!(damaged[0].off, positions[1].off);
+
+    // 只读打开不会截断分段，也不会写入索引
+    assert_eq!(std::fs::read(&seg_path).unwrap(), data);
+    assert!(!chat_dir.join(LOG_DIR).join(INDEX_FILENAME).exists());
+  }
+
+  #[test]
+  fn rejects_invalid_record_length() {
+    let chat_dir = chat_dir();
+    let (seg_path, _) = three_records(&chat_dir);
+    let mut file = OpenOptions::new().append(true).open(&seg_path).unwrap();
+    file.write_all(&u32::MAX.to_le_bytes()).unwrap();
+    file.write_all(&[RECORD_VERSION; 16]).unwrap();
+    let len = file.metadata().unwrap().len();
+    drop(file);
+
+    let log = ChatLog::open(&chat_dir).unwrap();
+    assert_eq!(ids(&log), ["msg-0", "msg-1", "msg-2"]);
+    assert_eq!(log.damaged_records().unwrap().len(), 1);
+
+    // 无法定位的内容保留下来，继续写入新的分段
+    let mut log = ChatLog::open_writable(&chat_dir).unwrap();
+    append(&mut log, 3);
+    assert_eq!(std::fs::metadata(&seg_path).unwrap().len(), len);
+    let log = ChatLog::open(&chat_dir).unwrap();
+    assert_eq!(ids(&log), ["msg-0", "msg-1", "msg-2", "msg-3"]);
+    assert_eq!(log.entries()[3].pos.seg, 1);
+  }
+
+  #[test]
+  fn truncates_incomplete_tail_only_when_writing() {
+    let chat_dir = chat_dir();
+    let (seg_path, positions) = three_records(&chat_dir);
+    let end = positions[2].off + positions[2].len as u64;
+
+    // 写入中断：记录头完整，内容只写入了一部分
+    let mut file = OpenOptions::new().append(true).open(&seg_path).unwrap();
+    file.write_all(&100u32.to_le_bytes()).unwrap();
+    file.write_all(&[RECORD_VERSION; 10]).unwrap();
+    drop(file);
+
+    let log = ChatLog::open(&chat_dir).unwrap();
+    assert_eq!(ids(&log), ["msg-0", "msg-1", "msg-2"]);
+    assert!(log.damaged_records().unwrap().is_empty());
+    assert_eq!(std::fs::metadata(&seg_path).unwrap().len(), end + 14);
+
+    let mut log = ChatLog::open_writable(&chat_dir).unwrap();
+    append(&mut log, 3);
+    let log = ChatLog::open(&chat_dir).unwrap();
+    assert_eq!(ids(&log), ["msg-0", "msg-1", "msg-2", "msg-3"]);
+    assert_eq!(log.entries()[3].pos.off, end);
+    assert_eq!(log.read_many(&[log.entries()[3].pos]).unwrap().len(), 1);
+  }
+
+  #[test]
+  fn rejects_record_longer_than_segment() {
+    let chat_dir = chat_dir();
+    let (seg_path, positions) = three_records(&chat_dir);
+    let file = OpenOptions::new().write(true).open(&seg_path).unwrap();
+    file.set_len(positions[2].off + 10).unwrap();
+    drop(file);
+
+    let log = ChatLog::open(&chat_dir).unwrap();
+    assert_eq!(ids(&log), ["msg-0", "msg-1"]);
+    assert!(log.read_many(&[positions[2]]).is_err());
+    let moved = RecordPos {
+      off: positions[1].off + 1,
+      ..positions[1]
+    };
+    assert!(log.read_many(&[moved]).is_err());
+  }
+}
@@ -1,13 +1,16 @@
-use super::{PASSWORD, SAVE_DIR};
-use crate::error::{Error, MapToCustomError, Result};
-use futures::stream::{self, StreamExt};
-use serde::Deserialize;
-use serde_json::{Value, from_reader, to_writer};
+use super::chat_drafts::remove_draft;
+use super::chat_tree::{ChatTree, NodeMeta, chat_lock, write_head};
+use super::{LEGACY_PASSWORD, SAVE_DIR, check_id};
+use crate::database::ChangeAction;
+use crate::error::{Error, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, from_reader};
 use std::fs::{File, create_dir_all};
+use std::ops::Range;
 use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 use tauri::async_runtime::spawn_blocking;
-use zip::write::SimpleFileOptions;
-use zip::{AesMode, CompressionMethod, ZipArchive, ZipWriter};
+use zip::ZipArchive;
 
 const MESSAGE_FILENAME: &str = "message.json";
 
@@ -17,9 +20,11 @@ pub struct ChatMessage {
   /// 此消息所属的对话 id
   chat_id: String,
   /// 此信息在对话中的 index
-  index: u16,
+  index: u32,
   /// 此信息的 id
   message_id: String,
+  /// 父消息 id，缺省时为当前激活分支上 index - 1 的消息
+  parent_id: Option<String>,
   /// 此消息的内容
   message: Value,
   /// 是否忽略已存在的文件进行覆盖
@@ -27,107 +32,202 @@ pub struct ChatMessage {
 }
 
 impl ChatMessage {
-  fn save_to_disk(self, path: PathBuf) -> Result<Option<PathBuf>> {
+  fn save_to_disk(self, dir: PathBuf) -> Result<Option<ChangeAction>> {
     let need_check = !self.force.unwrap_or(false);
     let id = self.message_id;
 
-    // 如果不存在父文件夹，创建文件夹
-    if let Some(parent) = path.parent() {
-      create_dir_all(parent)?;
+    // 如果不存在文件夹，创建文件夹
+    let _guard = chat_lock(&dir);
+    create_dir_all(&dir)?;
+    let mut tree = ChatTree::open(&dir)?;
+    let is_new = tree.get(&id).is_none();
+
+    // 如果存在这个记录那么跳过，强制覆盖时保留原有的分支信息
+    let (index, meta) = match tree.get(&id) {
+      Some(_) if need_check => {
+        remove_draft(&dir, &id)?;
+        return Ok(None);
+      }
+      Some(node) => (node.index, node.meta.clone()),
+      None => {
+        let parent = match (&self.parent_id, self.index) {
+          (Some(parent_id), _) => tree.get(parent_id),
+          (None, 0) => None,
+          (None, index) => tree.active_path().get(index as usize - 1).copied(),
+        };
+        let parent_index = parent.map(|it| it.index + 1).unwrap_or_default();
+        if self.index != parent_index || (self.parent_id.is_some() && parent.is_none()) {
+          return Err(Error::new(format!(
+            "对话记录 {id} 与父消息不匹配，尝试刷新获取最新对话"
+          )));
+        }
+        let created = SystemTime::now()
+          .duration_since(UNIX_EPOCH)
+          .map(|it| it.as_millis() as u64)
+          .unwrap_or_default();
+        let meta = NodeMeta {
+          id: id.clone(),
+          parent: parent.map(|it| it.meta.id.clone()),
+          created,
+        };
+        (self.index, meta)
+      }
+    };
+
+    tree.append(&meta, index, &self.message)?;
+    // 最终消息保存完成，流式生成时的草稿不再需要
+    remove_draft(&dir, &id)?;
+
+    // 新保存的消息成为当前激活分支
+    if is_new {
+      write_head(&dir, Some(&id))?;
     }
 
-    // 如果存在这个记录且 id 匹配那么跳过，id 不匹配会报错
-    if need_check && path.try_exists()? {
-      let file = File::open(&path)?;
-      let archive = ZipArchive::new(file)?;
-      return match archive.comment() == id.as_bytes() {
-        true => Ok(None),
-        false => Err(Error::new(format!(
-          "对话记录 {id} 冲突，尝试刷新获取最新对话或强制覆盖"
-        ))),
-      };
+    match is_new {
+      true => Ok(Some(ChangeAction::Insert)),
+      false => Ok(Some(ChangeAction::Update)),
     }
+  }
+}
 
-    let message = self.message;
+impl ChatMessage {
+  pub fn new(
+    chat_id: &str,
+    index: u32,
+    message_id: String,
+    parent_id: Option<String>,
+    message: Value,
+  ) -> Self {
+    Self {
+      chat_id: chat_id.to_string(),
+      index,
+      message_id,
+      parent_id,
+      message,
+      force: None,
+    }
+  }
 
-    let file = File::create(&path)?;
-    let mut writer = ZipWriter::new(file);
+  /// 覆盖已存在的同一消息，保留原有的分支信息
+  pub fn overwrite(mut self) -> Self {
+    self.force = Some(true);
+    self
+  }
 
-    let options = SimpleFileOptions::default()
-      .compression_method(CompressionMethod::Stored)
-      .with_aes_encryption(AesMode::Aes256, PASSWORD);
+  pub fn chat_id(&self) -> &str {
+    &self.chat_id
+  }
 
-    writer.start_file(MESSAGE_FILENAME, options)?;
-    to_writer(&mut writer, &message)?;
-    writer.set_comment(id);
+  pub fn message_id(&self) -> &str {
+    &self.message_id
+  }
 
-    writer.finish()?;
+  pub fn index(&self) -> u32 {
+    self.index
+  }
 
-    Ok(Some(path))
+  /// 消息中全部文本内容，用于建立搜索索引
+  pub fn text(&self) -> String {
+    message_text(&self.message)
   }
-}
 
-impl ChatMessage {
-  pub async fn save(self, app_data: &Path) -> Result<Option<PathBuf>> {
-    let path = app_data
-      .join(SAVE_DIR)
-      .join(&self.chat_id)
-      .join(format!("{:04}.message", &self.index));
-    spawn_blocking(move || self.save_to_disk(path)).await?
+  /// 保存消息，返回记录修改时使用的操作：新增或覆盖已存在的消息，已存在且不覆盖时返回空
+  pub async fn save(self, app_data: &Path) -> Result<Option<ChangeAction>> {
+    check_id("对话", &self.chat_id)?;
+    check_id("消息", &self.message_id)?;
+    let dir = app_data.join(SAVE_DIR).join(&self.chat_id);
+    spawn_blocking(move || self.save_to_disk(dir)).await?
   }
 
-  pub async fn read_all(app_data: &Path, chat_id: String) -> Result<Vec<Value>> {
+  /// 读取当前激活分支上 `range` 范围内的消息，`range` 接收分支长度，返回读取的范围
+  async fn read_active(
+    app_data: &Path,
+    chat_id: String,
+    range: impl FnOnce(usize) -> Range<usize> + Send + 'static,
+  ) -> Result<(Vec<Value>, usize)> {
+    check_id("对话", &chat_id)?;
     let dir = app_data.join(SAVE_DIR).join(chat_id);
-    let indexed_paths = spawn_blocking(move || {
-      let mut indexed_paths = Vec::new();
-
-      let entries = std::fs::read_dir(dir)?;
-      for entry in entries {
-        let path = entry?.path();
-        // 检查文件扩展名是否为 .message
-        if path.extension().filter(|it| *it == "message").is_none() {
-          continue;
-        }
+    spawn_blocking(move || {
+      // 旧版本的对话在第一次打开时迁移到日志，迁移失败时仍按旧版本读取
+      let _guard = chat_lock(&dir);
+      let tree = ChatTree::open(&dir).or_else(|_| ChatTree::load(&dir))?;
+      let path = tree.active_path();
+      let total = path.len();
+      let range = range(total);
+      let nodes = path.get(range).unwrap_or_default();
+      Ok((tree.read(nodes)?, total))
+    })
+    .await?
+  }
 
-        // 提取文件名（不含扩展名）
-        let file_name = path
-          .file_stem()
-          .and_then(|it| it.to_str())
-          .map_custom_err(|_| format!("文件名解析失败: {}", path.display()))?;
-
-        // 解析数字
-        let index = file_name
-          .parse::<u16>()
-          .map_custom_err(|e| format!("文件名解析失败（{}）: {}", file_name, e))?;
-        indexed_paths.push((index, path));
-      }
+  /// 读取当前激活分支上的全部消息
+  pub async fn read_all(app_data: &Path, chat_id: String) -> Result<Vec<Value>> {
+    let (messages, _) = Self::read_active(app_data, chat_id, |total| 0..total).await?;
+    Ok(messages)
+  }
 
-      // 按索引排序
-      indexed_paths.sort_by_key(|&(index, _)| index);
+  /// 读取 index 小于 `before_index` 的最新 `limit` 条消息，缺省时从最后一条消息开始
+  pub async fn read_page(
+    app_data: &Path,
+    chat_id: String,
+    before_index: Option<u32>,
+    limit: u32,
+  ) -> Result<ChatPage> {
+    let range = move |total: usize| {
+      let end = before_index.map_or(total, |it| total.min(it as usize));
+      end.saturating_sub(limit as usize)..end
+    };
+    let (mut messages, total) = Self::read_active(app_data, chat_id, range).await?;
+    messages.reverse();
+    let total = total as u32;
+    Ok(ChatPage { messages, total })
+  }
 
-      Ok::<Vec<(u16, PathBuf)>, Error>(indexed_paths)
-    })
-    .await??;
-
-    // 并发读取所有文件
-    let values: Vec<Value> = stream::iter(indexed_paths)
-      .map(|(_, path)| async move {
-        spawn_blocking(move || {
-          let file = File::open(path)?;
-          let mut archive = ZipArchive::new(file)?;
-          let entry = archive.by_name_decrypt(MESSAGE_FILENAME, PASSWORD.as_bytes())?;
-          let value: Value = from_reader(entry)?;
-          Ok(value)
-        })
-        .await?
-      })
-      // 限制同时最多只有 10 个任务在跑
-      .buffered(10)
-      .collect::<Vec<Result<Value>>>()
-      .await
-      .into_iter()
-      .collect::<Result<Vec<_>>>()?;
-
-    Ok(values)
+  /// 读取 index 大于等于 `index` 的全部消息，用于增量刷新
+  pub async fn read_since(app_data: &Path, chat_id: String, index: u32) -> Result<Vec<Value>> {
+    let range = move |total: usize| total.min(index as usize)..total;
+    let (messages, _) = Self::read_active(app_data, chat_id, range).await?;
+    Ok(messages)
   }
 }
+
+/// 分页读取的一页消息
+#[derive(Debug, Serialize)]
+pub struct ChatPage {
+  /// 按 index 从大到小排列，第一条为最新的消息
+  messages: Vec<Value>,
+  /// 当前激活分支上的消息总数
+  total: u32,
+}
+
+/// 解密读取单个消息文件
+pub(super) fn read_from_disk(path: &Path) -> Result<Value> {
+  let file = File::open(path)?;
+  let mut archive = ZipArchive::new(file)?;
+  let entry = archive.by_name_decrypt(MESSAGE_FILENAME, LEGACY_PASSWORD.as_bytes())?;
+  Ok(from_reader(entry)?)
+}
+
+/// 提取消息中引用的对话文件 id（即 `file` 类型且 url 为 `file-` 开头的 part）
+pub(super) fn file_refs(message: &Value) -> impl Iterator<Item = &str> {
+  message
+    .get("parts")
+    .and_then(Value::as_array)
+    .into_iter()
+    .flatten()
+    .filter(|part| part.get("type").and_then(Value::as_str) == Some("file"))
+    .filter_map(|part| part.get("url").and_then(Value::as_str))
+    .filter(|url| url.starts_with("file-"))
+}
+
+/// 拼接消息中全部 `text` 类型 part 的内容
+pub fn message_text(message: &Value) -> String {
+  let texts = message
+    .get("parts")
+    .and_then(Value::as_array)
+    .into_iter()
+    .flatten()
+    .filter(|part| part.get("type").and_then(Value::as_str) == Some("text"))
+    .filter_map(|part| part.get("text").and_then(Value::as_str));
+  texts.collect::<Vec<_>>().join("\n\n")
+}
@@ -0,0 +1,369 @@
+use super::atomic_write;
+use super::chat_log::{ChatLog, RecordPos};
+use super::chat_messages::read_from_disk;
+use crate::error::{Error, MapToCustomError, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeSet, HashMap};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::{Condvar, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use zip::ZipArchive;
+
+const HEAD_FILENAME: &str = "HEAD";
+
+/// 正在写入的对话文件夹
+static LOCKED_CHATS: Mutex<BTreeSet<PathBuf>> = Mutex::new(BTreeSet::new());
+static CHAT_UNLOCKED: Condvar = Condvar::new();
+/// 单个对话的写入锁持有读锁，需要所有对话都停止写入的操作持有写锁
+static ALL_CHATS: RwLock<()> = RwLock::new(());
+
+/// 单个对话的写入锁，释放时唤醒等待同一对话的线程
+pub(super) struct ChatLock {
+  dir: PathBuf,
+  _all: RwLockReadGuard<'static, ()>,
+}
+
+impl Drop for ChatLock {
+  fn drop(&mut self) {
+    let mut locked = LOCKED_CHATS.lock().unwrap_or_else(|it| it.into_inner());
+    locked.remove(&self.dir);
+    CHAT_UNLOCKED.notify_all();
+  }
+}
+
+/// 写入对话时持有，避免同一对话的追加、迁移、截断时重写日志以及重新加密同时进行
+///
+/// 同一线程不能同时持有两个对话的锁
+pub(super) fn chat_lock(dir: &Path) -> ChatLock {
+  let all = ALL_CHATS.read().unwrap_or_else(|it| it.into_inner());
+  let mut locked = LOCKED_CHATS.lock().unwrap_or_else(|it| it.into_inner());
+  while locked.contains(dir) {
+    locked = CHAT_UNLOCKED
+      .wait(locked)
+      .unwrap_or_else(|it| it.into_inner());
+  }
+  locked.insert(dir.to_path_buf());
+  ChatLock {
+    dir: dir.to_path_buf(),
+    _all: all,
+  }
+}
+
+/// 等待全部对话的写入完成，持有期间不会有对话写入
+pub(super) fn all_chats_lock() -> RwLockWriteGuard<'static, ()> {
+  ALL_CHATS.write().unwrap_or_else(|it| it.into_inner())
+}
+
+/// 消息节点信息
+///
+/// 旧版本的消息文件注释只有消息 id，父节点为上一条旧版本消息
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(super) struct NodeMeta {
+  pub id: String,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub parent: Option<String>,
+  /// 创建时间，毫秒时间戳，用于分支排序
+  #[serde(default)]
+  pub created: u64,
+}
+
+impl NodeMeta {
+  /// 解析消息文件注释，返回节点信息以及是否为旧版本格式
+  pub(super) fn from_comment(comment: &[u8]) -> Result<(Self, bool)> {
+    if comment.starts_with(b"{") {
+      return Ok((serde_json::from_slice(comment)?, false));
+    }
+    let id = std::str::from_utf8(comment)
+      .map_custom_err(|e| format!("消息注释解析失败: {e}"))?
+      .to_string();
+    Ok((
+      NodeMeta {
+        id,
+        parent: None,
+        created: 0,
+      },
+      true,
+    ))
+  }
+}
+
+/// 消息保存的位置
+#[derive(Debug)]
+enum NodeLocation {
+  /// 旧版本中每条消息单独保存的 zip 文件
+  File(PathBuf),
+  /// 对话日志中的一条记录
+  Log(RecordPos),
+}
+
+#[derive(Debug)]
+pub(super) struct ChatNode {
+  /// 在对话中的深度，即线性对话中的 index
+  pub index: u32,
+  pub meta: NodeMeta,
+  location: NodeLocation,
+}
+
+/// 对话树，由对话日志或旧版本的消息文件构成
+pub(super) struct ChatTree {
+  dir: PathBuf,
+  nodes: Vec<ChatNode>,
+  head: Option<String>,
+  log: Option<ChatLog>,
+}
+
+/// 旧版本的消息文件，`{index:04}.message` 或 `{index:04}-{id}.message`
+fn legacy_files(dir: &Path) -> Result<Vec<(u32, PathBuf)>> {
+  let mut files = Vec::new();
+  for entry in std::fs::read_dir(dir)? {
+    let path = entry?.path();
+    // 检查文件扩展名是否为 .message
+    if path.extension().filter(|it| *it == "message").is_none() {
+      continue;
+    }
+
+    let file_name = path
+      .file_stem()
+      .and_then(|it| it.to_str())
+      .map_custom_err(|_| format!("文件名解析失败: {}", path.display()))?;
+    let index = file_name.split_once('-').map_or(file_name, |(it, _)| it);
+    let index = index
+      .parse::<u32>()
+      .map_custom_err(|e| format!("文件名解析失败（{}）: {}", file_name, e))?;
+    files.push((index, path));
+  }
+  Ok(files)
+}
+
+fn load_legacy_nodes(dir: &Path) -> Result<Vec<ChatNode>> {
+  let mut nodes = Vec::new();
+  let mut legacy = Vec::new();
+
+  for (index, path) in legacy_files(dir)? {
+    let archive = ZipArchive::new(File::open(&path)?)?;
+    let (meta, is_legacy) = NodeMeta::from_comment(archive.comment())?;
+    if is_legacy {
+      legacy.push(nodes.len());
+    }
+    let location = NodeLocation::File(path);
+    nodes.push(ChatNode {
+      index,
+      meta,
+      location,
+    });
+  }
+
+  // 最早版本的消息为线性对话，依次连接为一条分支
+  legacy.sort_by_key(|&it| nodes[it].index);
+  for pair in legacy.windows(2) {
+    nodes[pair[1]].meta.parent = Some(nodes[pair[0]].meta.id.clone());
+  }
+  Ok(nodes)
+}
+
+fn load_log_nodes(log: &ChatLog) -> Vec<ChatNode> {
+  // 同一条消息以最后一条记录为准
+  let mut nodes: Vec<ChatNode> = Vec::new();
+  let mut positions: HashMap<&str, usize> = HashMap::new();
+  for entry in log.entries() {
+    let node = ChatNode {
+      index: entry.index,
+      meta: entry.meta.clone(),
+      location: NodeLocation::Log(entry.pos),
+    };
+    match positions.get(entry.meta.id.as_str()) {
+      Some(&it) => nodes[it] = node,
+      None => {
+        positions.insert(&entry.meta.id, nodes.len());
+        nodes.push(node);
+      }
+    }
+  }
+  nodes
+}
+
+impl ChatTree {
+  /// 读取对话树，存在日志时读取日志，否则读取旧版本的消息文件，不会修改任何文件
+  pub fn load(dir: &Path) -> Result<Self> {
+    Self::load_with(dir, ChatLog::open)
+  }
+
+  fn load_with(dir: &Path, open_log: fn(&Path) -> Result<ChatLog>) -> Result<Self> {
+    let (mut nodes, log) = if ChatLog::exists(dir) {
+      let log = open_log(dir)?;
+      (load_log_nodes(&log), Some(log))
+    } else {
+      (load_legacy_nodes(dir)?, None)
+    };
+
+    nodes.sort_by(|a, b| {
+      (a.index, a.meta.created, &a.meta.id).cmp(&(b.index, b.meta.created, &b.meta.id))
+    });
+
+    let head = match std::fs::read_to_string(dir.join(HEAD_FILENAME)) {
+      Ok(it) => Some(it.trim().to_string()),
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+      Err(e) => return Err(e.into()),
+    };
+
+    Ok(Self {
+      dir: dir.to_path_buf(),
+      nodes,
+      head,
+      log,
+    })
+  }
+
+  /// 读取对话树用于写入，旧版本的消息文件以及旧版本加密的记录会先迁移到日志中
+  ///
+  /// 调用时需要持有对话的 [`chat_lock`]
+  pub fn open(dir: &Path) -> Result<Self> {
+    let tree = Self::load_with(dir, ChatLog::open_writable)?;
+    if let Some(log) = &tree.log {
+      // 迁移完成后未来得及删除的旧版本文件
+      for (_, path) in legacy_files(dir)? {
+        std::fs::remove_file(path)?;
+      }
+      if !log.has_legacy_records() {
+        return Ok(tree);
+      }
+      // 使用对话的数据密钥重新加密旧版本的记录
+      tree.rewrite(|_| true)?;
+      return Self::load_with(dir, ChatLog::open_writable);
+    }
+
+    let files = legacy_files(dir)?;
+    tree.rewrite(|_| true)?;
+    for (_, path) in files {
+      std::fs::remove_file(path)?;
+    }
+    Self::load_with(dir, ChatLog::open_writable)
+  }
+
+  /// 日志中无法读取并且不属于任何消息的记录，包括记录头损坏后无法定位的内容
+  pub fn lost_records(&self) -> Result<Vec<RecordPos>> {
+    let Some(log) = &self.log else {
+      return Ok(Vec::new());
+    };
+    let known = self
+      .nodes
+      .iter()
+      .filter_map(|it| match it.location {
+        NodeLocation::Log(pos) => Some((pos.seg, pos.off)),
+        NodeLocation::File(_) => None,
+      })
+      .collect::<BTreeSet<_>>();
+    let mut lost = log.damaged_records()?;
+    lost.retain(|it| !known.contains(&(it.seg, it.off)));
+    Ok(lost)
+  }
+
+  pub fn nodes(&self) -> &[ChatNode] {
+    &self.nodes
+  }
+
+  pub fn get(&self, id: &str) -> Option<&ChatNode> {
+    self.nodes.iter().find(|it| it.meta.id == id)
+  }
+
+  /// 读取多条消息的内容，返回顺序与 `nodes` 一致
+  pub fn read(&self, nodes: &[&ChatNode]) -> Result<Vec<Value>> {
+    let mut positions = Vec::new();
+    let mut values = Vec::new();
+    for node in nodes {
+      match &node.location {
+        NodeLocation::File(path) => values.push(read_from_disk(path)?),
+        NodeLocation::Log(pos) => positions.push(*pos),
+      }
+    }
+    match &self.log {
+      Some(log) if !positions.is_empty() => log.read_many(&positions),
+      _ => Ok(values),
+    }
+  }
+
+  /// 追加写入一条消息，需要通过 [`ChatTree::open`] 打开
+  pub fn append(&mut self, meta: &NodeMeta, index: u32, message: &Value) -> Result<PathBuf> {
+    let log = self
+      .log
+      .as_mut()
+      .ok_or_else(|| Error::new("对话日志尚未打开"))?;
+    log.append(meta, index, message)
+  }
+
+  /// 只保留满足 `keep` 的消息，重写整个对话日志
+  pub fn rewrite(&self, keep: impl Fn(&ChatNode) -> bool) -> Result<()> {
+    let nodes: Vec<&ChatNode> = self.nodes.iter().filter(|it| keep(it)).collect();
+    let values = self.read(&nodes)?;
+    let records = nodes
+      .iter()
+      .zip(values)
+      .map(|(node, value)| (node.meta.clone(), node.index, value))
+      .collect();
+    ChatLog::rewrite(&self.dir, records)
+  }
+
+  fn children<'a>(&'a self, parent: Option<&'a str>) -> impl Iterator<Item = &'a ChatNode> {
+    self
+      .nodes
+      .iter()
+      .filter(move |it| it.meta.parent.as_deref() == parent)
+  }
+
+  /// 与指定消息拥有同一父消息的全部消息（包括其自身），按创建顺序排列
+  pub fn siblings(&self, id: &str) -> Result<Vec<&ChatNode>> {
+    let node = self
+      .get(id)
+      .ok_or_else(|| Error::NotFound(format!("message({id})")))?;
+    Ok(self.children(node.meta.parent.as_deref()).collect())
+  }
+
+  /// 从指定消息开始，沿最新的子消息找到叶子消息
+  pub fn leaf_of<'a>(&'a self, mut node: &'a ChatNode) -> &'a ChatNode {
+    // 限制步数，避免损坏的数据形成环
+    for _ in 0..self.nodes.len() {
+      match self.children(Some(&node.meta.id)).last() {
+        Some(child) => node = child,
+        None => break,
+      }
+    }
+    node
+  }
+
+  /// 当前激活的分支，从根消息到叶子消息
+  pub fn active_path(&self) -> Vec<&ChatNode> {
+    let start = self
+      .head
+      .as_deref()
+      .and_then(|it| self.get(it))
+      .or_else(|| self.children(None).last());
+    let Some(start) = start else {
+      return Vec::new();
+    };
+
+    let mut path = vec![self.leaf_of(start)];
+    while path.len() < self.nodes.len() {
+      let parent = path.last().and_then(|it| it.meta.parent.as_deref());
+      match parent.and_then(|it| self.get(it)) {
+        Some(node) => path.push(node),
+        None => break,
+      }
+    }
+    path.reverse();
+    path
+  }
+}
+
+/// 设置当前激活分支的叶子消息
+pub(super) fn write_head(dir: &Path, id: Option<&str>) -> Result<()> {
+  let path = dir.join(HEAD_FILENAME);
+  match id {
+    Some(id) => atomic_write(&path, id.as_bytes())?,
+    None => match std::fs::remove_file(path) {
+      Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+      _ => {}
+    },
+  }
+  Ok(())
+}
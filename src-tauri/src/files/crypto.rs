@@ -0,0 +1,150 @@
+use super::LEGACY_PASSWORD;
+use crate::error::{Error, Result};
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Nonce};
+use flate2::Compression;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use sha2::{Digest, Sha256};
+use std::io::{Read, Write};
+
+const NONCE_LEN: usize = 12;
+/// 新格式的数据头，后接 4 字节的密钥标识
+const MAGIC: &[u8; 4] = b"NSK\x02";
+/// 先使用 deflate 压缩再加密的数据头，格式与 [`MAGIC`] 相同
+const COMPRESSED_MAGIC: &[u8; 4] = b"NSK\x03";
+const TAG_LEN: usize = 4;
+/// 用于判断内容是否值得压缩的采样长度
+const SAMPLE_LEN: usize = 64 << 10;
+/// 压缩后不小于原始大小的该比例时不压缩，如图片、压缩包等已经压缩过的内容
+const MIN_RATIO: f64 = 0.9;
+pub(super) const HEADER_LEN: usize = MAGIC.len() + TAG_LEN;
+
+/// AES-256 密钥
+#[derive(Clone)]
+pub(super) struct Key([u8; 32]);
+
+impl Key {
+  /// 随机生成密钥
+  pub fn generate() -> Self {
+    let mut key = [0u8; 32];
+    OsRng.fill_bytes(&mut key);
+    Self(key)
+  }
+
+  pub fn from_slice(data: &[u8]) -> Result<Self> {
+    let key = data.try_into().map_err(|_| Error::new("密钥长度错误"))?;
+    Ok(Self(key))
+  }
+
+  pub fn as_bytes(&self) -> &[u8] {
+    &self.0
+  }
+
+  /// 密钥标识，用于在多个密钥中找到加密时使用的密钥
+  pub fn tag(&self) -> [u8; TAG_LEN] {
+    let hash = Sha256::digest(self.0);
+    [hash[0], hash[1], hash[2], hash[3]]
+  }
+
+  fn cipher(&self) -> Aes256Gcm {
+    Aes256Gcm::new(&self.0.into())
+  }
+}
+
+impl std::fmt::Debug for Key {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.write_str("Key(..)")
+  }
+}
+
+/// 旧版本所有数据共用的密钥，由源码中公开的密码生成，只用于读取旧数据
+fn legacy_key() -> Key {
+  Key(Sha256::digest(LEGACY_PASSWORD.as_bytes()).into())
+}
+
+fn decrypt(key: &Key, sealed: &[u8]) -> Option<Vec<u8>> {
+  if sealed.len() < NONCE_LEN {
+    return None;
+  }
+  let (nonce, data) = sealed.split_at(NONCE_LEN);
+  key.cipher().decrypt(Nonce::from_slice(nonce), data).ok()
+}
+
+fn deflate(data: &[u8]) -> Result<Vec<u8>> {
+  let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+  encoder.write_all(data)?;
+  Ok(encoder.finish()?)
+}
+
+/// 压缩后明显变小时返回压缩结果，先用开头的一部分判断，避免压缩大文件时白白耗时
+fn compress(plain: &[u8]) -> Result<Option<Vec<u8>>> {
+  let worth = |compressed: usize, len: usize| (compressed as f64) < len as f64 * MIN_RATIO;
+  if plain.len() > SAMPLE_LEN && !worth(deflate(&plain[..SAMPLE_LEN])?.len(), SAMPLE_LEN) {
+    return Ok(None);
+  }
+  let compressed = deflate(plain)?;
+  Ok(worth(compressed.len(), plain.len()).then_some(compressed))
+}
+
+/// 加密数据，返回 `MAGIC || 密钥标识 || nonce || 密文`
+///
+/// 压缩后明显变小的数据先压缩，数据头为 [`COMPRESSED_MAGIC`]
+pub(super) fn seal(key: &Key, plain: &[u8]) -> Result<Vec<u8>> {
+  let compressed = compress(plain)?;
+  let (magic, plain) = match &compressed {
+    Some(it) => (COMPRESSED_MAGIC, it.as_slice()),
+    None => (MAGIC, plain),
+  };
+  let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+  let sealed = key
+    .cipher()
+    .encrypt(&nonce, plain)
+    .map_err(|_| Error::new("数据加密失败"))?;
+
+  let mut out = Vec::with_capacity(HEADER_LEN + NONCE_LEN + sealed.len());
+  out.extend_from_slice(magic);
+  out.extend_from_slice(&key.tag());
+  out.extend_from_slice(&nonce);
+  out.extend_from_slice(&sealed);
+  Ok(out)
+}
+
+/// 是否为旧版本使用公开密码加密的数据
+pub(super) fn is_legacy(sealed: &[u8]) -> bool {
+  !sealed.starts_with(MAGIC) && !is_compressed(sealed)
+}
+
+/// 是否为压缩后加密的数据
+pub(super) fn is_compressed(sealed: &[u8]) -> bool {
+  sealed.starts_with(COMPRESSED_MAGIC)
+}
+
+/// 数据是否由 `key` 加密
+pub(super) fn sealed_with(key: &Key, sealed: &[u8]) -> bool {
+  !is_legacy(sealed) && sealed.get(MAGIC.len()..HEADER_LEN) == Some(&key.tag()[..])
+}
+
+/// 解密 [`seal`] 生成的数据，`keys` 中找不到对应密钥时按旧版本格式解密
+pub(super) fn open(keys: &[Key], sealed: &[u8]) -> Result<Vec<u8>> {
+  if !is_legacy(sealed) && sealed.len() >= HEADER_LEN {
+    let (header, data) = sealed.split_at(HEADER_LEN);
+    let tag = &header[MAGIC.len()..];
+    let plain = keys
+      .iter()
+      .filter(|it| it.tag() == tag)
+      .find_map(|it| decrypt(it, data));
+    match plain {
+      Some(plain) if is_compressed(sealed) => {
+        let mut data = Vec::new();
+        DeflateDecoder::new(plain.as_slice()).read_to_end(&mut data)?;
+        return Ok(data);
+      }
+      Some(plain) => return Ok(plain),
+      None => {}
+    }
+  }
+  // 旧版本的数据以随机 nonce 开头，极小概率与数据头相同，因此始终尝试一次
+  decrypt(&legacy_key(), sealed).ok_or_else(|| Error::new("数据解密失败"))
+}
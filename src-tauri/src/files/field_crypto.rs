@@ -0,0 +1,84 @@
+use super::crypto;
+use super::key_store::{KeySet, keys_generation, notes_key_path};
+use crate::error::Result;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// 数据库中加密字段的前缀，之后为 base64 编码的加密数据
+const FIELD_PREFIX: &str = "\u{1}nsk:";
+
+/// 缓存的数据密钥以及读取时的密钥版本
+type CachedKeys = Option<(u64, Arc<KeySet>)>;
+
+/// 数据库字段加密，使用 `keys/notes.key` 中的数据密钥
+///
+/// 没有前缀的字段视为明文，加密前保存的数据可以直接读取
+///
+/// 数据密钥读取后缓存，主密钥或密钥文件变化（解锁、轮换）后重新读取
+#[derive(Clone)]
+pub struct FieldCipher {
+  key_path: PathBuf,
+  keys: Arc<Mutex<CachedKeys>>,
+}
+
+/// 解析字段中的加密数据，明文字段返回空
+fn sealed_bytes(value: &str) -> Option<Vec<u8>> {
+  let data = BASE64.decode(value.strip_prefix(FIELD_PREFIX)?).ok()?;
+  (!crypto::is_legacy(&data)).then_some(data)
+}
+
+impl FieldCipher {
+  pub fn new(app_data: &Path) -> Self {
+    Self {
+      key_path: notes_key_path(app_data),
+      keys: Arc::default(),
+    }
+  }
+
+  /// 读取数据密钥，`create` 时不存在则生成；密钥版本没有变化时使用缓存
+  fn keys(&self, create: bool) -> Result<Arc<KeySet>> {
+    let generation = keys_generation();
+    if let Some((cached, keys)) = &*self.keys.lock().unwrap_or_else(|it| it.into_inner())
+      && *cached == generation
+      && !(create && keys.is_empty())
+    {
+      return Ok(keys.clone());
+    }
+
+    let keys = Arc::new(match create {
+      true => KeySet::load_or_create(&self.key_path)?,
+      false => KeySet::load(&self.key_path)?,
+    });
+    *self.keys.lock().unwrap_or_else(|it| it.into_inner()) = Some((generation, keys.clone()));
+    Ok(keys)
+  }
+
+  /// 字段是否已经加密
+  pub fn is_sealed(value: &str) -> bool {
+    sealed_bytes(value).is_some()
+  }
+
+  pub fn seal(&self, plain: &str) -> Result<String> {
+    let keys = self.keys(true)?;
+    let sealed = keys.seal(plain.as_bytes())?;
+    Ok(format!("{FIELD_PREFIX}{}", BASE64.encode(sealed)))
+  }
+
+  pub fn open(&self, value: &str) -> Result<String> {
+    let Some(sealed) = sealed_bytes(value) else {
+      return Ok(value.to_string());
+    };
+    let keys = self.keys(false)?;
+    Ok(String::from_utf8_lossy(&keys.open(&sealed)?).to_string())
+  }
+
+  /// 字段是否已经使用当前数据密钥加密，轮换密钥后需要重新加密
+  pub fn is_current(&self, value: &str) -> Result<bool> {
+    let Some(sealed) = sealed_bytes(value) else {
+      return Ok(false);
+    };
+    Ok(self.keys(false)?.is_current(&sealed))
+  }
+}
@@ -0,0 +1,382 @@
+use super::SAVE_DIR;
+use super::blob_store::BlobStore;
+use super::chat_tree::chat_lock;
+use super::crypto::{self, Key};
+use super::key_store::{
+  DataKeyFile, KEY_DIR, MasterKey, MasterKeyFile, WrappedKey, current_master, data_key_files,
+  decode, new_master, read_json, replace_master, set_pending, shared_key_path, update_key_file,
+  write_protected,
+};
+use super::reencrypt::{chat_dirs, reseal_chat, reseal_shared, verify_chat, verify_shared};
+use crate::emitter::event;
+use crate::error::{Error, Result};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tauri::async_runtime::spawn_blocking;
+
+/// 轮换任务文件，位于 `keys/` 下，任务完成后删除
+const JOB_FILENAME: &str = "rotation.json";
+/// 轮换进度事件，内容为 [`KeyRotationProgress`]
+const KEY_ROTATION_EVENT: &str = "storage-key-rotation-event";
+
+static RUNNING: AtomicBool = AtomicBool::new(false);
+static PROGRESS: Mutex<Option<KeyRotationProgress>> = Mutex::new(None);
+
+/// 轮换阶段，按顺序执行，每个阶段都可以重复执行
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum RotationPhase {
+  /// 为全部数据密钥加入新主密钥的加密结果，重新加密时先生成新的数据密钥
+  Wrap,
+  /// 使用新的数据密钥重新加密全部对话以及文件内容
+  Reencrypt,
+  /// 确认全部数据都可以使用新密钥解密
+  Verify,
+  /// 写入新的主密钥
+  Switch,
+  /// 删除旧主密钥的加密结果以及旧的数据密钥
+  Prune,
+}
+
+/// `keys/rotation.json`
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RotationJob {
+  master: MasterKeyFile,
+  /// 新主密钥，由旧主密钥加密（base64），使用密码时无法从主密钥文件恢复
+  pending_key: String,
+  old_master_id: String,
+  /// 是否同时更换数据密钥并重新加密全部数据
+  reencrypt: bool,
+  phase: RotationPhase,
+  /// 当前阶段已完成的对话
+  #[serde(default)]
+  done: BTreeSet<String>,
+}
+
+/// 轮换进度
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct KeyRotationProgress {
+  phase: RotationPhase,
+  done: usize,
+  total: usize,
+  finished: bool,
+  /// 失败原因，旧密钥仍然保留，可以继续执行
+  error: Option<String>,
+}
+
+fn job_path(app_data: &Path) -> PathBuf {
+  app_data.join(KEY_DIR).join(JOB_FILENAME)
+}
+
+fn save_job(app_data: &Path, job: &RotationJob) -> Result<()> {
+  write_protected(&job_path(app_data), &serde_json::to_vec(job)?)
+}
+
+fn report(progress: KeyRotationProgress) {
+  *PROGRESS.lock().unwrap_or_else(|it| it.into_inner()) = Some(progress.clone());
+  event(KEY_ROTATION_EVENT, progress);
+}
+
+fn report_phase(phase: RotationPhase, done: usize, total: usize) {
+  report(KeyRotationProgress {
+    phase,
+    done,
+    total,
+    finished: false,
+    error: None,
+  });
+}
+
+/// 对话 id，用于记录完成的对话
+fn chat_name(chat_dir: &Path) -> String {
+  chat_dir
+    .file_name()
+    .map(|it| it.to_string_lossy().to_string())
+    .unwrap_or_default()
+}
+
+/// 确认对话使用新的数据密钥加密，中途写入的旧密钥数据会重新加密后再次确认
+///
+/// 调用时需要持有 [`chat_lock`]
+fn ensure_chat(chat_dir: &Path) -> Result<()> {
+  if verify_chat(chat_dir).is_ok() {
+    return Ok(());
+  }
+  reseal_chat(chat_dir)?;
+  verify_chat(chat_dir).map_err(|e| Error::new(format!("{}: {e}", chat_name(chat_dir))))
+}
+
+fn ensure_shared(app_data: &Path) -> Result<()> {
+  if verify_shared(app_data).is_ok() {
+    return Ok(());
+  }
+  reseal_shared(app_data)?;
+  verify_shared(app_data)
+}
+
+/// 为数据密钥加入新主密钥的加密结果，`reencrypt` 时在最前面加入新的数据密钥
+fn wrap_phase(app_data: &Path, job: &RotationJob, old: &MasterKey, new: &MasterKey) -> Result<()> {
+  let files = data_key_files(app_data)?;
+  for (i, path) in files.iter().enumerate() {
+    update_key_file(path, |file| {
+      let first_wrapped = file
+        .keys
+        .first()
+        .is_some_and(|it| it.wraps.contains_key(&new.id));
+      let mut changed = false;
+      if job.reencrypt && !first_wrapped {
+        let mut wrapped = WrappedKey {
+          wraps: BTreeMap::new(),
+        };
+        let key = Key::generate();
+        wrapped.wrap(&key, old)?;
+        wrapped.wrap(&key, new)?;
+        file.keys.insert(0, wrapped);
+        changed = true;
+      }
+      for wrapped in &mut file.keys {
+        if !wrapped.wraps.contains_key(&new.id) {
+          let key = wrapped.unwrap(old)?;
+          wrapped.wrap(&key, new)?;
+          changed = true;
+        }
+      }
+      Ok(changed)
+    })?;
+    report_phase(RotationPhase::Wrap, i + 1, files.len());
+  }
+  Ok(())
+}
+
+fn reencrypt_phase(app_data: &Path, job: &mut RotationJob) -> Result<()> {
+  let chats = chat_dirs(app_data)?;
+  let total = chats.len() + 1;
+  for chat_dir in &chats {
+    let name = chat_name(chat_dir);
+    if job.done.contains(&name) {
+      continue;
+    }
+    {
+      let _guard = chat_lock(chat_dir);
+      reseal_chat(chat_dir).map_err(|e| Error::new(format!("{name}: {e}")))?;
+    }
+    job.done.insert(name);
+    save_job(app_data, job)?;
+    report_phase(RotationPhase::Reencrypt, job.done.len(), total);
+  }
+  reseal_shared(app_data)?;
+  report_phase(RotationPhase::Reencrypt, total, total);
+  Ok(())
+}
+
+/// 确认全部数据密钥都可以用新主密钥解密，并且与旧主密钥解密的结果一致
+fn verify_phase(
+  app_data: &Path,
+  job: &RotationJob,
+  old: &MasterKey,
+  new: &MasterKey,
+) -> Result<()> {
+  let files = data_key_files(app_data)?;
+  let chats = match job.reencrypt {
+    true => chat_dirs(app_data)?,
+    false => Vec::new(),
+  };
+  let total = files.len() + chats.len();
+  for (i, path) in files.iter().enumerate() {
+    let file = read_json::<DataKeyFile>(path)?.unwrap_or_default();
+    for wrapped in &file.keys {
+      let key = wrapped
+        .unwrap(new)
+        .map_err(|e| Error::new(format!("{}: {e}", path.display())))?;
+      if key.as_bytes() != wrapped.unwrap(old)?.as_bytes() {
+        return Err(Error::new(format!("{}: 数据密钥不一致", path.display())));
+      }
+    }
+    report_phase(RotationPhase::Verify, i + 1, total);
+  }
+  if !job.reencrypt {
+    return Ok(());
+  }
+
+  for (i, chat_dir) in chats.iter().enumerate() {
+    let _guard = chat_lock(chat_dir);
+    ensure_chat(chat_dir)?;
+    report_phase(RotationPhase::Verify, files.len() + i + 1, total);
+  }
+  ensure_shared(app_data)
+}
+
+/// 只保留新主密钥的加密结果，`keep_first` 时只保留用于加密的数据密钥
+fn prune_key_file(path: &Path, new: &MasterKey, keep_first: bool) -> Result<()> {
+  update_key_file(path, |file| {
+    if keep_first {
+      file.keys.truncate(1);
+    }
+    for wrapped in &mut file.keys {
+      wrapped.wraps.retain(|id, _| *id == new.id);
+    }
+    Ok(true)
+  })
+}
+
+fn prune_phase(app_data: &Path, job: &RotationJob, new: &MasterKey) -> Result<()> {
+  let files = data_key_files(app_data)?;
+  let shared = shared_key_path(app_data);
+  let root = app_data.join(SAVE_DIR);
+  for (i, path) in files.iter().enumerate() {
+    let chat_dir = path
+      .parent()
+      .filter(|it| it.parent() == Some(root.as_path()));
+    match chat_dir {
+      // 持有写入锁再次确认，避免删除轮换期间写入的数据使用的密钥
+      Some(chat_dir) if job.reencrypt => {
+        let _guard = chat_lock(chat_dir);
+        ensure_chat(chat_dir)?;
+        prune_key_file(path, new, true)?;
+      }
+      _ if job.reencrypt && *path == shared => {
+        ensure_shared(app_data)?;
+        BlobStore::new(app_data).with_current_keys(|| prune_key_file(path, new, true))?;
+      }
+      // 暂存区中的对话不会重新加密，笔记字段在数据库迁移时才重新加密，保留全部数据密钥
+      _ => prune_key_file(path, new, false)?,
+    }
+    report_phase(RotationPhase::Prune, i + 1, files.len());
+  }
+  Ok(())
+}
+
+fn run(app_data: &Path) -> Result<()> {
+  let Some(mut job) = read_json::<RotationJob>(&job_path(app_data))? else {
+    return Ok(());
+  };
+  let current = current_master()?;
+  let (old, new) = if current.id == job.master.id {
+    // 新主密钥已经写入，只剩下清理
+    job.phase = job.phase.max(RotationPhase::Prune);
+    (None, current)
+  } else if current.id == job.old_master_id {
+    let sealed = decode(&job.pending_key)?;
+    let key = Key::from_slice(&crypto::open(std::slice::from_ref(&current.key), &sealed)?)?;
+    let new = MasterKey {
+      id: job.master.id.clone(),
+      key,
+    };
+    (Some(current), new)
+  } else {
+    return Err(Error::new("密钥轮换任务与当前主密钥不匹配"));
+  };
+
+  if let Some(old) = &old {
+    // 轮换期间新建的数据密钥同时由新旧主密钥加密
+    set_pending(Some(new.clone()));
+    if job.phase == RotationPhase::Wrap {
+      wrap_phase(app_data, &job, old, &new)?;
+      job.phase = match job.reencrypt {
+        true => RotationPhase::Reencrypt,
+        false => RotationPhase::Verify,
+      };
+      save_job(app_data, &job)?;
+    }
+    if job.phase == RotationPhase::Reencrypt {
+      reencrypt_phase(app_data, &mut job)?;
+      job.phase = RotationPhase::Verify;
+      job.done.clear();
+      save_job(app_data, &job)?;
+    }
+    if job.phase == RotationPhase::Verify {
+      verify_phase(app_data, &job, old, &new)?;
+      job.phase = RotationPhase::Switch;
+      save_job(app_data, &job)?;
+    }
+    report_phase(RotationPhase::Switch, 0, 1);
+    replace_master(app_data, &job.master, new.clone())?;
+    job.phase = RotationPhase::Prune;
+    save_job(app_data, &job)?;
+  }
+
+  prune_phase(app_data, &job, &new)?;
+  std::fs::remove_file(job_path(app_data))?;
+  Ok(())
+}
+
+/// 在后台线程中执行轮换任务，已经在执行时忽略
+fn spawn(app_data: PathBuf) {
+  if RUNNING.swap(true, Ordering::SeqCst) {
+    return;
+  }
+  std::thread::spawn(move || {
+    let result = run(&app_data);
+    let last = PROGRESS.lock().unwrap_or_else(|it| it.into_inner()).clone();
+    report(KeyRotationProgress {
+      phase: last.map_or(RotationPhase::Prune, |it| it.phase),
+      done: 0,
+      total: 0,
+      finished: result.is_ok(),
+      error: result.err().map(|e| e.to_string()),
+    });
+    RUNNING.store(false, Ordering::SeqCst);
+  });
+}
+
+/// 存在未完成的轮换任务时在后台继续执行，需要主密钥已解锁
+pub(super) fn resume(app_data: &Path) -> Result<()> {
+  if job_path(app_data).try_exists()? && current_master().is_ok() {
+    spawn(app_data.to_path_buf());
+  }
+  Ok(())
+}
+
+/// 开始轮换主密钥，`passphrase` 为空时新的主密钥随机生成
+///
+/// `reencrypt` 时同时更换数据密钥并重新加密全部数据，否则只重新加密数据密钥。
+/// 任务在后台执行，进度通过事件发送，中断后下次启动或解锁时继续；
+/// 全部数据确认可以使用新密钥解密后才会删除旧密钥。
+pub async fn rotate_storage_keys(
+  app_data: &Path,
+  passphrase: Option<String>,
+  reencrypt: bool,
+) -> Result<()> {
+  let app_data = app_data.to_path_buf();
+  spawn_blocking(move || {
+    let old = current_master()?;
+    if RUNNING.load(Ordering::SeqCst) || job_path(&app_data).try_exists()? {
+      return Err(Error::new("存在未完成的密钥轮换，请等待完成或继续执行"));
+    }
+    let (master, new) = new_master(passphrase.as_deref())?;
+    let job = RotationJob {
+      master,
+      pending_key: BASE64.encode(crypto::seal(&old.key, new.key.as_bytes())?),
+      old_master_id: old.id,
+      reencrypt,
+      phase: RotationPhase::Wrap,
+      done: BTreeSet::new(),
+    };
+    save_job(&app_data, &job)?;
+    spawn(app_data);
+    Ok(())
+  })
+  .await?
+}
+
+/// 继续执行上次失败的轮换任务
+pub async fn resume_key_rotation(app_data: &Path) -> Result<()> {
+  let app_data = app_data.to_path_buf();
+  spawn_blocking(move || {
+    current_master()?;
+    resume(&app_data)
+  })
+  .await?
+}
+
+/// 最近一次轮换的进度，没有轮换时为空
+pub fn key_rotation_progress() -> Option<KeyRotationProgress> {
+  PROGRESS.lock().unwrap_or_else(|it| it.into_inner()).clone()
+}
@@ -0,0 +1,466 @@
+use super::crypto::{self, Key};
+use super::{SAVE_DIR, STAGING_DIR, reencrypt, temp_path};
+use crate::error::{Error, Result};
+use aes_gcm::aead::OsRng;
+use aes_gcm::aead::rand_core::RngCore;
+use argon2::{Algorithm, Argon2, Params, Version};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions, create_dir_all};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard, RwLock};
+use tauri::async_runtime::spawn_blocking;
+
+pub(super) const KEY_DIR: &str = "keys";
+const MASTER_FILENAME: &str = "master.json";
+const SHARED_KEY_FILENAME: &str = "shared.key";
+const NOTES_KEY_FILENAME: &str = "notes.key";
+const CHAT_KEY_FILENAME: &str = "data.key";
+const BLOB_NAME_KEY_FILENAME: &str = "blob-name.key";
+/// 用于校验密码是否正确的明文
+const PASSPHRASE_CHECK: &[u8] = b"note-secretary";
+const SALT_LEN: usize = 16;
+
+/// 主密钥的来源
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+enum MasterSource {
+  /// 随机生成，保存在仅当前用户可读的文件中
+  Random { key: String },
+  /// 由用户密码通过 Argon2id 派生，文件中只保存参数以及校验数据
+  Passphrase(PassphraseParams),
+}
+
+/// 由密码派生密钥的参数以及用于校验密码的数据
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct PassphraseParams {
+  salt: String,
+  m_cost: u32,
+  t_cost: u32,
+  p_cost: u32,
+  check: String,
+}
+
+impl PassphraseParams {
+  /// 生成新的参数，返回参数以及派生的密钥
+  pub fn new(passphrase: &str) -> Result<(Self, Key)> {
+    if passphrase.is_empty() {
+      return Err(Error::new("密码不能为空"));
+    }
+    let mut salt = [0u8; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+    let params = Params::default();
+    let key = derive_key(passphrase, &salt, params.clone())?;
+    let this = Self {
+      salt: BASE64.encode(salt),
+      m_cost: params.m_cost(),
+      t_cost: params.t_cost(),
+      p_cost: params.p_cost(),
+      check: BASE64.encode(crypto::seal(&key, PASSPHRASE_CHECK)?),
+    };
+    Ok((this, key))
+  }
+
+  /// 派生密钥并校验密码是否正确
+  pub fn derive(&self, passphrase: &str) -> Result<Key> {
+    let params = Params::new(self.m_cost, self.t_cost, self.p_cost, None)
+      .map_err(|e| Error::new(format!("密钥参数错误: {e}")))?;
+    let key = derive_key(passphrase, &decode(&self.salt)?, params)?;
+    match crypto::open(std::slice::from_ref(&key), &decode(&self.check)?) {
+      Ok(it) if it == PASSPHRASE_CHECK => Ok(key),
+      _ => Err(Error::new("密码错误")),
+    }
+  }
+}
+
+/// `keys/master.json`
+#[derive(Clone, Serialize, Deserialize)]
+pub(super) struct MasterKeyFile {
+  /// 主密钥 id，数据密钥按此 id 记录由哪个主密钥加密
+  pub id: String,
+  #[serde(flatten)]
+  source: MasterSource,
+}
+
+#[derive(Clone)]
+pub(super) struct MasterKey {
+  pub id: String,
+  pub key: Key,
+}
+
+/// 数据密钥文件，第一个密钥用于加密，其余密钥只用于解密
+///
+/// 每个密钥可以同时由多个主密钥加密，更换主密钥时先加入新的加密结果再删除旧的，中断不会丢失密钥
+#[derive(Default, Serialize, Deserialize)]
+pub(super) struct DataKeyFile {
+  pub keys: Vec<WrappedKey>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(super) struct WrappedKey {
+  /// 主密钥 id -> 加密后的数据密钥（base64）
+  pub wraps: BTreeMap<String, String>,
+}
+
+/// 已解锁的主密钥，使用密码时启动后需要先解锁
+static MASTER: RwLock<Option<MasterKey>> = RwLock::new(None);
+/// 轮换中的新主密钥，轮换期间新建的数据密钥同时由新旧主密钥加密
+static PENDING: RwLock<Option<MasterKey>> = RwLock::new(None);
+/// 创建以及更新密钥文件时持有，避免并发创建出不同的数据密钥
+static KEYS_LOCK: Mutex<()> = Mutex::new(());
+/// 主密钥或数据密钥文件变化时递增，缓存的数据密钥据此失效
+static KEYS_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// 存储加密的状态
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StorageKeyStatus {
+  /// 主密钥是否由密码派生
+  passphrase: bool,
+  unlocked: bool,
+}
+
+fn master_path(app_data: &Path) -> PathBuf {
+  app_data.join(KEY_DIR).join(MASTER_FILENAME)
+}
+
+pub(super) fn shared_key_path(app_data: &Path) -> PathBuf {
+  app_data.join(KEY_DIR).join(SHARED_KEY_FILENAME)
+}
+
+/// 加密数据库中笔记字段的数据密钥
+pub(super) fn notes_key_path(app_data: &Path) -> PathBuf {
+  app_data.join(KEY_DIR).join(NOTES_KEY_FILENAME)
+}
+
+/// 计算文件内容标识的密钥，只在本机使用
+pub(super) fn blob_name_key_path(app_data: &Path) -> PathBuf {
+  app_data.join(KEY_DIR).join(BLOB_NAME_KEY_FILENAME)
+}
+
+pub(super) fn chat_key_path(chat_dir: &Path) -> PathBuf {
+  chat_dir.join(CHAT_KEY_FILENAME)
+}
+
+pub(super) fn decode(value: &str) -> Result<Vec<u8>> {
+  BASE64
+    .decode(value)
+    .map_err(|_| Error::new("密钥文件已损坏"))
+}
+
+/// 与 [`super::atomic_write`] 相同，但文件只允许当前用户读写
+pub(super) fn write_protected(path: &Path, data: &[u8]) -> Result<()> {
+  if let Some(parent) = path.parent() {
+    create_dir_all(parent)?;
+  }
+  let temp_path = temp_path(path);
+  let mut options = OpenOptions::new();
+  options.write(true).create(true).truncate(true);
+  #[cfg(unix)]
+  {
+    use std::os::unix::fs::OpenOptionsExt;
+    options.mode(0o600);
+  }
+  let mut file = options.open(&temp_path)?;
+  file.write_all(data)?;
+  file.sync_all()?;
+  std::fs::rename(temp_path, path)?;
+  Ok(())
+}
+
+pub(super) fn read_json<T: serde::de::DeserializeOwned>(path: &Path) -> Result<Option<T>> {
+  match File::open(path) {
+    Ok(file) => Ok(Some(serde_json::from_reader(file)?)),
+    Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+    Err(e) => Err(e.into()),
+  }
+}
+
+fn derive_key(passphrase: &str, salt: &[u8], params: Params) -> Result<Key> {
+  let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, params);
+  let mut key = [0u8; 32];
+  argon2
+    .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+    .map_err(|e| Error::new(format!("密钥派生失败: {e}")))?;
+  Key::from_slice(&key)
+}
+
+pub(super) fn current_master() -> Result<MasterKey> {
+  let master = MASTER.read().unwrap_or_else(|it| it.into_inner());
+  master
+    .clone()
+    .ok_or_else(|| Error::new("存储已锁定，请先输入密码解锁"))
+}
+
+fn set_master(master: MasterKey) {
+  *MASTER.write().unwrap_or_else(|it| it.into_inner()) = Some(master);
+  keys_changed();
+}
+
+fn pending_master() -> Option<MasterKey> {
+  PENDING.read().unwrap_or_else(|it| it.into_inner()).clone()
+}
+
+pub(super) fn set_pending(master: Option<MasterKey>) {
+  *PENDING.write().unwrap_or_else(|it| it.into_inner()) = master;
+  keys_changed();
+}
+
+/// 当前的密钥版本，读取数据密钥前获取，之后版本变化时需要重新读取
+pub(super) fn keys_generation() -> u64 {
+  KEYS_GENERATION.load(Ordering::SeqCst)
+}
+
+fn keys_changed() {
+  KEYS_GENERATION.fetch_add(1, Ordering::SeqCst);
+}
+
+/// 创建以及更新密钥文件时持有
+pub(super) fn keys_lock() -> MutexGuard<'static, ()> {
+  KEYS_LOCK.lock().unwrap_or_else(|it| it.into_inner())
+}
+
+/// 生成新的主密钥，`passphrase` 为空时随机生成
+pub(super) fn new_master(passphrase: Option<&str>) -> Result<(MasterKeyFile, MasterKey)> {
+  let id = uuid::Uuid::new_v4().simple().to_string();
+  let (source, key) = match passphrase {
+    None => {
+      let key = Key::generate();
+      let source = MasterSource::Random {
+        key: BASE64.encode(key.as_bytes()),
+      };
+      (source, key)
+    }
+    Some(passphrase) => {
+      let (params, key) = PassphraseParams::new(passphrase)?;
+      (MasterSource::Passphrase(params), key)
+    }
+  };
+  let file = MasterKeyFile {
+    id: id.clone(),
+    source,
+  };
+  Ok((file, MasterKey { id, key }))
+}
+
+/// 读取主密钥，首次启动时随机生成
+///
+/// 返回 `false` 表示主密钥由密码派生，需要等待 [`unlock_storage`]
+pub(super) fn load_master(app_data: &Path) -> Result<bool> {
+  let _guard = keys_lock();
+  let path = master_path(app_data);
+  let file = match read_json::<MasterKeyFile>(&path)? {
+    Some(it) => it,
+    None => {
+      let (file, master) = new_master(None)?;
+      write_protected(&path, &serde_json::to_vec(&file)?)?;
+      set_master(master);
+      return Ok(true);
+    }
+  };
+
+  match file.source {
+    MasterSource::Random { key } => {
+      let key = Key::from_slice(&decode(&key)?)?;
+      set_master(MasterKey { id: file.id, key });
+      Ok(true)
+    }
+    MasterSource::Passphrase(_) => Ok(current_master().is_ok_and(|it| it.id == file.id)),
+  }
+}
+
+fn unlock_blocking(app_data: &Path, passphrase: &str) -> Result<()> {
+  let file = read_json::<MasterKeyFile>(&master_path(app_data))?
+    .ok_or_else(|| Error::NotFound("master key".into()))?;
+  let MasterSource::Passphrase(params) = file.source else {
+    return Ok(());
+  };
+  let key = params.derive(passphrase)?;
+  set_master(MasterKey { id: file.id, key });
+  Ok(())
+}
+
+/// 用密码解锁存储，解锁后继续执行启动时跳过的数据迁移
+pub async fn unlock_storage(app_data: &Path, passphrase: String) -> Result<()> {
+  let app_data = app_data.to_path_buf();
+  spawn_blocking(move || {
+    unlock_blocking(&app_data, &passphrase)?;
+    reencrypt::start(&app_data)
+  })
+  .await?
+}
+
+pub async fn storage_key_status(app_data: &Path) -> Result<StorageKeyStatus> {
+  let path = master_path(app_data);
+  spawn_blocking(move || {
+    let file = read_json::<MasterKeyFile>(&path)?;
+    let unlocked =
+      current_master().is_ok_and(|master| file.as_ref().is_some_and(|it| it.id == master.id));
+    let passphrase = matches!(file.map(|it| it.source), Some(MasterSource::Passphrase(_)));
+    Ok(StorageKeyStatus {
+      passphrase,
+      unlocked,
+    })
+  })
+  .await?
+}
+
+/// 用主密钥解密数据密钥文件中的全部密钥
+fn unwrap_keys(file: &DataKeyFile, master: &MasterKey) -> Result<Vec<Key>> {
+  let mut keys = Vec::with_capacity(file.keys.len());
+  for wrapped in &file.keys {
+    keys.push(wrapped.unwrap(master)?);
+  }
+  Ok(keys)
+}
+
+impl WrappedKey {
+  pub fn unwrap(&self, master: &MasterKey) -> Result<Key> {
+    let sealed = self
+      .wraps
+      .get(&master.id)
+      .ok_or_else(|| Error::new("数据密钥与主密钥不匹配"))?;
+    let plain = crypto::open(std::slice::from_ref(&master.key), &decode(sealed)?)?;
+    Key::from_slice(&plain)
+  }
+
+  /// 加入由 `master` 加密的结果
+  pub fn wrap(&mut self, key: &Key, master: &MasterKey) -> Result<()> {
+    let sealed = crypto::seal(&master.key, key.as_bytes())?;
+    self.wraps.insert(master.id.clone(), BASE64.encode(sealed));
+    Ok(())
+  }
+}
+
+/// 用当前的主密钥以及轮换中的新主密钥加密数据密钥
+fn wrap_key(key: &Key) -> Result<WrappedKey> {
+  let mut wrapped = WrappedKey {
+    wraps: BTreeMap::new(),
+  };
+  wrapped.wrap(key, &current_master()?)?;
+  if let Some(pending) = pending_master() {
+    wrapped.wrap(key, &pending)?;
+  }
+  Ok(wrapped)
+}
+
+/// 一组数据密钥，加密使用第一个密钥，解密时按数据中的密钥标识选择
+#[derive(Clone)]
+pub(super) struct KeySet(Vec<Key>);
+
+impl KeySet {
+  /// 读取数据密钥，密钥文件不存在时返回空的密钥集合，只能解密旧版本的数据
+  pub fn load(path: &Path) -> Result<Self> {
+    match read_json::<DataKeyFile>(path)? {
+      Some(file) => Ok(Self(unwrap_keys(&file, &current_master()?)?)),
+      None => Ok(Self(Vec::new())),
+    }
+  }
+
+  /// 读取数据密钥，不存在时生成新的密钥
+  pub fn load_or_create(path: &Path) -> Result<Self> {
+    Self::load_or_import(path, || Ok(Key::generate()))
+  }
+
+  /// 读取数据密钥，不存在时保存 `key` 返回的密钥，用于导入已有的密钥
+  pub fn load_or_import(path: &Path, key: impl FnOnce() -> Result<Key>) -> Result<Self> {
+    let master = current_master()?;
+    let _guard = keys_lock();
+    if let Some(file) = read_json::<DataKeyFile>(path)? {
+      return Ok(Self(unwrap_keys(&file, &master)?));
+    }
+
+    let key = key()?;
+    let file = DataKeyFile {
+      keys: vec![wrap_key(&key)?],
+    };
+    write_protected(path, &serde_json::to_vec(&file)?)?;
+    keys_changed();
+    Ok(Self(vec![key]))
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.0.is_empty()
+  }
+
+  /// 只包含用于加密的密钥，用于确认数据已经使用该密钥加密
+  pub fn current(&self) -> Self {
+    Self(self.0.iter().take(1).cloned().collect())
+  }
+
+  /// 数据是否已经使用当前密钥加密
+  pub fn is_current(&self, sealed: &[u8]) -> bool {
+    self
+      .0
+      .first()
+      .is_some_and(|it| crypto::sealed_with(it, sealed))
+  }
+
+  /// 最早生成的密钥，轮换时新密钥加在前面并保留旧密钥，因此该密钥保持不变
+  pub fn oldest(&self) -> Result<&Key> {
+    self.0.last().ok_or_else(|| Error::new("缺少数据密钥"))
+  }
+
+  pub fn seal(&self, plain: &[u8]) -> Result<Vec<u8>> {
+    let key = self.0.first().ok_or_else(|| Error::new("缺少数据密钥"))?;
+    crypto::seal(key, plain)
+  }
+
+  pub fn open(&self, sealed: &[u8]) -> Result<Vec<u8>> {
+    crypto::open(&self.0, sealed)
+  }
+}
+
+/// 全部数据密钥文件，包括暂存区中的对话
+pub(super) fn data_key_files(app_data: &Path) -> Result<Vec<PathBuf>> {
+  let mut files = vec![
+    shared_key_path(app_data),
+    notes_key_path(app_data),
+    blob_name_key_path(app_data),
+  ];
+  let root = app_data.join(SAVE_DIR);
+  for dir in [root.clone(), root.join(STAGING_DIR)] {
+    if !dir.is_dir() {
+      continue;
+    }
+    for entry in std::fs::read_dir(dir)? {
+      files.push(chat_key_path(&entry?.path()));
+    }
+  }
+  files.retain(|it| it.is_file());
+  Ok(files)
+}
+
+/// 读取并修改数据密钥文件，`update` 返回 `true` 时写回
+pub(super) fn update_key_file(
+  path: &Path,
+  update: impl FnOnce(&mut DataKeyFile) -> Result<bool>,
+) -> Result<()> {
+  let _guard = keys_lock();
+  let Some(mut file) = read_json::<DataKeyFile>(path)? else {
+    return Ok(());
+  };
+  if update(&mut file)? {
+    write_protected(path, &serde_json::to_vec(&file)?)?;
+    keys_changed();
+  }
+  Ok(())
+}
+
+/// 替换主密钥文件并使用新的主密钥
+pub(super) fn replace_master(
+  app_data: &Path,
+  file: &MasterKeyFile,
+  master: MasterKey,
+) -> Result<()> {
+  let _guard = keys_lock();
+  write_protected(&master_path(app_data), &serde_json::to_vec(file)?)?;
+  set_master(master);
+  set_pending(None);
+  Ok(())
+}
@@ -1,8 +1,110 @@
+#[cfg(feature = "bench")]
+pub mod bench;
+mod blob_store;
+mod chat_dirs;
+mod chat_drafts;
+mod chat_export;
 mod chat_files;
+mod chat_gc;
+mod chat_import;
+mod chat_log;
 mod chat_messages;
+mod chat_tree;
+mod crypto;
+mod field_crypto;
+mod key_rotation;
+mod key_store;
+mod note_files;
+mod note_lock;
+mod recompress;
+mod reencrypt;
+mod remote_fetch;
+mod storage_check;
+mod sync_key;
+mod text_extract;
+mod thumbnails;
+mod web_clip;
 
 const SAVE_DIR: &str = "chats";
-const PASSWORD: &str = "note-secretary.vuhe.top";
+const STAGING_DIR: &str = ".staging";
+/// 旧版本所有数据共用的加密密码，只用于读取和迁移旧数据
+const LEGACY_PASSWORD: &str = "note-secretary.vuhe.top";
 
-pub use chat_files::ChatFile;
-pub use chat_messages::ChatMessage;
+pub use chat_dirs::{ChatBranch, ChatDir, StoredMessage};
+pub use chat_drafts::ChatDraft;
+pub use chat_export::{ExportFormat, export_chat, messages_summary, messages_to_markdown};
+pub use chat_files::{ChatFile, ChatFileStat};
+pub use chat_gc::{CHAT_GC_SCHEDULE_KEY, ChatGcReport, ChatGcSchedule, collect_chat_garbage};
+pub use chat_import::{ImportReport, ImportSource, import_chats};
+pub use chat_messages::{ChatMessage, message_text};
+pub use field_crypto::FieldCipher;
+pub use key_rotation::{
+  KeyRotationProgress, key_rotation_progress, resume_key_rotation, rotate_storage_keys,
+};
+pub use key_store::{StorageKeyStatus, storage_key_status, unlock_storage};
+pub use note_files::{delete_note_files, read_note_file};
+pub use note_lock::{NoteKey, NoteLock, end_note_session, note_session, start_note_session};
+pub use recompress::recompress_storage;
+pub use remote_fetch::{FETCH_POLICY_KEY, FetchPolicy};
+pub use storage_check::{StorageReport, check_storage};
+pub use sync_key::{SyncKey, SyncKeyParams};
+pub use text_extract::{ExtractedText, extract_chat_file, message_search_text};
+pub use thumbnails::{ThumbnailFormat, ThumbnailSource, thumbnail};
+pub use web_clip::clip_web_page;
+
+use crate::AppDataPath;
+use crate::error::{Error, Result};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tauri::Manager;
+
+/// 检查 id 是否可以安全地作为文件名使用
+fn check_id(kind: &str, id: &str) -> Result<()> {
+  let valid = !id.is_empty()
+    && !id.starts_with('.')
+    && id
+      .chars()
+      .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+  match valid {
+    true => Ok(()),
+    false => Err(Error::new(format!("非法的{kind} id: {id}"))),
+  }
+}
+
+/// 同一文件夹中不会重复的临时文件路径，`<文件名>.<随机>.tmp`
+fn temp_path(path: &Path) -> PathBuf {
+  let mut name = path.file_name().unwrap_or_default().to_os_string();
+  name.push(format!(".{}.tmp", uuid::Uuid::new_v4().simple()));
+  path.with_file_name(name)
+}
+
+/// 先写入临时文件再替换，保证文件内容完整
+fn atomic_write(path: &Path, data: &[u8]) -> std::io::Result<()> {
+  let temp_path = temp_path(path);
+  let result = std::fs::File::create(&temp_path)
+    .and_then(|mut file| {
+      file.write_all(data)?;
+      file.sync_all()
+    })
+    .and_then(|_| std::fs::rename(&temp_path, path));
+  if result.is_err() {
+    let _ = std::fs::remove_file(&temp_path);
+  }
+  result
+}
+
+pub fn setup_chat_dir(app: &tauri::App) -> tauri::Result<()> {
+  let app_data_path = app.state::<AppDataPath>();
+  let app_data = &app_data_path.0;
+  // 使用密码时需要等待解锁后再迁移
+  let result = chat_dirs::cleanup_staging(app_data)
+    .and_then(|_| key_store::load_master(app_data))
+    .and_then(|unlocked| match unlocked {
+      true => reencrypt::start(app_data),
+      false => Ok(()),
+    });
+  result.map_err(|e| tauri::Error::Anyhow(e.into()))?;
+
+  chat_gc::spawn_schedule(app.handle().clone(), app_data_path.0.clone());
+  Ok(())
+}
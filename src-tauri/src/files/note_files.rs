@@ -0,0 +1,75 @@
+use super::blob_store::{BlobStore, sha256_hex};
+use super::chat_files::{FILE_DIR_NAME, FileRef, read_ref, release_refs, sniff_mime, write_ref};
+use super::check_id;
+use crate::error::{Error, Result};
+use std::path::{Path, PathBuf};
+use tauri::async_runtime::spawn_blocking;
+
+pub(super) const NOTE_DIR: &str = "notes";
+
+/// 笔记引用的文件，`notes/<note_id>/files/<file_id>.ref`，内容与对话文件共用 [`BlobStore`]
+pub(super) fn note_files_dir(app_data: &Path, note_id: &str) -> Result<PathBuf> {
+  check_id("笔记", note_id)?;
+  Ok(app_data.join(NOTE_DIR).join(note_id).join(FILE_DIR_NAME))
+}
+
+/// 保存笔记中引用的文件，返回文件 id
+pub async fn save_note_file(
+  app_data: &Path,
+  note_id: &str,
+  data: Vec<u8>,
+  name: Option<String>,
+  source: &str,
+) -> Result<String> {
+  let files_dir = note_files_dir(app_data, note_id)?;
+  let store = BlobStore::new(app_data);
+  let file_id = format!("file-{}", uuid::Uuid::new_v4().simple());
+  let source = source.to_string();
+  spawn_blocking(move || {
+    store.put(&data, |blob| {
+      let file_ref = FileRef {
+        blob: blob.to_string(),
+        mime_type: Some(sniff_mime(&data, name.as_deref(), None)),
+        name,
+        size: Some(data.len() as u64),
+        sha256: Some(sha256_hex(&data)),
+        source: Some(source),
+      };
+      write_ref(&files_dir, &file_id, &file_ref)?;
+      Ok(None)
+    })?;
+    Ok(file_id)
+  })
+  .await?
+}
+
+/// 读取笔记中引用的文件
+pub async fn read_note_file(app_data: &Path, note_id: &str, file_id: &str) -> Result<Vec<u8>> {
+  check_id("文件", file_id)?;
+  let files_dir = note_files_dir(app_data, note_id)?;
+  let store = BlobStore::new(app_data);
+  let file_id = file_id.to_string();
+  spawn_blocking(move || {
+    let file_ref =
+      read_ref(&files_dir, &file_id)?.ok_or_else(|| Error::NotFound(format!("file({file_id})")))?;
+    store.read(&file_ref.blob)
+  })
+  .await?
+}
+
+/// 删除笔记的全部文件
+pub async fn delete_note_files(app_data: &Path, note_id: &str) -> Result<()> {
+  let files_dir = note_files_dir(app_data, note_id)?;
+  let app_data = app_data.to_path_buf();
+  spawn_blocking(move || {
+    release_refs(&app_data, &files_dir)?;
+    if let Some(dir) = files_dir.parent() {
+      match std::fs::remove_dir_all(dir) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+      }
+    }
+    Ok(())
+  })
+  .await?
+}
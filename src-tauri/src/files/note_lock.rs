@@ -0,0 +1,78 @@
+use super::crypto::{self, Key};
+use super::key_store::PassphraseParams;
+use crate::error::{Error, Result};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use serde::{Deserialize, Serialize};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// 解锁后没有访问笔记时保持解锁的时间
+const UNLOCK_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// 已解锁笔记的密钥以及过期时间，只保存在内存中
+static SESSIONS: Mutex<Vec<(String, Key, Instant)>> = Mutex::new(Vec::new());
+
+/// 笔记密码的派生参数，以 json 保存在数据库中
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct NoteLock(PassphraseParams);
+
+/// 由笔记密码派生的密钥，用于加密笔记的正文和总结
+#[derive(Clone)]
+pub struct NoteKey(Key);
+
+impl NoteLock {
+  /// 为笔记设置新的密码，返回参数以及派生的密钥
+  pub fn new(password: &str) -> Result<(Self, NoteKey)> {
+    let (params, key) = PassphraseParams::new(password)?;
+    Ok((Self(params), NoteKey(key)))
+  }
+
+  /// 校验密码并派生密钥
+  pub fn unlock(&self, password: &str) -> Result<NoteKey> {
+    self.0.derive(password).map(NoteKey)
+  }
+}
+
+impl NoteKey {
+  pub fn seal(&self, plain: &str) -> Result<String> {
+    Ok(BASE64.encode(crypto::seal(&self.0, plain.as_bytes())?))
+  }
+
+  pub fn open(&self, sealed: &str) -> Result<String> {
+    let sealed = BASE64
+      .decode(sealed)
+      .map_err(|_| Error::new("笔记内容已损坏"))?;
+    let plain = crypto::open(std::slice::from_ref(&self.0), &sealed)?;
+    Ok(String::from_utf8_lossy(&plain).to_string())
+  }
+}
+
+/// 全部未过期的解锁记录
+fn sessions() -> MutexGuard<'static, Vec<(String, Key, Instant)>> {
+  let mut sessions = SESSIONS.lock().unwrap_or_else(|it| it.into_inner());
+  let now = Instant::now();
+  sessions.retain(|(_, _, expires)| *expires > now);
+  sessions
+}
+
+/// 保存解锁后的密钥，超过 [`UNLOCK_TIMEOUT`] 没有访问时自动锁定
+pub fn start_note_session(note_id: &str, key: NoteKey) {
+  let mut sessions = sessions();
+  sessions.retain(|(id, ..)| id != note_id);
+  sessions.push((note_id.to_string(), key.0, Instant::now() + UNLOCK_TIMEOUT));
+}
+
+/// 已解锁笔记的密钥，访问时延长解锁时间
+pub fn note_session(note_id: &str) -> Option<NoteKey> {
+  let mut sessions = sessions();
+  let (_, key, expires) = sessions.iter_mut().find(|(id, ..)| id == note_id)?;
+  *expires = Instant::now() + UNLOCK_TIMEOUT;
+  Some(NoteKey(key.clone()))
+}
+
+/// 立即锁定笔记
+pub fn end_note_session(note_id: &str) {
+  sessions().retain(|(id, ..)| id != note_id);
+}
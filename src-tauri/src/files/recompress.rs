@@ -0,0 +1,164 @@
+use super::blob_store::BlobStore;
+use super::chat_drafts::DRAFT_DIR_NAME;
+use super::chat_files::FILE_DIR_NAME;
+use super::chat_log::ChatLog;
+use super::chat_tree::{ChatTree, chat_lock};
+use super::key_store::{KEY_DIR, KeySet, chat_key_path, current_master};
+use super::reencrypt::{chat_dirs, files_in};
+use super::{atomic_write, crypto};
+use crate::emitter::event;
+use crate::error::{Error, Result};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use tauri::async_runtime::spawn_blocking;
+
+/// 旧版本未压缩的数据全部压缩后写入的标记文件，位于 `keys/` 下
+const RECOMPRESSED_MARKER: &str = "recompressed";
+/// 压缩进度事件，内容为 [`RecompressProgress`]
+const RECOMPRESS_EVENT: &str = "storage-recompress-event";
+
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// 压缩进度，`done` 和 `total` 为对话数量
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RecompressProgress {
+  done: usize,
+  total: usize,
+  /// 已节省的字节数
+  saved_bytes: u64,
+  finished: bool,
+  /// 失败原因，未压缩的数据仍然可以正常读取
+  error: Option<String>,
+}
+
+/// 文件夹中全部文件的大小
+fn dir_size(dir: &Path) -> Result<u64> {
+  let mut size = 0;
+  if !dir.is_dir() {
+    return Ok(size);
+  }
+  for entry in std::fs::read_dir(dir)? {
+    let entry = entry?;
+    let meta = entry.metadata()?;
+    size += match meta.is_dir() {
+      true => dir_size(&entry.path())?,
+      false => meta.len(),
+    };
+  }
+  Ok(size)
+}
+
+/// 压缩后重新加密没有压缩的文件，压缩后不能变小的文件保持原样
+fn recompress_files(keys: &KeySet, paths: Vec<PathBuf>) -> Result<u64> {
+  let mut saved = 0;
+  for path in paths {
+    let sealed = std::fs::read(&path)?;
+    if crypto::is_compressed(&sealed) {
+      continue;
+    }
+    let resealed = keys.seal(&keys.open(&sealed)?)?;
+    if crypto::is_compressed(&resealed) {
+      atomic_write(&path, &resealed)?;
+      saved += sealed.len().saturating_sub(resealed.len()) as u64;
+    }
+  }
+  Ok(saved)
+}
+
+/// 压缩单个对话的日志记录、草稿以及提取文本的缓存，返回节省的字节数
+fn recompress_chat(chat_dir: &Path) -> Result<u64> {
+  let _guard = chat_lock(chat_dir);
+  let tree = ChatTree::open(chat_dir)?;
+  let mut saved = 0;
+  if ChatLog::exists(chat_dir) && ChatLog::open(chat_dir)?.uncompressed_records()? > 0 {
+    let before = dir_size(chat_dir)?;
+    tree.rewrite(|_| true)?;
+    saved += before.saturating_sub(dir_size(chat_dir)?);
+  }
+  let keys = KeySet::load_or_create(&chat_key_path(chat_dir))?;
+  let drafts = files_in(&chat_dir.join(DRAFT_DIR_NAME), &["draft"], false)?;
+  saved += recompress_files(&keys, drafts)?;
+  let caches = files_in(&chat_dir.join(FILE_DIR_NAME), &["text"], false)?;
+  saved += recompress_files(&keys, caches)?;
+  Ok(saved)
+}
+
+fn run(app_data: &Path) -> Result<u64> {
+  let dirs = chat_dirs(app_data)?;
+  let total = dirs.len();
+  let mut saved = 0;
+  let mut failed = Vec::new();
+  for (done, chat_dir) in dirs.iter().enumerate() {
+    event(
+      RECOMPRESS_EVENT,
+      RecompressProgress {
+        done,
+        total,
+        saved_bytes: saved,
+        finished: false,
+        error: None,
+      },
+    );
+    // 单个对话失败时继续处理其他对话
+    match recompress_chat(chat_dir) {
+      Ok(it) => saved += it,
+      Err(e) => failed.push(format!("{}: {e}", chat_dir.display())),
+    }
+  }
+  saved += BlobStore::new(app_data).recompress()?;
+  if !failed.is_empty() {
+    return Err(Error::new(format!(
+      "部分对话压缩失败，下次启动时重试\n{}",
+      failed.join("\n")
+    )));
+  }
+  atomic_write(&app_data.join(KEY_DIR).join(RECOMPRESSED_MARKER), b"")?;
+  Ok(saved)
+}
+
+/// 在后台线程中压缩全部数据，已经在执行时忽略
+fn spawn(app_data: PathBuf) {
+  if RUNNING.swap(true, Ordering::SeqCst) {
+    return;
+  }
+  std::thread::spawn(move || {
+    let total = chat_dirs(&app_data).map_or(0, |it| it.len());
+    let result = run(&app_data);
+    event(
+      RECOMPRESS_EVENT,
+      RecompressProgress {
+        done: total,
+        total,
+        saved_bytes: *result.as_ref().unwrap_or(&0),
+        finished: result.is_ok(),
+        error: result.err().map(|e| e.to_string()),
+      },
+    );
+    RUNNING.store(false, Ordering::SeqCst);
+  });
+}
+
+/// 旧版本的数据还没有压缩时在后台压缩，需要主密钥已解锁
+pub(super) fn resume(app_data: &Path) -> Result<()> {
+  let marker = app_data.join(KEY_DIR).join(RECOMPRESSED_MARKER);
+  if !marker.try_exists()? {
+    spawn(app_data.to_path_buf());
+  }
+  Ok(())
+}
+
+/// 在后台重新压缩全部对话和文件内容，进度通过事件发送
+pub async fn recompress_storage(app_data: &Path) -> Result<()> {
+  let app_data = app_data.to_path_buf();
+  spawn_blocking(move || {
+    current_master()?;
+    if RUNNING.load(Ordering::SeqCst) {
+      return Err(Error::new("正在压缩本地存储，请等待完成"));
+    }
+    spawn(app_data);
+    Ok(())
+  })
+  .await?
+}
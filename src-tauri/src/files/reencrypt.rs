@@ -0,0 +1,210 @@
+use super::blob_store::BlobStore;
+use super::chat_drafts::DRAFT_DIR_NAME;
+use super::chat_files::{FILE_DIR_NAME, migrate_legacy_files, repair_ref_counts};
+use super::chat_log::ChatLog;
+use super::chat_tree::{ChatTree, chat_lock};
+use super::key_store::{KEY_DIR, KeySet, chat_key_path, shared_key_path};
+use super::thumbnails::THUMBNAIL_DIR;
+use super::{SAVE_DIR, atomic_write, check_id, crypto, key_rotation, recompress};
+use crate::emitter::toaster;
+use crate::error::{Error, Result};
+use std::fs::{File, remove_file};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// 旧版本数据全部重新加密后写入的标记文件，位于 `keys/` 下
+const REENCRYPTED_MARKER: &str = "reencrypted";
+
+/// 使用 `keys` 重新加密旧版本以及其他密钥加密的文件，返回文件是否被修改
+fn reseal_file(keys: &KeySet, path: &Path) -> Result<bool> {
+  let sealed = std::fs::read(path)?;
+  if keys.is_current(&sealed) {
+    return Ok(false);
+  }
+  atomic_write(path, &keys.seal(&keys.open(&sealed)?)?)?;
+  Ok(true)
+}
+
+/// 文件是否使用当前数据密钥加密，只读取数据头
+pub(super) fn is_current_file(keys: &KeySet, path: &Path) -> Result<bool> {
+  let mut header = Vec::with_capacity(crypto::HEADER_LEN);
+  File::open(path)?
+    .take(crypto::HEADER_LEN as u64)
+    .read_to_end(&mut header)?;
+  Ok(keys.is_current(&header))
+}
+
+/// 文件夹中指定扩展名的文件，`recursive` 时包括一层子文件夹
+pub(super) fn files_in(dir: &Path, extensions: &[&str], recursive: bool) -> Result<Vec<PathBuf>> {
+  let mut files = Vec::new();
+  if !dir.is_dir() {
+    return Ok(files);
+  }
+  for entry in std::fs::read_dir(dir)? {
+    let path = entry?.path();
+    if recursive && path.is_dir() {
+      files.extend(files_in(&path, extensions, false)?);
+      continue;
+    }
+    let matched = path
+      .extension()
+      .and_then(|it| it.to_str())
+      .is_some_and(|it| extensions.contains(&it));
+    if matched {
+      files.push(path);
+    }
+  }
+  Ok(files)
+}
+
+/// 重新加密文件夹中指定扩展名的文件
+fn reseal_dir(keys: &KeySet, dir: &Path, extensions: &[&str], recursive: bool) -> Result<()> {
+  for path in files_in(dir, extensions, recursive)? {
+    reseal_file(keys, &path)?;
+  }
+  Ok(())
+}
+
+/// 重新加密缓存文件，无法解密的缓存直接删除，使用时会重新生成
+fn reseal_cache_dir(keys: &KeySet, dir: &Path, extensions: &[&str], recursive: bool) -> Result<()> {
+  for path in files_in(dir, extensions, recursive)? {
+    if reseal_file(keys, &path).is_err() {
+      remove_file(&path)?;
+    }
+  }
+  Ok(())
+}
+
+/// 删除没有使用当前数据密钥加密的缓存文件
+fn remove_stale_caches(
+  keys: &KeySet,
+  dir: &Path,
+  extensions: &[&str],
+  recursive: bool,
+) -> Result<()> {
+  for path in files_in(dir, extensions, recursive)? {
+    if !is_current_file(keys, &path)? {
+      remove_file(&path)?;
+    }
+  }
+  Ok(())
+}
+
+/// 使用当前数据密钥重新加密单个对话：日志记录、草稿以及提取文本的缓存
+///
+/// 调用时需要持有 [`chat_lock`]
+pub(super) fn reseal_chat(chat_dir: &Path) -> Result<()> {
+  // 打开对话时会重写旧版本加密的日志
+  let tree = ChatTree::open(chat_dir)?;
+  if ChatLog::open(chat_dir)?.stale_records()? > 0 {
+    tree.rewrite(|_| true)?;
+  }
+  let keys = KeySet::load_or_create(&chat_key_path(chat_dir))?;
+  reseal_dir(&keys, &chat_dir.join(DRAFT_DIR_NAME), &["draft"], false)?;
+  reseal_cache_dir(&keys, &chat_dir.join(FILE_DIR_NAME), &["text"], false)
+}
+
+/// 确认对话的全部内容都使用当前数据密钥加密并且可以解密，删除其他密钥加密的缓存
+///
+/// 调用时需要持有 [`chat_lock`]
+pub(super) fn verify_chat(chat_dir: &Path) -> Result<()> {
+  let tree = ChatTree::load(chat_dir)?;
+  let stale = match ChatLog::exists(chat_dir) {
+    true => ChatLog::open(chat_dir)?.stale_records()?,
+    false => tree.nodes().len(),
+  };
+  if stale > 0 {
+    return Err(Error::new("存在未重新加密的对话记录"));
+  }
+  let nodes = tree.nodes().iter().collect::<Vec<_>>();
+  tree.read(&nodes)?;
+
+  let keys = KeySet::load(&chat_key_path(chat_dir))?;
+  for path in files_in(&chat_dir.join(DRAFT_DIR_NAME), &["draft"], false)? {
+    let sealed = std::fs::read(&path)?;
+    if !keys.is_current(&sealed) {
+      return Err(Error::new("存在未重新加密的草稿"));
+    }
+    keys.open(&sealed)?;
+  }
+  remove_stale_caches(&keys, &chat_dir.join(FILE_DIR_NAME), &["text"], false)
+}
+
+/// 使用当前的共享数据密钥重新加密文件内容以及缩略图
+pub(super) fn reseal_shared(app_data: &Path) -> Result<()> {
+  BlobStore::new(app_data).reseal()?;
+  let shared = KeySet::load_or_create(&shared_key_path(app_data))?;
+  reseal_cache_dir(
+    &shared,
+    &app_data.join(THUMBNAIL_DIR),
+    &["jpg", "webp"],
+    true,
+  )
+}
+
+/// 确认文件内容都使用当前的共享数据密钥加密，删除其他密钥加密的缩略图
+pub(super) fn verify_shared(app_data: &Path) -> Result<()> {
+  BlobStore::new(app_data).verify()?;
+  let shared = KeySet::load(&shared_key_path(app_data))?;
+  remove_stale_caches(
+    &shared,
+    &app_data.join(THUMBNAIL_DIR),
+    &["jpg", "webp"],
+    true,
+  )
+}
+
+/// 对话文件夹，不包括暂存区等隐藏文件夹
+pub(super) fn chat_dirs(app_data: &Path) -> Result<Vec<PathBuf>> {
+  let mut dirs = Vec::new();
+  let root = app_data.join(SAVE_DIR);
+  if !root.is_dir() {
+    return Ok(dirs);
+  }
+  for entry in std::fs::read_dir(&root)? {
+    let path = entry?.path();
+    let name = path.file_name().map(|it| it.to_string_lossy().to_string());
+    if path.is_dir() && name.is_some_and(|it| check_id("对话", &it).is_ok()) {
+      dirs.push(path);
+    }
+  }
+  Ok(dirs)
+}
+
+/// 将旧版本使用公开密码加密的数据重新加密为对话以及共享的数据密钥
+///
+/// 单个对话迁移失败时跳过，旧数据仍然可以读取，下次启动时重试
+fn migrate_all(app_data: &Path) -> Result<bool> {
+  let mut completed = true;
+  for chat_dir in chat_dirs(app_data)? {
+    let _guard = chat_lock(&chat_dir);
+    completed &= reseal_chat(&chat_dir).is_ok();
+  }
+  reseal_shared(app_data)?;
+  Ok(completed)
+}
+
+/// 主密钥可用后执行的迁移，启动时或使用密码解锁后调用
+pub(super) fn start(app_data: &Path) -> Result<()> {
+  let failed = migrate_legacy_files(app_data)?;
+  if !failed.is_empty() {
+    toaster::warning(
+      "部分旧版本文件迁移失败，下次启动时重试",
+      Some(&failed.join("\n")),
+    );
+  }
+  // 修正上次运行中断时留下的引用计数偏差
+  repair_ref_counts(app_data)?;
+
+  let marker = app_data.join(KEY_DIR).join(REENCRYPTED_MARKER);
+  if !marker.try_exists()? && migrate_all(app_data)? {
+    atomic_write(&marker, b"")?;
+  }
+  // 继续上次中断的密钥轮换
+  key_rotation::resume(app_data)?;
+  // 旧版本的数据全部迁移后再压缩，避免同一份数据重写两次
+  if marker.try_exists()? {
+    recompress::resume(app_data)?;
+  }
+  Ok(())
+}
@@ -0,0 +1,443 @@
+use crate::error::{Error, FetchError, Result};
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs};
+use std::time::Duration;
+use tauri::async_runtime::spawn_blocking;
+use tauri_plugin_http::reqwest::header::{CONTENT_LENGTH, CONTENT_TYPE, LOCATION};
+use tauri_plugin_http::reqwest::{self, Client, Response, Url, redirect};
+
+/// 获取策略在设置中的名称
+pub const FETCH_POLICY_KEY: &str = "fetch_policy";
+
+/// 远程获取文件的限制
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct FetchPolicy {
+  /// 建立连接的超时时间，秒
+  pub connect_timeout_secs: u64,
+  /// 单次请求的超时时间（包括读取内容），秒
+  pub timeout_secs: u64,
+  /// 内容大小上限，字节
+  pub max_bytes: u64,
+  /// 最多跟随的重定向次数
+  pub max_redirects: usize,
+  /// 允许的协议
+  pub allowed_schemes: Vec<String>,
+  /// 允许的内容类型前缀，如 `image/`，为空时不限制
+  pub allowed_content_types: Vec<String>,
+  /// 是否禁止访问本机及局域网地址
+  pub block_private_network: bool,
+}
+
+impl Default for FetchPolicy {
+  fn default() -> Self {
+    Self {
+      connect_timeout_secs: 10,
+      timeout_secs: 60,
+      max_bytes: 50 << 20,
+      max_redirects: 5,
+      allowed_schemes: vec!["http".to_string(), "https".to_string()],
+      allowed_content_types: Vec::new(),
+      block_private_network: true,
+    }
+  }
+}
+
+/// 远程获取的文件内容
+pub struct FetchedFile {
+  pub data: Vec<u8>,
+  /// 重定向之后的最终地址
+  pub url: Url,
+  pub content_type: Option<String>,
+}
+
+fn is_blocked_v4(ip: Ipv4Addr) -> bool {
+  let [a, b, ..] = ip.octets();
+  ip.is_loopback()
+    || ip.is_private()
+    || ip.is_link_local()
+    || ip.is_unspecified()
+    || ip.is_broadcast()
+    || ip.is_multicast()
+    // 0.0.0.0/8 与运营商级 NAT 100.64.0.0/10
+    || a == 0
+    || (a == 100 && (b & 0xc0) == 64)
+}
+
+/// 是否为本机、局域网或其他非公网地址
+fn is_blocked_ip(ip: IpAddr) -> bool {
+  match ip {
+    IpAddr::V4(ip) => is_blocked_v4(ip),
+    IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+      Some(ip) => is_blocked_v4(ip),
+      None => {
+        let first = ip.segments()[0];
+        ip.is_loopback()
+          || ip.is_unspecified()
+          || ip.is_multicast()
+          // 唯一本地地址 fc00::/7 与链路本地地址 fe80::/10
+          || (first & 0xfe00) == 0xfc00
+          || (first & 0xffc0) == 0xfe80
+      }
+    },
+  }
+}
+
+fn map_request_err(e: reqwest::Error) -> Error {
+  match e.is_timeout() {
+    true => FetchError::Timeout.into(),
+    false => e.into(),
+  }
+}
+
+impl FetchPolicy {
+  fn check_scheme(&self, url: &Url) -> Result<()> {
+    let scheme = url.scheme();
+    if !self
+      .allowed_schemes
+      .iter()
+      .any(|it| it.eq_ignore_ascii_case(scheme))
+    {
+      return Err(FetchError::SchemeNotAllowed(scheme.to_string()).into());
+    }
+    Ok(())
+  }
+
+  /// 检查内容类型是否在允许的范围内
+  pub(super) fn check_content_type(&self, actual: &str) -> Result<()> {
+    if self.allowed_content_types.is_empty() {
+      return Ok(());
+    }
+    let mut allowed = self.allowed_content_types.iter();
+    if !allowed.any(|it| actual.starts_with(it.as_str())) {
+      return Err(FetchError::ContentType(actual.to_string()).into());
+    }
+    Ok(())
+  }
+
+  /// 为指定地址创建请求客户端
+  ///
+  /// 域名在这里解析并检查，客户端固定使用检查过的地址，避免请求时再次解析到其他地址
+  async fn client_for(&self, url: &Url) -> Result<Client> {
+    let invalid = || FetchError::InvalidUrl(url.to_string());
+    let host = url.host_str().ok_or_else(invalid)?;
+    let port = url.port_or_known_default().ok_or_else(invalid)?;
+
+    let mut builder = Client::builder()
+      .redirect(redirect::Policy::none())
+      .connect_timeout(Duration::from_secs(self.connect_timeout_secs))
+      .timeout(Duration::from_secs(self.timeout_secs));
+
+    // IPv6 地址带有方括号
+    let name = host
+      .trim_start_matches('[')
+      .trim_end_matches(']')
+      .to_string();
+    let addrs = match name.parse::<IpAddr>() {
+      Ok(ip) => vec![SocketAddr::new(ip, port)],
+      Err(_) => {
+        let lookup = name.clone();
+        let addrs = spawn_blocking(move || (lookup.as_str(), port).to_socket_addrs())
+          .await?
+          .map_err(|_| FetchError::Resolve(name.clone()))?
+          .collect::<Vec<_>>();
+        if addrs.is_empty() {
+          return Err(FetchError::Resolve(name).into());
+        }
+        builder = builder.resolve_to_addrs(&name, &addrs);
+        addrs
+      }
+    };
+
+    if self.block_private_network {
+      if addrs.iter().any(|it| is_blocked_ip(it.ip())) {
+        return Err(FetchError::BlockedAddress(name).into());
+      }
+      // 代理会绕过上面的地址检查
+      builder = builder.no_proxy();
+    }
+    Ok(builder.build()?)
+  }
+
+  /// 发送请求并逐个检查重定向目标
+  async fn send(&self, url: &str) -> Result<Response> {
+    let mut url = Url::parse(url).map_err(|_| FetchError::InvalidUrl(url.to_string()))?;
+    let mut redirects = 0;
+    loop {
+      self.check_scheme(&url)?;
+      let client = self.client_for(&url).await?;
+      let response = client
+        .get(url.clone())
+        .send()
+        .await
+        .map_err(map_request_err)?;
+
+      let location = response
+        .headers()
+        .get(LOCATION)
+        .and_then(|it| it.to_str().ok());
+      let location = match (response.status().is_redirection(), location) {
+        (true, Some(it)) => it,
+        _ => return Ok(response),
+      };
+      if redirects >= self.max_redirects {
+        return Err(FetchError::TooManyRedirects(self.max_redirects).into());
+      }
+      redirects += 1;
+      url = url
+        .join(location)
+        .map_err(|_| FetchError::InvalidUrl(location.to_string()))?;
+    }
+  }
+
+  /// 按策略获取远程文件，内容超过上限时立即中止
+  pub async fn fetch(&self, url: &str) -> Result<FetchedFile> {
+    let mut response = self.send(url).await?;
+    let status = response.status();
+    if !status.is_success() {
+      return Err(FetchError::Status(status.as_u16()).into());
+    }
+
+    let content_type = response
+      .headers()
+      .get(CONTENT_TYPE)
+      .and_then(|it| it.to_str().ok())
+      .map(str::to_string);
+    self.check_content_type(content_type.as_deref().unwrap_or_default())?;
+
+    let content_length = response
+      .headers()
+      .get(CONTENT_LENGTH)
+      .and_then(|it| it.to_str().ok())
+      .and_then(|it| it.parse::<u64>().ok());
+    if content_length.is_some_and(|it| it > self.max_bytes) {
+      return Err(FetchError::TooLarge(self.max_bytes).into());
+    }
+
+    let mut data = Vec::with_capacity(content_length.unwrap_or_default() as usize);
+    while let Some(chunk) = response.chunk().await.map_err(map_request_err)? {
+      if (data.len() + chunk.len()) as u64 > self.max_bytes {
+        return Err(FetchError::TooLarge(self.max_bytes).into());
+      }
+      data.extend_from_slice(&chunk);
+    }
+
+    let url = response.url().clone();
+    Ok(FetchedFile {
+      data,
+      url,
+      content_type,
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io::{BufRead, BufReader, Write};
+  use std::net::TcpListener;
+  use tauri::async_runtime::block_on;
+
+  /// 本机的测试服务，`respond` 按请求路径返回完整的响应内容
+  fn serve(respond: impl Fn(&str) -> Vec<u8> + Send + 'static) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let base = format!("http://{}", listener.local_addr().unwrap());
+    std::thread::spawn(move || {
+      for stream in listener.incoming() {
+        let Ok(mut stream) = stream else {
+          continue;
+        };
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut request = String::new();
+        reader.read_line(&mut request).unwrap_or_default();
+        // 跳过请求头
+        let mut line = String::new();
+        while reader.read_line(&mut line).unwrap_or_default() > 2 {
+          line.clear();
+        }
+        let path = request.split_whitespace().nth(1).unwrap_or("/");
+        let _ = stream.write_all(&respond(path));
+      }
+    });
+    base
+  }
+
+  fn response(status: &str, headers: &[(&str, &str)], body: &[u8]) -> Vec<u8> {
+    let mut data = format!("HTTP/1.1 {status}\r\nContent-Length: {}\r\n", body.len());
+    for (name, value) in headers {
+      data.push_str(&format!("{name}: {value}\r\n"));
+    }
+    data.push_str("\r\n");
+    let mut data = data.into_bytes();
+    data.extend_from_slice(body);
+    data
+  }
+
+  fn redirect(location: &str) -> Vec<u8> {
+    response("302 Found", &[("Location", location)], b"")
+  }
+
+  /// 测试服务在本机上，除检查地址的测试外都需要允许访问本机
+  fn local_policy() -> FetchPolicy {
+    FetchPolicy {
+      block_private_network: false,
+      ..Default::default()
+    }
+  }
+
+  fn fetch_err(policy: &FetchPolicy, url: &str) -> FetchError {
+    match block_on(policy.fetch(url)) {
+      Err(Error::Fetch(e)) => e,
+      Err(e) => panic!("意外的错误: {e}"),
+      Ok(_) => panic!("请求应当失败: {url}"),
+    }
+  }
+
+  #[test]
+  fn follows_redirects_within_limit() {
+    let base = serve(|path| match path.strip_prefix("/hop/") {
+      Some("0") => response("200 OK", &[("Content-Type", "text/plain")], b"done"),
+      Some(n) => redirect(&format!("/hop/{}", n.parse::<u32>().unwrap() - 1)),
+      None => response("404 Not Found", &[], b""),
+    });
+    let policy = FetchPolicy {
+      max_redirects: 3,
+      ..local_policy()
+    };
+
+    let fetched = block_on(policy.fetch(&format!("{base}/hop/3"))).unwrap();
+    assert_eq!(fetched.data, b"done");
+    assert_eq!(fetched.url.path(), "/hop/0");
+    assert_eq!(fetched.content_type.as_deref(), Some("text/plain"));
+
+    let e = fetch_err(&policy, &format!("{base}/hop/4"));
+    assert!(matches!(e, FetchError::TooManyRedirects(3)), "{e}");
+  }
+
+  #[test]
+  fn rejects_redirect_to_other_scheme() {
+    let base = serve(|path| match path {
+      "/ftp" => redirect("ftp://example.com/file"),
+      "/https" => redirect("https://example.com/file"),
+      _ => response("200 OK", &[], b"ok"),
+    });
+
+    let e = fetch_err(&local_policy(), &format!("{base}/ftp"));
+    assert!(
+      matches!(e, FetchError::SchemeNotAllowed(ref it) if it == "ftp"),
+      "{e}"
+    );
+
+    let http_only = FetchPolicy {
+      allowed_schemes: vec!["http".to_string()],
+      ..local_policy()
+    };
+    let e = fetch_err(&http_only, &format!("{base}/https"));
+    assert!(
+      matches!(e, FetchError::SchemeNotAllowed(ref it) if it == "https"),
+      "{e}"
+    );
+  }
+
+  #[test]
+  fn rejects_declared_oversize_body() {
+    let base = serve(|_| response("200 OK", &[], &[0; 64]));
+    let policy = FetchPolicy {
+      max_bytes: 16,
+      ..local_policy()
+    };
+    let e = fetch_err(&policy, &format!("{base}/"));
+    assert!(matches!(e, FetchError::TooLarge(16)), "{e}");
+
+    let exact = FetchPolicy {
+      max_bytes: 64,
+      ..local_policy()
+    };
+    assert_eq!(block_on(exact.fetch(&base)).unwrap().data.len(), 64);
+  }
+
+  #[test]
+  fn rejects_streamed_oversize_body() {
+    // 没有 Content-Length，只能在读取时计数
+    let base = serve(|_| {
+      let mut data = b"HTTP/1.1 200 OK\r\nConnection: close\r\n\r\n".to_vec();
+      data.extend_from_slice(&[0; 64]);
+      data
+    });
+    let policy = FetchPolicy {
+      max_bytes: 16,
+      ..local_policy()
+    };
+    let e = fetch_err(&policy, &format!("{base}/"));
+    assert!(matches!(e, FetchError::TooLarge(16)), "{e}");
+  }
+
+  #[test]
+  fn times_out_slow_response() {
+    let base = serve(|_| {
+      std::thread::sleep(Duration::from_secs(3));
+      response("200 OK", &[], b"late")
+    });
+    let policy = FetchPolicy {
+      timeout_secs: 1,
+      ..local_policy()
+    };
+    let e = fetch_err(&policy, &format!("{base}/"));
+    assert!(matches!(e, FetchError::Timeout), "{e}");
+  }
+
+  #[test]
+  fn filters_content_type() {
+    let base = serve(|path| match path {
+      "/image" => response("200 OK", &[("Content-Type", "image/png")], b"png"),
+      _ => response("200 OK", &[("Content-Type", "text/html")], b"<html>"),
+    });
+    let policy = FetchPolicy {
+      allowed_content_types: vec!["image/".to_string()],
+      ..local_policy()
+    };
+
+    let fetched = block_on(policy.fetch(&format!("{base}/image"))).unwrap();
+    assert_eq!(fetched.data, b"png");
+    let e = fetch_err(&policy, &format!("{base}/page"));
+    assert!(
+      matches!(e, FetchError::ContentType(ref it) if it == "text/html"),
+      "{e}"
+    );
+  }
+
+  #[test]
+  fn blocks_loopback_by_default() {
+    let base = serve(|_| response("200 OK", &[], b"secret"));
+    let port = base.rsplit(':').next().unwrap();
+    let policy = FetchPolicy::default();
+
+    let e = fetch_err(&policy, &base);
+    assert!(matches!(e, FetchError::BlockedAddress(_)), "{e}");
+    // 域名解析到本机时同样禁止
+    let e = fetch_err(&policy, &format!("http://localhost:{port}/"));
+    assert!(matches!(e, FetchError::BlockedAddress(_)), "{e}");
+  }
+
+  #[test]
+  fn classifies_private_addresses() {
+    for ip in [
+      "127.0.0.1",
+      "10.1.2.3",
+      "172.16.0.1",
+      "192.168.1.1",
+      "169.254.0.1",
+      "100.64.0.1",
+      "0.1.2.3",
+      "::1",
+      "fd00::1",
+      "fe80::1",
+      "::ffff:127.0.0.1",
+    ] {
+      assert!(is_blocked_ip(ip.parse().unwrap()), "{ip}");
+    }
+    for ip in ["1.1.1.1", "100.128.0.1", "2606:4700::1111"] {
+      assert!(!is_blocked_ip(ip.parse().unwrap()), "{ip}");
+    }
+  }
+}
@@ -0,0 +1,441 @@
+use super::blob_store::BlobStore;
+use super::chat_drafts::{DRAFT_DIR_NAME, read_draft};
+use super::chat_files::{
+  FILE_DIR_NAME, read_legacy_file, read_ref, ref_ids, ref_path, release_ref,
+};
+use super::chat_log::{ChatLog, LOG_DIR};
+use super::chat_messages::read_from_disk;
+use super::chat_tree::{ChatTree, NodeMeta, chat_lock};
+use super::key_store::{KeySet, chat_key_path, current_master, notes_key_path, shared_key_path};
+use super::reencrypt::{chat_dirs, files_in};
+use crate::database::{ChangeAction, ChangeEntity, DatabaseHandler};
+use crate::error::{Error, Result};
+use crate::sync::request_sync;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs::{File, create_dir_all, rename};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tauri::async_runtime::spawn_blocking;
+use zip::ZipArchive;
+
+/// 隔离区，修复时将无法读取的文件移动到 `quarantine/<时间戳>/` 下，保持原有的相对路径
+const QUARANTINE_DIR: &str = "quarantine";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum IssueKind {
+  /// 数据密钥无法解密
+  KeyFile,
+  /// 旧版本的消息文件无法打开、解密或解析
+  BrokenMessage,
+  /// 旧版本的消息文件名无法解析为 index
+  BadFileName,
+  /// 消息文件注释中的 id 与文件名或消息内容不一致
+  CommentMismatch,
+  /// 消息的 index 不连续，或父消息不存在
+  IndexGap,
+  /// 对话日志无法打开
+  BrokenLog,
+  /// 对话日志中的记录无法解密或解析
+  BrokenRecord,
+  /// 草稿无法解密
+  BrokenDraft,
+  /// 文件引用无法解析，或引用的内容不存在、无法解密
+  BrokenFile,
+  /// 数据库完整性检查失败
+  Database,
+}
+
+/// 检查发现的问题
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StorageIssue {
+  kind: IssueKind,
+  chat_id: Option<String>,
+  /// 相对于应用数据文件夹的路径
+  path: Option<String>,
+  message: String,
+  /// 是否可以通过隔离或重写修复
+  repairable: bool,
+  repaired: bool,
+}
+
+/// 检查结果，修复时同时记录每个问题是否已修复
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StorageReport {
+  repair: bool,
+  checked_chats: usize,
+  issues: Vec<StorageIssue>,
+  /// 本次修复使用的隔离文件夹
+  quarantine: Option<String>,
+}
+
+/// 单次检查的上下文
+struct Checker {
+  app_data: PathBuf,
+  repair: bool,
+  quarantine: PathBuf,
+  report: StorageReport,
+}
+
+impl Checker {
+  fn relative(&self, path: &Path) -> String {
+    path
+      .strip_prefix(&self.app_data)
+      .unwrap_or(path)
+      .to_string_lossy()
+      .to_string()
+  }
+
+  fn push(&mut self, kind: IssueKind, chat_id: &str, path: Option<&Path>, error: &Error) {
+    let repairable = !matches!(kind, IssueKind::KeyFile | IssueKind::IndexGap);
+    self.report.issues.push(StorageIssue {
+      kind,
+      chat_id: (!chat_id.is_empty()).then(|| chat_id.to_string()),
+      path: path.map(|it| self.relative(it)),
+      message: error.to_string(),
+      repairable,
+      repaired: false,
+    });
+  }
+
+  fn mark_repaired(&mut self, from: usize) {
+    for issue in &mut self.report.issues[from..] {
+      issue.repaired = issue.repairable;
+    }
+  }
+
+  /// 将文件或文件夹移动到隔离区
+  fn quarantine(&mut self, path: &Path) -> Result<()> {
+    let target = self.quarantine.join(self.relative(path));
+    if let Some(parent) = target.parent() {
+      create_dir_all(parent)?;
+    }
+    rename(path, target)?;
+    self.report.quarantine = Some(self.relative(&self.quarantine));
+    Ok(())
+  }
+
+  /// 复制单个文件到隔离区，用于删除前保留原始数据
+  fn backup_file(&mut self, path: &Path) -> Result<()> {
+    let target = self.quarantine.join(self.relative(path));
+    if let Some(parent) = target.parent() {
+      create_dir_all(parent)?;
+    }
+    std::fs::copy(path, target)?;
+    self.report.quarantine = Some(self.relative(&self.quarantine));
+    Ok(())
+  }
+
+  /// 复制文件夹到隔离区，用于重写前保留原始数据
+  fn backup(&mut self, dir: &Path) -> Result<()> {
+    let target = self.quarantine.join(self.relative(dir));
+    create_dir_all(&target)?;
+    for entry in std::fs::read_dir(dir)? {
+      let path = entry?.path();
+      if path.is_file() {
+        std::fs::copy(&path, target.join(path.file_name().unwrap_or_default()))?;
+      }
+    }
+    self.report.quarantine = Some(self.relative(&self.quarantine));
+    Ok(())
+  }
+
+  /// 检查并修复单个文件，`check` 失败时记录问题，修复时移动到隔离区
+  fn check_file(
+    &mut self,
+    kind: IssueKind,
+    chat_id: &str,
+    path: &Path,
+    check: impl FnOnce() -> Result<()>,
+  ) -> Result<bool> {
+    let Err(e) = check() else {
+      return Ok(true);
+    };
+    let from = self.report.issues.len();
+    self.push(kind, chat_id, Some(path), &e);
+    if self.repair {
+      self.quarantine(path)?;
+      self.mark_repaired(from);
+    }
+    Ok(false)
+  }
+
+  fn check_chat(&mut self, chat_dir: &Path) -> Result<()> {
+    let chat_id = chat_dir
+      .file_name()
+      .map(|it| it.to_string_lossy().to_string())
+      .unwrap_or_default();
+    let _guard = chat_lock(chat_dir);
+
+    let key_path = chat_key_path(chat_dir);
+    let keys = match KeySet::load(&key_path) {
+      Ok(it) => it,
+      Err(e) => {
+        // 密钥无法使用时其余内容都无法解密，不再继续检查
+        self.push(IssueKind::KeyFile, &chat_id, Some(&key_path), &e);
+        return Ok(());
+      }
+    };
+
+    self.check_legacy_messages(&chat_id, chat_dir)?;
+    if ChatLog::exists(chat_dir) {
+      self.check_log(&chat_id, chat_dir)?;
+    }
+
+    for path in files_in(&chat_dir.join(DRAFT_DIR_NAME), &["draft"], false)? {
+      self.check_file(IssueKind::BrokenDraft, &chat_id, &path, || {
+        read_draft(&keys, &path).map(|_| ())
+      })?;
+    }
+
+    let store = BlobStore::new(&self.app_data);
+    let files_dir = chat_dir.join(FILE_DIR_NAME);
+    for file_id in ref_ids(&files_dir)? {
+      self.check_ref(&chat_id, &store, &files_dir, &file_id)?;
+    }
+    // 迁移失败而保留下来的旧版本文件
+    for path in files_in(&files_dir, &["file"], false)? {
+      let file_id = path.file_stem().unwrap_or_default().to_string_lossy();
+      self.check_file(IssueKind::BrokenFile, &chat_id, &path, || {
+        read_legacy_file(&files_dir, &file_id).map(|_| ())
+      })?;
+    }
+    Ok(())
+  }
+
+  /// 文件引用以及引用的内容能否读取，修复时保留引用的副本并释放内容的引用计数
+  fn check_ref(
+    &mut self,
+    chat_id: &str,
+    store: &BlobStore,
+    files_dir: &Path,
+    file_id: &str,
+  ) -> Result<()> {
+    let path = ref_path(files_dir, file_id);
+    let file_ref = match read_ref(files_dir, file_id) {
+      Ok(Some(it)) => it,
+      Ok(None) => return Ok(()),
+      // 引用无法解析时不知道对应的内容，隔离后由下次启动时的重新计数修正
+      Err(e) => {
+        return self
+          .check_file(IssueKind::BrokenFile, chat_id, &path, || Err(e))
+          .map(|_| ());
+      }
+    };
+    let Err(e) = store.read(&file_ref.blob) else {
+      return Ok(());
+    };
+
+    let from = self.report.issues.len();
+    self.push(IssueKind::BrokenFile, chat_id, Some(&path), &e);
+    if self.repair {
+      self.backup_file(&path)?;
+      release_ref(store, files_dir, file_id)?;
+      self.mark_repaired(from);
+    }
+    Ok(())
+  }
+
+  /// 旧版本的消息文件：能否打开并解密，注释中的 id 是否与文件名和内容一致，index 是否连续
+  fn check_legacy_messages(&mut self, chat_id: &str, chat_dir: &Path) -> Result<()> {
+    let mut indices = BTreeSet::new();
+    for path in files_in(chat_dir, &["message"], false)? {
+      let name = path
+        .file_stem()
+        .map(|it| it.to_string_lossy().to_string())
+        .unwrap_or_default();
+      let (index, name_id) = match name.split_once('-') {
+        Some((index, id)) => (index, Some(id)),
+        None => (name.as_str(), None),
+      };
+      let Ok(index) = index.parse::<u32>() else {
+        let e = Error::new(format!("文件名解析失败: {name}"));
+        self.check_file(IssueKind::BadFileName, chat_id, &path, || Err(e))?;
+        continue;
+      };
+
+      let mut comment_id = None;
+      let readable = self.check_file(IssueKind::BrokenMessage, chat_id, &path, || {
+        let archive = ZipArchive::new(File::open(&path)?)?;
+        let (meta, _) = NodeMeta::from_comment(archive.comment())?;
+        let message = read_from_disk(&path)?;
+        comment_id = Some((meta.id, message));
+        Ok(())
+      })?;
+      let Some((id, message)) = comment_id.filter(|_| readable) else {
+        continue;
+      };
+      let message_id = message.get("id").and_then(Value::as_str);
+      let matched = name_id.is_none_or(|it| it == id) && message_id.is_none_or(|it| it == id);
+      if !matched {
+        let e = Error::new(format!(
+          "消息文件注释中的 id（{id}）与文件名或消息内容不一致"
+        ));
+        self.check_file(IssueKind::CommentMismatch, chat_id, &path, || Err(e))?;
+        continue;
+      }
+      indices.insert(index);
+    }
+
+    if let Some(missing) = (0..indices.len() as u32).find(|it| !indices.contains(it)) {
+      let e = Error::new(format!("消息 index 不连续，缺少 {missing}"));
+      self.push(IssueKind::IndexGap, chat_id, Some(chat_dir), &e);
+    }
+    Ok(())
+  }
+
+  /// 对话日志：每条记录能否解密，父消息是否存在以及 index 是否连续
+  fn check_log(&mut self, chat_id: &str, chat_dir: &Path) -> Result<()> {
+    let tree = match ChatTree::load(chat_dir) {
+      Ok(it) => it,
+      Err(e) => {
+        let log_dir = chat_dir.join(LOG_DIR);
+        return self
+          .check_file(IssueKind::BrokenLog, chat_id, &log_dir, || Err(e))
+          .map(|_| ());
+      }
+    };
+
+    let from = self.report.issues.len();
+    let mut broken = HashSet::new();
+    for node in tree.nodes() {
+      if let Err(e) = tree.read(&[node]) {
+        let e = Error::new(format!("消息 {}: {e}", node.meta.id));
+        self.push(
+          IssueKind::BrokenRecord,
+          chat_id,
+          Some(&chat_dir.join(LOG_DIR)),
+          &e,
+        );
+        broken.insert(node.meta.id.clone());
+      }
+    }
+
+    // 记录头损坏后无法定位的内容，以及不属于任何消息的损坏记录
+    let lost = tree.lost_records()?;
+    for pos in &lost {
+      let e = Error::new(format!(
+        "分段 {} 偏移 {} 处长度为 {} 的内容无法读取",
+        pos.seg, pos.off, pos.len
+      ));
+      self.push(
+        IssueKind::BrokenRecord,
+        chat_id,
+        Some(&chat_dir.join(LOG_DIR)),
+        &e,
+      );
+    }
+
+    let indices: HashMap<&str, u32> = tree
+      .nodes()
+      .iter()
+      .map(|it| (it.meta.id.as_str(), it.index))
+      .collect();
+    for node in tree.nodes() {
+      let expected = match node.meta.parent.as_deref() {
+        None => Some(0),
+        Some(parent) => indices.get(parent).map(|it| it + 1),
+      };
+      let message = match expected {
+        Some(it) if it == node.index => continue,
+        Some(it) => format!(
+          "消息 {} 的 index 为 {}，应为 {it}",
+          node.meta.id, node.index
+        ),
+        None => format!("消息 {} 的父消息不存在", node.meta.id),
+      };
+      self.push(IssueKind::IndexGap, chat_id, None, &Error::new(message));
+    }
+
+    // 先备份原始日志，再重写为只包含可以读取的记录
+    if self.repair && !(broken.is_empty() && lost.is_empty()) {
+      self.backup(&chat_dir.join(LOG_DIR))?;
+      tree.rewrite(|it| !broken.contains(&it.meta.id))?;
+      self.mark_repaired(from);
+    }
+    Ok(())
+  }
+
+  /// 全部对话共用的数据密钥
+  fn check_keys(&mut self) {
+    for path in [
+      shared_key_path(&self.app_data),
+      notes_key_path(&self.app_data),
+    ] {
+      if let Err(e) = KeySet::load(&path) {
+        self.push(IssueKind::KeyFile, "", Some(&path), &e);
+      }
+    }
+  }
+}
+
+fn check_blocking(app_data: PathBuf, repair: bool) -> Result<StorageReport> {
+  // 存储锁定时全部密钥都无法读取，直接返回而不是逐个报告为密钥问题
+  current_master()?;
+  let millis = SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|it| it.as_millis())
+    .unwrap_or_default();
+  let mut checker = Checker {
+    quarantine: app_data.join(QUARANTINE_DIR).join(millis.to_string()),
+    app_data,
+    repair,
+    report: StorageReport {
+      repair,
+      ..Default::default()
+    },
+  };
+
+  checker.check_keys();
+  for chat_dir in chat_dirs(&checker.app_data)? {
+    checker.check_chat(&chat_dir)?;
+    checker.report.checked_chats += 1;
+  }
+  Ok(checker.report)
+}
+
+/// 检查本地存储：对话中的消息、日志、草稿和文件能否读取，以及数据库的完整性
+///
+/// `repair` 时将无法读取的文件移动到隔离区，并重写包含损坏记录的对话日志，
+/// 其余内容可以继续正常读取；密钥、index 不连续以及数据库的问题只报告不修复
+pub async fn check_storage(
+  app_data: &Path,
+  database: &DatabaseHandler,
+  repair: bool,
+) -> Result<StorageReport> {
+  let path = app_data.to_path_buf();
+  let mut report = spawn_blocking(move || check_blocking(path, repair)).await??;
+
+  // 修复过的对话重建搜索索引，并记录修改以便同步
+  let repaired = report
+    .issues
+    .iter()
+    .filter(|it| it.repaired)
+    .filter_map(|it| it.chat_id.clone())
+    .collect::<BTreeSet<_>>();
+  for chat_id in &repaired {
+    database.remove_chat_index(chat_id, 0).await?;
+    database.index_chat(app_data, chat_id).await?;
+    database
+      .log_change(ChangeEntity::Chat, chat_id, None, ChangeAction::Update)
+      .await?;
+  }
+  if !repaired.is_empty() {
+    request_sync();
+  }
+  for message in database.integrity_check().await? {
+    report.issues.push(StorageIssue {
+      kind: IssueKind::Database,
+      chat_id: None,
+      path: Some("data.sqlite".to_string()),
+      message,
+      repairable: false,
+      repaired: false,
+    });
+  }
+  Ok(report)
+}
@@ -0,0 +1,38 @@
+use super::crypto::{self, Key};
+use super::key_store::PassphraseParams;
+use crate::error::Result;
+use serde::{Deserialize, Serialize};
+
+/// 同步密码的派生参数，以 json 保存在存储桶中，所有设备使用同一份参数
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct SyncKeyParams(PassphraseParams);
+
+/// 由同步密码派生的密钥，上传的内容全部使用该密钥加密
+///
+/// 本地数据密钥每台设备各不相同，不能直接用于同步
+#[derive(Clone)]
+pub struct SyncKey(Key);
+
+impl SyncKeyParams {
+  /// 第一次同步时生成参数，返回参数以及派生的密钥
+  pub fn new(passphrase: &str) -> Result<(Self, SyncKey)> {
+    let (params, key) = PassphraseParams::new(passphrase)?;
+    Ok((Self(params), SyncKey(key)))
+  }
+
+  /// 校验同步密码并派生密钥
+  pub fn unlock(&self, passphrase: &str) -> Result<SyncKey> {
+    self.0.derive(passphrase).map(SyncKey)
+  }
+}
+
+impl SyncKey {
+  pub fn seal(&self, plain: &[u8]) -> Result<Vec<u8>> {
+    crypto::seal(&self.0, plain)
+  }
+
+  pub fn open(&self, sealed: &[u8]) -> Result<Vec<u8>> {
+    crypto::open(std::slice::from_ref(&self.0), sealed)
+  }
+}
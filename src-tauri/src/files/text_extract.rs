@@ -0,0 +1,517 @@
+use super::blob_store::BlobStore;
+use super::chat_files::{FILE_DIR_NAME, load_ref, sniff_mime};
+use super::chat_messages::file_refs;
+use super::key_store::{KeySet, chat_key_path};
+use super::reencrypt::files_in;
+use super::{SAVE_DIR, atomic_write, check_id, message_text};
+use crate::error::{Error, Result};
+use ego_tree::NodeRef;
+use quick_xml::Reader;
+use quick_xml::escape::resolve_predefined_entity;
+use quick_xml::events::{BytesRef, BytesStart, Event};
+use scraper::{ElementRef, Html, Node, Selector};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::io::{Cursor, Read};
+use std::path::{Path, PathBuf};
+use tauri::async_runtime::spawn_blocking;
+use zip::ZipArchive;
+
+/// 提取结果的缓存，与文件引用放在一起
+const TEXT_EXTENSION: &str = "text";
+/// 压缩包中单个文件解压后的大小上限，避免压缩炸弹耗尽内存
+const MAX_ENTRY_BYTES: u64 = 64 << 20;
+
+/// 不包含正文的 HTML 标签
+const SKIP_TAGS: &[&str] = &[
+  "script", "style", "noscript", "template", "head", "svg", "iframe",
+];
+/// 前后需要换行的 HTML 标签
+const BLOCK_TAGS: &[&str] = &[
+  "address",
+  "article",
+  "aside",
+  "blockquote",
+  "br",
+  "dd",
+  "div",
+  "dl",
+  "dt",
+  "figcaption",
+  "figure",
+  "footer",
+  "header",
+  "h4",
+  "h5",
+  "h6",
+  "hr",
+  "li",
+  "main",
+  "nav",
+  "ol",
+  "p",
+  "pre",
+  "section",
+  "table",
+  "tr",
+  "ul",
+];
+/// 作为章节分隔的标题标签
+const SECTION_TAGS: &[&str] = &["h1", "h2", "h3"];
+
+/// 提取出的一段文本，对应 PDF 的一页或文档中的一个章节
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TextSection {
+  /// 页码或章节标题
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub title: Option<String>,
+  pub text: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExtractedText {
+  /// 来源文件的内容标识，文件被覆盖后缓存失效
+  content_id: String,
+  /// 文件类型：pdf、docx、epub、html、text
+  kind: String,
+  sections: Vec<TextSection>,
+}
+
+impl ExtractedText {
+  /// 拼接为一段文本，章节标题作为小标题
+  pub fn to_plain(&self) -> String {
+    let sections = self.sections.iter().map(|it| match &it.title {
+      Some(title) => format!("## {title}\n\n{}", it.text),
+      None => it.text.clone(),
+    });
+    sections.collect::<Vec<_>>().join("\n\n")
+  }
+}
+
+/// 合并空白字符，去除行尾空白以及多余的空行
+fn clean_text(text: &str) -> String {
+  let mut result = String::with_capacity(text.len());
+  let mut blank = 0;
+  for line in text.lines().map(str::trim_end) {
+    if line.trim().is_empty() {
+      blank += 1;
+      continue;
+    }
+    if !result.is_empty() {
+      result.push_str(if blank > 0 { "\n\n" } else { "\n" });
+    }
+    blank = 0;
+    result.push_str(line);
+  }
+  result
+}
+
+fn collapse_whitespace(text: &str) -> String {
+  text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// 按章节收集文本
+#[derive(Default)]
+struct SectionWriter {
+  sections: Vec<TextSection>,
+  title: Option<String>,
+  buffer: String,
+}
+
+impl SectionWriter {
+  fn flush(&mut self) {
+    let text = clean_text(&self.buffer);
+    if !text.is_empty() || self.title.is_some() {
+      let title = self.title.take();
+      self.sections.push(TextSection { title, text });
+    }
+    self.buffer.clear();
+  }
+
+  fn start_section(&mut self, title: String) {
+    self.flush();
+    self.title = Some(title).filter(|it| !it.is_empty());
+  }
+
+  fn newline(&mut self) {
+    let len = self.buffer.trim_end_matches(' ').len();
+    self.buffer.truncate(len);
+    if !self.buffer.is_empty() && !self.buffer.ends_with('\n') {
+      self.buffer.push('\n');
+    }
+  }
+
+  fn paragraph(&mut self, text: &str) {
+    self.newline();
+    self.buffer.push_str(text);
+    self.buffer.push_str("\n\n");
+  }
+
+  fn push_inline(&mut self, text: &str) {
+    let starts_with_space = text.starts_with(char::is_whitespace);
+    let ends_with_space = text.ends_with(char::is_whitespace);
+    let text = collapse_whitespace(text);
+    let at_line_start = self.buffer.is_empty() || self.buffer.ends_with(['\n', ' ']);
+    if starts_with_space && !at_line_start {
+      self.buffer.push(' ');
+    }
+    self.buffer.push_str(&text);
+    if ends_with_space && !text.is_empty() {
+      self.buffer.push(' ');
+    }
+  }
+
+  fn finish(mut self) -> Vec<TextSection> {
+    self.flush();
+    self.sections
+  }
+}
+
+fn walk_html(node: NodeRef<Node>, writer: &mut SectionWriter, in_pre: bool) {
+  match node.value() {
+    Node::Text(text) if in_pre => writer.buffer.push_str(text),
+    Node::Text(text) => writer.push_inline(text),
+    Node::Element(element) => {
+      let name = element.name();
+      if SKIP_TAGS.contains(&name) {
+        return;
+      }
+      if SECTION_TAGS.contains(&name) {
+        let title = ElementRef::wrap(node)
+          .map(|it| collapse_whitespace(&it.text().collect::<String>()))
+          .unwrap_or_default();
+        writer.start_section(title);
+        return;
+      }
+
+      let block = BLOCK_TAGS.contains(&name);
+      if block {
+        writer.newline();
+      }
+      if name == "li" {
+        writer.buffer.push_str("- ");
+      }
+      for child in node.children() {
+        walk_html(child, writer, in_pre || name == "pre");
+      }
+      if block {
+        writer.newline();
+      }
+    }
+    _ => {}
+  }
+}
+
+/// 提取 HTML 正文，返回文档标题以及按 h1~h3 分隔的章节
+fn html_sections(html: &str) -> (Option<String>, Vec<TextSection>) {
+  let document = Html::parse_document(html);
+  let title = Selector::parse("title").ok().and_then(|it| {
+    let title = document.select(&it).next()?;
+    Some(collapse_whitespace(&title.text().collect::<String>())).filter(|it| !it.is_empty())
+  });
+
+  let mut writer = SectionWriter::default();
+  let body = Selector::parse("body")
+    .ok()
+    .and_then(|it| document.select(&it).next());
+  match body {
+    Some(body) => walk_html(*body, &mut writer, false),
+    None => walk_html(*document.root_element(), &mut writer, false),
+  }
+  (title, writer.finish())
+}
+
+fn extract_pdf(data: &[u8]) -> Result<Vec<TextSection>> {
+  // 解析损坏的 PDF 时可能会 panic
+  let pages = std::panic::catch_unwind(|| pdf_extract::extract_text_from_mem_by_pages(data))
+    .map_err(|_| Error::new("PDF 解析失败"))?
+    .map_err(|e| Error::new(format!("PDF 解析失败: {e}")))?;
+  let pages = pages
+    .into_iter()
+    .enumerate()
+    .map(|(index, text)| TextSection {
+      title: Some(format!("第 {} 页", index + 1)),
+      text: clean_text(&text),
+    });
+  Ok(pages.collect())
+}
+
+fn read_zip_entry(archive: &mut ZipArchive<Cursor<&[u8]>>, name: &str) -> Result<String> {
+  // 不信任文件头中记录的大小，按实际读取的长度限制
+  let mut text = String::new();
+  archive
+    .by_name(name)?
+    .take(MAX_ENTRY_BYTES + 1)
+    .read_to_string(&mut text)?;
+  if text.len() as u64 > MAX_ENTRY_BYTES {
+    return Err(Error::new(format!("{name} 过大，无法提取文本")));
+  }
+  Ok(text)
+}
+
+fn xml_error(e: impl Into<quick_xml::Error>) -> Error {
+  Error::new(format!("XML 解析失败: {}", e.into()))
+}
+
+/// 解析 XML 实体引用，如 `&amp;` 与 `&#20013;`
+fn resolve_ref(reference: &BytesRef) -> Result<String> {
+  if let Some(c) = reference.resolve_char_ref().map_err(xml_error)? {
+    return Ok(c.to_string());
+  }
+  let name = reference.decode().map_err(xml_error)?;
+  Ok(
+    resolve_predefined_entity(&name)
+      .unwrap_or_default()
+      .to_string(),
+  )
+}
+
+fn attribute(element: &BytesStart, name: &str) -> Result<Option<String>> {
+  let Some(attr) = element.try_get_attribute(name).map_err(xml_error)? else {
+    return Ok(None);
+  };
+  Ok(Some(attr.unescape_value().map_err(xml_error)?.to_string()))
+}
+
+fn extract_docx(data: &[u8]) -> Result<Vec<TextSection>> {
+  let mut archive = ZipArchive::new(Cursor::new(data))?;
+  let xml = read_zip_entry(&mut archive, "word/document.xml")?;
+  let mut reader = Reader::from_str(&xml);
+
+  let mut writer = SectionWriter::default();
+  let mut paragraph = String::new();
+  let mut heading = false;
+  let mut in_text = false;
+  loop {
+    match reader.read_event().map_err(xml_error)? {
+      Event::Start(e) if e.local_name().as_ref() == b"t" => in_text = true,
+      Event::End(e) => match e.local_name().as_ref() {
+        b"t" => in_text = false,
+        // 标题样式的段落作为章节分隔
+        b"p" if heading => {
+          writer.start_section(collapse_whitespace(&paragraph));
+          paragraph.clear();
+          heading = false;
+        }
+        b"p" => {
+          writer.paragraph(&paragraph);
+          paragraph.clear();
+        }
+        _ => {}
+      },
+      Event::Empty(e) => match e.local_name().as_ref() {
+        b"tab" => paragraph.push('\t'),
+        b"br" | b"cr" => paragraph.push('\n'),
+        b"pStyle" => {
+          let style = attribute(&e, "w:val")?.unwrap_or_default();
+          heading =
+            style.starts_with("Heading") || style.starts_with("heading") || style == "Title";
+        }
+        _ => {}
+      },
+      Event::Text(e) if in_text => paragraph.push_str(&e.decode().map_err(xml_error)?),
+      Event::GeneralRef(e) if in_text => paragraph.push_str(&resolve_ref(&e)?),
+      Event::Eof => break,
+      _ => {}
+    }
+  }
+  Ok(writer.finish())
+}
+
+/// 按阅读顺序列出 EPUB 中的全部章节文件
+fn epub_spine(archive: &mut ZipArchive<Cursor<&[u8]>>) -> Result<Vec<String>> {
+  let container = read_zip_entry(archive, "META-INF/container.xml")?;
+  let mut reader = Reader::from_str(&container);
+  let mut opf_path = None;
+  loop {
+    match reader.read_event().map_err(xml_error)? {
+      Event::Start(e) | Event::Empty(e) if e.local_name().as_ref() == b"rootfile" => {
+        opf_path = attribute(&e, "full-path")?;
+        break;
+      }
+      Event::Eof => break,
+      _ => {}
+    }
+  }
+  let opf_path = opf_path.ok_or_else(|| Error::new("EPUB 缺少 rootfile"))?;
+  let base = opf_path.rsplit_once('/').map_or("", |(it, _)| it);
+
+  let opf = read_zip_entry(archive, &opf_path)?;
+  let mut reader = Reader::from_str(&opf);
+  let mut manifest = HashMap::new();
+  let mut spine = Vec::new();
+  loop {
+    match reader.read_event().map_err(xml_error)? {
+      Event::Start(e) | Event::Empty(e) => match e.local_name().as_ref() {
+        b"item" => {
+          if let (Some(id), Some(href)) = (attribute(&e, "id")?, attribute(&e, "href")?) {
+            manifest.insert(id, href);
+          }
+        }
+        b"itemref" => spine.extend(attribute(&e, "idref")?),
+        _ => {}
+      },
+      Event::Eof => break,
+      _ => {}
+    }
+  }
+
+  let paths = spine.iter().filter_map(|it| manifest.get(it)).map(|href| {
+    let href = percent_encoding::percent_decode_str(href).decode_utf8_lossy();
+    match base.is_empty() {
+      true => href.to_string(),
+      false => format!("{base}/{href}"),
+    }
+  });
+  Ok(paths.collect())
+}
+
+fn extract_epub(data: &[u8]) -> Result<Vec<TextSection>> {
+  let mut archive = ZipArchive::new(Cursor::new(data))?;
+  let mut sections = Vec::new();
+  for path in epub_spine(&mut archive)? {
+    // 个别章节缺失时跳过
+    let Ok(html) = read_zip_entry(&mut archive, &path) else {
+      continue;
+    };
+    let (title, mut chapter) = html_sections(&html);
+    // 章节开头没有标题时使用文档标题
+    if let Some(first) = chapter.first_mut()
+      && first.title.is_none()
+    {
+      first.title = title;
+    }
+    sections.extend(chapter);
+  }
+  Ok(sections)
+}
+
+/// 根据 MIME 类型提取文本，返回文件类型与章节
+fn extract(data: &[u8], mime_type: &str) -> Result<(&'static str, Vec<TextSection>)> {
+  match mime_type {
+    "application/pdf" => Ok(("pdf", extract_pdf(data)?)),
+    "application/vnd.openxmlformats-officedocument.wordprocessingml.document" => {
+      Ok(("docx", extract_docx(data)?))
+    }
+    "application/epub+zip" => Ok(("epub", extract_epub(data)?)),
+    "text/html" | "application/xhtml+xml" => {
+      let html = String::from_utf8_lossy(data);
+      Ok(("html", html_sections(&html).1))
+    }
+    _ => {
+      let text = std::str::from_utf8(data)
+        .map_err(|_| Error::new(format!("不支持提取此类型文件的文本: {mime_type}")))?;
+      let section = TextSection {
+        title: None,
+        text: clean_text(text),
+      };
+      Ok(("text", vec![section]))
+    }
+  }
+}
+
+fn cache_path(files_dir: &Path, file_id: &str) -> PathBuf {
+  files_dir.join(format!("{file_id}.{TEXT_EXTENSION}"))
+}
+
+/// 删除文件的文本缓存，不存在时忽略
+pub(super) fn remove_text_cache(files_dir: &Path, file_id: &str) -> Result<()> {
+  match std::fs::remove_file(cache_path(files_dir, file_id)) {
+    Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+    _ => Ok(()),
+  }
+}
+
+/// 文件夹中全部文本缓存对应的文件 id
+pub(super) fn text_cache_ids(files_dir: &Path) -> Result<Vec<String>> {
+  let mut ids = Vec::new();
+  for path in files_in(files_dir, &[TEXT_EXTENSION], false)? {
+    if let Some(id) = path.file_stem().and_then(|it| it.to_str()) {
+      ids.push(id.to_string());
+    }
+  }
+  Ok(ids)
+}
+
+fn read_cache(
+  keys: &KeySet,
+  files_dir: &Path,
+  file_id: &str,
+  content_id: &str,
+) -> Option<ExtractedText> {
+  let sealed = std::fs::read(cache_path(files_dir, file_id)).ok()?;
+  let text: ExtractedText = serde_json::from_slice(&keys.open(&sealed).ok()?).ok()?;
+  (text.content_id == content_id).then_some(text)
+}
+
+fn extract_blocking(app_data: PathBuf, chat_id: String, file_id: String) -> Result<ExtractedText> {
+  let store = BlobStore::new(&app_data);
+  let chat_dir = app_data.join(SAVE_DIR).join(chat_id);
+  let files_dir = chat_dir.join(FILE_DIR_NAME);
+  let file_ref = load_ref(&store, &files_dir, &file_id)?;
+  let keys = KeySet::load_or_create(&chat_key_path(&chat_dir))?;
+  if let Some(text) = read_cache(&keys, &files_dir, &file_id, &file_ref.blob) {
+    return Ok(text);
+  }
+
+  let data = store.read(&file_ref.blob)?;
+  let mime_type = match file_ref.mime_type {
+    Some(it) => it,
+    None => sniff_mime(&data, file_ref.name.as_deref(), None),
+  };
+  let (kind, sections) = extract(&data, &mime_type)?;
+  let text = ExtractedText {
+    content_id: file_ref.blob,
+    kind: kind.to_string(),
+    sections,
+  };
+
+  let sealed = keys.seal(&serde_json::to_vec(&text)?)?;
+  atomic_write(&cache_path(&files_dir, &file_id), &sealed)?;
+  Ok(text)
+}
+
+/// 提取对话文件中的文本，结果缓存在 `files/<file_id>.text`
+pub async fn extract_chat_file(
+  app_data: &Path,
+  chat_id: String,
+  file_id: String,
+) -> Result<ExtractedText> {
+  check_id("对话", &chat_id)?;
+  check_id("文件", &file_id)?;
+  let app_data = app_data.to_path_buf();
+  spawn_blocking(move || extract_blocking(app_data, chat_id, file_id)).await?
+}
+
+/// 消息的搜索文本，包括附件中已提取过的文本，不会触发新的提取
+pub async fn message_search_text(app_data: &Path, chat_id: &str, message: &Value) -> String {
+  let mut text = message_text(message);
+  let chat_dir = app_data.join(SAVE_DIR).join(chat_id);
+  let files_dir = chat_dir.join(FILE_DIR_NAME);
+  let file_ids: Vec<String> = file_refs(message).map(str::to_owned).collect();
+  if file_ids.is_empty() {
+    return text;
+  }
+
+  let store = BlobStore::new(app_data);
+  let attachments = spawn_blocking(move || {
+    let Ok(keys) = KeySet::load(&chat_key_path(&chat_dir)) else {
+      return Vec::new();
+    };
+    let texts = file_ids.iter().filter_map(|file_id| {
+      let file_ref = load_ref(&store, &files_dir, file_id).ok()?;
+      read_cache(&keys, &files_dir, file_id, &file_ref.blob).map(|it| it.to_plain())
+    });
+    texts.collect::<Vec<_>>()
+  })
+  .await
+  .unwrap_or_default();
+
+  for attachment in attachments {
+    text.push_str("\n\n");
+    text.push_str(&attachment);
+  }
+  text
+}
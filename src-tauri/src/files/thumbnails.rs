@@ -0,0 +1,174 @@
+use super::blob_store::BlobStore;
+use super::chat_files::{FILE_DIR_NAME, read_legacy_file, read_ref};
+use super::key_store::{KeySet, shared_key_path};
+use super::note_files::note_files_dir;
+use super::reencrypt::files_in;
+use super::{SAVE_DIR, atomic_write, check_id};
+use crate::error::{Error, Result};
+use image::{DynamicImage, ImageFormat, ImageReader};
+use std::fs::create_dir_all;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use tauri::async_runtime::spawn_blocking;
+
+pub(super) const THUMBNAIL_DIR: &str = "thumbnails";
+const MIN_SIZE: u32 = 16;
+const MAX_SIZE: u32 = 2048;
+
+/// 缩略图的来源图片
+pub enum ThumbnailSource {
+  /// 已保存的对话文件
+  ChatFile { chat_id: String, file_id: String },
+  /// 笔记引用的文件
+  NoteFile { note_id: String, file_id: String },
+  /// 本地文件路径
+  Path(PathBuf),
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub enum ThumbnailFormat {
+  #[default]
+  Jpeg,
+  /// 无损 WebP，保留透明通道
+  Webp,
+}
+
+impl ThumbnailFormat {
+  pub fn parse(value: &str) -> Option<Self> {
+    match value {
+      "jpeg" | "jpg" => Some(Self::Jpeg),
+      "webp" => Some(Self::Webp),
+      _ => None,
+    }
+  }
+
+  pub fn mime_type(self) -> &'static str {
+    match self {
+      Self::Jpeg => "image/jpeg",
+      Self::Webp => "image/webp",
+    }
+  }
+
+  fn extension(self) -> &'static str {
+    match self {
+      Self::Jpeg => "jpg",
+      Self::Webp => "webp",
+    }
+  }
+}
+
+/// 按需读取原图内容
+type LoadImage = Box<dyn FnOnce() -> Result<Vec<u8>> + Send>;
+
+/// 读取来源图片，返回内容哈希以及按需读取内容的函数
+///
+/// 对话文件的哈希直接来自文件引用，缓存命中时不需要读取原图
+fn resolve(app_data: &Path, source: ThumbnailSource) -> Result<(String, LoadImage)> {
+  match source {
+    ThumbnailSource::ChatFile { chat_id, file_id } => {
+      check_id("对话", &chat_id)?;
+      check_id("文件", &file_id)?;
+      let files_dir = app_data.join(SAVE_DIR).join(&chat_id).join(FILE_DIR_NAME);
+      if let Some(file_ref) = read_ref(&files_dir, &file_id)? {
+        let store = BlobStore::new(app_data);
+        let hash = file_ref.blob.clone();
+        return Ok((file_ref.blob, Box::new(move || store.read(&hash))));
+      }
+      // 旧版本的文件只能读取内容后计算标识
+      let data = read_legacy_file(&files_dir, &file_id)?;
+      let hash = BlobStore::new(app_data).content_ids()?.of(&data);
+      Ok((hash, Box::new(move || Ok(data))))
+    }
+    ThumbnailSource::NoteFile { note_id, file_id } => {
+      check_id("文件", &file_id)?;
+      let files_dir = note_files_dir(app_data, &note_id)?;
+      let file_ref = read_ref(&files_dir, &file_id)?
+        .ok_or_else(|| Error::NotFound(format!("file({file_id})")))?;
+      let store = BlobStore::new(app_data);
+      let hash = file_ref.blob.clone();
+      Ok((file_ref.blob, Box::new(move || store.read(&hash))))
+    }
+    ThumbnailSource::Path(path) => {
+      let data = std::fs::read(path)?;
+      let hash = BlobStore::new(app_data).content_ids()?.of(&data);
+      Ok((hash, Box::new(move || Ok(data))))
+    }
+  }
+}
+
+/// 缩放图片并编码，图片小于指定尺寸时不放大
+fn render(data: &[u8], size: u32, format: ThumbnailFormat) -> Result<Vec<u8>> {
+  let image = ImageReader::new(Cursor::new(data))
+    .with_guessed_format()?
+    .decode()?;
+  let image = match image.width() > size || image.height() > size {
+    true => image.thumbnail(size, size),
+    false => image,
+  };
+
+  let mut buffer = Cursor::new(Vec::new());
+  match format {
+    // JPEG 不支持透明通道
+    ThumbnailFormat::Jpeg => {
+      DynamicImage::ImageRgb8(image.to_rgb8()).write_to(&mut buffer, ImageFormat::Jpeg)?
+    }
+    ThumbnailFormat::Webp => {
+      DynamicImage::ImageRgba8(image.to_rgba8()).write_to(&mut buffer, ImageFormat::WebP)?
+    }
+  }
+  Ok(buffer.into_inner())
+}
+
+/// 全部缩略图缓存，以及生成缓存时使用的内容标识
+pub(super) fn thumbnail_caches(app_data: &Path) -> Result<Vec<(String, PathBuf)>> {
+  let paths = files_in(&app_data.join(THUMBNAIL_DIR), &["jpg", "webp"], true)?;
+  let caches = paths.into_iter().filter_map(|path| {
+    let name = path.file_stem()?.to_str()?;
+    let (hash, _) = name.split_once('-')?;
+    Some((hash.to_string(), path))
+  });
+  Ok(caches.collect())
+}
+
+fn thumbnail_blocking(
+  app_data: PathBuf,
+  source: ThumbnailSource,
+  size: u32,
+  format: ThumbnailFormat,
+) -> Result<Vec<u8>> {
+  let (hash, load) = resolve(&app_data, source)?;
+  let path = app_data
+    .join(THUMBNAIL_DIR)
+    .join(&hash[..2])
+    .join(format!("{hash}-{size}.{}", format.extension()));
+
+  // 缓存损坏时重新生成
+  if let Ok(sealed) = std::fs::read(&path)
+    && let Ok(keys) = KeySet::load(&shared_key_path(&app_data))
+    && let Ok(data) = keys.open(&sealed)
+  {
+    return Ok(data);
+  }
+
+  let data = render(&load()?, size, format)?;
+  // 写入缓存失败不影响本次结果，下次重新生成即可
+  let _ = KeySet::load_or_create(&shared_key_path(&app_data))
+    .and_then(|keys| keys.seal(&data))
+    .and_then(|sealed| {
+      path.parent().map_or(Ok(()), create_dir_all)?;
+      Ok(atomic_write(&path, &sealed)?)
+    });
+  Ok(data)
+}
+
+/// 生成最长边不超过 `size` 的缩略图，结果按内容哈希缓存在 `thumbnails/` 下
+pub async fn thumbnail(
+  app_data: &Path,
+  source: ThumbnailSource,
+  size: u32,
+  format: ThumbnailFormat,
+) -> Result<Vec<u8>> {
+  let size = size.clamp(MIN_SIZE, MAX_SIZE);
+  let app_data = app_data.to_path_buf();
+  spawn_blocking(move || thumbnail_blocking(app_data, source, size, format)).await?
+}
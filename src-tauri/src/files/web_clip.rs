@@ -0,0 +1,644 @@
+use super::note_files::save_note_file;
+use super::remote_fetch::FetchPolicy;
+use crate::error::{FetchError, Result};
+use data_url::DataUrl;
+use ego_tree::NodeRef;
+use encoding_rs::{Encoding, UTF_8};
+use scraper::{ElementRef, Html, Node, Selector};
+use std::collections::HashMap;
+use std::path::Path;
+use tauri_plugin_http::reqwest::Url;
+
+/// 摘要的最大字符数
+const SUMMARY_CHARS: usize = 120;
+/// 单个页面最多下载的图片数量
+const MAX_IMAGES: usize = 50;
+/// 参与正文评分的段落最少字符数
+const MIN_PARAGRAPH_CHARS: usize = 25;
+/// 在页面开头查找 `<meta>` 编码声明的字节数，与浏览器的预扫描范围一致
+const META_SCAN_BYTES: usize = 1024;
+
+/// 不属于正文的标签
+const SKIP_TAGS: &[&str] = &[
+  "script", "style", "noscript", "template", "nav", "footer", "aside", "form", "button", "iframe",
+  "svg", "canvas", "input", "select", "textarea",
+];
+/// class 或 id 中包含这些词的元素通常不是正文
+const NOISE_HINTS: &[&str] = &[
+  "comment",
+  "share",
+  "social",
+  "related",
+  "sidebar",
+  "advert",
+  "promo",
+  "banner",
+  "popup",
+  "cookie",
+  "newsletter",
+  "breadcrumb",
+];
+/// 转换时作为段落处理的标签
+const BLOCK_TAGS: &[&str] = &[
+  "p",
+  "div",
+  "section",
+  "article",
+  "main",
+  "header",
+  "figure",
+  "figcaption",
+  "dl",
+  "dt",
+  "dd",
+  "address",
+  "details",
+  "summary",
+];
+
+/// 剪藏得到的页面内容
+pub struct ClippedPage {
+  pub title: String,
+  pub summary: String,
+  /// markdown 正文，不包括来源信息
+  pub content: String,
+  /// 重定向之后的页面地址
+  pub url: String,
+}
+
+/// `Content-Type` 或 `<meta>` 中 `charset=` 声明的编码
+fn charset_param(value: &str) -> Option<&'static Encoding> {
+  let value = value.to_ascii_lowercase();
+  let start = value.find("charset")? + "charset".len();
+  let rest = value[start..].trim_start().strip_prefix('=')?.trim_start();
+  let rest = rest.trim_start_matches(['"', '\'']);
+  let end = rest
+    .find(|c: char| matches!(c, '"' | '\'' | ';' | '/' | '>') || c.is_whitespace())
+    .unwrap_or(rest.len());
+  Encoding::for_label(&rest.as_bytes()[..end])
+}
+
+/// 页面开头的 `<meta charset>` 或 `<meta http-equiv="Content-Type">` 声明的编码
+fn meta_charset(data: &[u8]) -> Option<&'static Encoding> {
+  let head = String::from_utf8_lossy(&data[..data.len().min(META_SCAN_BYTES)]).to_ascii_lowercase();
+  let declared = head.split("<meta").skip(1).find_map(|tag| {
+    let end = tag.find('>').unwrap_or(tag.len());
+    charset_param(&tag[..end])
+  });
+  // 页面内容已经按 ASCII 兼容的编码读到了声明，UTF-16 的声明按 UTF-8 处理
+  declared.map(Encoding::output_encoding)
+}
+
+/// 解码页面内容，编码依次来自 BOM、`Content-Type` 与 `<meta>` 声明，都没有时按 UTF-8 处理
+fn decode_html(data: &[u8], content_type: Option<&str>) -> String {
+  let encoding = content_type
+    .and_then(charset_param)
+    .or_else(|| meta_charset(data))
+    .unwrap_or(UTF_8);
+  // 存在 BOM 时 decode 使用 BOM 指定的编码
+  encoding.decode(data).0.into_owned()
+}
+
+fn collapse_whitespace(text: &str) -> String {
+  text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn element_text(element: ElementRef) -> String {
+  collapse_whitespace(&element.text().collect::<String>())
+}
+
+fn is_noise(element: ElementRef) -> bool {
+  let value = element.value();
+  let hints = [value.attr("class"), value.attr("id"), value.attr("role")];
+  hints.into_iter().flatten().any(|it| {
+    let it = it.to_ascii_lowercase();
+    NOISE_HINTS.iter().any(|hint| it.contains(hint)) || it == "navigation"
+  })
+}
+
+fn select_first<'a>(document: &'a Html, selector: &str) -> Option<ElementRef<'a>> {
+  let selector = Selector::parse(selector).ok()?;
+  document.select(&selector).next()
+}
+
+/// 页面标题，依次尝试 `og:title`、`<title>` 与第一个 `<h1>`
+fn page_title(document: &Html) -> Option<String> {
+  let og_title = select_first(document, r#"meta[property="og:title"]"#)
+    .and_then(|it| it.value().attr("content"))
+    .map(collapse_whitespace);
+  let title = || select_first(document, "title").map(element_text);
+  let heading = || select_first(document, "h1").map(element_text);
+  og_title
+    .into_iter()
+    .chain(title())
+    .chain(heading())
+    .find(|it| !it.is_empty())
+}
+
+/// 链接文字占全部文字的比例，导航和链接列表的比例很高
+fn link_density(element: ElementRef) -> f64 {
+  let total = element_text(element).chars().count();
+  if total == 0 {
+    return 1.0;
+  }
+  let Ok(selector) = Selector::parse("a") else {
+    return 0.0;
+  };
+  let links: usize = element
+    .select(&selector)
+    .map(|it| element_text(it).chars().count())
+    .sum();
+  links as f64 / total as f64
+}
+
+/// 找到正文所在的元素
+///
+/// 按段落为父元素和祖父元素计分，选出得分最高的元素；
+/// 如果该元素位于 `<article>` 或 `<main>` 中，则使用外层元素以保留标题和图片
+fn main_content(document: &Html) -> Option<ElementRef<'_>> {
+  let selector = Selector::parse("p, pre, blockquote").ok()?;
+  let mut scores: HashMap<ego_tree::NodeId, f64> = HashMap::new();
+  for paragraph in document.select(&selector) {
+    let text = element_text(paragraph);
+    let chars = text.chars().count();
+    if chars < MIN_PARAGRAPH_CHARS {
+      continue;
+    }
+    let commas = text.matches([',', '，', '。']).count() as f64;
+    let score = 1.0 + commas + (chars as f64 / 100.0).min(3.0);
+
+    let mut weight = 1.0;
+    for ancestor in paragraph.ancestors().take(2) {
+      *scores.entry(ancestor.id()).or_default() += score * weight;
+      weight /= 2.0;
+    }
+  }
+
+  let best = scores
+    .into_iter()
+    .filter_map(|(id, score)| {
+      let element = ElementRef::wrap(document.tree.get(id)?)?;
+      Some((element, score * (1.0 - link_density(element))))
+    })
+    .max_by(|a, b| a.1.total_cmp(&b.1))
+    .map(|(element, _)| element)?;
+
+  let container = best
+    .ancestors()
+    .filter_map(ElementRef::wrap)
+    .find(|it| matches!(it.value().name(), "article" | "main"));
+  Some(container.unwrap_or(best))
+}
+
+/// HTML 转换为 markdown，图片先以占位符记录，下载完成后再替换
+struct MarkdownWriter<'a> {
+  base: &'a Url,
+  /// 图片地址，下标即占位符编号
+  images: Vec<String>,
+}
+
+/// 列表缩进的占位字符，[`normalize`] 会去除行首空白，转换完成后再替换为空格
+const INDENT: char = '\u{2}';
+
+fn image_placeholder(index: usize) -> String {
+  format!("\u{1}IMG{index}\u{1}")
+}
+
+impl MarkdownWriter<'_> {
+  /// 解析为绝对地址，只保留 `schemes` 中的协议
+  ///
+  /// 协议在解析之后检查，`JavaScript:`、夹杂空白等写法都已经被规范化
+  fn resolve(&self, href: &str, schemes: &[&str]) -> Option<String> {
+    let href = href.trim();
+    if href.is_empty() || href.starts_with('#') {
+      return None;
+    }
+    let url = self.base.join(href).ok()?;
+    schemes.contains(&url.scheme()).then(|| url.into())
+  }
+
+  fn children(&mut self, node: NodeRef<Node>) -> String {
+    node.children().map(|it| self.node(it)).collect()
+  }
+
+  /// 行内内容，换行替换为空格
+  fn inline(&mut self, node: NodeRef<Node>) -> String {
+    collapse_whitespace(&self.children(node))
+  }
+
+  fn list(&mut self, node: NodeRef<Node>, ordered: bool) -> String {
+    let mut result = String::from("\n\n");
+    let mut number = 0;
+    for child in node.children().filter_map(ElementRef::wrap) {
+      if child.value().name() != "li" {
+        continue;
+      }
+      number += 1;
+      let marker = match ordered {
+        true => format!("{number}. "),
+        false => "- ".to_string(),
+      };
+      let content = normalize(&self.children(*child));
+      // 列表项中的多行内容需要缩进，空行会结束列表项因此去除
+      let indent = INDENT.to_string().repeat(marker.len());
+      let content = content
+        .lines()
+        .filter(|it| !it.trim().is_empty())
+        .collect::<Vec<_>>()
+        .join(&format!("\n{indent}"));
+      result.push_str(&marker);
+      result.push_str(&content);
+      result.push('\n');
+    }
+    result.push('\n');
+    result
+  }
+
+  fn table(&mut self, element: ElementRef) -> String {
+    let (Ok(row_selector), Ok(cell_selector)) = (Selector::parse("tr"), Selector::parse("th, td"))
+    else {
+      return String::new();
+    };
+    let mut rows = Vec::new();
+    for row in element.select(&row_selector) {
+      let cells: Vec<String> = row
+        .select(&cell_selector)
+        .map(|it| self.inline(*it).replace('|', "\\|"))
+        .collect();
+      if !cells.is_empty() {
+        rows.push(cells);
+      }
+    }
+    let Some(columns) = rows.iter().map(Vec::len).max() else {
+      return String::new();
+    };
+
+    let mut result = String::from("\n\n");
+    for (index, row) in rows.iter().enumerate() {
+      let mut cells = row.clone();
+      cells.resize(columns, String::new());
+      result.push_str(&format!("| {} |\n", cells.join(" | ")));
+      if index == 0 {
+        result.push_str(&format!("|{}\n", " --- |".repeat(columns)));
+      }
+    }
+    result.push('\n');
+    result
+  }
+
+  fn node(&mut self, node: NodeRef<Node>) -> String {
+    let element = match node.value() {
+      Node::Text(text) => {
+        // 保留首尾的空白，避免相邻的行内元素粘连
+        let collapsed = collapse_whitespace(text);
+        let prefix = if text.starts_with(char::is_whitespace) {
+          " "
+        } else {
+          ""
+        };
+        let suffix = match text.ends_with(char::is_whitespace) && !collapsed.is_empty() {
+          true => " ",
+          false => "",
+        };
+        return format!("{prefix}{collapsed}{suffix}");
+      }
+      Node::Element(_) => ElementRef::wrap(node),
+      _ => None,
+    };
+    let Some(element) = element else {
+      return String::new();
+    };
+    let name = element.value().name();
+    if SKIP_TAGS.contains(&name) || is_noise(element) {
+      return String::new();
+    }
+
+    match name {
+      "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+        let level = name[1..].parse().unwrap_or(1);
+        let text = self.inline(node);
+        match text.is_empty() {
+          true => String::new(),
+          false => format!("\n\n{} {text}\n\n", "#".repeat(level)),
+        }
+      }
+      "br" => "\n".to_string(),
+      "hr" => "\n\n---\n\n".to_string(),
+      "strong" | "b" => wrap_inline(&self.inline(node), "**"),
+      "em" | "i" => wrap_inline(&self.inline(node), "*"),
+      "del" | "s" => wrap_inline(&self.inline(node), "~~"),
+      "code" => wrap_inline(&element.text().collect::<String>(), "`"),
+      "pre" => {
+        let code = element.text().collect::<String>();
+        format!("\n\n```\n{}\n```\n\n", code.trim_end())
+      }
+      "a" => {
+        let text = self.inline(node);
+        let href = element
+          .value()
+          .attr("href")
+          .and_then(|it| self.resolve(it, &["http", "https", "mailto"]));
+        match (text.is_empty(), href) {
+          (true, _) => String::new(),
+          (false, Some(href)) => format!("[{text}]({href})"),
+          (false, _) => text,
+        }
+      }
+      "img" => {
+        // 懒加载的图片地址通常放在 data-src 中
+        let attrs = ["data-src", "data-original", "src"];
+        let src = attrs
+          .iter()
+          .filter_map(|it| element.value().attr(it))
+          .find_map(|it| self.resolve(it, &["http", "https", "data"]));
+        let Some(src) = src else {
+          return String::new();
+        };
+        let alt = collapse_whitespace(element.value().attr("alt").unwrap_or_default());
+        let index = match self.images.iter().position(|it| *it == src) {
+          Some(index) => index,
+          None => {
+            self.images.push(src);
+            self.images.len() - 1
+          }
+        };
+        format!("![{alt}]({})", image_placeholder(index))
+      }
+      "ul" => self.list(node, false),
+      "ol" => self.list(node, true),
+      "blockquote" => {
+        let content = normalize(&self.children(node));
+        let lines = content
+          .lines()
+          .map(|it| format!("> {it}").trim_end().to_string());
+        format!("\n\n{}\n\n", lines.collect::<Vec<_>>().join("\n"))
+      }
+      "table" => self.table(element),
+      _ if BLOCK_TAGS.contains(&name) => format!("\n\n{}\n\n", self.children(node)),
+      _ => self.children(node),
+    }
+  }
+}
+
+fn wrap_inline(text: &str, mark: &str) -> String {
+  let text = text.trim();
+  match text.is_empty() {
+    true => String::new(),
+    false => format!("{mark}{text}{mark}"),
+  }
+}
+
+/// 去除行尾空白、行首多余的空格以及连续的空行
+fn normalize(markdown: &str) -> String {
+  let mut result = String::with_capacity(markdown.len());
+  let mut blank = false;
+  let mut in_code = false;
+  for line in markdown.lines() {
+    if line.trim_start().starts_with("```") {
+      in_code = !in_code;
+    }
+    let line = match in_code {
+      true => line.trim_end(),
+      false => line.trim(),
+    };
+    if line.is_empty() && !in_code {
+      blank = !result.is_empty();
+      continue;
+    }
+    if blank {
+      result.push('\n');
+      blank = false;
+    }
+    result.push_str(line);
+    result.push('\n');
+  }
+  result.trim_end().to_string()
+}
+
+/// 摘要，取正文中第一段文字
+fn summary_of(markdown: &str) -> String {
+  let paragraph = markdown
+    .split("\n\n")
+    .map(str::trim)
+    .find(|it| !it.is_empty() && !it.starts_with(['#', '!', '|', '`', '>']))
+    .unwrap_or_default();
+  let mut summary: String = paragraph.chars().take(SUMMARY_CHARS).collect();
+  if paragraph.chars().count() > SUMMARY_CHARS {
+    summary.push('…');
+  }
+  summary
+}
+
+/// 解析页面，返回标题、带图片占位符的 markdown 以及图片地址
+///
+/// [`Html`] 不能跨越 await，因此解析过程放在同步函数中
+fn convert(html: &str, base: &Url) -> (Option<String>, String, Vec<String>) {
+  let document = Html::parse_document(html);
+  let title = page_title(&document);
+  let root = main_content(&document)
+    .or_else(|| select_first(&document, "body"))
+    .unwrap_or_else(|| document.root_element());
+
+  let mut writer = MarkdownWriter {
+    base,
+    images: Vec::new(),
+  };
+  let markdown = normalize(&writer.node(*root)).replace(INDENT, " ");
+  (title, markdown, writer.images)
+}
+
+/// 解码 `data:` 地址中的图片，大小和类型同样受获取策略的限制
+fn decode_data_image(src: &str, policy: &FetchPolicy) -> Result<Vec<u8>> {
+  // 解码后的大小至少是编码内容长度的三分之一，明显超过上限时不再解码
+  let encoded = src.split_once(',').map_or(src.len(), |(_, it)| it.len());
+  if encoded as u64 / 3 > policy.max_bytes {
+    return Err(FetchError::TooLarge(policy.max_bytes).into());
+  }
+  let url = DataUrl::process(src)?;
+  policy.check_content_type(&url.mime_type().to_string())?;
+  let (data, _) = url.decode_to_vec()?;
+  if data.len() as u64 > policy.max_bytes {
+    return Err(FetchError::TooLarge(policy.max_bytes).into());
+  }
+  Ok(data)
+}
+
+/// 下载图片并保存到笔记中，返回本地文件 id
+async fn download_image(
+  app_data: &Path,
+  policy: &FetchPolicy,
+  note_id: &str,
+  src: &str,
+) -> Result<String> {
+  if src.starts_with("data:") {
+    let data = decode_data_image(src, policy)?;
+    return save_note_file(app_data, note_id, data, None, "url").await;
+  }
+  let fetched = policy.fetch(src).await?;
+  let name = fetched
+    .url
+    .path_segments()
+    .and_then(|mut it| it.next_back())
+    .filter(|it| !it.is_empty())
+    .map(str::to_string);
+  save_note_file(app_data, note_id, fetched.data, name, "url").await
+}
+
+/// 剪藏网页为 markdown，图片下载到笔记的文件中
+///
+/// 提供 `html` 时不会请求页面，只使用 `url` 解析相对地址
+pub async fn clip_web_page(
+  app_data: &Path,
+  policy: &FetchPolicy,
+  note_id: &str,
+  url: &str,
+  html: Option<String>,
+) -> Result<ClippedPage> {
+  let (html, base) = match html {
+    Some(html) => {
+      let base = Url::parse(url).map_err(|_| FetchError::InvalidUrl(url.to_string()))?;
+      (html, base)
+    }
+    None => {
+      let fetched = policy.fetch(url).await?;
+      let html = decode_html(&fetched.data, fetched.content_type.as_deref());
+      (html, fetched.url)
+    }
+  };
+
+  let (title, mut content, images) = convert(&html, &base);
+
+  // 下载失败的图片保留原地址
+  let mut image_policy = policy.clone();
+  image_policy.allowed_content_types = vec!["image/".to_string()];
+  for (index, src) in images.iter().enumerate() {
+    let local = match index < MAX_IMAGES {
+      true => download_image(app_data, &image_policy, note_id, src)
+        .await
+        .ok(),
+      false => None,
+    };
+    let target = local.unwrap_or_else(|| src.clone());
+    content = content.replace(&image_placeholder(index), &target);
+  }
+
+  let title = title.unwrap_or_else(|| base.host_str().unwrap_or(url).to_string());
+  Ok(ClippedPage {
+    summary: summary_of(&content),
+    title,
+    content,
+    url: base.to_string(),
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::error::Error;
+  use encoding_rs::{GBK, SHIFT_JIS};
+
+  const ARTICLE_HTML: &str = include_str!("../../tests/fixtures/web_clip/article.html");
+  const ARTICLE_MD: &str = include_str!("../../tests/fixtures/web_clip/article.md");
+
+  fn base() -> Url {
+    Url::parse("https://blog.example.com/posts/runtime.html").unwrap()
+  }
+
+  /// 与剪藏时相同，用图片地址替换占位符
+  fn convert_with_images(html: &str) -> (Option<String>, String, Vec<String>) {
+    let (title, mut markdown, images) = convert(html, &base());
+    for (index, src) in images.iter().enumerate() {
+      markdown = markdown.replace(&image_placeholder(index), src);
+    }
+    (title, markdown, images)
+  }
+
+  #[test]
+  fn converts_article_fixture() {
+    let (title, markdown, images) = convert_with_images(ARTICLE_HTML);
+    assert_eq!(title.as_deref(), Some("Rust 异步运行时的工作原理"));
+    assert_eq!(
+      images,
+      [
+        "https://blog.example.com/posts/images/scheduler.png",
+        "data:text/html;base64,PHNjcmlwdD4=",
+      ]
+    );
+    assert_eq!(format!("{markdown}\n"), ARTICLE_MD);
+    assert_eq!(
+      summary_of(&markdown),
+      "异步运行时负责调度任务、驱动 IO 事件，并在任务可以继续执行时唤醒它们。理解这一点，是写好异步代码的前提。"
+    );
+  }
+
+  #[test]
+  fn drops_script_links_in_any_case() {
+    for href in [
+      "javascript:alert(1)",
+      "JavaScript:alert(1)",
+      " JAVASCRIPT:alert(1)",
+      "java\tscript:alert(1)",
+      "vbscript:msgbox(1)",
+      "data:text/html,<script>alert(1)</script>",
+    ] {
+      let html = format!(r#"<p><a href="{href}">链接</a></p>"#);
+      let (_, markdown, _) = convert(&html, &base());
+      assert_eq!(markdown, "链接", "{href}");
+    }
+    let (_, markdown, _) = convert(r#"<p><a href="mailto:a@example.com">邮件</a></p>"#, &base());
+    assert_eq!(markdown, "[邮件](mailto:a@example.com)");
+  }
+
+  #[test]
+  fn falls_back_to_title_and_heading() {
+    let html = "<html><head><title> 页面标题 </title></head><body><h1>正文标题</h1></body></html>";
+    assert_eq!(convert(html, &base()).0.as_deref(), Some("页面标题"));
+    let html = "<html><body><h1>正文标题</h1><p>内容</p></body></html>";
+    assert_eq!(convert(html, &base()).0.as_deref(), Some("正文标题"));
+  }
+
+  #[test]
+  fn decodes_declared_charset() {
+    let html = "<html><head><meta charset=\"gbk\"><title>中文标题</title></head></html>";
+    let (data, ..) = GBK.encode(html);
+    assert!(decode_html(&data, None).contains("中文标题"));
+    // Content-Type 优先于页面中的声明
+    let (data, ..) = SHIFT_JIS.encode("<meta charset=\"gbk\"><p>日本語</p>");
+    let decoded = decode_html(&data, Some("text/html; charset=Shift_JIS"));
+    assert!(decoded.contains("日本語"));
+
+    let html = r#"<meta http-equiv="Content-Type" content="text/html; charset=gb2312"><p>简体</p>"#;
+    let (data, ..) = GBK.encode(html);
+    assert!(decode_html(&data, Some("text/html")).contains("简体"));
+  }
+
+  #[test]
+  fn prefers_bom_and_defaults_to_utf8() {
+    let mut data = b"\xEF\xBB\xBF".to_vec();
+    data.extend_from_slice("<meta charset=\"gbk\"><p>统一码</p>".as_bytes());
+    assert!(decode_html(&data, Some("text/html; charset=gbk")).contains("统一码"));
+    assert_eq!(decode_html("<p>默认</p>".as_bytes(), None), "<p>默认</p>");
+  }
+
+  #[test]
+  fn limits_data_url_images() {
+    let policy = FetchPolicy {
+      max_bytes: 8,
+      allowed_content_types: vec!["image/".to_string()],
+      ..Default::default()
+    };
+    // "image" 的 base64
+    let data = decode_data_image("data:image/png;base64,aW1hZ2U=", &policy).unwrap();
+    assert_eq!(data, b"image");
+
+    let oversize = format!("data:image/png;base64,{}", "QUFB".repeat(4));
+    let e = decode_data_image(&oversize, &policy).unwrap_err();
+    assert!(matches!(e, Error::Fetch(FetchError::TooLarge(8))), "{e}");
+    let huge = format!("data:image/png;base64,{}", "QUFB".repeat(1 << 10));
+    let e = decode_data_image(&huge, &policy).unwrap_err();
+    assert!(matches!(e, Error::Fetch(FetchError::TooLarge(8))), "{e}");
+
+    let e = decode_data_image("data:text/html;base64,PHNjcmlwdD4=", &policy).unwrap_err();
+    assert!(matches!(e, Error::Fetch(FetchError::ContentType(_))), "{e}");
+  }
+}
@@ -3,8 +3,13 @@ mod database;
 mod emitter;
 mod error;
 mod files;
+mod sync;
 mod uri_scheme;
 
+#[cfg(feature = "bench")]
+#[doc(hidden)]
+pub use files::bench;
+
 use command::AppCommand;
 use std::path::PathBuf;
 use tauri::{Manager, Result};
@@ -87,6 +92,8 @@ pub fn run() {
         let window = win_builder.build()?;
         set_macos_title_bar(&window)?;
       }
+      #[cfg(not(target_os = "macos"))]
+      win_builder.build()?;
 
       // 设置全局事件通知器
       emitter::setup_emitter(app)?;
@@ -94,6 +101,10 @@ pub fn run() {
       // 设置数据库和向量引擎
       setup_work_dir(app)?;
       database::setup_database(app)?;
+      files::setup_chat_dir(app)?;
+      database::spawn_note_migration(app.handle());
+      database::spawn_chat_indexing(app.handle());
+      sync::setup_sync(app)?;
 
       Ok(())
     })
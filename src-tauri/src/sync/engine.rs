@@ -0,0 +1,1170 @@
+use super::merge::conflict_title;
+use super::s3::{ENCODE_SET, S3Client, sha256_hex};
+use super::{SyncSettings, SyncStatus};
+use crate::database::{
+  ChangeAction, ChangeEntity, Chat, DatabaseHandler, Note, Persona, new_note_id,
+};
+use crate::error::{Error, Result};
+use crate::files::{
+  ChatDir, ChatFile, ChatMessage, StoredMessage, SyncKey, SyncKeyParams, delete_note_files,
+};
+use percent_encoding::{percent_decode_str, utf8_percent_encode};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+use tauri::async_runtime::spawn_blocking;
+
+/// 同步密码的派生参数在存储桶中的对象名
+const KEY_PARAMS_OBJECT: &str = "sync-key.json";
+/// 上次同步时各对象的状态在设置中的名称
+const SYNC_STATE_KEY: &str = "s3_sync_state";
+/// 状态中最多列出的失败项
+const MAX_ERRORS: usize = 5;
+
+/// 上次同步完成时各对象的状态，用于区分本地和远程各自的修改
+#[derive(Default, Serialize, Deserialize)]
+struct SyncState {
+  objects: BTreeMap<String, SyncedObject>,
+  #[serde(default)]
+  local: LocalIndex,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct SyncedObject {
+  /// 同步时内容的哈希
+  hash: String,
+  /// 同步时远程对象的 ETag
+  etag: String,
+}
+
+/// 对话中的单条消息
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RemoteMessage {
+  index: u32,
+  parent_id: Option<String>,
+  message: Value,
+}
+
+/// 对话文件的元数据，对象内容为元数据 json、换行以及文件内容
+#[derive(Serialize, Deserialize)]
+struct RemoteFileMeta {
+  name: Option<String>,
+}
+
+/// 同步的一项内容，对应存储桶中 `<prefix>` 之后的对象名
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Item {
+  /// `notes/<id>.json`
+  Note(String),
+  /// `personas/<id>.json`
+  Persona(String),
+  /// `chats/<chat_id>/chat.json`
+  Chat(String),
+  /// `chats/<chat_id>/messages/<message_id>.json`
+  Message(String, String),
+  /// `chats/<chat_id>/files/<file_id>`
+  File(String, String),
+}
+
+fn segment(id: &str) -> String {
+  utf8_percent_encode(id, ENCODE_SET).to_string()
+}
+
+fn parse_segment(value: &str) -> Option<String> {
+  let id = percent_decode_str(value).decode_utf8().ok()?;
+  (!id.is_empty()).then(|| id.to_string())
+}
+
+impl Item {
+  fn parse(key: &str) -> Option<Self> {
+    let parts = key.split('/').collect::<Vec<_>>();
+    let json = |name: &str| parse_segment(name.strip_suffix(".json")?);
+    let item = match parts.as_slice() {
+      ["notes", name] => Self::Note(json(name)?),
+      ["personas", name] => Self::Persona(json(name)?),
+      ["chats", chat, "chat.json"] => Self::Chat(parse_segment(chat)?),
+      ["chats", chat, "messages", name] => Self::Message(parse_segment(chat)?, json(name)?),
+      ["chats", chat, "files", file] => Self::File(parse_segment(chat)?, parse_segment(file)?),
+      _ => return None,
+    };
+    Some(item)
+  }
+
+  fn key(&self) -> String {
+    match self {
+      Self::Note(id) => format!("notes/{}.json", segment(id)),
+      Self::Persona(id) => format!("personas/{}.json", segment(id)),
+      Self::Chat(id) => format!("{}chat.json", chat_prefix(id)),
+      Self::Message(chat, id) => format!("{}messages/{}.json", chat_prefix(chat), segment(id)),
+      Self::File(chat, id) => format!("{}files/{}", chat_prefix(chat), segment(id)),
+    }
+  }
+
+  fn chat_id(&self) -> Option<&str> {
+    match self {
+      Self::Chat(chat) | Self::Message(chat, _) | Self::File(chat, _) => Some(chat),
+      _ => None,
+    }
+  }
+}
+
+/// 对话中全部对象共同的前缀
+fn chat_prefix(chat_id: &str) -> String {
+  format!("chats/{}/", segment(chat_id))
+}
+
+/// 对话文件的哈希，由元数据和内容的 SHA-256 计算，只用于与上次同步时的状态比较
+fn file_hash(meta: &RemoteFileMeta, sha256: &str) -> Result<String> {
+  let mut data = serde_json::to_vec(meta)?;
+  data.push(b'\n');
+  data.extend_from_slice(sha256.as_bytes());
+  Ok(sha256_hex(&data))
+}
+
+/// 本地内容，文件只在上传时读取
+enum Payload {
+  Data(Vec<u8>),
+  File(ChatFile, RemoteFileMeta),
+}
+
+struct LocalItem {
+  item: Item,
+  hash: String,
+  /// 消息的 index，用于处理远程删除的消息
+  index: Option<u32>,
+  /// 上次收集后没有变化的内容为空，需要上传时重新读取
+  payload: Option<Payload>,
+}
+
+impl LocalItem {
+  fn data<T: Serialize>(item: Item, value: &T) -> Result<Self> {
+    let data = serde_json::to_vec(value)?;
+    Ok(Self {
+      item,
+      hash: sha256_hex(&data),
+      index: None,
+      payload: Some(Payload::Data(data)),
+    })
+  }
+}
+
+/// 上次收集的本地内容，之后按变更日志只重新读取修改过的笔记、面具和对话
+#[derive(Default, Serialize, Deserialize)]
+struct LocalIndex {
+  /// 已经处理的最后一条变更的序号，为空时需要读取全部内容
+  seq: Option<i64>,
+  items: BTreeMap<String, IndexedItem>,
+  /// 无法读取的对象名前缀，每次同步时重新读取
+  skipped: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct IndexedItem {
+  hash: String,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  index: Option<u32>,
+}
+
+/// 本地的全部内容，以及无法读取、本次不参与同步的对象名前缀
+#[derive(Default)]
+struct LocalItems {
+  items: BTreeMap<String, LocalItem>,
+  skipped: Vec<String>,
+}
+
+impl LocalItems {
+  fn insert(&mut self, item: LocalItem) {
+    self.items.insert(item.item.key(), item);
+  }
+
+  /// 设置了密码的笔记以及无法读取的对话不参与同步，也不会因为本地缺失而删除远程对象
+  fn is_skipped(&self, key: &str) -> bool {
+    self
+      .skipped
+      .iter()
+      .any(|it| key == it || (it.ends_with('/') && key.starts_with(it.as_str())))
+  }
+
+  fn hash(&self, key: &str) -> Option<&str> {
+    self.items.get(key).map(|it| it.hash.as_str())
+  }
+}
+
+async fn collect_chat(app_data: &Path, chat_id: &str, local: &mut LocalItems) -> Result<()> {
+  for node in ChatDir::new(app_data, chat_id)?.read_nodes().await? {
+    let StoredMessage {
+      index,
+      message_id,
+      parent_id,
+      message,
+    } = node;
+    let item = Item::Message(chat_id.to_string(), message_id);
+    let message = RemoteMessage {
+      index,
+      parent_id,
+      message,
+    };
+    local.insert(LocalItem {
+      index: Some(index),
+      ..LocalItem::data(item, &message)?
+    });
+  }
+  for file_id in ChatDir::new(app_data, chat_id)?.file_ids().await? {
+    let file = ChatFile::new(chat_id, &file_id);
+    let stat = file.clone().stat(app_data).await?;
+    let meta = RemoteFileMeta {
+      name: stat.name().map(str::to_string),
+    };
+    local.insert(LocalItem {
+      item: Item::File(chat_id.to_string(), file_id),
+      hash: file_hash(&meta, stat.sha256())?,
+      index: None,
+      payload: Some(Payload::File(file, meta)),
+    });
+  }
+  Ok(())
+}
+
+/// 读取单个笔记、面具或对话中的全部内容，`chat_dirs` 为本地存在的对话文件夹
+async fn collect_entity(
+  app_data: &Path,
+  db: &DatabaseHandler,
+  entity: &Item,
+  chat_dirs: &BTreeSet<String>,
+  local: &mut LocalItems,
+) -> Result<()> {
+  match entity {
+    Item::Note(id) => match db.find_note_detail(id).await {
+      Ok(None) => {}
+      Ok(Some(detail)) if !detail.locked => {
+        local.insert(LocalItem::data(entity.clone(), &detail.note)?)
+      }
+      _ => local.skipped.push(entity.key()),
+    },
+    Item::Persona(id) => {
+      let personas = db.find_all_personas().await?;
+      if let Some(persona) = personas.iter().find(|it| &it.id == id) {
+        local.insert(LocalItem::data(entity.clone(), persona)?);
+      }
+    }
+    Item::Chat(id) => {
+      if let Some(chat) = db.find_chat_by_id(id).await? {
+        local.insert(LocalItem::data(entity.clone(), &chat)?);
+      }
+      // 存储未解锁等原因无法读取的对话整体跳过
+      let mut chat = LocalItems::default();
+      if chat_dirs.contains(id) {
+        match collect_chat(app_data, id, &mut chat).await {
+          Ok(()) => local.items.extend(chat.items),
+          Err(_) => local.skipped.push(chat_prefix(id)),
+        }
+      }
+    }
+    Item::Message(..) | Item::File(..) => {}
+  }
+  Ok(())
+}
+
+/// 序号大于 `seq` 的变更涉及的笔记、面具和对话，以及最后一条变更的序号
+async fn changed_entities(db: &DatabaseHandler, mut seq: i64) -> Result<(i64, BTreeSet<Item>)> {
+  let mut changed = BTreeSet::new();
+  loop {
+    let changes = db.changes_since(seq, None).await?;
+    let Some(last) = changes.last() else {
+      break;
+    };
+    seq = last.seq;
+    changed.extend(changes.into_iter().map(|it| match it.entity {
+      ChangeEntity::Note => Item::Note(it.entity_id),
+      ChangeEntity::Persona => Item::Persona(it.entity_id),
+      ChangeEntity::Chat => Item::Chat(it.entity_id),
+    }));
+  }
+  Ok((seq, changed))
+}
+
+/// 收集本地内容，`index` 中有上次收集的结果时只重新读取之后修改过的内容
+///
+/// 没有变化的内容只有哈希，需要上传时再读取；笔记中引用的文件（如剪藏网页时保存的图片）
+/// 只保存在本机，不参与同步，其他设备上的笔记中无法显示这些文件
+async fn collect_local(
+  app_data: &Path,
+  db: &DatabaseHandler,
+  index: &mut LocalIndex,
+) -> Result<LocalItems> {
+  // 先读取变更序号再读取内容，期间的修改在下次同步时重新读取
+  let (seq, entities) = match index.seq {
+    Some(seq) => {
+      let (seq, mut entities) = changed_entities(db, seq).await?;
+      // 上次无法读取的内容重新读取
+      entities.extend(
+        index
+          .skipped
+          .iter()
+          .filter_map(|key| Item::parse(key).or_else(|| Item::parse(&format!("{key}chat.json")))),
+      );
+      (seq, entities)
+    }
+    None => {
+      let seq = db.last_change_seq().await?;
+      let mut entities = BTreeSet::new();
+      for summary in db.find_all_notes().await? {
+        entities.insert(Item::Note(summary.id));
+      }
+      for persona in db.find_all_personas().await? {
+        entities.insert(Item::Persona(persona.id));
+      }
+      for chat in db.find_all_chats().await? {
+        entities.insert(Item::Chat(chat.id));
+      }
+      for chat_id in ChatDir::list_ids(app_data).await? {
+        entities.insert(Item::Chat(chat_id));
+      }
+      index.items.clear();
+      (seq, entities)
+    }
+  };
+
+  let chat_dirs = ChatDir::list_ids(app_data).await?.into_iter().collect();
+  let mut fresh = LocalItems::default();
+  for entity in &entities {
+    let key = entity.key();
+    match entity {
+      Item::Chat(id) => {
+        let prefix = chat_prefix(id);
+        index.items.retain(|it, _| !it.starts_with(&prefix));
+      }
+      _ => {
+        index.items.remove(&key);
+      }
+    }
+    collect_entity(app_data, db, entity, &chat_dirs, &mut fresh).await?;
+  }
+
+  for (key, it) in &fresh.items {
+    let item = IndexedItem {
+      hash: it.hash.clone(),
+      index: it.index,
+    };
+    index.items.insert(key.clone(), item);
+  }
+  index.skipped = fresh.skipped.clone();
+  index.seq = Some(seq);
+
+  let mut local = LocalItems {
+    skipped: fresh.skipped,
+    ..Default::default()
+  };
+  for (key, it) in &index.items {
+    let Some(item) = Item::parse(key) else {
+      continue;
+    };
+    local.insert(LocalItem {
+      item,
+      hash: it.hash.clone(),
+      index: it.index,
+      payload: None,
+    });
+  }
+  local.items.extend(fresh.items);
+  Ok(local)
+}
+
+/// 从远程下载的内容
+enum Pulled {
+  Note(Note),
+  Persona(Persona),
+  Chat(Chat),
+  Message(String, String, RemoteMessage),
+  File(String, String, RemoteFileMeta, Vec<u8>),
+}
+
+impl Pulled {
+  /// 解析下载的内容，返回内容以及与本地一致的哈希
+  fn parse(item: Item, data: Vec<u8>) -> Result<(Self, String)> {
+    let hash = sha256_hex(&data);
+    let pulled = match item {
+      Item::Note(_) => Self::Note(serde_json::from_slice(&data)?),
+      Item::Persona(_) => Self::Persona(serde_json::from_slice(&data)?),
+      Item::Chat(_) => Self::Chat(serde_json::from_slice(&data)?),
+      Item::Message(chat, id) => Self::Message(chat, id, serde_json::from_slice(&data)?),
+      Item::File(chat, id) => {
+        let split = data.iter().position(|it| *it == b'\n');
+        let split = split.ok_or_else(|| Error::new("对话文件的格式错误"))?;
+        let meta: RemoteFileMeta = serde_json::from_slice(&data[..split])?;
+        let content = data[split + 1..].to_vec();
+        let hash = file_hash(&meta, &sha256_hex(&content))?;
+        return Ok((Self::File(chat, id, meta, content), hash));
+      }
+    };
+    Ok((pulled, hash))
+  }
+
+  /// 写入顺序：父消息先于子消息，对话先于其中的文件
+  fn order(&self) -> (u8, u32) {
+    match self {
+      Self::Note(_) => (0, 0),
+      Self::Persona(_) => (1, 0),
+      Self::Chat(_) => (2, 0),
+      Self::Message(_, _, message) => (3, message.index),
+      Self::File(..) => (4, 0),
+    }
+  }
+}
+
+/// 读取同步密码的派生参数并校验密码，`create` 时存储桶中没有参数则生成新的参数
+async fn sync_key(
+  client: &S3Client,
+  prefix: &str,
+  passphrase: &str,
+  create: bool,
+) -> Result<Option<SyncKey>> {
+  if passphrase.is_empty() {
+    return Err(Error::new("请先设置同步密码"));
+  }
+  let name = format!("{prefix}{KEY_PARAMS_OBJECT}");
+  let passphrase = passphrase.to_string();
+  match client.get(&name).await? {
+    Some((data, _)) => {
+      let params: SyncKeyParams = serde_json::from_slice(&data)?;
+      let key = spawn_blocking(move || params.unlock(&passphrase)).await?;
+      key
+        .map(Some)
+        .map_err(|e| Error::new(format!("同步{e}，所有设备需要使用同一同步密码")))
+    }
+    None if create => {
+      let (params, key) = spawn_blocking(move || SyncKeyParams::new(&passphrase)).await??;
+      client.put(&name, serde_json::to_vec(&params)?).await?;
+      Ok(Some(key))
+    }
+    None => Ok(None),
+  }
+}
+
+/// 使用设置连接存储桶，存储桶中已有同步数据时同时校验同步密码
+pub async fn check_connection(settings: &SyncSettings) -> Result<()> {
+  let client = S3Client::new(settings)?;
+  sync_key(&client, &settings.key_prefix(), &settings.passphrase, false).await?;
+  Ok(())
+}
+
+struct Context<'a> {
+  app_data: &'a Path,
+  db: &'a DatabaseHandler,
+  client: S3Client,
+  key: SyncKey,
+  prefix: String,
+  state: SyncState,
+  status: SyncStatus,
+  errors: Vec<String>,
+}
+
+impl Context<'_> {
+  fn failed(&mut self, key: &str, e: Error) {
+    self.errors.push(format!("{key}: {e}"));
+  }
+
+  async fn apply(&self, pulled: Pulled) -> Result<()> {
+    match pulled {
+      Pulled::Note(note) => match self.db.find_note_by_id(&note.id).await? {
+        Some(_) => self.db.update_note(&note).await?,
+        None => self.db.insert_note(&note).await?,
+      },
+      Pulled::Persona(persona) => self.db.save_persona(persona).await?,
+      Pulled::Chat(chat) => {
+        let chat_id = chat.id.clone();
+        self.db.save_chat(chat).await?;
+        self
+          .db
+          .log_change(ChangeEntity::Chat, &chat_id, None, ChangeAction::Update)
+          .await?;
+      }
+      Pulled::Message(chat_id, message_id, remote) => {
+        let message = ChatMessage::new(
+          &chat_id,
+          remote.index,
+          message_id.clone(),
+          remote.parent_id,
+          remote.message,
+        );
+        let text = message.text();
+        let action = message.overwrite().save(self.app_data).await?;
+        self
+          .db
+          .index_chat_message(&chat_id, &message_id, remote.index, &text)
+          .await?;
+        self
+          .db
+          .log_change(
+            ChangeEntity::Chat,
+            &chat_id,
+            Some(&message_id),
+            action.unwrap_or(ChangeAction::Update),
+          )
+          .await?;
+      }
+      Pulled::File(chat_id, file_id, meta, data) => {
+        let file = ChatFile::synced(&chat_id, &file_id, meta.name, data);
+        file.save(self.app_data, self.db).await?;
+        self
+          .db
+          .log_change(
+            ChangeEntity::Chat,
+            &chat_id,
+            Some(&file_id),
+            ChangeAction::Insert,
+          )
+          .await?;
+      }
+    }
+    Ok(())
+  }
+
+  /// 两端都修改过的对象，将远程的版本另存为副本，返回 `false` 表示该内容不能另存副本
+  ///
+  /// 笔记另存为冲突笔记，面具另存为新的面具，消息另存为同一父消息下的分支
+  async fn save_conflict_copy(&self, pulled: Pulled) -> Result<bool> {
+    match pulled {
+      Pulled::Note(note) => {
+        let copy = Note {
+          id: new_note_id(),
+          title: conflict_title(self.db, &note).await?,
+          ..note
+        };
+        self.db.insert_note(&copy).await?;
+      }
+      Pulled::Persona(persona) => {
+        let ids = self
+          .db
+          .find_all_personas()
+          .await?
+          .into_iter()
+          .map(|it| it.id)
+          .collect::<BTreeSet<_>>();
+        let id = (1..)
+          .map(|n| match n {
+            1 => format!("{}（冲突）", persona.id),
+            n => format!("{}（冲突 {n}）", persona.id),
+          })
+          .find(|it| !ids.contains(it))
+          .unwrap_or_default();
+        self.db.save_persona(Persona { id, ..persona }).await?;
+      }
+      Pulled::Message(chat_id, message_id, remote) => {
+        let copy_id = format!("msg-{}", uuid::Uuid::new_v4().simple());
+        let mut message = remote.message;
+        if let Some(id) = message.get_mut("id") {
+          *id = Value::String(copy_id.clone());
+        }
+        let copy = ChatMessage::new(
+          &chat_id,
+          remote.index,
+          copy_id.clone(),
+          remote.parent_id,
+          message,
+        );
+        let text = copy.text();
+        copy.save(self.app_data).await?;
+        // 新保存的消息会成为激活分支，切换回本机的版本
+        ChatDir::new(self.app_data, &chat_id)?
+          .switch_branch(message_id)
+          .await?;
+        self
+          .db
+          .index_chat_message(&chat_id, &copy_id, remote.index, &text)
+          .await?;
+        self
+          .db
+          .log_change(
+            ChangeEntity::Chat,
+            &chat_id,
+            Some(&copy_id),
+            ChangeAction::Insert,
+          )
+          .await?;
+      }
+      Pulled::Chat(_) | Pulled::File(..) => return Ok(false),
+    }
+    Ok(true)
+  }
+
+  /// 处理两端都修改过的对象，返回无法处理、本次不能上传的对象
+  ///
+  /// 远程版本另存为副本后，本地版本在上传时覆盖远程对象；不能另存副本的内容两端各自保留，
+  /// 不覆盖远程对象，之后任一端再次修改时按普通的修改同步
+  async fn resolve_conflicts(
+    &mut self,
+    conflicts: Vec<(String, (Pulled, String, String))>,
+    local: &LocalItems,
+  ) -> Vec<String> {
+    let mut unresolved = Vec::new();
+    for (key, (value, hash, etag)) in conflicts {
+      let object = match self.save_conflict_copy(value).await {
+        Ok(true) => SyncedObject { hash, etag },
+        Ok(false) => SyncedObject {
+          hash: local.hash(&key).unwrap_or_default().to_string(),
+          etag,
+        },
+        Err(e) => {
+          self.failed(&key, e);
+          unresolved.push(key);
+          continue;
+        }
+      };
+      self.state.objects.insert(key.clone(), object);
+      self.status.conflicts.push(key);
+    }
+    unresolved
+  }
+
+  /// 下载远程修改过的对象，本地也修改过的对象保留本地的版本，远程的版本另存为副本
+  ///
+  /// 返回无法处理冲突的对象，这些对象本次不上传，避免覆盖远程的修改
+  async fn pull(&mut self, remote: &BTreeMap<String, String>, local: &LocalItems) -> Vec<String> {
+    let mut pulled = Vec::new();
+    let mut conflicts = Vec::new();
+    for (key, etag) in remote {
+      let known = self.state.objects.get(key);
+      if local.is_skipped(key) || known.is_some_and(|it| &it.etag == etag) {
+        continue;
+      }
+      let local_hash = local.hash(key);
+      let local_changed = match (known, local_hash) {
+        (_, None) => false,
+        (Some(known), Some(hash)) => known.hash != hash,
+        (None, Some(_)) => true,
+      };
+
+      let result = async {
+        let Some((data, etag)) = self.client.get(&format!("{}{key}", self.prefix)).await? else {
+          return Ok(None);
+        };
+        let item = Item::parse(key).ok_or_else(|| Error::new("无法识别的对象"))?;
+        let data = self.key.open(&data)?;
+        let (value, hash) = Pulled::parse(item, data)?;
+        Ok::<_, Error>(Some((value, hash, etag)))
+      }
+      .await;
+      match result {
+        Ok(Some((_, hash, etag))) if local_hash == Some(hash.as_str()) => {
+          let object = SyncedObject { hash, etag };
+          self.state.objects.insert(key.clone(), object);
+        }
+        Ok(Some(it)) if local_changed => conflicts.push((key.clone(), it)),
+        Ok(Some(it)) => pulled.push((key.clone(), it)),
+        Ok(None) => {}
+        Err(e) => self.failed(key, e),
+      }
+    }
+
+    pulled.sort_by_key(|(_, (value, ..))| value.order());
+    for (key, (value, hash, etag)) in pulled {
+      match self.apply(value).await {
+        Ok(()) => {
+          self.state.objects.insert(key, SyncedObject { hash, etag });
+          self.status.downloaded += 1;
+        }
+        Err(e) => self.failed(&key, e),
+      }
+    }
+    self.resolve_conflicts(conflicts, local).await
+  }
+
+  /// 删除对话或其中的消息，之后的消息在本地有新的修改时保留整个对话
+  async fn remove_chat_items(
+    &mut self,
+    chat_id: &str,
+    removed: &BTreeSet<String>,
+    local: &mut LocalItems,
+  ) -> Result<()> {
+    let prefix = chat_prefix(chat_id);
+    let chat_removed = removed.contains(&Item::Chat(chat_id.to_string()).key());
+    let from_index = match chat_removed {
+      true => Some(0),
+      false => removed
+        .iter()
+        .filter_map(|key| local.items.get(key)?.index)
+        .min(),
+    };
+    let affected = local.items.iter().filter(|(key, it)| {
+      key.starts_with(&prefix)
+        && match (&it.item, from_index) {
+          (Item::Message(..), Some(from)) => it.index.is_some_and(|it| it >= from),
+          (Item::Message(..), None) => false,
+          _ => chat_removed || removed.contains(*key),
+        }
+    });
+    let affected = affected.map(|(key, _)| key.clone()).collect::<Vec<_>>();
+    if affected.iter().any(|key| !removed.contains(key)) {
+      self.status.conflicts.push(prefix);
+      return Ok(());
+    }
+
+    match (chat_removed, from_index) {
+      (true, _) => {
+        ChatDir::new(self.app_data, chat_id)?.delete().await?;
+        self.db.remove_chat_index(chat_id, 0).await?;
+        self.db.delete_chat_by_id(chat_id).await?;
+        self
+          .db
+          .log_change(ChangeEntity::Chat, chat_id, None, ChangeAction::Delete)
+          .await?;
+      }
+      (false, Some(from)) => {
+        ChatDir::new(self.app_data, chat_id)?.truncate(from).await?;
+        self.db.remove_chat_index(chat_id, from).await?;
+        self
+          .db
+          .log_change(ChangeEntity::Chat, chat_id, None, ChangeAction::Update)
+          .await?;
+      }
+      (false, None) => {}
+    }
+    for key in affected {
+      if let Some(LocalItem {
+        item: Item::File(chat, file),
+        ..
+      }) = local.items.get(&key)
+        && !chat_removed
+      {
+        ChatFile::new(chat, file).delete(self.app_data).await?;
+        self
+          .db
+          .log_change(ChangeEntity::Chat, chat, Some(file), ChangeAction::Delete)
+          .await?;
+      }
+      local.items.remove(&key);
+      self.status.deleted += 1;
+    }
+    Ok(())
+  }
+
+  /// 删除远程已经删除、本地没有再修改的内容
+  async fn remove_deleted(&mut self, remote: &BTreeMap<String, String>, local: &mut LocalItems) {
+    let gone = self
+      .state
+      .objects
+      .keys()
+      .filter(|key| !remote.contains_key(*key) && !local.is_skipped(key))
+      .cloned()
+      .collect::<Vec<_>>();
+    let mut removed = BTreeSet::new();
+    for key in gone {
+      // 本地也已删除，或者本地有新的修改需要重新上传
+      if let Some(known) = self.state.objects.remove(&key)
+        && local.hash(&key) == Some(known.hash.as_str())
+      {
+        removed.insert(key);
+      }
+    }
+
+    let mut chats = BTreeSet::new();
+    for key in &removed {
+      let Some(item) = local.items.get(key).map(|it| it.item.clone()) else {
+        continue;
+      };
+      if let Some(chat_id) = item.chat_id() {
+        chats.insert(chat_id.to_string());
+        continue;
+      }
+      let result = match &item {
+        Item::Note(id) => {
+          async {
+            self.db.delete_note_by_id(id).await?;
+            delete_note_files(self.app_data, id).await
+          }
+          .await
+        }
+        Item::Persona(id) => self.db.delete_persona_by_id(id).await,
+        _ => Ok(()),
+      };
+      match result {
+        Ok(()) => {
+          local.items.remove(key);
+          self.status.deleted += 1;
+        }
+        Err(e) => self.failed(key, e),
+      }
+    }
+    for chat_id in chats {
+      if let Err(e) = self.remove_chat_items(&chat_id, &removed, local).await {
+        self.failed(&chat_prefix(&chat_id), e);
+      }
+    }
+  }
+
+  /// 读取上次收集后没有变化、本次需要上传的内容，返回最新的哈希和内容
+  async fn reload(&self, item: &Item) -> Result<(String, Payload)> {
+    let entity = match item {
+      Item::Message(chat_id, _) | Item::File(chat_id, _) => Item::Chat(chat_id.clone()),
+      it => it.clone(),
+    };
+    let chat_dirs = ChatDir::list_ids(self.app_data).await?;
+    let chat_dirs = chat_dirs.into_iter().collect();
+    let mut local = LocalItems::default();
+    collect_entity(self.app_data, self.db, &entity, &chat_dirs, &mut local).await?;
+    local
+      .items
+      .remove(&item.key())
+      .and_then(|it| Some((it.hash, it.payload?)))
+      .ok_or_else(|| Error::new("本地内容已经删除"))
+  }
+
+  /// 上传本地修改过的内容
+  async fn push(&mut self, remote: &BTreeMap<String, String>, local: LocalItems) {
+    for (key, item) in local.items {
+      let known = self.state.objects.get(&key);
+      if known.is_some_and(|it| it.hash == item.hash) && remote.contains_key(&key) {
+        continue;
+      }
+      let result = async {
+        let (hash, payload) = match item.payload {
+          Some(payload) => (item.hash, payload),
+          None => self.reload(&item.item).await?,
+        };
+        let data = match payload {
+          Payload::Data(data) => data,
+          Payload::File(file, meta) => {
+            let mut data = serde_json::to_vec(&meta)?;
+            data.push(b'\n');
+            data.extend(file.read(self.app_data).await?);
+            data
+          }
+        };
+        let name = format!("{}{key}", self.prefix);
+        let etag = self.client.put(&name, self.key.seal(&data)?).await?;
+        Ok::<_, Error>((hash, etag))
+      }
+      .await;
+      match result {
+        Ok((hash, etag)) => {
+          if let Some(it) = self.state.local.items.get_mut(&key) {
+            it.hash.clone_from(&hash);
+          }
+          let object = SyncedObject { hash, etag };
+          self.state.objects.insert(key, object);
+          self.status.uploaded += 1;
+        }
+        Err(e) => self.failed(&key, e),
+      }
+    }
+  }
+
+  /// 删除本地已经删除、远程没有再修改的对象
+  async fn push_deleted(&mut self, remote: &BTreeMap<String, String>, local: &BTreeSet<String>) {
+    let deleted = self
+      .state
+      .objects
+      .iter()
+      .filter(|(key, it)| !local.contains(*key) && remote.get(*key) == Some(&it.etag))
+      .map(|(key, _)| key.clone())
+      .collect::<Vec<_>>();
+    for key in deleted {
+      match self.client.delete(&format!("{}{key}", self.prefix)).await {
+        Ok(()) => {
+          self.state.objects.remove(&key);
+          self.status.deleted += 1;
+        }
+        Err(e) => self.failed(&key, e),
+      }
+    }
+  }
+
+  async fn run(&mut self, remote: BTreeMap<String, String>, mut local: LocalItems) {
+    let unresolved = self.pull(&remote, &local).await;
+    // 拉取后重新读取本地内容，下载的内容不需要再上传，冲突的副本需要上传
+    if self.status.downloaded > 0 || !self.status.conflicts.is_empty() {
+      match collect_local(self.app_data, self.db, &mut self.state.local).await {
+        Ok(it) => local = it,
+        Err(e) => return self.failed("local", e),
+      }
+    }
+    local.skipped.extend(unresolved);
+    self.remove_deleted(&remote, &mut local).await;
+
+    // 无法读取的内容既不上传也不删除
+    let mut present = local.items.keys().cloned().collect::<BTreeSet<_>>();
+    present.extend(
+      self
+        .state
+        .objects
+        .keys()
+        .filter(|key| local.is_skipped(key))
+        .cloned(),
+    );
+    self.push(&remote, local).await;
+    self.push_deleted(&remote, &present).await;
+  }
+}
+
+/// 与存储桶双向同步一次：先拉取远程的修改和删除，再上传本地的修改和删除
+///
+/// 同一对象在两端都修改过时保留本地的版本，远程的版本另存为副本并记录在冲突中
+pub(super) async fn sync(
+  app_data: &Path,
+  db: &DatabaseHandler,
+  settings: &SyncSettings,
+) -> Result<SyncStatus> {
+  let client = S3Client::new(settings)?;
+  let prefix = settings.key_prefix();
+  let key = sync_key(&client, &prefix, &settings.passphrase, true)
+    .await?
+    .ok_or_else(|| Error::new("无法生成同步密钥"))?;
+  let remote = client
+    .list(&prefix)
+    .await?
+    .into_iter()
+    .filter_map(|it| Some((it.key.strip_prefix(&prefix)?.to_string(), it.etag)))
+    .filter(|(key, _)| Item::parse(key).is_some())
+    .collect::<BTreeMap<_, _>>();
+  let mut state: SyncState = db.get_setting(SYNC_STATE_KEY).await?;
+  let local = collect_local(app_data, db, &mut state.local).await?;
+
+  let mut context = Context {
+    app_data,
+    db,
+    client,
+    key,
+    prefix,
+    state,
+    status: SyncStatus::default(),
+    errors: Vec::new(),
+  };
+  context.run(remote, local).await;
+  db.save_setting(SYNC_STATE_KEY, &context.state).await?;
+
+  let mut status = context.status;
+  if !context.errors.is_empty() {
+    let mut error = context.errors[..context.errors.len().min(MAX_ERRORS)].join("\n");
+    if context.errors.len() > MAX_ERRORS {
+      error.push_str(&format!("\n等 {} 项同步失败", context.errors.len()));
+    }
+    status.error = Some(error);
+  }
+  Ok(status)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::super::s3_stub::test_settings;
+  use super::*;
+  use std::path::PathBuf;
+  use tauri::async_runtime::block_on;
+
+  /// 一台同步的设备，使用单独的数据文件夹和数据库
+  struct Device {
+    app_data: PathBuf,
+    db: DatabaseHandler,
+  }
+
+  impl Device {
+    async fn new() -> Self {
+      let app_data =
+        std::env::temp_dir().join(format!("note-secretary-{}", uuid::Uuid::new_v4().simple()));
+      std::fs::create_dir_all(&app_data).unwrap();
+      let db = DatabaseHandler::open(&app_data).await.unwrap();
+      Self { app_data, db }
+    }
+
+    async fn sync(&self, settings: &SyncSettings) -> SyncStatus {
+      let status = sync(&self.app_data, &self.db, settings).await.unwrap();
+      assert_eq!(status.error, None);
+      status
+    }
+
+    async fn content(&self, id: &str) -> Option<String> {
+      let note = self.db.find_note_by_id(id).await.unwrap();
+      note.map(|it| it.content)
+    }
+
+    /// 保存对话并记录变更，与导入对话时一致
+    async fn save_chat(&self, title: &str) {
+      self.db.save_chat(chat(title)).await.unwrap();
+      self
+        .db
+        .log_change(ChangeEntity::Chat, "chat-1", None, ChangeAction::Update)
+        .await
+        .unwrap();
+    }
+
+    async fn chat_title(&self) -> String {
+      let chat = self.db.find_chat_by_id("chat-1").await.unwrap();
+      chat.unwrap().title
+    }
+
+    /// 按标题排序的全部笔记的标题和正文
+    async fn notes(&self) -> Vec<(String, String)> {
+      let mut notes = Vec::new();
+      for summary in self.db.find_all_notes().await.unwrap() {
+        let content = self.content(&summary.id).await.unwrap();
+        notes.push((summary.title, content));
+      }
+      notes.sort();
+      notes
+    }
+  }
+
+  impl Drop for Device {
+    fn drop(&mut self) {
+      let _ = std::fs::remove_dir_all(&self.app_data);
+    }
+  }
+
+  fn note(content: &str) -> Note {
+    Note {
+      id: new_note_id(),
+      category: "默认".to_string(),
+      title: "笔记".to_string(),
+      summary: String::new(),
+      content: content.to_string(),
+    }
+  }
+
+  fn chat(title: &str) -> Chat {
+    Chat {
+      id: "chat-1".to_string(),
+      title: title.to_string(),
+      created_at: 1,
+      updated_at: 1,
+      source: None,
+    }
+  }
+
+  /// 两台设备都同步了同一篇笔记
+  async fn synced_note(settings: &SyncSettings) -> (Device, Device, Note) {
+    let (a, b) = (Device::new().await, Device::new().await);
+    let note = note("原文\n");
+    a.db.insert_note(&note).await.unwrap();
+    a.sync(settings).await;
+    b.sync(settings).await;
+    (a, b, note)
+  }
+
+  #[test]
+  fn uploads_and_downloads_changes() {
+    let settings = test_settings();
+    block_on(async {
+      let (a, b) = (Device::new().await, Device::new().await);
+      let note = note("原文\n");
+      a.db.insert_note(&note).await.unwrap();
+      assert_eq!(a.sync(&settings).await.uploaded, 1);
+
+      assert_eq!(b.sync(&settings).await.downloaded, 1);
+      assert_eq!(b.content(&note.id).await.as_deref(), Some("原文\n"));
+
+      // 下载的内容不再上传，没有修改时不再传输
+      for device in [&a, &b] {
+        let status = device.sync(&settings).await;
+        assert_eq!((status.uploaded, status.downloaded), (0, 0));
+      }
+
+      b.db.update_note_content(&note.id, "修改\n").await.unwrap();
+      assert_eq!(b.sync(&settings).await.uploaded, 1);
+      assert_eq!(a.sync(&settings).await.downloaded, 1);
+      assert_eq!(a.content(&note.id).await.as_deref(), Some("修改\n"));
+    });
+  }
+
+  #[test]
+  fn deletes_on_both_sides() {
+    let settings = test_settings();
+    block_on(async {
+      let (a, b, note) = synced_note(&settings).await;
+      b.db.delete_note_by_id(&note.id).await.unwrap();
+      assert_eq!(b.sync(&settings).await.deleted, 1);
+
+      assert_eq!(a.sync(&settings).await.deleted, 1);
+      assert_eq!(a.content(&note.id).await, None);
+      let status = b.sync(&settings).await;
+      assert_eq!(
+        (status.uploaded, status.downloaded, status.deleted),
+        (0, 0, 0)
+      );
+    });
+  }
+
+  #[test]
+  fn keeps_local_change_to_remotely_deleted_note() {
+    let settings = test_settings();
+    block_on(async {
+      let (a, b, note) = synced_note(&settings).await;
+      a.db.delete_note_by_id(&note.id).await.unwrap();
+      a.sync(&settings).await;
+
+      b.db.update_note_content(&note.id, "修改\n").await.unwrap();
+      let status = b.sync(&settings).await;
+      assert_eq!((status.uploaded, status.deleted), (1, 0));
+      assert_eq!(b.content(&note.id).await.as_deref(), Some("修改\n"));
+
+      assert_eq!(a.sync(&settings).await.downloaded, 1);
+      assert_eq!(a.content(&note.id).await.as_deref(), Some("修改\n"));
+    });
+  }
+
+  #[test]
+  fn saves_remote_version_of_conflicting_note() {
+    let settings = test_settings();
+    block_on(async {
+      let (a, b, note) = synced_note(&settings).await;
+      a.db
+        .update_note_content(&note.id, "本机 A\n")
+        .await
+        .unwrap();
+      b.db
+        .update_note_content(&note.id, "本机 B\n")
+        .await
+        .unwrap();
+      a.sync(&settings).await;
+
+      // B 保留自己的版本，A 的版本另存为冲突笔记，两者都上传
+      let status = b.sync(&settings).await;
+      assert_eq!(status.conflicts, vec![Item::Note(note.id.clone()).key()]);
+      assert_eq!(status.uploaded, 2);
+      assert_eq!(b.content(&note.id).await.as_deref(), Some("本机 B\n"));
+      let expected = vec![
+        ("笔记".to_string(), "本机 B\n".to_string()),
+        ("笔记（冲突）".to_string(), "本机 A\n".to_string()),
+      ];
+      assert_eq!(b.notes().await, expected);
+
+      assert_eq!(a.sync(&settings).await.downloaded, 2);
+      assert_eq!(a.notes().await, expected);
+    });
+  }
+
+  #[test]
+  fn keeps_both_versions_of_conflicting_chat() {
+    let settings = test_settings();
+    block_on(async {
+      let (a, b) = (Device::new().await, Device::new().await);
+      a.save_chat("原标题").await;
+      a.sync(&settings).await;
+      b.sync(&settings).await;
+
+      a.save_chat("A 的标题").await;
+      b.save_chat("B 的标题").await;
+      a.sync(&settings).await;
+
+      // 对话不能另存副本，两边各自保留，不覆盖远程的对象
+      let status = b.sync(&settings).await;
+      assert_eq!(
+        status.conflicts,
+        vec![Item::Chat("chat-1".to_string()).key()]
+      );
+      assert_eq!((status.uploaded, status.downloaded), (0, 0));
+      let status = a.sync(&settings).await;
+      assert_eq!((status.uploaded, status.downloaded), (0, 0));
+
+      assert_eq!(a.chat_title().await, "A 的标题");
+      assert_eq!(b.chat_title().await, "B 的标题");
+
+      // 之后再修改时按普通的修改同步
+      b.save_chat("新标题").await;
+      assert_eq!(b.sync(&settings).await.uploaded, 1);
+      assert_eq!(a.sync(&settings).await.downloaded, 1);
+      assert_eq!(a.chat_title().await, "新标题");
+    });
+  }
+}
@@ -0,0 +1,394 @@
+use crate::database::{DatabaseHandler, Note, new_note_id};
+use crate::error::{Error, Result};
+use serde::{Deserialize, Serialize};
+use similar::{Algorithm, DiffOp, capture_diff_slices};
+
+const LOCAL_MARKER: &str = "<<<<<<< 本机";
+const SEPARATOR_MARKER: &str = "=======";
+const REMOTE_MARKER: &str = ">>>>>>> 远程";
+
+/// 两边都修改了同一段内容时的处理方式
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ConflictStyle {
+  /// 两边的内容都保留，使用 `<<<<<<<`、`=======`、`>>>>>>>` 标记
+  #[default]
+  Markers,
+  /// 使用本机的内容
+  Local,
+  /// 使用远程的内容
+  Remote,
+}
+
+/// 无法自动合并的一段内容
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MergeConflict {
+  /// 在合并结果中的起始行号，从 1 开始
+  pub line: usize,
+  pub base: String,
+  pub local: String,
+  pub remote: String,
+}
+
+/// 三方合并的结果
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MergeResult {
+  pub content: String,
+  /// 为空时全部修改都已自动合并
+  pub conflicts: Vec<MergeConflict>,
+}
+
+impl MergeResult {
+  pub fn is_clean(&self) -> bool {
+    self.conflicts.is_empty()
+  }
+}
+
+fn is_blank(line: &str) -> bool {
+  line.trim().is_empty()
+}
+
+/// `base` 中每一行在 `other` 中对应的行，修改或删除的行为空
+fn matches(base: &[&str], other: &[&str]) -> Vec<Option<usize>> {
+  let mut result = vec![None; base.len()];
+  for op in capture_diff_slices(Algorithm::Myers, base, other) {
+    if let DiffOp::Equal {
+      old_index,
+      new_index,
+      len,
+    } = op
+    {
+      for offset in 0..len {
+        result[old_index + offset] = Some(new_index + offset);
+      }
+    }
+  }
+  result
+}
+
+/// 合并时逐段写入的内容
+struct Output {
+  lines: Vec<String>,
+}
+
+impl Output {
+  /// 追加若干行，上一行没有换行符时先补上
+  fn push<'a>(&mut self, lines: impl IntoIterator<Item = &'a str>) {
+    for line in lines {
+      self.end_line();
+      self.lines.push(line.to_string());
+    }
+  }
+
+  /// 为最后一行补上换行符
+  fn end_line(&mut self) {
+    if let Some(last) = self.lines.last_mut()
+      && !last.ends_with('\n')
+    {
+      last.push('\n');
+    }
+  }
+
+  fn ends_with_blank(&self) -> bool {
+    self.lines.last().is_none_or(|it| is_blank(it))
+  }
+}
+
+/// 两边都在段落之间插入了新内容时依次保留两边的内容
+///
+/// 插入位置在段落中间时不能确定先后关系，按冲突处理；原文为空时两边各自写入了整篇内容，
+/// 同样按冲突处理，不在这里合并
+fn merge_inserts(output: &mut Output, local: &[&str], remote: &[&str], at_boundary: bool) -> bool {
+  let starts_paragraph =
+    |lines: &[&str]| at_boundary || lines.first().is_some_and(|it| is_blank(it));
+  if !starts_paragraph(local) || !starts_paragraph(remote) {
+    return false;
+  }
+  output.push(local.iter().copied());
+  if !output.ends_with_blank() && !remote.first().is_some_and(|it| is_blank(it)) {
+    output.push(["\n"]);
+  }
+  output.push(remote.iter().copied());
+  true
+}
+
+/// 按行对 markdown 进行三方合并
+///
+/// 只有一边修改的内容自动合并，两边都在段落之间插入的内容都保留，
+/// 两边对同一段内容做了不同的修改时按 `style` 处理并记录为冲突
+pub fn merge_text(base: &str, local: &str, remote: &str, style: ConflictStyle) -> MergeResult {
+  let base = base.split_inclusive('\n').collect::<Vec<_>>();
+  let local = local.split_inclusive('\n').collect::<Vec<_>>();
+  let remote = remote.split_inclusive('\n').collect::<Vec<_>>();
+  let local_matches = matches(&base, &local);
+  let remote_matches = matches(&base, &remote);
+
+  let mut output = Output { lines: Vec::new() };
+  let mut conflicts = Vec::new();
+  let (mut i, mut a, mut b) = (0, 0, 0);
+  while i < base.len() || a < local.len() || b < remote.len() {
+    // 两边都没有修改的行
+    if i < base.len() && local_matches[i] == Some(a) && remote_matches[i] == Some(b) {
+      output.push([base[i]]);
+      (i, a, b) = (i + 1, a + 1, b + 1);
+      continue;
+    }
+
+    // 到下一个两边都没有修改的行之前为一段修改
+    let next = (i..base.len()).find_map(|j| match (local_matches[j], remote_matches[j]) {
+      (Some(x), Some(y)) => Some((j, x, y)),
+      _ => None,
+    });
+    let (j, x, y) = next.unwrap_or((base.len(), local.len(), remote.len()));
+    let (base_chunk, local_chunk, remote_chunk) = (&base[i..j], &local[a..x], &remote[b..y]);
+    if local_chunk == base_chunk || local_chunk == remote_chunk {
+      output.push(remote_chunk.iter().copied());
+    } else if remote_chunk == base_chunk {
+      output.push(local_chunk.iter().copied());
+    } else if !(base_chunk.is_empty()
+      && !base.is_empty()
+      && merge_inserts(
+        &mut output,
+        local_chunk,
+        remote_chunk,
+        i == 0 || is_blank(base[i - 1]),
+      ))
+    {
+      conflicts.push(MergeConflict {
+        line: output.lines.len() + 1,
+        base: base_chunk.concat(),
+        local: local_chunk.concat(),
+        remote: remote_chunk.concat(),
+      });
+      match style {
+        ConflictStyle::Markers => {
+          output.push([LOCAL_MARKER]);
+          output.push(local_chunk.iter().copied());
+          output.push([SEPARATOR_MARKER]);
+          output.push(remote_chunk.iter().copied());
+          output.push([REMOTE_MARKER]);
+          // 冲突在文件末尾时保持原有的结尾
+          if local.last().is_none_or(|it| it.ends_with('\n')) {
+            output.end_line();
+          }
+        }
+        ConflictStyle::Local => output.push(local_chunk.iter().copied()),
+        ConflictStyle::Remote => output.push(remote_chunk.iter().copied()),
+      }
+    }
+    (i, a, b) = (j, x, y);
+  }
+
+  MergeResult {
+    content: output.lines.concat(),
+    conflicts,
+  }
+}
+
+/// 合并笔记的结果
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NoteMerge {
+  #[serde(flatten)]
+  pub result: MergeResult,
+  /// 保存远程版本的冲突笔记 id
+  pub conflict_note_id: Option<String>,
+}
+
+/// 冲突笔记的标题，和同一分组中已有的笔记不重复
+pub(super) async fn conflict_title(db: &DatabaseHandler, note: &Note) -> Result<String> {
+  let titles = db
+    .find_all_notes()
+    .await?
+    .into_iter()
+    .filter(|it| it.category == note.category)
+    .map(|it| it.title)
+    .collect::<Vec<_>>();
+  let title = (1..)
+    .map(|n| match n {
+      1 => format!("{}（冲突）", note.title),
+      n => format!("{}（冲突 {n}）", note.title),
+    })
+    .find(|it| !titles.contains(it))
+    .unwrap_or_default();
+  Ok(title)
+}
+
+/// 将远程的修改合并到本机的笔记中
+///
+/// `conflict_note` 为 `true` 时冲突部分保留本机的内容，
+/// 远程的版本另存为同一分组中的冲突笔记；否则在正文中插入冲突标记
+pub async fn merge_note(
+  db: &DatabaseHandler,
+  id: &str,
+  base: &str,
+  remote: &str,
+  conflict_note: bool,
+) -> Result<NoteMerge> {
+  let note = db
+    .find_note_by_id(id)
+    .await?
+    .ok_or_else(|| Error::NotFound(format!("note({id})")))?;
+  let style = match conflict_note {
+    true => ConflictStyle::Local,
+    false => ConflictStyle::Markers,
+  };
+  let result = merge_text(base, &note.content, remote, style);
+  let content = Some(result.content.as_str()).filter(|it| *it != note.content);
+
+  let copy = match conflict_note && !result.is_clean() {
+    true => Some(Note {
+      id: new_note_id(),
+      title: conflict_title(db, &note).await?,
+      content: merge_text(base, &note.content, remote, ConflictStyle::Remote).content,
+      ..note
+    }),
+    false => None,
+  };
+  db.save_merged_note(id, content, copy.as_ref()).await?;
+  Ok(NoteMerge {
+    result,
+    conflict_note_id: copy.map(|it| it.id),
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tauri::async_runtime::block_on;
+
+  fn merge(base: &str, local: &str, remote: &str) -> MergeResult {
+    merge_text(base, local, remote, ConflictStyle::Markers)
+  }
+
+  #[test]
+  fn applies_changes_from_one_side() {
+    let base = "# 标题\n\n第一段\n\n第二段\n";
+    let local = "# 标题\n\n第一段（本机）\n\n第二段\n";
+    let remote = "# 标题\n\n第一段\n\n第二段（远程）\n";
+    let result = merge(base, local, remote);
+    assert!(result.is_clean());
+    assert_eq!(
+      result.content,
+      "# 标题\n\n第一段（本机）\n\n第二段（远程）\n"
+    );
+
+    // 两边相同的修改只保留一份
+    let result = merge(base, local, local);
+    assert!(result.is_clean());
+    assert_eq!(result.content, local);
+  }
+
+  #[test]
+  fn keeps_both_paragraph_inserts() {
+    let base = "第一段\n\n第二段\n";
+    let local = "第一段\n\n本机插入\n\n第二段\n";
+    let remote = "第一段\n\n远程插入\n\n第二段\n";
+    let result = merge(base, local, remote);
+    assert!(result.is_clean());
+    assert_eq!(result.content, "第一段\n\n本机插入\n\n远程插入\n\n第二段\n");
+  }
+
+  #[test]
+  fn marks_conflicting_changes() {
+    let base = "第一行\n第二行\n第三行\n";
+    let local = "第一行\n本机\n第三行\n";
+    let remote = "第一行\n远程\n第三行\n";
+    let result = merge(base, local, remote);
+    assert_eq!(
+      result.content,
+      "第一行\n<<<<<<< 本机\n本机\n=======\n远程\n>>>>>>> 远程\n第三行\n"
+    );
+    assert_eq!(result.conflicts.len(), 1);
+    let conflict = &result.conflicts[0];
+    assert_eq!(conflict.line, 2);
+    assert_eq!(
+      (
+        conflict.base.as_str(),
+        conflict.local.as_str(),
+        conflict.remote.as_str()
+      ),
+      ("第二行\n", "本机\n", "远程\n")
+    );
+
+    let local_style = merge_text(base, local, remote, ConflictStyle::Local);
+    assert_eq!(local_style.content, local);
+    let remote_style = merge_text(base, local, remote, ConflictStyle::Remote);
+    assert_eq!(remote_style.content, remote);
+    assert_eq!(remote_style.conflicts.len(), 1);
+  }
+
+  #[test]
+  fn conflicts_on_inserts_inside_paragraph() {
+    let base = "第一行\n第二行\n";
+    let result = merge(base, "第一行\n本机\n第二行\n", "第一行\n远程\n第二行\n");
+    assert_eq!(result.conflicts.len(), 1);
+  }
+
+  #[test]
+  fn conflicts_on_whole_document_inserts() {
+    let result = merge("", "本机的笔记\n", "远程的笔记\n");
+    assert_eq!(result.conflicts.len(), 1);
+    assert_eq!(
+      result.content,
+      "<<<<<<< 本机\n本机的笔记\n=======\n远程的笔记\n>>>>>>> 远程\n"
+    );
+    // 只有一边写入时直接使用该内容
+    assert!(merge("", "本机的笔记\n", "").is_clean());
+  }
+
+  #[test]
+  fn keeps_missing_trailing_newline() {
+    let base = "第一行\n\n第三行";
+    let result = merge(base, "第一行（本机）\n\n第三行", "第一行\n\n第三行（远程）");
+    assert!(result.is_clean());
+    assert_eq!(result.content, "第一行（本机）\n\n第三行（远程）");
+  }
+
+  #[test]
+  fn saves_conflict_note_with_merged_content() {
+    let app_data =
+      std::env::temp_dir().join(format!("note-secretary-{}", uuid::Uuid::new_v4().simple()));
+    std::fs::create_dir_all(&app_data).unwrap();
+    block_on(async {
+      let db = DatabaseHandler::open(&app_data).await.unwrap();
+      let note = Note {
+        id: new_note_id(),
+        category: "默认".to_string(),
+        title: "笔记".to_string(),
+        summary: String::new(),
+        content: "第一行\n本机\n第三行\n".to_string(),
+      };
+      db.insert_note(&note).await.unwrap();
+
+      let base = "第一行\n第二行\n第三行\n";
+      let merge = merge_note(&db, &note.id, base, "第一行\n远程\n第三行\n", true)
+        .await
+        .unwrap();
+      assert_eq!(merge.result.conflicts.len(), 1);
+      let copy_id = merge.conflict_note_id.unwrap();
+      let copy = db.find_note_by_id(&copy_id).await.unwrap().unwrap();
+      assert_eq!(copy.title, "笔记（冲突）");
+      assert_eq!(copy.content, "第一行\n远程\n第三行\n");
+      let local = db.find_note_by_id(&note.id).await.unwrap().unwrap();
+      assert_eq!(local.content, note.content);
+
+      // 没有冲突时只更新正文
+      let merge = merge_note(
+        &db,
+        &note.id,
+        &note.content,
+        "第一行\n本机\n第三行\n新增\n",
+        true,
+      )
+      .await
+      .unwrap();
+      assert!(merge.result.is_clean() && merge.conflict_note_id.is_none());
+      let local = db.find_note_by_id(&note.id).await.unwrap().unwrap();
+      assert_eq!(local.content, "第一行\n本机\n第三行\n新增\n");
+    });
+    let _ = std::fs::remove_dir_all(&app_data);
+  }
+}
@@ -0,0 +1,203 @@
+mod engine;
+mod merge;
+mod s3;
+#[cfg(test)]
+mod s3_stub;
+
+use crate::AppDataPath;
+use crate::database::DatabaseHandler;
+use crate::emitter::event;
+use crate::error::{OnceLockSetup, Result};
+use crate::files::FieldCipher;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::sync::mpsc::{RecvTimeoutError, Sender, channel};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tauri::async_runtime::block_on;
+use tauri::{AppHandle, Manager};
+
+pub use engine::check_connection;
+pub use merge::{ConflictStyle, MergeResult, NoteMerge, merge_note, merge_text};
+
+/// 同步设置在设置中的名称
+pub const SYNC_SETTINGS_KEY: &str = "s3_sync";
+/// 同步状态事件，内容为 [`SyncStatus`]
+const SYNC_EVENT: &str = "s3-sync-event";
+/// 收到同步请求后等待的时间，连续的修改只同步一次
+const DEBOUNCE: Duration = Duration::from_secs(3);
+
+static TRIGGER: OnceLock<Sender<()>> = OnceLock::new();
+static STATUS: Mutex<Option<SyncStatus>> = Mutex::new(None);
+
+/// S3 兼容对象存储的同步设置
+///
+/// `secret_access_key` 和 `passphrase` 使用笔记的数据密钥加密后保存在数据库中
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct SyncSettings {
+  pub enabled: bool,
+  /// 服务地址，如 `https://s3.amazonaws.com`、`http://127.0.0.1:9000`
+  pub endpoint: String,
+  pub region: String,
+  pub bucket: String,
+  /// 对象名前缀，多个应用共用存储桶时使用
+  pub prefix: String,
+  pub access_key_id: String,
+  pub secret_access_key: String,
+  /// 同步密码，上传的内容使用由其派生的密钥加密，所有设备需要使用同一密码
+  pub passphrase: String,
+  /// 使用 `endpoint/bucket/key` 形式的地址，MinIO 等自建服务需要开启
+  pub path_style: bool,
+  /// 自动同步的间隔，分钟
+  pub interval_minutes: u64,
+}
+
+impl Default for SyncSettings {
+  fn default() -> Self {
+    Self {
+      enabled: false,
+      endpoint: String::new(),
+      region: "us-east-1".to_string(),
+      bucket: String::new(),
+      prefix: String::new(),
+      access_key_id: String::new(),
+      secret_access_key: String::new(),
+      passphrase: String::new(),
+      path_style: true,
+      interval_minutes: 15,
+    }
+  }
+}
+
+impl SyncSettings {
+  /// 对象名前缀，非空时以 `/` 结尾
+  fn key_prefix(&self) -> String {
+    match self.prefix.trim_matches('/') {
+      "" => String::new(),
+      prefix => format!("{prefix}/"),
+    }
+  }
+
+  fn interval(&self) -> Duration {
+    Duration::from_secs(self.interval_minutes.max(1) * 60)
+  }
+}
+
+/// 读取同步设置，敏感字段解密后返回
+pub async fn load_sync_settings(db: &DatabaseHandler, app_data: &Path) -> Result<SyncSettings> {
+  let mut settings: SyncSettings = db.get_setting(SYNC_SETTINGS_KEY).await?;
+  let cipher = FieldCipher::new(app_data);
+  settings.secret_access_key = cipher.open(&settings.secret_access_key)?;
+  settings.passphrase = cipher.open(&settings.passphrase)?;
+  Ok(settings)
+}
+
+/// 加密敏感字段后保存同步设置，并立即按新设置同步一次
+pub async fn save_sync_settings(
+  db: &DatabaseHandler,
+  app_data: &Path,
+  mut settings: SyncSettings,
+) -> Result<()> {
+  let cipher = FieldCipher::new(app_data);
+  settings.secret_access_key = cipher.seal(&settings.secret_access_key)?;
+  settings.passphrase = cipher.seal(&settings.passphrase)?;
+  db.save_setting(SYNC_SETTINGS_KEY, &settings).await?;
+  request_sync();
+  Ok(())
+}
+
+/// 最近一次同步的结果
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SyncStatus {
+  running: bool,
+  /// 上次成功完成同步的时间，毫秒时间戳
+  last_synced_at: Option<u64>,
+  uploaded: usize,
+  downloaded: usize,
+  deleted: usize,
+  /// 本地和远程都修改过的对象，保留本地的版本，远程的版本另存为副本
+  conflicts: Vec<String>,
+  /// 失败原因，部分内容失败时其余内容仍会同步
+  error: Option<String>,
+}
+
+fn now_millis() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|it| it.as_millis() as u64)
+    .unwrap_or_default()
+}
+
+fn report(status: SyncStatus) {
+  *STATUS.lock().unwrap_or_else(|it| it.into_inner()) = Some(status.clone());
+  event(SYNC_EVENT, status);
+}
+
+/// 最近一次同步的结果，还没有同步时为空
+pub fn sync_status() -> Option<SyncStatus> {
+  STATUS.lock().unwrap_or_else(|it| it.into_inner()).clone()
+}
+
+/// 通知后台同步，数据修改后调用，未开启同步时忽略
+pub fn request_sync() {
+  if let Some(trigger) = TRIGGER.get() {
+    let _ = trigger.send(());
+  }
+}
+
+/// 执行一次同步，返回距离下次自动同步的间隔
+async fn run_once(app: &AppHandle) -> Duration {
+  let path = app.state::<AppDataPath>();
+  let database = app.state::<DatabaseHandler>();
+  let settings = match load_sync_settings(&database, &path.0).await {
+    Ok(it) => it,
+    Err(_) => return SyncSettings::default().interval(),
+  };
+  if !settings.enabled {
+    return settings.interval();
+  }
+
+  let last = sync_status().unwrap_or_default();
+  report(SyncStatus {
+    running: true,
+    ..last.clone()
+  });
+  let status = match engine::sync(&path.0, &database, &settings).await {
+    Ok(status) => SyncStatus {
+      last_synced_at: Some(now_millis()),
+      ..status
+    },
+    Err(e) => SyncStatus {
+      running: false,
+      last_synced_at: last.last_synced_at,
+      error: Some(e.to_string()),
+      ..Default::default()
+    },
+  };
+  report(status);
+  settings.interval()
+}
+
+/// 启动同步的后台线程：启动时先拉取一次，之后按间隔或收到同步请求时同步
+pub fn setup_sync(app: &tauri::App) -> tauri::Result<()> {
+  let (sender, receiver) = channel();
+  TRIGGER.setup(sender, "sync trigger")?;
+  let app = app.handle().clone();
+  std::thread::spawn(move || {
+    let mut wait = Duration::ZERO;
+    loop {
+      match receiver.recv_timeout(wait) {
+        Ok(()) => {
+          std::thread::sleep(DEBOUNCE);
+          while receiver.try_recv().is_ok() {}
+        }
+        Err(RecvTimeoutError::Timeout) => {}
+        Err(RecvTimeoutError::Disconnected) => break,
+      }
+      wait = block_on(run_once(&app));
+    }
+  });
+  Ok(())
+}
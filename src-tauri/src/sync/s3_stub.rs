@@ -0,0 +1,339 @@
+use super::SyncSettings;
+use super::s3::ENCODE_SET;
+use hmac::{Hmac, Mac};
+use percent_encoding::{percent_decode_str, utf8_percent_encode};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex, OnceLock};
+
+/// 进程内服务的访问密钥和存储桶
+const ACCESS_KEY_ID: &str = "stub-access-key";
+const SECRET_ACCESS_KEY: &str = "stub-secret-key";
+const BUCKET: &str = "note-secretary";
+/// 列出对象时每页最多返回的数量，用于测试分页
+const PAGE_SIZE: usize = 2;
+
+type Objects = Arc<Mutex<BTreeMap<String, Vec<u8>>>>;
+
+/// 测试使用的同步设置，每次调用使用不同的对象名前缀
+///
+/// 设置了 `SYNC_TEST_ENDPOINT` 时连接该地址的服务（如本机的 MinIO），
+/// 同时读取 `SYNC_TEST_REGION`、`SYNC_TEST_BUCKET`、`SYNC_TEST_ACCESS_KEY` 和
+/// `SYNC_TEST_SECRET_KEY`；否则使用进程内只实现同步所需接口的服务
+pub(super) fn test_settings() -> SyncSettings {
+  let var = |name: &str, default: &str| std::env::var(name).unwrap_or_else(|_| default.to_string());
+  let settings = match std::env::var("SYNC_TEST_ENDPOINT") {
+    Ok(endpoint) => SyncSettings {
+      endpoint,
+      region: var("SYNC_TEST_REGION", "us-east-1"),
+      bucket: var("SYNC_TEST_BUCKET", BUCKET),
+      access_key_id: var("SYNC_TEST_ACCESS_KEY", "minioadmin"),
+      secret_access_key: var("SYNC_TEST_SECRET_KEY", "minioadmin"),
+      ..Default::default()
+    },
+    Err(_) => SyncSettings {
+      endpoint: stub_endpoint(),
+      bucket: BUCKET.to_string(),
+      access_key_id: ACCESS_KEY_ID.to_string(),
+      secret_access_key: SECRET_ACCESS_KEY.to_string(),
+      ..Default::default()
+    },
+  };
+  SyncSettings {
+    enabled: true,
+    prefix: format!("test-{}", uuid::Uuid::new_v4().simple()),
+    passphrase: "sync passphrase".to_string(),
+    path_style: true,
+    ..settings
+  }
+}
+
+/// 启动进程内的服务，全部测试共用，返回服务地址
+fn stub_endpoint() -> String {
+  static ENDPOINT: OnceLock<String> = OnceLock::new();
+  let endpoint = ENDPOINT.get_or_init(|| {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("无法启动测试服务");
+    let endpoint = format!(
+      "http://{}",
+      listener.local_addr().expect("无法读取服务地址")
+    );
+    let objects = Objects::default();
+    std::thread::spawn(move || {
+      for stream in listener.incoming().flatten() {
+        let objects = objects.clone();
+        std::thread::spawn(move || serve(stream, objects));
+      }
+    });
+    endpoint
+  });
+  endpoint.clone()
+}
+
+struct Request {
+  method: String,
+  path: String,
+  query: String,
+  headers: HashMap<String, String>,
+  body: Vec<u8>,
+}
+
+impl Request {
+  fn header(&self, name: &str) -> &str {
+    self
+      .headers
+      .get(name)
+      .map(String::as_str)
+      .unwrap_or_default()
+  }
+
+  /// 解码后的查询参数
+  fn params(&self) -> BTreeMap<String, String> {
+    self
+      .query
+      .split('&')
+      .filter(|it| !it.is_empty())
+      .map(|it| {
+        let (name, value) = it.split_once('=').unwrap_or((it, ""));
+        (decode(name), decode(value))
+      })
+      .collect()
+  }
+}
+
+struct Reply {
+  status: u16,
+  headers: Vec<(&'static str, String)>,
+  body: Vec<u8>,
+}
+
+impl Reply {
+  fn new(status: u16, body: impl Into<Vec<u8>>) -> Self {
+    Self {
+      status,
+      headers: Vec::new(),
+      body: body.into(),
+    }
+  }
+
+  fn error(status: u16, code: &str) -> Self {
+    let body = format!("<Error><Code>{code}</Code><Message>{code}</Message></Error>");
+    Self::new(status, body)
+  }
+}
+
+fn decode(value: &str) -> String {
+  percent_decode_str(value).decode_utf8_lossy().to_string()
+}
+
+fn encode(value: &str) -> String {
+  utf8_percent_encode(value, ENCODE_SET).to_string()
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+  Sha256::digest(data)
+    .iter()
+    .map(|it| format!("{it:02x}"))
+    .collect()
+}
+
+fn hmac_sha256(key: &[u8], data: &str) -> Vec<u8> {
+  let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC 接受任意长度的密钥");
+  mac.update(data.as_bytes());
+  mac.finalize().into_bytes().to_vec()
+}
+
+fn read_request(reader: &mut BufReader<TcpStream>) -> Option<Request> {
+  let mut line = String::new();
+  if reader.read_line(&mut line).ok()? == 0 {
+    return None;
+  }
+  let mut parts = line.split_whitespace();
+  let method = parts.next()?.to_string();
+  let target = parts.next()?;
+  let (path, query) = target.split_once('?').unwrap_or((target, ""));
+  let (path, query) = (path.to_string(), query.to_string());
+
+  let mut headers = HashMap::new();
+  loop {
+    let mut line = String::new();
+    reader.read_line(&mut line).ok()?;
+    let Some((name, value)) = line.trim_end().split_once(':') else {
+      break;
+    };
+    headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_string());
+  }
+  let length = headers
+    .get("content-length")
+    .and_then(|it| it.parse().ok())
+    .unwrap_or(0);
+  let mut body = vec![0; length];
+  reader.read_exact(&mut body).ok()?;
+  Some(Request {
+    method,
+    path,
+    query,
+    headers,
+    body,
+  })
+}
+
+fn serve(stream: TcpStream, objects: Objects) {
+  let Ok(mut writer) = stream.try_clone() else {
+    return;
+  };
+  let mut reader = BufReader::new(stream);
+  while let Some(request) = read_request(&mut reader) {
+    let reply = match verify(&request) {
+      Ok(()) => handle(&request, &objects),
+      Err(code) => Reply::error(403, code),
+    };
+    let mut head = format!(
+      "HTTP/1.1 {} Stub\r\nContent-Length: {}\r\n",
+      reply.status,
+      reply.body.len()
+    );
+    for (name, value) in &reply.headers {
+      head.push_str(&format!("{name}: {value}\r\n"));
+    }
+    head.push_str("\r\n");
+    if writer.write_all(head.as_bytes()).is_err() || writer.write_all(&reply.body).is_err() {
+      return;
+    }
+  }
+}
+
+/// 按 SigV4 重新计算签名并与请求中的签名比较，失败时返回错误码
+fn verify(request: &Request) -> Result<(), &'static str> {
+  let authorization = request.header("authorization");
+  let field = |name: &str| {
+    authorization
+      .split([' ', ','])
+      .find_map(|it| it.strip_prefix(name))
+      .unwrap_or_default()
+  };
+  let credential = field("Credential=").split('/').collect::<Vec<_>>();
+  let [access_key_id, date, region, "s3", "aws4_request"] = credential.as_slice() else {
+    return Err("AuthorizationHeaderMalformed");
+  };
+  if *access_key_id != ACCESS_KEY_ID {
+    return Err("InvalidAccessKeyId");
+  }
+  let payload_hash = request.header("x-amz-content-sha256");
+  if payload_hash != sha256_hex(&request.body) {
+    return Err("XAmzContentSHA256Mismatch");
+  }
+  let date_time = request.header("x-amz-date");
+  if !date_time.starts_with(date) {
+    return Err("AuthorizationHeaderMalformed");
+  }
+
+  // 路径和查询参数按规范重新编码，查询参数按名称排序
+  let path = request
+    .path
+    .split('/')
+    .map(|it| encode(&decode(it)))
+    .collect::<Vec<_>>()
+    .join("/");
+  let query = request
+    .params()
+    .iter()
+    .map(|(name, value)| format!("{}={}", encode(name), encode(value)))
+    .collect::<Vec<_>>()
+    .join("&");
+  let signed_headers = field("SignedHeaders=");
+  let headers = signed_headers
+    .split(';')
+    .map(|name| format!("{name}:{}\n", request.header(name)))
+    .collect::<String>();
+  let canonical_request = format!(
+    "{}\n{path}\n{query}\n{headers}\n{signed_headers}\n{payload_hash}",
+    request.method
+  );
+  let scope = format!("{date}/{region}/s3/aws4_request");
+  let string_to_sign = format!(
+    "AWS4-HMAC-SHA256\n{date_time}\n{scope}\n{}",
+    sha256_hex(canonical_request.as_bytes())
+  );
+  let signing_key = [*date, *region, "s3", "aws4_request"].iter().fold(
+    format!("AWS4{SECRET_ACCESS_KEY}").into_bytes(),
+    |key, it| hmac_sha256(&key, it),
+  );
+  let signature = hmac_sha256(&signing_key, &string_to_sign)
+    .iter()
+    .map(|it| format!("{it:02x}"))
+    .collect::<String>();
+  match field("Signature=") == signature {
+    true => Ok(()),
+    false => Err("SignatureDoesNotMatch"),
+  }
+}
+
+fn etag(data: &[u8]) -> String {
+  format!("\"{}\"", &sha256_hex(data)[..32])
+}
+
+fn handle(request: &Request, objects: &Objects) -> Reply {
+  let Some(key) = request.path.strip_prefix(&format!("/{BUCKET}/")) else {
+    return Reply::error(404, "NoSuchBucket");
+  };
+  let key = key.split('/').map(decode).collect::<Vec<_>>().join("/");
+  let mut objects = objects.lock().unwrap_or_else(|it| it.into_inner());
+  match (request.method.as_str(), key.as_str()) {
+    ("GET", "") => list(&objects, &request.params()),
+    ("GET", _) => match objects.get(&key) {
+      Some(data) => Reply {
+        headers: vec![("ETag", etag(data))],
+        ..Reply::new(200, data.clone())
+      },
+      None => Reply::error(404, "NoSuchKey"),
+    },
+    ("PUT", _) => {
+      let reply = Reply {
+        headers: vec![("ETag", etag(&request.body))],
+        ..Reply::new(200, Vec::new())
+      };
+      objects.insert(key, request.body.clone());
+      reply
+    }
+    ("DELETE", _) => {
+      objects.remove(&key);
+      Reply::new(204, Vec::new())
+    }
+    _ => Reply::error(405, "MethodNotAllowed"),
+  }
+}
+
+/// ListObjectsV2，继续列出的标识为上一页最后一个对象名
+fn list(objects: &BTreeMap<String, Vec<u8>>, params: &BTreeMap<String, String>) -> Reply {
+  if params.get("list-type").map(String::as_str) != Some("2") {
+    return Reply::error(400, "InvalidArgument");
+  }
+  let prefix = params.get("prefix").cloned().unwrap_or_default();
+  let after = params.get("continuation-token");
+  let mut matched = objects
+    .iter()
+    .filter(|(key, _)| key.starts_with(&prefix) && after.is_none_or(|it| *key > it));
+  let page = matched.by_ref().take(PAGE_SIZE).collect::<Vec<_>>();
+  let truncated = matched.next().is_some();
+
+  let escape = |it: &str| quick_xml::escape::escape(it).to_string();
+  let mut body = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?><ListBucketResult>");
+  for (key, data) in &page {
+    body.push_str(&format!(
+      "<Contents><Key>{}</Key><ETag>{}</ETag></Contents>",
+      escape(key),
+      escape(&etag(data))
+    ));
+  }
+  body.push_str(&format!("<IsTruncated>{truncated}</IsTruncated>"));
+  if let Some((last, _)) = page.last().filter(|_| truncated) {
+    body.push_str(&format!(
+      "<NextContinuationToken>{}</NextContinuationToken>",
+      escape(last)
+    ));
+  }
+  body.push_str("</ListBucketResult>");
+  Reply::new(200, body)
+}
@@ -1,49 +1,119 @@
 use super::{RespData, result_to_resp};
+use crate::AppDataPath;
 use crate::error::{Error, Result};
+use crate::files::{ChatFile, ThumbnailFormat, ThumbnailSource, read_note_file, thumbnail};
 use mime_guess::{from_path as guess_mime, mime};
+use std::path::PathBuf;
 use tauri::async_runtime::{spawn, spawn_blocking};
 use tauri::http::Request;
-use tauri::{Runtime, UriSchemeContext, UriSchemeResponder as Resp};
+use tauri::{Manager, Runtime, UriSchemeContext, UriSchemeResponder as Resp};
 
 type Ctx<'a, R> = UriSchemeContext<'a, R>;
 
 enum QueryImageType {
   File,
   Id,
+  Note,
 }
 
-async fn handle_image(req: Request<Vec<u8>>) -> Result<RespData> {
-  let query_type = match req.uri().query() {
-    Some(it) if it == "type=file" => QueryImageType::File,
-    Some(it) if it == "type=id" => QueryImageType::Id,
-    _ => return Err(Error::NotFound("image".into())),
-  };
+/// 图片请求参数：`type=file|id|note`，可选 `size=<最长边>` 以及 `format=jpeg|webp`
+struct ImageQuery {
+  query_type: QueryImageType,
+  size: Option<u32>,
+  format: ThumbnailFormat,
+}
+
+impl ImageQuery {
+  fn parse(query: Option<&str>) -> Result<Self> {
+    let mut query_type = None;
+    let mut size = None;
+    let mut format = ThumbnailFormat::default();
+    for pair in query.unwrap_or_default().split('&') {
+      match pair.split_once('=') {
+        Some(("type", "file")) => query_type = Some(QueryImageType::File),
+        Some(("type", "id")) => query_type = Some(QueryImageType::Id),
+        Some(("type", "note")) => query_type = Some(QueryImageType::Note),
+        Some(("size", value)) => {
+          let value = value.parse().map_err(|_| Error::new("非法的图片尺寸"))?;
+          size = Some(value);
+        }
+        Some(("format", value)) => {
+          format = ThumbnailFormat::parse(value).ok_or_else(|| Error::new("不支持的图片格式"))?;
+        }
+        _ => {}
+      }
+    }
+    let query_type = query_type.ok_or_else(|| Error::NotFound("image".into()))?;
+    Ok(Self {
+      query_type,
+      size,
+      format,
+    })
+  }
+}
 
-  let path = percent_encoding::percent_decode(&req.uri().path().as_bytes())
+async fn handle_image(app_data: PathBuf, req: Request<Vec<u8>>) -> Result<RespData> {
+  let query = ImageQuery::parse(req.uri().query())?;
+
+  let path = percent_encoding::percent_decode(req.uri().path().as_bytes())
     .decode_utf8_lossy()
     .to_string();
 
-  match query_type {
-    QueryImageType::File => {
-      let reader = {
-        let path = path.clone();
-        move || std::fs::read(path)
-      };
-      let file = spawn_blocking(reader).await??;
+  // `id` 与 `note` 的路径为 `<chat_id|note_id>/<file_id>`
+  let split_ids = || {
+    path
+      .trim_start_matches('/')
+      .split_once('/')
+      .map(|(owner, file_id)| (owner.to_string(), file_id.to_string()))
+      .ok_or_else(|| Error::NotFound("image".into()))
+  };
+  let source = match query.query_type {
+    QueryImageType::File => ThumbnailSource::Path(PathBuf::from(&path)),
+    QueryImageType::Id => {
+      let (chat_id, file_id) = split_ids()?;
+      ThumbnailSource::ChatFile { chat_id, file_id }
+    }
+    QueryImageType::Note => {
+      let (note_id, file_id) = split_ids()?;
+      ThumbnailSource::NoteFile { note_id, file_id }
+    }
+  };
+
+  // 指定尺寸时返回缩略图
+  if let Some(size) = query.size {
+    let data = thumbnail(&app_data, source, size, query.format).await?;
+    return Ok((query.format.mime_type(), data));
+  }
+
+  match source {
+    ThumbnailSource::Path(file_path) => {
+      let file = spawn_blocking(move || std::fs::read(file_path)).await??;
       let mime_type = guess_mime(&path)
         .first_raw()
         .unwrap_or(mime::IMAGE_JPEG.essence_str());
       Ok((mime_type, file))
     }
-    QueryImageType::Id => {
-      todo!("需要确定文件保存后实现")
+    ThumbnailSource::ChatFile { chat_id, file_id } => {
+      let file = ChatFile::new(&chat_id, &file_id).read(&app_data).await?;
+      let mime_type = infer::get(&file)
+        .map(|it| it.mime_type())
+        .unwrap_or(mime::IMAGE_JPEG.essence_str());
+      Ok((mime_type, file))
+    }
+    ThumbnailSource::NoteFile { note_id, file_id } => {
+      let file = read_note_file(&app_data, &note_id, &file_id).await?;
+      let mime_type = infer::get(&file)
+        .map(|it| it.mime_type())
+        .unwrap_or(mime::IMAGE_JPEG.essence_str());
+      Ok((mime_type, file))
     }
   }
 }
 
-pub fn handler<R: Runtime>(_: Ctx<'_, R>, req: Request<Vec<u8>>, resp: Resp) {
+pub fn handler<R: Runtime>(ctx: Ctx<'_, R>, req: Request<Vec<u8>>, resp: Resp) {
+  let app_data = ctx.app_handle().state::<AppDataPath>().0.clone();
   spawn(async move {
-    let result = handle_image(req).await;
+    let result = handle_image(app_data, req).await;
     resp.respond(result_to_resp(result));
   });
 }